@@ -395,7 +395,7 @@ pub fn gatt(input: TokenStream) -> TokenStream {
 
             // If "Client Characteristic Configuration" Descriptor is present,
             // It must always be the first one after the characteristic attribute.
-            // This assumption arises in `../../bleps/src/async_attribute_server.rs`
+            // This assumption arises in `../../bleps/src/attribute_server.rs`
             if characteristic.notify {
                 let mut ccd_data: Vec<u8> = Vec::new();
                 ccd_data.extend(&[0u8, 0u8]);
@@ -1,6 +1,18 @@
-use crate::{Data, HciConnection};
+use crate::{Data, HciConnection, PacketQueue, DATA_CAPACITY, DATA_HEADROOM};
 
-#[derive(Debug, Clone, Copy)]
+/// Number of connections for which outstanding ACL packets are tracked
+pub(crate) const MAX_ACL_CONNECTIONS: usize = 8;
+
+/// Largest ACL payload which fits into [Data]
+pub(crate) const MAX_ACL_FRAGMENT_LEN: usize = DATA_CAPACITY - DATA_HEADROOM;
+
+/// Number of ACL packets held back while the controller has no free buffers
+pub(crate) const ACL_QUEUE_SIZE: usize = 4;
+
+/// Time to wait for the controller to free a buffer while the ACL queue is full
+pub const ACL_TIMEOUT_MILLIS: u64 = 1000;
+
+#[derive(Debug, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct AclPacket {
     pub handle: u16,
@@ -113,8 +125,18 @@ impl AclPacket {
 
     // including type (0x02)
     pub fn encode(handle: u16, pb: BoundaryFlag, bc: HostBroadcastFlag, payload: Data) -> Data {
-        let mut data = Data::new(&[0x02]);
+        let mut data = payload;
+        data.prepend(&Self::header(handle, pb, bc, data.len));
+        data
+    }
 
+    /// The packet type and ACL header in front of a payload of `len` bytes
+    pub(crate) fn header(
+        handle: u16,
+        pb: BoundaryFlag,
+        bc: HostBroadcastFlag,
+        len: usize,
+    ) -> [u8; 5] {
         let mut raw_handle = handle;
 
         raw_handle |= match pb {
@@ -131,13 +153,97 @@ impl AclPacket {
             HostBroadcastFlag::Reserved => 0b11,
         } << 14;
 
-        data.append(&[(raw_handle & 0xff) as u8, ((raw_handle >> 8) & 0xff) as u8]);
+        [
+            0x02,
+            (raw_handle & 0xff) as u8,
+            ((raw_handle >> 8) & 0xff) as u8,
+            (len & 0xff) as u8,
+            ((len >> 8) & 0xff) as u8,
+        ]
+    }
+}
+
+/// Tracks the ACL buffers of the controller
+///
+/// The controller reports how many ACL packets it can buffer via LE Read Buffer Size and
+/// frees buffers via the Number Of Completed Packets event. Packets which don't fit into
+/// the controller's buffers are queued until buffers are freed.
+pub(crate) struct AclFlowControl {
+    total_packets: u16,
+    max_len: u16,
+    pending: [(u16, u16); MAX_ACL_CONNECTIONS],
+    pub(crate) queue: PacketQueue<ACL_QUEUE_SIZE>,
+}
 
-        let len = payload.len;
-        data.append(&[(len & 0xff) as u8, ((len >> 8) & 0xff) as u8]);
+impl AclFlowControl {
+    pub(crate) fn new() -> Self {
+        Self {
+            total_packets: 0,
+            max_len: 0,
+            pending: [(0, 0); MAX_ACL_CONNECTIONS],
+            queue: PacketQueue::new(),
+        }
+    }
 
-        data.append(payload.as_slice());
+    /// Set the buffer size read from the controller
+    pub(crate) fn set_buffer_size(&mut self, max_len: u16, total_packets: u16) {
+        self.max_len = max_len;
+        self.total_packets = total_packets;
+    }
 
-        data
+    /// Largest payload to send in a single ACL packet
+    pub(crate) fn fragment_len(&self) -> usize {
+        match self.max_len as usize {
+            0 => MAX_ACL_FRAGMENT_LEN,
+            len => usize::min(len, MAX_ACL_FRAGMENT_LEN),
+        }
+    }
+
+    fn outstanding(&self) -> u16 {
+        self.pending.iter().map(|(_, count)| count).sum()
+    }
+
+    /// If the controller has a free buffer, without a known buffer size there is no limit
+    pub(crate) fn can_send(&self) -> bool {
+        self.total_packets == 0 || self.outstanding() < self.total_packets
+    }
+
+    /// Record a packet written to the controller
+    pub(crate) fn sent(&mut self, handle: u16) {
+        if let Some(entry) = self
+            .pending
+            .iter_mut()
+            .find(|(h, count)| *h == handle && *count > 0)
+        {
+            entry.1 += 1;
+        } else if let Some(entry) = self.pending.iter_mut().find(|(_, count)| *count == 0) {
+            *entry = (handle, 1);
+        }
     }
+
+    /// Release buffers reported by a Number Of Completed Packets event
+    pub(crate) fn completed(&mut self, handle: u16, count: u16) {
+        if let Some(entry) = self
+            .pending
+            .iter_mut()
+            .find(|(h, count)| *h == handle && *count > 0)
+        {
+            entry.1 = entry.1.saturating_sub(count);
+        }
+    }
+
+    /// The controller frees all buffers of a connection on disconnect, packets still queued
+    /// for it are dropped
+    pub(crate) fn disconnected(&mut self, handle: u16) {
+        for entry in self.pending.iter_mut().filter(|(h, _)| *h == handle) {
+            entry.1 = 0;
+        }
+        self.queue.retain(|packet| packet_handle(packet) != handle);
+    }
+}
+
+/// Connection handle of an encoded ACL packet including the type byte
+pub(crate) fn packet_handle(packet: &Data) -> u16 {
+    let bytes = packet.as_slice();
+    u16::from_le_bytes([bytes[1], bytes[2]]) & 0b111111111111
 }
@@ -1,4 +1,4 @@
-use crate::{att::Uuid, Data};
+use crate::{att::Uuid, extended_advertising::MAX_EXTENDED_ADVERTISING_DATA_LEN, Data};
 
 pub const AD_FLAG_LE_LIMITED_DISCOVERABLE: u8 = 0b00000001;
 pub const LE_GENERAL_DISCOVERABLE: u8 = 0b00000010;
@@ -123,3 +123,33 @@ pub fn create_advertising_data(ad: &[AdStructure]) -> Result<Data, Advertisement
 
     Ok(data)
 }
+
+/// Creates the data of an extended advertising set
+///
+/// Unlike [create_advertising_data] the result has no length prefix and isn't padded.
+pub fn create_extended_advertising_data(
+    ad: &[AdStructure],
+) -> Result<Data, AdvertisementDataError> {
+    let mut data = Data::default();
+
+    for item in ad.iter() {
+        if data.len + ad_structure_len(item) > MAX_EXTENDED_ADVERTISING_DATA_LEN {
+            return Err(AdvertisementDataError::TooLong);
+        }
+        data.append_ad_structure(item);
+    }
+
+    Ok(data)
+}
+
+fn ad_structure_len(src: &AdStructure) -> usize {
+    2 + match src {
+        AdStructure::Flags(_) => 1,
+        AdStructure::ServiceUuids16(uuids) => uuids.len() * 2,
+        AdStructure::ServiceUuids128(uuids) => uuids.len() * 16,
+        AdStructure::ShortenedLocalName(name) | AdStructure::CompleteLocalName(name) => name.len(),
+        AdStructure::ServiceData16 { data, .. } => data.len() + 2,
+        AdStructure::ManufacturerSpecificData { payload, .. } => payload.len() + 2,
+        AdStructure::Unknown { data, .. } => data.len(),
+    }
+}
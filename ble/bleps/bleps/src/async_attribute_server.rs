@@ -4,6 +4,7 @@ use core::marker::PhantomData;
 use core::cell::RefCell;
 
 use critical_section::Mutex;
+use embedded_hal_async::delay::DelayNs;
 use futures::future::Either;
 use futures::pin_mut;
 use rand_core::{CryptoRng, RngCore};
@@ -15,21 +16,23 @@ use crate::{
     asynch::Ble,
     att::Uuid,
     attribute::Attribute,
-    attribute_server::{AttributeServerError, NotificationData, WorkResult},
+    attribute_server::{AttributeServerError, Connections, NotificationData, WorkResult},
+    clock::Clock,
     Addr,
 };
 
-pub struct AttributeServer<'a, T, R: CryptoRng + RngCore>
+pub struct AttributeServer<'a, T, C, R: CryptoRng + RngCore>
 where
     T: embedded_io_async::Read + embedded_io_async::Write,
+    C: Clock + DelayNs,
 {
-    pub(crate) ble: &'a mut Ble<T>,
-    pub(crate) src_handle: u16,
-    pub(crate) mtu: u16,
+    pub(crate) ble: &'a mut Ble<T, C>,
+    pub(crate) connections: Connections,
     pub(crate) attributes: &'a mut [Attribute<'a>],
+    pub(crate) bonded_peers: Option<&'a [Addr]>,
 
     #[cfg(feature = "crypto")]
-    pub(crate) security_manager: AsyncSecurityManager<'a, Ble<T>, R>,
+    pub(crate) security_manager: AsyncSecurityManager<'a, Ble<T, C>, R>,
 
     #[cfg(feature = "crypto")]
     pub(crate) pin_callback: Option<&'a mut dyn FnMut(u32)>,
@@ -38,18 +41,21 @@ where
     phantom: PhantomData<R>,
 }
 
-impl<'a, T, R: CryptoRng + RngCore> AttributeServer<'a, T, R>
+impl<'a, T, C, R: CryptoRng + RngCore> AttributeServer<'a, T, C, R>
 where
     T: embedded_io_async::Read + embedded_io_async::Write,
+    C: Clock + DelayNs,
 {
     /// Create a new instance of the AttributeServer
     ///
     /// When _NOT_ using the `crypto` feature you can pass a mutual reference to `bleps::no_rng::NoRng`
+    /// With the `crypto` feature the RNG creates the pairing keys, targets without an entropy
+    /// source can seed one from the controller with `Ble::controller_rng`
     pub fn new(
-        ble: &'a mut Ble<T>,
+        ble: &'a mut Ble<T, C>,
         attributes: &'a mut [Attribute<'a>],
         rng: &'a mut R,
-    ) -> AttributeServer<'a, T, R> {
+    ) -> AttributeServer<'a, T, C, R> {
         AttributeServer::new_with_ltk(
             ble,
             attributes,
@@ -61,12 +67,12 @@ where
 
     /// Create a new instance, optionally provide an LTK
     pub fn new_with_ltk(
-        ble: &'a mut Ble<T>,
+        ble: &'a mut Ble<T, C>,
         attributes: &'a mut [Attribute<'a>],
         _local_addr: Addr,
         _ltk: Option<u128>,
         _rng: &'a mut R,
-    ) -> AttributeServer<'a, T, R> {
+    ) -> AttributeServer<'a, T, C, R> {
         for (i, attr) in attributes.iter_mut().enumerate() {
             attr.handle = i as u16 + 1;
         }
@@ -92,9 +98,9 @@ where
 
         AttributeServer {
             ble,
-            src_handle: 0,
-            mtu: crate::attribute_server::BASE_MTU,
+            connections: Connections::new(),
             attributes,
+            bonded_peers: None,
 
             #[cfg(feature = "crypto")]
             security_manager,
@@ -116,7 +122,9 @@ where
         None
     }
 
-    /// Run the GATT server until disconnect
+    /// Run the GATT server until a client disconnects
+    ///
+    /// Notifications are only sent to clients which subscribed to the characteristic
     pub async fn run<F, N>(&mut self, notifier: &'a mut F) -> Result<(), AttributeServerError>
     where
         F: FnMut() -> N,
@@ -129,42 +137,6 @@ where
                 let notification: Option<NotificationData> =
                     critical_section::with(|cs| notification_to_send.borrow_ref_mut(cs).take());
 
-                // check if notifications are enabled for the characteristic handle
-                let notification = if let Some(notification) = notification {
-                    let attr = self
-                        .attributes
-                        .iter()
-                        .enumerate()
-                        .find(|(_idx, attr)| attr.handle == notification.handle);
-                    let enabled = if let Some((idx, _)) = attr {
-                        // assume the next descriptor is the "Client Characteristic Configuration" Descriptor
-                        // which is always true when using the macro
-                        if self.attributes.len() > idx + 1
-                            && self.attributes[idx + 1].uuid == Uuid::Uuid16(0x2902)
-                        {
-                            let mut cccd = [0u8; 1];
-                            let cccd_len =
-                                self.get_characteristic_value((idx + 2) as u16, 0, &mut cccd[..]);
-                            if let Some(1) = cccd_len {
-                                cccd[0] == 1
-                            } else {
-                                false
-                            }
-                        } else {
-                            false
-                        }
-                    } else {
-                        false
-                    };
-                    if enabled {
-                        Some(notification)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                };
-
                 self.do_work_with_notification(notification).await
             };
             pin_mut!(notifier_future);
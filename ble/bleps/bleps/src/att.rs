@@ -23,6 +23,8 @@ const ATT_EXECUTE_WRITE_RESP_OPCODE: u8 = 0x19;
 pub const ATT_READ_BLOB_REQ_OPCODE: u8 = 0x0c;
 const ATT_READ_BLOB_RESP_OPCODE: u8 = 0x0d;
 const ATT_HANDLE_VALUE_NTF_OPTCODE: u8 = 0x1b;
+const ATT_HANDLE_VALUE_IND_OPCODE: u8 = 0x1d;
+const ATT_HANDLE_VALUE_CFM_OPCODE: u8 = 0x1e;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -183,6 +185,47 @@ pub enum Att {
     },
 }
 
+/// Responses and server initiated PDUs received by a client
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AttResponse {
+    Error {
+        request_opcode: u8,
+        handle: u16,
+        code: u8,
+    },
+    ExchangeMtu {
+        mtu: u16,
+    },
+    ReadByGroupType {
+        length: u8,
+        data: Data,
+    },
+    ReadByType {
+        length: u8,
+        data: Data,
+    },
+    FindInformation {
+        format: u8,
+        data: Data,
+    },
+    Read {
+        data: Data,
+    },
+    ReadBlob {
+        data: Data,
+    },
+    Write,
+    Notification {
+        handle: u16,
+        data: Data,
+    },
+    Indication {
+        handle: u16,
+        data: Data,
+    },
+}
+
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum AttDecodeError {
@@ -192,9 +235,10 @@ pub enum AttDecodeError {
 }
 
 impl Att {
-    pub fn decode(packet: L2capPacket) -> Result<Self, AttDecodeError> {
-        let opcode = packet.payload.as_slice()[0];
-        let payload = &packet.payload.as_slice()[1..];
+    pub fn decode(packet: &L2capPacket) -> Result<Self, AttDecodeError> {
+        let Some((&opcode, payload)) = packet.payload.as_slice().split_first() else {
+            return Err(AttDecodeError::UnexpectedPayload);
+        };
 
         match opcode {
             ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE => {
@@ -308,6 +352,62 @@ impl Att {
     }
 }
 
+impl AttResponse {
+    pub fn decode(packet: &L2capPacket) -> Result<Self, AttDecodeError> {
+        let Some((&opcode, payload)) = packet.payload.as_slice().split_first() else {
+            return Err(AttDecodeError::UnexpectedPayload);
+        };
+
+        match opcode {
+            ATT_ERROR_RESPONSE_OPCODE if payload.len() == 4 => Ok(Self::Error {
+                request_opcode: payload[0],
+                handle: (payload[1] as u16) + ((payload[2] as u16) << 8),
+                code: payload[3],
+            }),
+            ATT_EXCHANGE_MTU_RESPONSE_OPCODE if payload.len() == 2 => Ok(Self::ExchangeMtu {
+                mtu: (payload[0] as u16) + ((payload[1] as u16) << 8),
+            }),
+            ATT_READ_BY_GROUP_TYPE_RESPONSE_OPCODE if !payload.is_empty() => {
+                Ok(Self::ReadByGroupType {
+                    length: payload[0],
+                    data: Data::new(&payload[1..]),
+                })
+            }
+            ATT_READ_BY_TYPE_RESPONSE_OPCODE if !payload.is_empty() => Ok(Self::ReadByType {
+                length: payload[0],
+                data: Data::new(&payload[1..]),
+            }),
+            ATT_FIND_INFORMATION_RSP_OPCODE if !payload.is_empty() => Ok(Self::FindInformation {
+                format: payload[0],
+                data: Data::new(&payload[1..]),
+            }),
+            ATT_READ_RESPONSE_OPCODE => Ok(Self::Read {
+                data: Data::new(payload),
+            }),
+            ATT_READ_BLOB_RESP_OPCODE => Ok(Self::ReadBlob {
+                data: Data::new(payload),
+            }),
+            ATT_WRITE_RESPONSE_OPCODE => Ok(Self::Write),
+            ATT_HANDLE_VALUE_NTF_OPTCODE if payload.len() >= 2 => Ok(Self::Notification {
+                handle: (payload[0] as u16) + ((payload[1] as u16) << 8),
+                data: Data::new(&payload[2..]),
+            }),
+            ATT_HANDLE_VALUE_IND_OPCODE if payload.len() >= 2 => Ok(Self::Indication {
+                handle: (payload[0] as u16) + ((payload[1] as u16) << 8),
+                data: Data::new(&payload[2..]),
+            }),
+            ATT_ERROR_RESPONSE_OPCODE
+            | ATT_EXCHANGE_MTU_RESPONSE_OPCODE
+            | ATT_READ_BY_GROUP_TYPE_RESPONSE_OPCODE
+            | ATT_READ_BY_TYPE_RESPONSE_OPCODE
+            | ATT_FIND_INFORMATION_RSP_OPCODE
+            | ATT_HANDLE_VALUE_NTF_OPTCODE
+            | ATT_HANDLE_VALUE_IND_OPCODE => Err(AttDecodeError::UnexpectedPayload),
+            _ => Err(AttDecodeError::UnknownOpcode(opcode, Data::new(payload))),
+        }
+    }
+}
+
 impl Data {
     pub fn append_attribute_data(
         &mut self,
@@ -334,9 +434,9 @@ impl Data {
         attribute_value: &Uuid,
     ) {
         let len = attribute_value.len() as u8;
-        if self.data[1] == 0 {
-            self.data[1] = len;
-        } else if self.data[1] != len {
+        if self.as_slice()[1] == 0 {
+            self.as_slice_mut()[1] = len;
+        } else if self.as_slice()[1] != len {
             panic!("Non-uniform UUIDs");
         }
         self.append_attribute_data(attribute_handle, end_group_handle, attribute_value);
@@ -362,11 +462,11 @@ impl Data {
         if size == 0 {
             panic!("Missing attribute payloads");
         }
-        if self.data[1] == 0 {
+        if self.as_slice()[1] == 0 {
             /* set size */
-            self.data[1] = size as u8;
+            self.as_slice_mut()[1] = size as u8;
         } else {
-            if size % self.data[1] as usize > 0 {
+            if size % self.as_slice()[1] as usize > 0 {
                 panic!("Non-uniform attribute payloads");
             }
         }
@@ -398,9 +498,9 @@ impl Data {
     }
 
     pub fn append_att_find_information_response(&mut self, handle: u16, uuid: &Uuid) -> bool {
-        if self.data[1] == 0 {
-            self.data[1] = uuid.get_type();
-        } else if self.data[1] != uuid.get_type() {
+        if self.as_slice()[1] == 0 {
+            self.as_slice_mut()[1] = uuid.get_type();
+        } else if self.as_slice()[1] != uuid.get_type() {
             return false;
         }
 
@@ -442,4 +542,64 @@ impl Data {
         data.append_value(handle);
         data
     }
+
+    pub fn new_att_exchange_mtu_request(mtu: u16) -> Self {
+        let mut data = Self::new(&[ATT_EXCHANGE_MTU_REQUEST_OPCODE]);
+        data.append_value(mtu);
+        data
+    }
+
+    pub fn new_att_read_by_group_type_request(start: u16, end: u16, group_type: &Uuid) -> Self {
+        let mut data = Self::new(&[ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE]);
+        data.append_value(start);
+        data.append_value(end);
+        data.append_uuid(group_type);
+        data
+    }
+
+    pub fn new_att_read_by_type_request(start: u16, end: u16, attribute_type: &Uuid) -> Self {
+        let mut data = Self::new(&[ATT_READ_BY_TYPE_REQUEST_OPCODE]);
+        data.append_value(start);
+        data.append_value(end);
+        data.append_uuid(attribute_type);
+        data
+    }
+
+    pub fn new_att_find_information_request(start: u16, end: u16) -> Self {
+        let mut data = Self::new(&[ATT_FIND_INFORMATION_REQ_OPCODE]);
+        data.append_value(start);
+        data.append_value(end);
+        data
+    }
+
+    pub fn new_att_read_request(handle: u16) -> Self {
+        let mut data = Self::new(&[ATT_READ_REQUEST_OPCODE]);
+        data.append_value(handle);
+        data
+    }
+
+    pub fn new_att_read_blob_request(handle: u16, offset: u16) -> Self {
+        let mut data = Self::new(&[ATT_READ_BLOB_REQ_OPCODE]);
+        data.append_value(handle);
+        data.append_value(offset);
+        data
+    }
+
+    pub fn new_att_write_request(handle: u16, value: &[u8]) -> Self {
+        let mut data = Self::new(&[ATT_WRITE_REQUEST_OPCODE]);
+        data.append_value(handle);
+        data.append(value);
+        data
+    }
+
+    pub fn new_att_write_cmd(handle: u16, value: &[u8]) -> Self {
+        let mut data = Self::new(&[ATT_WRITE_CMD_OPCODE]);
+        data.append_value(handle);
+        data.append(value);
+        data
+    }
+
+    pub fn new_att_value_cfm() -> Self {
+        Self::new(&[ATT_HANDLE_VALUE_CFM_OPCODE])
+    }
 }
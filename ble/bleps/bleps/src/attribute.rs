@@ -22,6 +22,31 @@ pub trait AttData {
         Ok(())
     }
 
+    /// Length the value can't grow beyond, if known
+    ///
+    /// Prepared writes past it are rejected before any of them is executed.
+    fn max_len(&self) -> Option<usize> {
+        None
+    }
+
+    /// Check a write of the complete value without executing it
+    ///
+    /// Prepared writes are only executed once this accepted the value of every attribute
+    /// they write to. By default only [Self::max_len] is checked.
+    fn validate_write(&self, offset: usize, data: &[u8]) -> Result<(), AttErrorCode> {
+        let Some(max_len) = self.max_len() else {
+            return Ok(());
+        };
+
+        if offset > max_len {
+            Err(AttErrorCode::InvalidOffset)
+        } else if offset + data.len() > max_len {
+            Err(AttErrorCode::InvalidAttributeValueLength)
+        } else {
+            Ok(())
+        }
+    }
+
     fn enable_notification(&mut self, _enabled: bool) -> Result<(), AttErrorCode> {
         Ok(())
     }
@@ -64,6 +89,10 @@ impl<'a, const N: usize> AttData for &'a mut [u8; N] {
         true
     }
 
+    fn max_len(&self) -> Option<usize> {
+        Some(N)
+    }
+
     fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), AttErrorCode> {
         if offset > N {
             return Ok(());
@@ -111,6 +140,10 @@ impl<'a> AttData for &'a mut [u8] {
         true
     }
 
+    fn max_len(&self) -> Option<usize> {
+        Some(self.len())
+    }
+
     fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), AttErrorCode> {
         let len = self.len();
         if offset > len {
@@ -165,6 +198,10 @@ impl<'a, T: Sized + 'static> AttData for &'a mut (T,) {
         true
     }
 
+    fn max_len(&self) -> Option<usize> {
+        Some(size_of::<T>())
+    }
+
     fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), AttErrorCode> {
         if offset > size_of::<T>() {
             return Ok(());
@@ -353,7 +390,7 @@ impl<'a> Attribute<'a> {
     pub(crate) fn value(&mut self) -> Result<Data, AttErrorCode> {
         let mut data = Data::default();
         if self.data.readable() {
-            let len = self.data.read(0, data.as_slice_mut())?;
+            let len = self.data.read(0, data.spare_capacity_mut())?;
             data.append_len(len);
         }
         Ok(data)
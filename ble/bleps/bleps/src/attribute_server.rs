@@ -4,25 +4,28 @@ use core::marker::PhantomData;
 use rand_core::{CryptoRng, RngCore};
 
 #[cfg(feature = "crypto")]
-use crate::sm::SecurityManager;
+use crate::sm::{PairingState, SecurityManager};
 use crate::{
-    acl::{AclPacket, BoundaryFlag, HostBroadcastFlag},
     att::{
-        Att, AttDecodeError, AttErrorCode, Uuid, ATT_FIND_BY_TYPE_VALUE_REQUEST_OPCODE,
-        ATT_FIND_INFORMATION_REQ_OPCODE, ATT_PREPARE_WRITE_REQ_OPCODE, ATT_READ_BLOB_REQ_OPCODE,
+        Att, AttDecodeError, AttErrorCode, Uuid, ATT_EXECUTE_WRITE_REQ_OPCODE,
+        ATT_FIND_BY_TYPE_VALUE_REQUEST_OPCODE, ATT_FIND_INFORMATION_REQ_OPCODE,
+        ATT_PREPARE_WRITE_REQ_OPCODE, ATT_READ_BLOB_REQ_OPCODE,
         ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE, ATT_READ_BY_TYPE_REQUEST_OPCODE,
         ATT_READ_REQUEST_OPCODE, ATT_WRITE_REQUEST_OPCODE,
     },
     attribute::Attribute,
     command::{Command, LE_OGF, SET_ADVERTISING_DATA_OCF},
-    event::EventType,
+    connection::ConnectionUpdateParameters,
+    event::{ErrorCode, EventType},
     l2cap::{L2capDecodeError, L2capPacket},
-    Addr, Ble, Data, Error,
+    signaling::{SignalingCommand, LE_SIGNALING_CHANNEL},
+    Addr, Ble, Data, Error, PeerAddressType,
 };
 
 pub const PRIMARY_SERVICE_UUID16: Uuid = Uuid::Uuid16(0x2800);
 pub const CHARACTERISTIC_UUID16: Uuid = Uuid::Uuid16(0x2803);
 pub const GENERIC_ATTRIBUTE_UUID16: Uuid = Uuid::Uuid16(0x1801);
+pub const CLIENT_CHARACTERISTIC_CONFIGURATION_UUID16: Uuid = Uuid::Uuid16(0x2902);
 
 /// The default value of MTU, which can be upgraded through negotiation
 /// with the client.
@@ -37,11 +40,33 @@ pub const MTU: u16 = 256;
 #[cfg(not(any(feature = "mtu128", feature = "mtu256")))]
 pub const MTU: u16 = 23;
 
+/// Maximum number of simultaneous connections the server keeps state for
+pub const MAX_CONNECTIONS: usize = 3;
+
+/// Maximum number of Client Characteristic Configuration descriptors tracked
+/// per connection
+pub const MAX_CCCDS: usize = 8;
+
+/// Maximum number of prepared writes queued per connection
+pub const MAX_PREPARED_WRITES: usize = 8;
+
+/// Size of the per-connection buffer holding prepared write values
+pub const PREPARE_QUEUE_SIZE: usize = 512;
+
+/// Maximum length of an attribute value ([Vol 3] Part F, Section 3.2.9)
+pub const MAX_ATTRIBUTE_VALUE_LEN: usize = 512;
+
 #[derive(Debug, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum WorkResult {
     DidWork,
     GotDisconnected,
+    /// A command was received on the LE signaling channel, e.g. the answer to
+    /// [crate::Ble::request_connection_parameter_update]
+    GotSignaling {
+        handle: u16,
+        command: SignalingCommand,
+    },
 }
 
 #[derive(Debug)]
@@ -50,6 +75,7 @@ pub enum AttributeServerError {
     L2capError(L2capDecodeError),
     AttError(AttDecodeError),
     SecurityManagerError,
+    HciError(Error),
 }
 
 impl From<L2capDecodeError> for AttributeServerError {
@@ -64,15 +90,275 @@ impl From<AttDecodeError> for AttributeServerError {
     }
 }
 
+impl From<Error> for AttributeServerError {
+    fn from(err: Error) -> Self {
+        AttributeServerError::HciError(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SecurityLevel {
+    /// The link is not encrypted
+    NoSecurity,
+    /// The link is encrypted
+    Encrypted,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+struct PreparedWrite {
+    handle: u16,
+    offset: u16,
+    start: usize,
+    len: usize,
+}
+
+/// Prepared writes of one connection, waiting for an Execute Write Request
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PrepareQueue {
+    writes: [PreparedWrite; MAX_PREPARED_WRITES],
+    count: usize,
+    buffer: [u8; PREPARE_QUEUE_SIZE],
+    used: usize,
+}
+
+impl PrepareQueue {
+    fn new() -> Self {
+        Self {
+            writes: [PreparedWrite::default(); MAX_PREPARED_WRITES],
+            count: 0,
+            buffer: [0u8; PREPARE_QUEUE_SIZE],
+            used: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        handle: u16,
+        offset: u16,
+        value: &[u8],
+    ) -> Result<(), AttErrorCode> {
+        if self.count == MAX_PREPARED_WRITES || self.used + value.len() > PREPARE_QUEUE_SIZE {
+            return Err(AttErrorCode::PrepareQueueFull);
+        }
+
+        self.buffer[self.used..][..value.len()].copy_from_slice(value);
+        self.writes[self.count] = PreparedWrite {
+            handle,
+            offset,
+            start: self.used,
+            len: value.len(),
+        };
+        self.count += 1;
+        self.used += value.len();
+        Ok(())
+    }
+
+    /// Iterates over the queued writes as `(handle, offset, value)`
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u16, u16, &[u8])> {
+        self.writes[..self.count]
+            .iter()
+            .map(|w| (w.handle, w.offset, &self.buffer[w.start..][..w.len]))
+    }
+
+    /// Iterates over the handles written to, each once in the order they were first queued
+    pub(crate) fn handles(&self) -> impl Iterator<Item = u16> + '_ {
+        let writes = &self.writes[..self.count];
+        writes
+            .iter()
+            .enumerate()
+            .filter(|(i, write)| writes[..*i].iter().all(|w| w.handle != write.handle))
+            .map(|(_, write)| write.handle)
+    }
+
+    /// Puts the queued writes to `handle` together into `value`
+    ///
+    /// Returns the offset and length of the complete value. Each write has to start within
+    /// or right after the part of the value queued before.
+    pub(crate) fn stage(
+        &self,
+        handle: u16,
+        value: &mut [u8; MAX_ATTRIBUTE_VALUE_LEN],
+    ) -> Result<(usize, usize), AttErrorCode> {
+        let mut staged: Option<(usize, usize)> = None;
+        for (_, offset, data) in self.iter().filter(|(h, ..)| *h == handle) {
+            let offset = offset as usize;
+            let (start, len) = staged.get_or_insert((offset, 0));
+            if offset > MAX_ATTRIBUTE_VALUE_LEN || offset < *start || offset > *start + *len {
+                return Err(AttErrorCode::InvalidOffset);
+            } else if offset + data.len() > MAX_ATTRIBUTE_VALUE_LEN {
+                return Err(AttErrorCode::InvalidAttributeValueLength);
+            }
+
+            value[offset - *start..][..data.len()].copy_from_slice(data);
+            *len = usize::max(*len, offset - *start + data.len());
+        }
+        Ok(staged.unwrap_or((0, 0)))
+    }
+}
+
+/// State the server keeps for each connected client
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConnectionState {
+    handle: u16,
+    mtu: u16,
+    security_level: SecurityLevel,
+    peer_address: Option<Addr>,
+    // (descriptor handle, value) pairs, a handle of 0 marks an unused slot
+    cccds: [(u16, u16); MAX_CCCDS],
+    pub(crate) prepare_queue: PrepareQueue,
+    #[cfg(feature = "crypto")]
+    pub(crate) pairing: PairingState,
+}
+
+impl ConnectionState {
+    fn new(handle: u16, peer_address: Option<Addr>) -> Self {
+        Self {
+            handle,
+            mtu: BASE_MTU,
+            security_level: SecurityLevel::NoSecurity,
+            peer_address,
+            cccds: [(0, 0); MAX_CCCDS],
+            prepare_queue: PrepareQueue::new(),
+            #[cfg(feature = "crypto")]
+            pairing: PairingState::default(),
+        }
+    }
+
+    /// The connection handle
+    pub fn handle(&self) -> u16 {
+        self.handle
+    }
+
+    /// The MTU negotiated with this client
+    pub fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    pub fn security_level(&self) -> SecurityLevel {
+        self.security_level
+    }
+
+    pub fn peer_address(&self) -> Option<Addr> {
+        self.peer_address
+    }
+
+    /// The value this client wrote to the given Client Characteristic Configuration descriptor
+    pub fn cccd(&self, descriptor_handle: u16) -> u16 {
+        self.cccds
+            .iter()
+            .find(|(handle, _)| *handle == descriptor_handle)
+            .map_or(0, |(_, value)| *value)
+    }
+
+    fn set_cccd(&mut self, descriptor_handle: u16, value: u16) -> Result<(), AttErrorCode> {
+        if let Some(slot) = self
+            .cccds
+            .iter_mut()
+            .find(|(handle, _)| *handle == descriptor_handle)
+        {
+            *slot = if value == 0 {
+                (0, 0)
+            } else {
+                (descriptor_handle, value)
+            };
+            return Ok(());
+        }
+
+        if value == 0 {
+            return Ok(());
+        }
+
+        let slot = self
+            .cccds
+            .iter_mut()
+            .find(|(handle, _)| *handle == 0)
+            .ok_or(AttErrorCode::InsufficientResources)?;
+        *slot = (descriptor_handle, value);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) struct Connections {
+    states: [Option<ConnectionState>; MAX_CONNECTIONS],
+}
+
+impl Connections {
+    pub(crate) fn new() -> Self {
+        Self {
+            states: core::array::from_fn(|_| None),
+        }
+    }
+
+    pub(crate) fn get(&self, handle: u16) -> Option<&ConnectionState> {
+        self.iter().find(|state| state.handle == handle)
+    }
+
+    pub(crate) fn get_mut(&mut self, handle: u16) -> Option<&mut ConnectionState> {
+        self.states
+            .iter_mut()
+            .flatten()
+            .find(|state| state.handle == handle)
+    }
+
+    /// Returns the state of the connection, creating it if there is room.
+    /// ACL data can arrive for links established before the server was created.
+    pub(crate) fn get_or_insert(&mut self, handle: u16) -> Option<&mut ConnectionState> {
+        if self.get(handle).is_none() {
+            self.insert(handle, None);
+        }
+        self.get_mut(handle)
+    }
+
+    pub(crate) fn insert(&mut self, handle: u16, peer_address: Option<Addr>) {
+        self.remove(handle);
+        match self.states.iter_mut().find(|state| state.is_none()) {
+            Some(slot) => *slot = Some(ConnectionState::new(handle, peer_address)),
+            None => log::warn!("No room to track connection {}", handle),
+        }
+    }
+
+    pub(crate) fn remove(&mut self, handle: u16) -> Option<ConnectionState> {
+        self.states
+            .iter_mut()
+            .find(|state| matches!(state, Some(state) if state.handle == handle))
+            .and_then(|state| state.take())
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &ConnectionState> {
+        self.states.iter().flatten()
+    }
+
+    /// Handles of all tracked connections
+    pub(crate) fn handles(&self) -> [Option<u16>; MAX_CONNECTIONS] {
+        core::array::from_fn(|i| self.states[i].as_ref().map(|state| state.handle))
+    }
+
+    pub(crate) fn mtu(&self, handle: u16) -> u16 {
+        self.get(handle).map_or(BASE_MTU, |state| state.mtu)
+    }
+
+    pub(crate) fn cccd(&self, handle: u16, descriptor_handle: u16) -> u16 {
+        self.get(handle)
+            .map_or(0, |state| state.cccd(descriptor_handle))
+    }
+}
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct AttributeServer<'a, R: CryptoRng + RngCore> {
     ble: &'a mut Ble<'a>,
-    // The MTU negotiated for this server. In principle this can be different per-client,
-    // but we only support one client at a time, so we only need to store one value
-    // (and reset it to the default when disconnecting).
-    mtu: u16,
-    src_handle: u16,
+    connections: Connections,
     attributes: &'a mut [Attribute<'a>],
+    bonded_peers: Option<&'a [Addr]>,
 
     #[cfg(feature = "crypto")]
     security_manager: SecurityManager<'a, Ble<'a>, R>,
@@ -88,9 +374,10 @@ pub struct AttributeServer<'a, R: CryptoRng + RngCore> {
 // The macro will remove async/await for the SYNC implementation
 bleps_dedup::dedup! {
     impl<'a, R: CryptoRng + RngCore> SYNC AttributeServer<'a, R>
-    impl<'a, T, R: CryptoRng + RngCore> ASYNC crate::async_attribute_server::AttributeServer<'a, T, R>
+    impl<'a, T, C, R: CryptoRng + RngCore> ASYNC crate::async_attribute_server::AttributeServer<'a, T, C, R>
         where
             T: embedded_io_async::Read + embedded_io_async::Write,
+            C: crate::clock::Clock + embedded_hal_async::delay::DelayNs,
     {
         pub fn get_characteristic_value(
             &mut self,
@@ -108,27 +395,137 @@ bleps_dedup::dedup! {
         }
 
         pub async fn update_le_advertising_data(&mut self, data: Data) -> Result<EventType, Error> {
-            self.ble
-                .write_bytes(Command::LeSetAdvertisingData { data }.encode().as_slice())
-                .await;
+            self.ble.send_command(Command::LeSetAdvertisingData { data }).await?;
             self.ble
                 .wait_for_command_complete(LE_OGF, SET_ADVERTISING_DATA_OCF)
                 .await?
                 .check_command_completed()
         }
 
+        /// Only accept connections from the given bonded peers, `None` accepts everyone again
+        ///
+        /// The peers are written to the filter accept list of the controller, advertise with
+        /// [crate::AdvertisingFilterPolicy::AllScanFilteredConnect] to have other peers rejected
+        /// by the controller already. Connections from unknown peers which still come through
+        /// are disconnected right away. Peers using a resolvable private address are only
+        /// recognized by their identity address if address resolution is enabled.
+        ///
+        /// Enabled advertising is paused while the list is written. If writing it fails the
+        /// previously bonded peers are kept.
+        pub async fn set_bonded_only(&mut self, peers: Option<&'a [Addr]>) -> Result<(), Error> {
+            // the controller refuses to change the list while advertising may use it
+            let advertising = self.ble.pause_advertising().await?;
+            let res = self.write_filter_accept_list(peers).await;
+            let resumed = self.ble.resume_advertising(advertising).await;
+            res?;
+            resumed?;
+
+            self.bonded_peers = peers;
+            Ok(())
+        }
+
+        async fn write_filter_accept_list(&mut self, peers: Option<&[Addr]>) -> Result<(), Error> {
+            self.ble.cmd_le_clear_filter_accept_list().await?;
+            let Some(peers) = peers else {
+                return Ok(());
+            };
+
+            let size = self.ble.cmd_le_read_filter_accept_list_size().await? as usize;
+            if peers.len() > size {
+                log::warn!("Filter accept list only holds {} of {} peers", size, peers.len());
+            }
+
+            for peer in peers.iter().take(size) {
+                let address_type = if peer.is_random() {
+                    PeerAddressType::Random
+                } else {
+                    PeerAddressType::Public
+                };
+                self.ble
+                    .cmd_le_add_device_to_filter_accept_list(address_type, peer.to_le_bytes())
+                    .await?;
+            }
+            Ok(())
+        }
+
+        fn is_bonded(&self, address: &Addr) -> bool {
+            match self.bonded_peers {
+                Some(peers) => peers.iter().any(|peer| peer.0 == address.0),
+                None => true,
+            }
+        }
+
+        /// Disconnects all connected clients
         pub async fn disconnect(&mut self, reason: u8) -> Result<EventType, Error> {
-            self.ble
-                .write_bytes(
-                    Command::Disconnect {
-                        connection_handle: 0,
-                        reason,
+            let mut res = Ok(EventType::Unknown);
+            for handle in self.connections.handles().into_iter().flatten() {
+                res = self.disconnect_connection(handle, reason).await;
+            }
+            res
+        }
+
+        /// Disconnects the client on the given connection
+        pub async fn disconnect_connection(&mut self, handle: u16, reason: u8) -> Result<EventType, Error> {
+            self.ble.cmd_disconnect(handle, reason).await
+        }
+
+        /// Drop the client's state; a new connection will need to renegotiate it.
+        fn connection_lost(&mut self, handle: u16) {
+            if let Some(state) = self.connections.remove(handle) {
+                for (descriptor_handle, _) in state.cccds {
+                    if descriptor_handle != 0 {
+                        let _ = self.update_notification_enabled(descriptor_handle);
                     }
-                    .encode()
-                    .as_slice(),
-                )
-                .await;
-            Ok(EventType::Unknown)
+                }
+            }
+        }
+
+        /// Reset the controller after a fault and restore the server's set up
+        ///
+        /// Called by [Self::do_work] once `Ble` reports a fault. All connections are lost, the
+        /// filter accept list of [Self::set_bonded_only] and the advertising are restored.
+        pub async fn recover(&mut self) -> Result<(), Error> {
+            for handle in self.connections.handles().into_iter().flatten() {
+                self.connection_lost(handle);
+            }
+
+            self.ble.reset_after_fault().await?;
+            if let Some(peers) = self.bonded_peers {
+                self.set_bonded_only(Some(peers)).await?;
+            }
+            self.ble.restore_advertising().await
+        }
+
+        /// State of the client on the given connection
+        pub fn connection(&self, handle: u16) -> Option<&ConnectionState> {
+            self.connections.get(handle)
+        }
+
+        /// Ask the client for new connection parameters, e.g. a longer interval while idle
+        ///
+        /// Sends an L2CAP Connection Parameter Update Request, which the client applies if it
+        /// accepts the parameters.
+        pub async fn request_connection_parameter_update(
+            &mut self,
+            handle: u16,
+            params: &ConnectionUpdateParameters,
+        ) -> Result<u8, Error> {
+            self.ble.request_connection_parameter_update(handle, params).await
+        }
+
+        /// Change the connection parameters via the controller, requires both sides to support
+        /// the Connection Parameters Request procedure
+        pub async fn update_connection_parameters(
+            &mut self,
+            handle: u16,
+            params: &ConnectionUpdateParameters,
+        ) -> Result<EventType, Error> {
+            self.ble.cmd_le_connection_update(handle, params).await
+        }
+
+        /// State of all connected clients
+        pub fn connections(&self) -> impl Iterator<Item = &ConnectionState> {
+            self.connections.iter()
         }
 
         pub async fn do_work(&mut self) -> Result<WorkResult, AttributeServerError> {
@@ -140,11 +537,17 @@ bleps_dedup::dedup! {
             notification_data: Option<NotificationData>,
         ) -> Result<WorkResult, AttributeServerError> {
             if let Some(notification_data) = notification_data {
-                let mut answer = notification_data.data;
-                answer.limit_len(self.mtu as usize - 3);
-                let mut data = Data::new_att_value_ntf(notification_data.handle);
-                data.append(&answer.as_slice());
-                self.write_att(self.src_handle, data).await;
+                self.send_notification(notification_data).await;
+            }
+
+            if self.ble.fault().is_some() {
+                let had_connections = self.connections.iter().next().is_some();
+                self.recover().await?;
+                return Ok(if had_connections {
+                    WorkResult::GotDisconnected
+                } else {
+                    WorkResult::DidWork
+                });
             }
 
             let packet = self.ble.poll().await;
@@ -157,60 +560,115 @@ bleps_dedup::dedup! {
                 None => Ok(WorkResult::DidWork),
                 Some(packet) => match packet {
                     crate::PollResult::Event(EventType::DisconnectComplete {
-                        handle: _,
+                        handle,
                         status: _,
                         reason: _,
                     }) => {
-                            // Reset the MTU; the next connection will need to renegotiate it.
-                            self.mtu = BASE_MTU;
-                            Ok(WorkResult::GotDisconnected)
+                        self.connection_lost(handle);
+                        Ok(WorkResult::GotDisconnected)
                     }
-                    crate::PollResult::Event(EventType::ConnectionComplete {
-                        status: _status,
-                        handle: _,
-                        role: _,
-                        peer_address: _peer_address,
-                        interval: _,
-                        latency: _,
-                        timeout: _,
-                    }) => {
-                        #[cfg(feature = "crypto")]
-                        if _status == 0 {
-                            self.security_manager.peer_address = Some(_peer_address);
+                    crate::PollResult::Event(
+                        EventType::ConnectionComplete {
+                            status,
+                            handle,
+                            peer_address,
+                            ..
+                        }
+                        | EventType::EnhancedConnectionComplete {
+                            status,
+                            handle,
+                            peer_address,
+                            ..
+                        },
+                    ) => {
+                        if status == 0 && !self.is_bonded(&peer_address) {
+                            log::warn!("Disconnecting {:?}, it isn't bonded", peer_address);
+                            self.ble
+                                .cmd_disconnect(handle, ErrorCode::AuthenticationFailure as u8)
+                                .await?;
+                            return Ok(WorkResult::DidWork);
+                        }
+
+                        if status == 0 {
+                            self.connections.insert(handle, Some(peer_address));
+                            #[cfg(feature = "crypto")]
+                            if let Some(state) = self.connections.get_mut(handle) {
+                                state.pairing = PairingState::new(self.security_manager.ltk);
+                            }
                         }
                         Ok(WorkResult::DidWork)
                     }
                     crate::PollResult::Event(EventType::LongTermKeyRequest {
-                        handle: _handle,
+                        handle,
                         random: _,
                         diversifier: _,
                     }) => {
                         #[cfg(feature = "crypto")]
-                        {
-                            if let Some(ltk) =  self.security_manager.ltk {
+                        let ltk = self.connections.get(handle).and_then(|state| state.pairing.ltk);
+                        #[cfg(not(feature = "crypto"))]
+                        let ltk = None;
+
+                        match ltk {
+                            Some(ltk) => {
+                                self.ble.cmd_long_term_key_request_reply(handle, ltk).await?;
+                            }
+                            None => {
                                 self.ble
-                                .cmd_long_term_key_request_reply(
-                                    _handle,
-                                    ltk,
-                                ).await.unwrap();
-                            } else {
-                                // TODO handle this via long term key request negative reply
+                                    .cmd_long_term_key_request_negative_reply(handle)
+                                    .await?;
                             }
                         }
                         Ok(WorkResult::DidWork)
                     }
+                    crate::PollResult::Event(EventType::EncryptionChange {
+                        status: 0,
+                        handle,
+                        enabled,
+                    }) => {
+                        if let Some(state) = self.connections.get_mut(handle) {
+                            state.security_level = if enabled {
+                                SecurityLevel::Encrypted
+                            } else {
+                                SecurityLevel::NoSecurity
+                            };
+                        }
+                        Ok(WorkResult::DidWork)
+                    }
                     crate::PollResult::Event(_) => Ok(WorkResult::DidWork),
                     crate::PollResult::AsyncData(packet) => {
                         let (src_handle, l2cap_packet) = L2capPacket::decode(packet)?;
-                        if l2cap_packet.channel == 6 {
+                        if l2cap_packet.channel == LE_SIGNALING_CHANNEL {
+                            let command = self
+                                .ble
+                                .handle_signaling(src_handle, l2cap_packet.payload.as_slice())
+                                .await?;
+                            Ok(match command {
+                                Some(command) => WorkResult::GotSignaling {
+                                    handle: src_handle,
+                                    command,
+                                },
+                                None => WorkResult::DidWork,
+                            })
+                        } else if l2cap_packet.channel == 6 {
                             // handle SM
                             #[cfg(feature = "crypto")]
-                            self.security_manager
-                                .handle(self.ble, src_handle, l2cap_packet.payload, &mut self.pin_callback).await?;
+                            if let Some(state) = self.connections.get_or_insert(src_handle) {
+                                self.security_manager
+                                    .handle(
+                                        self.ble,
+                                        src_handle,
+                                        &mut state.pairing,
+                                        state.peer_address,
+                                        l2cap_packet.payload,
+                                        &mut self.pin_callback,
+                                    )
+                                    .await?;
+                            }
                             Ok(WorkResult::DidWork)
                         } else {
-                        let packet = Att::decode(l2cap_packet)?;
+                        let packet = Att::decode(&l2cap_packet)?;
                         log::trace!("att: {:x?}", packet);
+                        self.connections.get_or_insert(src_handle);
                         match packet {
                             Att::ReadByGroupTypeReq {
                                 start,
@@ -235,12 +693,10 @@ bleps_dedup::dedup! {
                             }
 
                             Att::WriteCmd { handle, data } => {
-                                self.src_handle = src_handle;
                                 self.handle_write_cmd(src_handle, handle, data).await;
                             }
 
                             Att::WriteReq { handle, data } => {
-                                self.src_handle = src_handle;
                                 self.handle_write_req(src_handle, handle, data).await;
                             }
 
@@ -315,11 +771,11 @@ bleps_dedup::dedup! {
                     log::debug!("found! {:x?}", att.handle);
                     handle = att.handle;
                     val = att.value();
-                    if let Ok(val) = val {
+                    if let Ok(val) = &val {
                         data.append_att_read_by_group_type_response(
                             att.handle,
                             att.last_handle_in_group,
-                            &Uuid::from(val),
+                            &Uuid::from(val.as_slice()),
                         );
                     }
                     break;
@@ -353,7 +809,7 @@ bleps_dedup::dedup! {
                     handle = att.handle;
 
                     if att.data.readable() {
-                        err = att.data.read(0, data.as_slice_mut());
+                        err = att.data.read(0, data.spare_capacity_mut());
                         if let Ok(len) = err {
                             data.append_len(len);
                             data.append_att_read_by_type_response();
@@ -378,11 +834,14 @@ bleps_dedup::dedup! {
 
             for att in self.attributes.iter_mut() {
                 if att.handle == handle {
-                    if att.data.readable() {
-                        err = att.data.read(0, data.as_slice_mut());
-                        if let Ok(len) = err {
-                            data.append_len(len);
-                        }
+                    if att.uuid == CLIENT_CHARACTERISTIC_CONFIGURATION_UUID16 {
+                        let value = self.connections.cccd(src_handle, handle);
+                        err = read_cccd(value, 0, data.spare_capacity_mut());
+                    } else if att.data.readable() {
+                        err = att.data.read(0, data.spare_capacity_mut());
+                    }
+                    if let Ok(len) = err {
+                        data.append_len(len);
                     }
                     break;
                 }
@@ -390,7 +849,7 @@ bleps_dedup::dedup! {
 
             let response = match err {
                 Ok(_) => {
-                    data.limit_len(self.mtu as usize);
+                    data.limit_len(self.connections.mtu(src_handle) as usize);
                     data
                 }
                 Err(e) => Data::new_att_error_response(ATT_READ_REQUEST_OPCODE, handle, e),
@@ -399,8 +858,8 @@ bleps_dedup::dedup! {
             self.write_att(src_handle, response).await;
         }
 
-        fn handle_write(&mut self, handle: u16, data: Data) -> Result<(), AttErrorCode> {
-            let Some((index, att)) = self.attributes.iter_mut().enumerate().find(|(_, att)| att.handle == handle) else {
+        fn handle_write(&mut self, src_handle: u16, handle: u16, data: Data) -> Result<(), AttErrorCode> {
+            let Some(att) = self.attributes.iter_mut().find(|att| att.handle == handle) else {
                 return Err(AttErrorCode::InvalidHandle);
             };
             if !att.data.writable() {
@@ -413,25 +872,88 @@ bleps_dedup::dedup! {
                 return Err(e);
             }
 
-            // If this is a Client Characteristic Configuration descriptor, notify the parent of a change
-            // otherwise return immediatly.
-            if att.uuid != Uuid::Uuid16(0x2902) {
-                return Ok(());
+            if att.uuid == CLIENT_CHARACTERISTIC_CONFIGURATION_UUID16 {
+                self.write_cccd(src_handle, handle, 0, data.as_slice())?;
+            }
+            Ok(())
+        }
+
+        /// Remembers the configuration a client wrote to a Client Characteristic Configuration
+        /// descriptor and notifies the parent of a change
+        fn write_cccd(&mut self, src_handle: u16, handle: u16, offset: usize, data: &[u8]) -> Result<(), AttErrorCode> {
+            let state = self
+                .connections
+                .get_or_insert(src_handle)
+                .ok_or(AttErrorCode::InsufficientResources)?;
+
+            // a write at an offset keeps the bytes of the current value before it
+            let mut value = if offset == 0 {
+                [0u8; 2]
+            } else {
+                state.cccd(handle).to_le_bytes()
+            };
+            for (byte, written) in value.iter_mut().skip(offset).zip(data) {
+                *byte = *written;
+            }
+
+            state.set_cccd(handle, u16::from_le_bytes(value))?;
+            self.update_notification_enabled(handle)
+        }
+
+        /// Enables notifications on the characteristic of a CCCD if any client subscribed to it
+        fn update_notification_enabled(&mut self, descriptor_handle: u16) -> Result<(), AttErrorCode> {
+            let enabled = self
+                .connections
+                .iter()
+                .any(|state| state.cccd(descriptor_handle) & 0x1 == 0x1);
+
+            // The CCCD is assumed to directly follow the characteristic value attribute,
+            // which is always true when using the macro.
+            let parent_index = (descriptor_handle as usize).wrapping_sub(2);
+            match self.attributes.get_mut(parent_index) {
+                Some(parent_att) => parent_att.data.enable_notification(enabled),
+                None => Ok(()),
             }
+        }
+
+        /// Handle of the CCCD belonging to the given characteristic value, if there is one
+        fn cccd_handle(&self, handle: u16) -> Option<u16> {
+            self.attributes
+                .get(handle as usize)
+                .filter(|att| att.uuid == CLIENT_CHARACTERISTIC_CONFIGURATION_UUID16)
+                .map(|att| att.handle)
+        }
+
+        async fn send_notification(&mut self, notification: NotificationData) {
+            let cccd_handle = self.cccd_handle(notification.handle);
 
-            // Here we make the same assumption made in async_atribute_server that the CCCD directly follows
-            // the charactaristic attribute.
-            let parrent_att = &mut self.attributes[index-1];
-            parrent_att.data.enable_notification(data.as_slice()[0] & 0x1 == 0x1)
+            for conn_handle in self.connections.handles().into_iter().flatten() {
+                if notification.connection.is_some_and(|target| target != conn_handle) {
+                    continue;
+                }
+
+                // Only notify clients which subscribed, if the characteristic can be subscribed to
+                if let Some(cccd_handle) = cccd_handle {
+                    if self.connections.cccd(conn_handle, cccd_handle) & 0x1 == 0 {
+                        continue;
+                    }
+                }
+
+                let value = notification.data.as_slice();
+                let len = value.len().min(self.connections.mtu(conn_handle) as usize - 3);
+                let mut data = Data::new_att_value_ntf(notification.handle);
+                data.append(&value[..len]);
+                self.write_att(conn_handle, data).await;
+            }
         }
 
-        async fn handle_write_cmd(&mut self, _src_handle: u16, handle: u16, data: Data) {
+        async fn handle_write_cmd(&mut self, src_handle: u16, handle: u16, data: Data) {
             // Write commands can't respond with an error.
-            let _ = self.handle_write(handle, data);
+            let _ = self.handle_write(src_handle, handle, data);
         }
 
         async fn handle_write_req(&mut self, src_handle: u16, handle: u16, data: Data) {
-            let err = self.handle_write(handle, data);
+            let err = self.handle_write(src_handle, handle, data);
 
             let response = match err {
                 Ok(()) => Data::new_att_write_response(),
@@ -441,9 +963,12 @@ bleps_dedup::dedup! {
         }
 
         async fn handle_exchange_mtu(&mut self, src_handle: u16, mtu: u16) {
-            self.mtu = mtu.min(MTU);
-            log::debug!("Requested MTU {mtu}, returning {}", self.mtu);
-            self.write_att(src_handle, Data::new_att_exchange_mtu_response(self.mtu))
+            let mtu = mtu.clamp(BASE_MTU, MTU);
+            if let Some(state) = self.connections.get_or_insert(src_handle) {
+                state.mtu = mtu;
+            }
+            log::debug!("Requested MTU {mtu}, returning {}", self.connections.mtu(src_handle));
+            self.write_att(src_handle, Data::new_att_exchange_mtu_response(self.connections.mtu(src_handle)))
                 .await;
         }
 
@@ -509,13 +1034,19 @@ bleps_dedup::dedup! {
             value: Data,
         ) {
             let mut data = Data::new_att_prepare_write_response(handle, offset);
-            let mut err = Err(AttErrorCode::AttributeNotFound);
+            let mut err = Err(AttErrorCode::InvalidHandle);
 
+            // Offset and length are only validated once the writes are executed
             for att in self.attributes.iter_mut() {
                 if att.handle == handle {
-                    if att.data.writable() {
-                        err = att.data.write(offset as usize, value.as_slice());
-                    }
+                    err = if att.data.writable() {
+                        match self.connections.get_or_insert(src_handle) {
+                            Some(state) => state.prepare_queue.push(handle, offset, value.as_slice()),
+                            None => Err(AttErrorCode::InsufficientResources),
+                        }
+                    } else {
+                        Err(AttErrorCode::WriteNotPermitted)
+                    };
                     data.append(value.as_slice());
                     break;
                 }
@@ -529,10 +1060,57 @@ bleps_dedup::dedup! {
             self.write_att(src_handle, response).await;
         }
 
-        async fn handle_execute_write(&mut self, src_handle: u16, _flags: u8) {
-            // for now we don't do anything here
-            self.write_att(src_handle, Data::new_att_execute_write_response())
-                .await;
+        async fn handle_execute_write(&mut self, src_handle: u16, flags: u8) {
+            let mut result = Ok(());
+
+            // The prepared writes are removed from the connection whether they are executed or
+            // cancelled
+            let queue = match self.connections.get_mut(src_handle) {
+                Some(state) => core::mem::replace(&mut state.prepare_queue, PrepareQueue::new()),
+                None => PrepareQueue::new(),
+            };
+
+            // flags: 0x00 cancels all prepared writes, 0x01 writes them
+            if flags & 0x01 == 0x01 {
+                // The writes to each handle are put together and nothing is written unless
+                // all complete values are valid, then each attribute is written once.
+                // Errors of the attribute's own write can't be undone though.
+                let mut value = [0u8; MAX_ATTRIBUTE_VALUE_LEN];
+                result = queue.handles().try_for_each(|handle| {
+                    let (offset, len) =
+                        queue.stage(handle, &mut value).map_err(|e| (handle, e))?;
+                    let att = self.attributes.iter().find(|att| att.handle == handle);
+                    validate_prepared_write(att, offset, &value[..len])
+                        .map_err(|e| (handle, e))
+                });
+
+                if result.is_ok() {
+                    result = queue.handles().try_for_each(|handle| {
+                        let (offset, len) =
+                            queue.stage(handle, &mut value).map_err(|e| (handle, e))?;
+                        self.execute_prepared_write(src_handle, handle, offset, &value[..len])
+                            .map_err(|e| (handle, e))
+                    });
+                }
+            }
+
+            let response = match result {
+                Ok(()) => Data::new_att_execute_write_response(),
+                Err((handle, e)) => Data::new_att_error_response(ATT_EXECUTE_WRITE_REQ_OPCODE, handle, e),
+            };
+            self.write_att(src_handle, response).await;
+        }
+
+        fn execute_prepared_write(&mut self, src_handle: u16, handle: u16, offset: usize, value: &[u8]) -> Result<(), AttErrorCode> {
+            let Some(att) = self.attributes.iter_mut().find(|att| att.handle == handle) else {
+                return Ok(());
+            };
+            att.data.write(offset, value)?;
+
+            if att.uuid == CLIENT_CHARACTERISTIC_CONFIGURATION_UUID16 {
+                self.write_cccd(src_handle, handle, offset, value)?;
+            }
+            Ok(())
         }
 
         async fn handle_read_blob(&mut self, src_handle: u16, handle: u16, offset: u16) {
@@ -541,11 +1119,14 @@ bleps_dedup::dedup! {
 
             for att in self.attributes.iter_mut() {
                 if att.handle == handle {
-                    if att.data.readable() {
-                        err = att.data.read(offset as usize, data.as_slice_mut());
-                        if let Ok(len) = err {
-                            data.append_len(len);
-                        }
+                    if att.uuid == CLIENT_CHARACTERISTIC_CONFIGURATION_UUID16 {
+                        let value = self.connections.cccd(src_handle, handle);
+                        err = read_cccd(value, offset as usize, data.spare_capacity_mut());
+                    } else if att.data.readable() {
+                        err = att.data.read(offset as usize, data.spare_capacity_mut());
+                    }
+                    if let Ok(len) = err {
+                        data.append_len(len);
                     }
                     break;
                 }
@@ -553,7 +1134,7 @@ bleps_dedup::dedup! {
 
             let response = match err {
                 Ok(_) => {
-                    data.limit_len(self.mtu as usize);
+                    data.limit_len(self.connections.mtu(src_handle) as usize);
                     data
                 }
                 Err(e) => Data::new_att_error_response(ATT_READ_BLOB_REQ_OPCODE, handle, e),
@@ -569,14 +1150,9 @@ bleps_dedup::dedup! {
             let res = L2capPacket::encode(data);
             log::trace!("encoded_l2cap {:x?}", res.as_slice());
 
-            let res = AclPacket::encode(
-                handle,
-                BoundaryFlag::FirstAutoFlushable,
-                HostBroadcastFlag::NoBroadcast,
-                res,
-            );
-            log::trace!("writing {:x?}", res.as_slice());
-            self.ble.write_bytes(res.as_slice()).await;
+            if let Err(err) = self.ble.write_l2cap(handle, res).await {
+                log::warn!("Failed to write ACL packet {:?}", err);
+            }
         }
     }
 }
@@ -585,6 +1161,8 @@ impl<'a, R: CryptoRng + RngCore> AttributeServer<'a, R> {
     /// Create a new instance of the AttributeServer
     ///
     /// When _NOT_ using the `crypto` feature you can pass a mutual reference to `bleps::no_rng::NoRng`
+    /// With the `crypto` feature the RNG creates the pairing keys, targets without an entropy
+    /// source can seed one from the controller with `Ble::controller_rng`
     pub fn new(
         ble: &'a mut Ble<'a>,
         attributes: &'a mut [Attribute<'a>],
@@ -632,9 +1210,9 @@ impl<'a, R: CryptoRng + RngCore> AttributeServer<'a, R> {
 
         AttributeServer {
             ble,
-            mtu: BASE_MTU,
-            src_handle: 0,
+            connections: Connections::new(),
             attributes,
+            bonded_peers: None,
 
             #[cfg(feature = "crypto")]
             security_manager,
@@ -661,17 +1239,48 @@ impl<'a, R: CryptoRng + RngCore> AttributeServer<'a, R> {
     }
 }
 
+/// Checks the complete value of queued writes before any queued write is executed
+fn validate_prepared_write(
+    att: Option<&Attribute>,
+    offset: usize,
+    value: &[u8],
+) -> Result<(), AttErrorCode> {
+    att.ok_or(AttErrorCode::InvalidHandle)?
+        .data
+        .validate_write(offset, value)
+}
+
+fn read_cccd(value: u16, offset: usize, buffer: &mut [u8]) -> Result<usize, AttErrorCode> {
+    let value = value.to_le_bytes();
+    let value = value.get(offset..).unwrap_or(&[]);
+    let len = value.len().min(buffer.len());
+    buffer[..len].copy_from_slice(&value[..len]);
+    Ok(len)
+}
+
 #[derive(Debug)]
 pub struct NotificationData {
     pub(crate) handle: u16,
     pub(crate) data: Data,
+    pub(crate) connection: Option<u16>,
 }
 
 impl NotificationData {
+    /// Notification sent to every client which subscribed to the characteristic
     pub fn new(handle: u16, data: &[u8]) -> Self {
         Self {
             handle,
             data: Data::new(data),
+            connection: None,
+        }
+    }
+
+    /// Notification sent only to the client on the given connection
+    pub fn new_for_connection(connection_handle: u16, handle: u16, data: &[u8]) -> Self {
+        Self {
+            handle,
+            data: Data::new(data),
+            connection: Some(connection_handle),
         }
     }
 }
@@ -0,0 +1,337 @@
+//! Capture of all HCI traffic for analysis with Wireshark
+//!
+//! [HciCapture] wraps a [HciConnection], `asynch::HciCapture` wraps the transport of
+//! `asynch::Ble`. Both record every command, event and ACL packet to an
+//! `embedded_io::Write` sink, either in btsnoop or pcap format.
+
+use core::cell::RefCell;
+
+use embedded_io_blocking::Write;
+
+use crate::HciConnection;
+
+/// Packets longer than this are truncated in the capture
+pub const MAX_CAPTURED_PACKET_LEN: usize = 300;
+
+/// btsnoop datalink type for HCI packets including the H4 packet type
+const BTSNOOP_DATALINK_H4: u32 = 1002;
+
+/// Microseconds from 0000-01-01 to 1970-01-01 as used by btsnoop timestamps
+const BTSNOOP_EPOCH_OFFSET_MICROS: u64 = 0x00dc_ddb3_0f2f_8000;
+
+/// LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR
+const PCAP_LINKTYPE_H4_WITH_PHDR: u32 = 201;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CaptureFormat {
+    /// btsnoop version 1 with datalink type H4
+    BtSnoop,
+    /// pcap with LINKTYPE_BLUETOOTH_HCI_H4_WITH_PHDR
+    Pcap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    /// From host to controller
+    Sent,
+    /// From controller to host
+    Received,
+}
+
+/// Splits a stream of H4 bytes into packets
+pub(crate) struct PacketAssembler {
+    buffer: [u8; MAX_CAPTURED_PACKET_LEN],
+    /// Bytes of the current packet seen so far
+    len: usize,
+}
+
+impl PacketAssembler {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buffer: [0u8; MAX_CAPTURED_PACKET_LEN],
+            len: 0,
+        }
+    }
+
+    /// Length of the whole packet, once enough of the header is known
+    fn packet_len(&self) -> Option<usize> {
+        let buffer = &self.buffer[..usize::min(self.len, MAX_CAPTURED_PACKET_LEN)];
+        match buffer {
+            // command: opcode and length
+            [0x01, _, _, len, ..] => Some(4 + *len as usize),
+            // ACL data: handle and length
+            [0x02, _, _, lo, hi, ..] => Some(5 + u16::from_le_bytes([*lo, *hi]) as usize),
+            // event: event code and length
+            [0x04, _, len, ..] => Some(3 + *len as usize),
+            [0x01 | 0x02 | 0x04, ..] => None,
+            // unknown packet type, it's not possible to find the end of it
+            [_, ..] => Some(1),
+            [] => None,
+        }
+    }
+
+    /// Feed a byte, returns the (possibly truncated) packet and its original length once
+    /// it is complete
+    pub(crate) fn push(&mut self, byte: u8) -> Option<(&[u8], usize)> {
+        if self.len < MAX_CAPTURED_PACKET_LEN {
+            self.buffer[self.len] = byte;
+        }
+        self.len += 1;
+
+        match self.packet_len() {
+            Some(packet_len) if self.len >= packet_len => {
+                let len = self.len;
+                self.len = 0;
+                Some((
+                    &self.buffer[..usize::min(len, MAX_CAPTURED_PACKET_LEN)],
+                    len,
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Writes records in the chosen format
+struct CaptureWriter<W>
+where
+    W: Write,
+{
+    sink: W,
+    format: CaptureFormat,
+    sent: PacketAssembler,
+    received: PacketAssembler,
+}
+
+impl<W> CaptureWriter<W>
+where
+    W: Write,
+{
+    fn new(sink: W, format: CaptureFormat) -> Self {
+        let mut writer = Self {
+            sink,
+            format,
+            sent: PacketAssembler::new(),
+            received: PacketAssembler::new(),
+        };
+        writer.write_header();
+        writer
+    }
+
+    fn write_header(&mut self) {
+        let res = match self.format {
+            CaptureFormat::BtSnoop => self.sink.write_all(b"btsnoop\0").and_then(|_| {
+                self.sink.write_all(&1u32.to_be_bytes())?;
+                self.sink.write_all(&BTSNOOP_DATALINK_H4.to_be_bytes())
+            }),
+            CaptureFormat::Pcap => {
+                let mut header = [0u8; 24];
+                header[0..4].copy_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+                header[4..6].copy_from_slice(&2u16.to_le_bytes());
+                header[6..8].copy_from_slice(&4u16.to_le_bytes());
+                // time zone and timestamp accuracy stay 0
+                header[16..20].copy_from_slice(&(MAX_CAPTURED_PACKET_LEN as u32 + 4).to_le_bytes());
+                header[20..24].copy_from_slice(&PCAP_LINKTYPE_H4_WITH_PHDR.to_le_bytes());
+                self.sink.write_all(&header)
+            }
+        };
+
+        if res.is_err() {
+            log::warn!("Failed to write capture header");
+        }
+    }
+
+    fn process(&mut self, direction: Direction, bytes: &[u8], millis: impl Fn() -> u64) {
+        for byte in bytes {
+            let assembler = match direction {
+                Direction::Sent => &mut self.sent,
+                Direction::Received => &mut self.received,
+            };
+
+            if let Some((packet, original_len)) = assembler.push(*byte) {
+                let res = write_record(
+                    &mut self.sink,
+                    self.format,
+                    direction,
+                    packet,
+                    original_len,
+                    millis(),
+                );
+                if res.is_err() {
+                    log::warn!("Failed to write capture record");
+                }
+            }
+        }
+    }
+}
+
+fn write_record<W>(
+    sink: &mut W,
+    format: CaptureFormat,
+    direction: Direction,
+    packet: &[u8],
+    original_len: usize,
+    millis: u64,
+) -> Result<(), W::Error>
+where
+    W: Write,
+{
+    let received = direction == Direction::Received;
+    let micros = millis * 1000;
+
+    match format {
+        CaptureFormat::BtSnoop => {
+            let is_command_or_event = matches!(packet.first(), Some(0x01 | 0x04));
+            let flags = received as u32 | (is_command_or_event as u32) << 1;
+
+            let mut header = [0u8; 24];
+            header[0..4].copy_from_slice(&(original_len as u32).to_be_bytes());
+            header[4..8].copy_from_slice(&(packet.len() as u32).to_be_bytes());
+            header[8..12].copy_from_slice(&flags.to_be_bytes());
+            // cumulative drops stay 0
+            header[16..24].copy_from_slice(&(micros + BTSNOOP_EPOCH_OFFSET_MICROS).to_be_bytes());
+            sink.write_all(&header)?;
+        }
+        CaptureFormat::Pcap => {
+            let mut header = [0u8; 20];
+            header[0..4].copy_from_slice(&((micros / 1_000_000) as u32).to_le_bytes());
+            header[4..8].copy_from_slice(&((micros % 1_000_000) as u32).to_le_bytes());
+            header[8..12].copy_from_slice(&(packet.len() as u32 + 4).to_le_bytes());
+            header[12..16].copy_from_slice(&(original_len as u32 + 4).to_le_bytes());
+            // the pseudo header holds the direction in network byte order
+            header[16..20].copy_from_slice(&(received as u32).to_be_bytes());
+            sink.write_all(&header)?;
+        }
+    }
+
+    sink.write_all(packet)
+}
+
+/// A [HciConnection] which records all traffic of the wrapped connection
+pub struct HciCapture<'a, W>
+where
+    W: Write,
+{
+    connector: &'a dyn HciConnection,
+    writer: RefCell<CaptureWriter<W>>,
+}
+
+impl<'a, W> HciCapture<'a, W>
+where
+    W: Write,
+{
+    /// Writes the file header to `sink` right away
+    pub fn new(connector: &'a dyn HciConnection, sink: W, format: CaptureFormat) -> Self {
+        Self {
+            connector,
+            writer: RefCell::new(CaptureWriter::new(sink, format)),
+        }
+    }
+
+    /// Returns the sink the capture was written to
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().sink
+    }
+}
+
+impl<'a, W> HciConnection for HciCapture<'a, W>
+where
+    W: Write,
+{
+    fn read(&self) -> Option<u8> {
+        let byte = self.connector.read()?;
+        self.writer
+            .borrow_mut()
+            .process(Direction::Received, &[byte], || self.connector.millis());
+        Some(byte)
+    }
+
+    fn write(&self, data: u8) {
+        self.connector.write(data);
+        self.writer
+            .borrow_mut()
+            .process(Direction::Sent, &[data], || self.connector.millis());
+    }
+
+    fn millis(&self) -> u64 {
+        self.connector.millis()
+    }
+}
+
+#[cfg(feature = "async")]
+pub mod asynch {
+    use super::*;
+    use crate::clock::Clock;
+
+    /// Wraps the transport of `asynch::Ble` and records all traffic
+    pub struct HciCapture<T, W, C>
+    where
+        W: Write,
+    {
+        hci: T,
+        clock: C,
+        writer: CaptureWriter<W>,
+    }
+
+    impl<T, W, C> HciCapture<T, W, C>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+        W: Write,
+        C: Clock,
+    {
+        /// Writes the file header to `sink` right away, the clock provides the timestamps
+        pub fn new(hci: T, clock: C, sink: W, format: CaptureFormat) -> Self {
+            Self {
+                hci,
+                clock,
+                writer: CaptureWriter::new(sink, format),
+            }
+        }
+
+        /// Returns the transport and the sink the capture was written to
+        pub fn into_inner(self) -> (T, W) {
+            (self.hci, self.writer.sink)
+        }
+    }
+
+    impl<T, W, C> embedded_io_async::ErrorType for HciCapture<T, W, C>
+    where
+        T: embedded_io_async::ErrorType,
+        W: Write,
+    {
+        type Error = T::Error;
+    }
+
+    impl<T, W, C> embedded_io_async::Read for HciCapture<T, W, C>
+    where
+        T: embedded_io_async::Read,
+        W: Write,
+        C: Clock,
+    {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let len = self.hci.read(buf).await?;
+            self.writer
+                .process(Direction::Received, &buf[..len], || self.clock.now_millis());
+            Ok(len)
+        }
+    }
+
+    impl<T, W, C> embedded_io_async::Write for HciCapture<T, W, C>
+    where
+        T: embedded_io_async::Write,
+        W: Write,
+        C: Clock,
+    {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            let len = self.hci.write(buf).await?;
+            self.writer
+                .process(Direction::Sent, &buf[..len], || self.clock.now_millis());
+            Ok(len)
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            self.hci.flush().await
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Time keeping for timeouts
+//!
+//! The blocking stack reads the time via [crate::HciConnection::millis] which e.g.
+//! [crate::HciConnector] takes from a [Clock]. `asynch::Ble` additionally sleeps while
+//! waiting for the controller, so it needs a clock which also implements
+//! `embedded_hal_async::delay::DelayNs`, see `ClockWithDelay`.
+
+/// Source of the current time in milliseconds
+///
+/// Implemented for functions and closures returning the milliseconds elapsed since an
+/// arbitrary point in time, e.g. `|| timer.now().ticks() / 1000`.
+pub trait Clock {
+    fn now_millis(&self) -> u64;
+}
+
+impl<F> Clock for F
+where
+    F: Fn() -> u64,
+{
+    fn now_millis(&self) -> u64 {
+        self()
+    }
+}
+
+#[cfg(feature = "async")]
+pub use asynch::ClockWithDelay;
+
+#[cfg(feature = "async")]
+mod asynch {
+    use embedded_hal_async::delay::DelayNs;
+
+    use super::Clock;
+
+    /// Combines a [Clock] with an `embedded_hal_async` delay for `asynch::Ble`
+    pub struct ClockWithDelay<C, D> {
+        clock: C,
+        delay: D,
+    }
+
+    impl<C, D> ClockWithDelay<C, D>
+    where
+        C: Clock,
+        D: DelayNs,
+    {
+        pub fn new(clock: C, delay: D) -> Self {
+            Self { clock, delay }
+        }
+    }
+
+    impl<C, D> Clock for ClockWithDelay<C, D>
+    where
+        C: Clock,
+    {
+        fn now_millis(&self) -> u64 {
+            self.clock.now_millis()
+        }
+    }
+
+    impl<C, D> DelayNs for ClockWithDelay<C, D>
+    where
+        D: DelayNs,
+    {
+        async fn delay_ns(&mut self, ns: u32) {
+            self.delay.delay_ns(ns).await
+        }
+
+        async fn delay_us(&mut self, us: u32) {
+            self.delay.delay_us(us).await
+        }
+
+        async fn delay_ms(&mut self, ms: u32) {
+            self.delay.delay_ms(ms).await
+        }
+    }
+}
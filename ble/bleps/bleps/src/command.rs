@@ -1,22 +1,200 @@
-use crate::{AdvertisingParameters, Data};
+use crate::{
+    connection::{
+        CodedPhyOptions, ConnectionUpdateParameters, CreateConnectionParameters, DataLength,
+    },
+    event::{EventMask, LeEventMask},
+    extended_advertising::{
+        AdvertisingDataOperation, AdvertisingSet, ExtendedAdvertisingParameters,
+    },
+    AdvertisingParameters, Data, PeerAddressType, ScanParameters,
+};
 
 pub const CONTROLLER_OGF: u8 = 0x03;
 pub const RESET_OCF: u16 = 0x03;
 pub const SET_EVENT_MASK_OCF: u16 = 0x01;
 
 pub const LE_OGF: u8 = 0x08;
+pub const LE_SET_EVENT_MASK_OCF: u16 = 0x01;
+pub const LE_READ_BUFFER_SIZE_OCF: u16 = 0x02;
+pub const READ_LOCAL_SUPPORTED_FEATURES_OCF: u16 = 0x03;
+pub const SET_RANDOM_ADDRESS_OCF: u16 = 0x05;
 pub const SET_ADVERTISING_PARAMETERS_OCF: u16 = 0x06;
+pub const READ_ADVERTISING_PHYSICAL_CHANNEL_TX_POWER_OCF: u16 = 0x07;
 pub const SET_ADVERTISING_DATA_OCF: u16 = 0x08;
 pub const SET_SCAN_RSP_DATA_OCF: u16 = 0x09;
 pub const SET_ADVERTISE_ENABLE_OCF: u16 = 0x0a;
+pub const SET_SCAN_PARAMETERS_OCF: u16 = 0x0b;
+pub const SET_SCAN_ENABLE_OCF: u16 = 0x0c;
+pub const CREATE_CONNECTION_OCF: u16 = 0x0d;
+pub const CREATE_CONNECTION_CANCEL_OCF: u16 = 0x0e;
+pub const READ_FILTER_ACCEPT_LIST_SIZE_OCF: u16 = 0x0f;
+pub const CLEAR_FILTER_ACCEPT_LIST_OCF: u16 = 0x10;
+pub const ADD_DEVICE_TO_FILTER_ACCEPT_LIST_OCF: u16 = 0x11;
+pub const REMOVE_DEVICE_FROM_FILTER_ACCEPT_LIST_OCF: u16 = 0x12;
+pub const CONNECTION_UPDATE_OCF: u16 = 0x13;
+pub const READ_CHANNEL_MAP_OCF: u16 = 0x15;
+pub const READ_REMOTE_FEATURES_OCF: u16 = 0x16;
+pub const ENCRYPT_OCF: u16 = 0x17;
+pub const RAND_OCF: u16 = 0x18;
+pub const START_ENCRYPTION_OCF: u16 = 0x19;
 pub const LONG_TERM_KEY_REQUEST_REPLY_OCF: u16 = 0x1a;
+pub const LONG_TERM_KEY_REQUEST_NEGATIVE_REPLY_OCF: u16 = 0x1b;
+pub const SET_DATA_LENGTH_OCF: u16 = 0x22;
+pub const READ_SUGGESTED_DEFAULT_DATA_LENGTH_OCF: u16 = 0x23;
+pub const WRITE_SUGGESTED_DEFAULT_DATA_LENGTH_OCF: u16 = 0x24;
+pub const ADD_DEVICE_TO_RESOLVING_LIST_OCF: u16 = 0x27;
+pub const REMOVE_DEVICE_FROM_RESOLVING_LIST_OCF: u16 = 0x28;
+pub const CLEAR_RESOLVING_LIST_OCF: u16 = 0x29;
+pub const SET_ADDRESS_RESOLUTION_ENABLE_OCF: u16 = 0x2d;
+pub const SET_RESOLVABLE_PRIVATE_ADDRESS_TIMEOUT_OCF: u16 = 0x2e;
+pub const READ_PHY_OCF: u16 = 0x30;
+pub const SET_PHY_OCF: u16 = 0x32;
+pub const SET_ADVERTISING_SET_RANDOM_ADDRESS_OCF: u16 = 0x35;
+pub const SET_EXTENDED_ADVERTISING_PARAMETERS_OCF: u16 = 0x36;
+pub const SET_EXTENDED_ADVERTISING_DATA_OCF: u16 = 0x37;
+pub const SET_EXTENDED_SCAN_RSP_DATA_OCF: u16 = 0x38;
+pub const SET_EXTENDED_ADVERTISING_ENABLE_OCF: u16 = 0x39;
+pub const REMOVE_ADVERTISING_SET_OCF: u16 = 0x3c;
+pub const CLEAR_ADVERTISING_SETS_OCF: u16 = 0x3d;
 
 pub const LINK_CONTROL_OGF: u8 = 0x01;
 pub const DISCONNECT_OCF: u16 = 0x06;
 
 pub const INFORMATIONAL_OGF: u8 = 0x04;
+pub const READ_LOCAL_VERSION_INFORMATION_OCF: u16 = 0x01;
+pub const READ_BUFFER_SIZE_OCF: u16 = 0x05;
 pub const READ_BD_ADDR_OCF: u16 = 0x09;
 
+pub const STATUS_PARAMETERS_OGF: u8 = 0x05;
+pub const READ_RSSI_OCF: u16 = 0x05;
+
+/// OGF of vendor specific commands
+pub const VENDOR_OGF: u8 = 0x3f;
+
+/// Most bytes of parameters a command can carry
+pub const MAX_COMMAND_PARAMETERS_LEN: usize = 255;
+
+/// Number of encoded commands which can wait for command credits
+pub(crate) const COMMAND_QUEUE_SIZE: usize = 4;
+
+/// Time to wait for the controller to answer a command without a timeout of its own
+pub const DEFAULT_COMMAND_TIMEOUT_MILLIS: u64 = 1000;
+
+/// Number of commands which can have a timeout of their own
+pub const MAX_COMMAND_TIMEOUTS: usize = 8;
+
+/// Time to wait for the controller to answer commands, configurable per opcode
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CommandTimeouts {
+    default_millis: u64,
+    timeouts: [Option<(u16, u64)>; MAX_COMMAND_TIMEOUTS],
+}
+
+impl CommandTimeouts {
+    pub const fn new() -> Self {
+        Self {
+            default_millis: DEFAULT_COMMAND_TIMEOUT_MILLIS,
+            timeouts: [None; MAX_COMMAND_TIMEOUTS],
+        }
+    }
+
+    /// Set the timeout of all commands without a timeout of their own
+    pub fn set_default(&mut self, millis: u64) {
+        self.default_millis = millis;
+    }
+
+    /// Set the timeout of the command with the given opcode
+    ///
+    /// Returns false if already [MAX_COMMAND_TIMEOUTS] other commands have their own timeout.
+    pub fn set(&mut self, opcode: u16, millis: u64) -> bool {
+        let slot = match self
+            .timeouts
+            .iter()
+            .position(|t| matches!(t, Some((code, _)) if *code == opcode))
+        {
+            Some(index) => index,
+            None => match self.timeouts.iter().position(Option::is_none) {
+                Some(index) => index,
+                None => return false,
+            },
+        };
+
+        self.timeouts[slot] = Some((opcode, millis));
+        true
+    }
+
+    /// Use the default timeout for the command with the given opcode again
+    pub fn remove(&mut self, opcode: u16) {
+        for timeout in self.timeouts.iter_mut() {
+            if matches!(timeout, Some((code, _)) if *code == opcode) {
+                *timeout = None;
+            }
+        }
+    }
+
+    /// The timeout of the command with the given opcode
+    pub fn get(&self, opcode: u16) -> u64 {
+        self.timeouts
+            .iter()
+            .flatten()
+            .find(|(code, _)| *code == opcode)
+            .map(|(_, millis)| *millis)
+            .unwrap_or(self.default_millis)
+    }
+}
+
+impl Default for CommandTimeouts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Return parameters of a command, decoded from a Command Complete event
+///
+/// Implement this together with [CustomCommand] to send commands `bleps` doesn't know about.
+pub trait ReturnParameters: Sized {
+    /// Decode the return parameters following the status, `None` if they are malformed
+    ///
+    /// Commands answered by a Command Status event have no return parameters, `data` is
+    /// empty then.
+    fn decode(data: &[u8]) -> Option<Self>;
+}
+
+impl ReturnParameters for () {
+    fn decode(_data: &[u8]) -> Option<Self> {
+        Some(())
+    }
+}
+
+impl ReturnParameters for u8 {
+    fn decode(data: &[u8]) -> Option<Self> {
+        data.first().copied()
+    }
+}
+
+impl ReturnParameters for u16 {
+    fn decode(data: &[u8]) -> Option<Self> {
+        Some(u16::from_le_bytes(data.get(..2)?.try_into().ok()?))
+    }
+}
+
+impl<const N: usize> ReturnParameters for [u8; N] {
+    fn decode(data: &[u8]) -> Option<Self> {
+        data.get(..N)?.try_into().ok()
+    }
+}
+
+/// A command unknown to `bleps`, e.g. a vendor specific one, sent with `Ble::cmd_custom`
+pub trait CustomCommand {
+    const OGF: u8;
+    const OCF: u16;
+    type ReturnParameters: ReturnParameters;
+
+    /// Append the parameters of the command, at most [MAX_COMMAND_PARAMETERS_LEN] bytes
+    fn write_parameters(&self, params: &mut Data);
+}
+
 #[derive(Debug)]
 pub struct CommandHeader {
     pub opcode: u16,
@@ -59,13 +237,141 @@ pub enum Command<'a> {
     Reset,
     LeSetAdvertisingParameters,
     LeSetAdvertisingParametersCustom(&'a AdvertisingParameters),
-    LeSetAdvertisingData { data: Data },
-    LeSetScanRspData { data: Data },
+    LeSetAdvertisingData {
+        data: Data,
+    },
+    LeSetScanRspData {
+        data: Data,
+    },
     LeSetAdvertiseEnable(bool),
-    Disconnect { connection_handle: u16, reason: u8 },
-    LeLongTermKeyRequestReply { handle: u16, ltk: u128 },
+    /// Random address in little-endian byte order
+    LeSetRandomAddress([u8; 6]),
+    LeSetScanParameters(&'a ScanParameters),
+    LeSetScanEnable {
+        enable: bool,
+        filter_duplicates: bool,
+    },
+    LeCreateConnection(&'a CreateConnectionParameters),
+    LeCreateConnectionCancel,
+    LeConnectionUpdate {
+        handle: u16,
+        params: &'a ConnectionUpdateParameters,
+    },
+    LeSetDataLength {
+        handle: u16,
+        length: DataLength,
+    },
+    LeReadSuggestedDefaultDataLength,
+    LeWriteSuggestedDefaultDataLength(DataLength),
+    LeReadPhy {
+        handle: u16,
+    },
+    /// PHYs are combinations of `PHY_LE_1M`, `PHY_LE_2M` and `PHY_LE_CODED`, 0 for no
+    /// preference
+    LeSetPhy {
+        handle: u16,
+        tx_phys: u8,
+        rx_phys: u8,
+        coded_options: CodedPhyOptions,
+    },
+    LeReadFilterAcceptListSize,
+    LeClearFilterAcceptList,
+    LeAddDeviceToFilterAcceptList {
+        address_type: PeerAddressType,
+        address: [u8; 6],
+    },
+    LeRemoveDeviceFromFilterAcceptList {
+        address_type: PeerAddressType,
+        address: [u8; 6],
+    },
+    /// IRKs of 0 disable address resolution for that side
+    LeAddDeviceToResolvingList {
+        peer_address_type: PeerAddressType,
+        peer_address: [u8; 6],
+        peer_irk: u128,
+        local_irk: u128,
+    },
+    LeRemoveDeviceFromResolvingList {
+        peer_address_type: PeerAddressType,
+        peer_address: [u8; 6],
+    },
+    LeClearResolvingList,
+    LeSetAddressResolutionEnable(bool),
+    /// Interval in seconds in which the controller generates new resolvable private addresses
+    LeSetResolvablePrivateAddressTimeout(u16),
+    Disconnect {
+        connection_handle: u16,
+        reason: u8,
+    },
+    LeLongTermKeyRequestReply {
+        handle: u16,
+        ltk: u128,
+    },
+    LeLongTermKeyRequestNegativeReply {
+        handle: u16,
+    },
+    LeStartEncryption {
+        handle: u16,
+        random: u64,
+        diversifier: u16,
+        ltk: u128,
+    },
+    LeSetAdvertisingSetRandomAddress {
+        handle: u8,
+        address: [u8; 6],
+    },
+    LeSetExtendedAdvertisingParameters(&'a ExtendedAdvertisingParameters),
+    LeSetExtendedAdvertisingData {
+        handle: u8,
+        operation: AdvertisingDataOperation,
+        data: &'a [u8],
+    },
+    LeSetExtendedScanRspData {
+        handle: u8,
+        operation: AdvertisingDataOperation,
+        data: &'a [u8],
+    },
+    LeSetExtendedAdvertisingEnable {
+        enable: bool,
+        sets: &'a [AdvertisingSet],
+    },
+    LeRemoveAdvertisingSet {
+        handle: u8,
+    },
+    LeClearAdvertisingSets,
     ReadBrAddr,
-    SetEventMask { events: [u8; 8] },
+    ReadBufferSize,
+    LeReadBufferSize,
+    /// Key and plaintext as numbers like in the security functions of the `crypto` module
+    LeEncrypt {
+        key: u128,
+        plaintext: u128,
+    },
+    LeRand,
+    /// Any command with the given parameters, at most [MAX_COMMAND_PARAMETERS_LEN] bytes
+    Raw {
+        ogf: u8,
+        ocf: u16,
+        params: &'a [u8],
+    },
+    ReadLocalVersionInformation,
+    LeReadLocalSupportedFeatures,
+    LeReadAdvertisingPhysicalChannelTxPower,
+    ReadRssi {
+        handle: u16,
+    },
+    LeReadChannelMap {
+        handle: u16,
+    },
+    LeReadRemoteFeatures {
+        handle: u16,
+    },
+    SetEventMask {
+        events: EventMask,
+    },
+    LeSetEventMask {
+        events: LeEventMask,
+    },
 }
 
 impl<'a> Command<'a> {
@@ -133,6 +439,231 @@ impl<'a> Command<'a> {
                 data[4] = if enable { 1 } else { 0 };
                 Data::new(&data)
             }
+            Command::LeSetRandomAddress(address) => {
+                let mut data = [0u8; 4 + 6];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, SET_RANDOM_ADDRESS_OCF, 0x06)
+                    .write_into(&mut data[1..]);
+                data[4..].copy_from_slice(&address);
+                Data::new(&data)
+            }
+            Command::LeSetScanParameters(params) => {
+                let mut data = [0u8; 4 + 7];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, SET_SCAN_PARAMETERS_OCF, 0x07)
+                    .write_into(&mut data[1..]);
+                data[4] = params.scan_type as u8;
+                data[5..][..2].copy_from_slice(&params.scan_interval.to_le_bytes());
+                data[7..][..2].copy_from_slice(&params.scan_window.to_le_bytes());
+                data[9] = params.own_address_type as u8;
+                data[10] = params.filter_policy as u8;
+                Data::new(&data)
+            }
+            Command::LeSetScanEnable {
+                enable,
+                filter_duplicates,
+            } => {
+                let mut data = [0u8; 6];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, SET_SCAN_ENABLE_OCF, 0x02)
+                    .write_into(&mut data[1..]);
+                data[4] = enable as u8;
+                data[5] = filter_duplicates as u8;
+                Data::new(&data)
+            }
+            Command::LeCreateConnection(params) => {
+                let mut data = [0u8; 4 + 25];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, CREATE_CONNECTION_OCF, 25)
+                    .write_into(&mut data[1..]);
+
+                let mut conn_params = Data::new(&[]);
+                conn_params.append(&params.scan_interval.to_le_bytes());
+                conn_params.append(&params.scan_window.to_le_bytes());
+                conn_params.append(&[params.filter_policy as u8]);
+                conn_params.append(&[params.peer_address_type as u8]);
+                conn_params.append(&params.peer_address);
+                conn_params.append(&[params.own_address_type as u8]);
+                conn_params.append(&params.interval_min.to_le_bytes());
+                conn_params.append(&params.interval_max.to_le_bytes());
+                conn_params.append(&params.max_latency.to_le_bytes());
+                conn_params.append(&params.supervision_timeout.to_le_bytes());
+                conn_params.append(&params.min_ce_length.to_le_bytes());
+                conn_params.append(&params.max_ce_length.to_le_bytes());
+
+                data[4..].copy_from_slice(conn_params.as_slice());
+                Data::new(&data)
+            }
+            Command::LeCreateConnectionCancel => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, CREATE_CONNECTION_CANCEL_OCF, 0x00)
+                    .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
+            Command::LeConnectionUpdate { handle, params } => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, CONNECTION_UPDATE_OCF, 14)
+                    .write_into(&mut data[1..]);
+
+                let mut data = Data::new(&data);
+                data.append(&handle.to_le_bytes());
+                data.append(&params.interval_min.to_le_bytes());
+                data.append(&params.interval_max.to_le_bytes());
+                data.append(&params.max_latency.to_le_bytes());
+                data.append(&params.supervision_timeout.to_le_bytes());
+                data.append(&params.min_ce_length.to_le_bytes());
+                data.append(&params.max_ce_length.to_le_bytes());
+                data
+            }
+            Command::LeSetDataLength { handle, length } => {
+                let mut data = [0u8; 4 + 6];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, SET_DATA_LENGTH_OCF, 0x06)
+                    .write_into(&mut data[1..]);
+                data[4..][..2].copy_from_slice(&handle.to_le_bytes());
+                data[6..][..2].copy_from_slice(&length.tx_octets.to_le_bytes());
+                data[8..][..2].copy_from_slice(&length.tx_time.to_le_bytes());
+                Data::new(&data)
+            }
+            Command::LeReadSuggestedDefaultDataLength => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, READ_SUGGESTED_DEFAULT_DATA_LENGTH_OCF, 0x00)
+                    .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
+            Command::LeWriteSuggestedDefaultDataLength(length) => {
+                let mut data = [0u8; 4 + 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, WRITE_SUGGESTED_DEFAULT_DATA_LENGTH_OCF, 0x04)
+                    .write_into(&mut data[1..]);
+                data[4..][..2].copy_from_slice(&length.tx_octets.to_le_bytes());
+                data[6..][..2].copy_from_slice(&length.tx_time.to_le_bytes());
+                Data::new(&data)
+            }
+            Command::LeReadPhy { handle } => {
+                let mut data = [0u8; 4 + 2];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, READ_PHY_OCF, 0x02).write_into(&mut data[1..]);
+                data[4..].copy_from_slice(&handle.to_le_bytes());
+                Data::new(&data)
+            }
+            Command::LeSetPhy {
+                handle,
+                tx_phys,
+                rx_phys,
+                coded_options,
+            } => {
+                let mut data = [0u8; 4 + 7];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, SET_PHY_OCF, 0x07).write_into(&mut data[1..]);
+                data[4..][..2].copy_from_slice(&handle.to_le_bytes());
+                // a PHY mask of 0 means no preference for that direction
+                data[6] = (tx_phys == 0) as u8 | ((rx_phys == 0) as u8) << 1;
+                data[7] = tx_phys;
+                data[8] = rx_phys;
+                data[9..].copy_from_slice(&(coded_options as u16).to_le_bytes());
+                Data::new(&data)
+            }
+            Command::LeReadFilterAcceptListSize => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, READ_FILTER_ACCEPT_LIST_SIZE_OCF, 0x00)
+                    .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
+            Command::LeClearFilterAcceptList => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, CLEAR_FILTER_ACCEPT_LIST_OCF, 0x00)
+                    .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
+            Command::LeAddDeviceToFilterAcceptList {
+                address_type,
+                address,
+            } => {
+                let mut data = [0u8; 4 + 7];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, ADD_DEVICE_TO_FILTER_ACCEPT_LIST_OCF, 0x07)
+                    .write_into(&mut data[1..]);
+                data[4] = address_type as u8;
+                data[5..].copy_from_slice(&address);
+                Data::new(&data)
+            }
+            Command::LeRemoveDeviceFromFilterAcceptList {
+                address_type,
+                address,
+            } => {
+                let mut data = [0u8; 4 + 7];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(
+                    LE_OGF,
+                    REMOVE_DEVICE_FROM_FILTER_ACCEPT_LIST_OCF,
+                    0x07,
+                )
+                .write_into(&mut data[1..]);
+                data[4] = address_type as u8;
+                data[5..].copy_from_slice(&address);
+                Data::new(&data)
+            }
+            Command::LeAddDeviceToResolvingList {
+                peer_address_type,
+                peer_address,
+                peer_irk,
+                local_irk,
+            } => {
+                let mut data = [0u8; 4 + 39];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, ADD_DEVICE_TO_RESOLVING_LIST_OCF, 39)
+                    .write_into(&mut data[1..]);
+                data[4] = peer_address_type as u8;
+                data[5..][..6].copy_from_slice(&peer_address);
+                data[11..][..16].copy_from_slice(&peer_irk.to_le_bytes());
+                data[27..].copy_from_slice(&local_irk.to_le_bytes());
+                Data::new(&data)
+            }
+            Command::LeRemoveDeviceFromResolvingList {
+                peer_address_type,
+                peer_address,
+            } => {
+                let mut data = [0u8; 4 + 7];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, REMOVE_DEVICE_FROM_RESOLVING_LIST_OCF, 0x07)
+                    .write_into(&mut data[1..]);
+                data[4] = peer_address_type as u8;
+                data[5..].copy_from_slice(&peer_address);
+                Data::new(&data)
+            }
+            Command::LeClearResolvingList => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, CLEAR_RESOLVING_LIST_OCF, 0x00)
+                    .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
+            Command::LeSetAddressResolutionEnable(enable) => {
+                let mut data = [0u8; 5];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, SET_ADDRESS_RESOLUTION_ENABLE_OCF, 0x01)
+                    .write_into(&mut data[1..]);
+                data[4] = enable as u8;
+                Data::new(&data)
+            }
+            Command::LeSetResolvablePrivateAddressTimeout(timeout) => {
+                let mut data = [0u8; 4 + 2];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(
+                    LE_OGF,
+                    SET_RESOLVABLE_PRIVATE_ADDRESS_TIMEOUT_OCF,
+                    0x02,
+                )
+                .write_into(&mut data[1..]);
+                data[4..].copy_from_slice(&timeout.to_le_bytes());
+                Data::new(&data)
+            }
             Command::Disconnect {
                 connection_handle,
                 reason,
@@ -154,6 +685,110 @@ impl<'a> Command<'a> {
                 data[6..].copy_from_slice(&ltk.to_le_bytes());
                 Data::new(&data)
             }
+            Command::LeLongTermKeyRequestNegativeReply { handle } => {
+                let mut data = [0u8; 4 + 2];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, LONG_TERM_KEY_REQUEST_NEGATIVE_REPLY_OCF, 0x02)
+                    .write_into(&mut data[1..]);
+                data[4..].copy_from_slice(&handle.to_le_bytes());
+                Data::new(&data)
+            }
+            Command::LeStartEncryption {
+                handle,
+                random,
+                diversifier,
+                ltk,
+            } => {
+                let mut data = [0u8; 4 + 28];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, START_ENCRYPTION_OCF, 28)
+                    .write_into(&mut data[1..]);
+                data[4..][..2].copy_from_slice(&handle.to_le_bytes());
+                data[6..][..8].copy_from_slice(&random.to_le_bytes());
+                data[14..][..2].copy_from_slice(&diversifier.to_le_bytes());
+                data[16..].copy_from_slice(&ltk.to_le_bytes());
+                Data::new(&data)
+            }
+            Command::LeSetAdvertisingSetRandomAddress { handle, address } => {
+                let mut data = [0u8; 4 + 7];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, SET_ADVERTISING_SET_RANDOM_ADDRESS_OCF, 0x07)
+                    .write_into(&mut data[1..]);
+                data[4] = handle;
+                data[5..].copy_from_slice(&address);
+                Data::new(&data)
+            }
+            Command::LeSetExtendedAdvertisingParameters(params) => {
+                let mut data = [0u8; 4 + 25];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, SET_EXTENDED_ADVERTISING_PARAMETERS_OCF, 25)
+                    .write_into(&mut data[1..]);
+
+                let mut adv_params = Data::new(&[]);
+                adv_params.append(&[params.handle]);
+                adv_params.append(&params.properties.to_le_bytes());
+                adv_params.append(&params.interval_min.to_le_bytes()[..3]);
+                adv_params.append(&params.interval_max.to_le_bytes()[..3]);
+                adv_params.append(&[params.channel_map]);
+                adv_params.append(&[params.own_address_type as u8]);
+                adv_params.append(&[params.peer_address_type as u8]);
+                adv_params.append(&params.peer_address);
+                adv_params.append(&[params.filter_policy as u8]);
+                adv_params.append(&[params.tx_power as u8]);
+                adv_params.append(&[params.primary_phy as u8]);
+                adv_params.append(&[params.secondary_max_skip]);
+                adv_params.append(&[params.secondary_phy as u8]);
+                adv_params.append(&[params.sid]);
+                adv_params.append(&[params.scan_request_notification as u8]);
+
+                data[4..].copy_from_slice(adv_params.as_slice());
+                Data::new(&data)
+            }
+            Command::LeSetExtendedAdvertisingData {
+                handle,
+                operation,
+                data,
+            } => encode_extended_data(SET_EXTENDED_ADVERTISING_DATA_OCF, handle, operation, data),
+            Command::LeSetExtendedScanRspData {
+                handle,
+                operation,
+                data,
+            } => encode_extended_data(SET_EXTENDED_SCAN_RSP_DATA_OCF, handle, operation, data),
+            Command::LeSetExtendedAdvertisingEnable { enable, sets } => {
+                let mut header = [0u8; 6];
+                header[0] = 0x01;
+                CommandHeader::from_ogf_ocf(
+                    LE_OGF,
+                    SET_EXTENDED_ADVERTISING_ENABLE_OCF,
+                    (2 + sets.len() * 4) as u8,
+                )
+                .write_into(&mut header[1..]);
+                header[4] = enable as u8;
+                header[5] = sets.len() as u8;
+
+                let mut res = Data::new(&header);
+                for set in sets {
+                    res.append(&[set.handle]);
+                    res.append(&set.duration.to_le_bytes());
+                    res.append(&[set.max_events]);
+                }
+                res
+            }
+            Command::LeRemoveAdvertisingSet { handle } => {
+                let mut data = [0u8; 5];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, REMOVE_ADVERTISING_SET_OCF, 0x01)
+                    .write_into(&mut data[1..]);
+                data[4] = handle;
+                Data::new(&data)
+            }
+            Command::LeClearAdvertisingSets => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, CLEAR_ADVERTISING_SETS_OCF, 0x00)
+                    .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
             Command::ReadBrAddr => {
                 log::debug!("command read br addr");
                 let mut data = [0u8; 4];
@@ -162,15 +797,131 @@ impl<'a> Command<'a> {
                     .write_into(&mut data[1..]);
                 Data::new(&data)
             }
+            Command::ReadBufferSize => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(INFORMATIONAL_OGF, READ_BUFFER_SIZE_OCF, 0x00)
+                    .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
+            Command::LeReadBufferSize => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, LE_READ_BUFFER_SIZE_OCF, 0x00)
+                    .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
+            Command::LeEncrypt { key, plaintext } => {
+                let mut data = [0u8; 4 + 32];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, ENCRYPT_OCF, 0x20).write_into(&mut data[1..]);
+                data[4..][..16].copy_from_slice(&key.to_le_bytes());
+                data[20..].copy_from_slice(&plaintext.to_le_bytes());
+                Data::new(&data)
+            }
+            Command::LeRand => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, RAND_OCF, 0x00).write_into(&mut data[1..]);
+                Data::new(&data)
+            }
+            Command::Raw { ogf, ocf, params } => {
+                let len = params.len().min(MAX_COMMAND_PARAMETERS_LEN);
+                let mut data = [0u8; 4 + MAX_COMMAND_PARAMETERS_LEN];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(ogf, ocf, len as u8).write_into(&mut data[1..]);
+                data[4..][..len].copy_from_slice(&params[..len]);
+                Data::new(&data[..4 + len])
+            }
+            Command::ReadLocalVersionInformation => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(
+                    INFORMATIONAL_OGF,
+                    READ_LOCAL_VERSION_INFORMATION_OCF,
+                    0x00,
+                )
+                .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
+            Command::LeReadLocalSupportedFeatures => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, READ_LOCAL_SUPPORTED_FEATURES_OCF, 0x00)
+                    .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
+            Command::LeReadAdvertisingPhysicalChannelTxPower => {
+                let mut data = [0u8; 4];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(
+                    LE_OGF,
+                    READ_ADVERTISING_PHYSICAL_CHANNEL_TX_POWER_OCF,
+                    0x00,
+                )
+                .write_into(&mut data[1..]);
+                Data::new(&data)
+            }
+            Command::ReadRssi { handle } => {
+                let mut data = [0u8; 4 + 2];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(STATUS_PARAMETERS_OGF, READ_RSSI_OCF, 0x02)
+                    .write_into(&mut data[1..]);
+                data[4..].copy_from_slice(&handle.to_le_bytes());
+                Data::new(&data)
+            }
+            Command::LeReadChannelMap { handle } => {
+                let mut data = [0u8; 4 + 2];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, READ_CHANNEL_MAP_OCF, 0x02)
+                    .write_into(&mut data[1..]);
+                data[4..].copy_from_slice(&handle.to_le_bytes());
+                Data::new(&data)
+            }
+            Command::LeReadRemoteFeatures { handle } => {
+                let mut data = [0u8; 4 + 2];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, READ_REMOTE_FEATURES_OCF, 0x02)
+                    .write_into(&mut data[1..]);
+                data[4..].copy_from_slice(&handle.to_le_bytes());
+                Data::new(&data)
+            }
             Command::SetEventMask { events } => {
                 log::debug!("command set event mask");
                 let mut data = [0u8; 12];
                 data[0] = 0x01;
                 CommandHeader::from_ogf_ocf(CONTROLLER_OGF, SET_EVENT_MASK_OCF, 0x08)
                     .write_into(&mut data[1..]);
-                data[4..].copy_from_slice(&events);
+                data[4..].copy_from_slice(&events.0.to_le_bytes());
+                Data::new(&data)
+            }
+            Command::LeSetEventMask { events } => {
+                let mut data = [0u8; 12];
+                data[0] = 0x01;
+                CommandHeader::from_ogf_ocf(LE_OGF, LE_SET_EVENT_MASK_OCF, 0x08)
+                    .write_into(&mut data[1..]);
+                data[4..].copy_from_slice(&events.0.to_le_bytes());
                 Data::new(&data)
             }
         }
     }
 }
+
+fn encode_extended_data(
+    ocf: u16,
+    handle: u8,
+    operation: AdvertisingDataOperation,
+    data: &[u8],
+) -> Data {
+    let mut header = [0u8; 8];
+    header[0] = 0x01;
+    CommandHeader::from_ogf_ocf(LE_OGF, ocf, (4 + data.len()) as u8).write_into(&mut header[1..]);
+    header[4] = handle;
+    header[5] = operation as u8;
+    // the controller should not fragment the data
+    header[6] = 0x01;
+    header[7] = data.len() as u8;
+    let mut res = Data::new(&header);
+    res.append(data);
+    res
+}
@@ -0,0 +1,223 @@
+use crate::{event::EventType, Addr, OwnAddressType, PeerAddressType};
+
+#[derive(Debug, Clone, Copy)]
+pub enum InitiatorFilterPolicy {
+    /// Connect to the device given by the peer address
+    PeerAddress = 0x00,
+    /// Connect to any device in the filter accept list
+    FilterAcceptList = 0x01,
+}
+
+/// Parameters of the LE Create Connection command
+#[derive(Debug, Clone, Copy)]
+pub struct CreateConnectionParameters {
+    /// Scan interval in units of 0.625 ms
+    pub scan_interval: u16,
+    /// Scan window in units of 0.625 ms
+    pub scan_window: u16,
+    pub filter_policy: InitiatorFilterPolicy,
+    pub peer_address_type: PeerAddressType,
+    /// Peer address in little-endian byte order
+    pub peer_address: [u8; 6],
+    pub own_address_type: OwnAddressType,
+    /// Minimum connection interval in units of 1.25 ms
+    pub interval_min: u16,
+    /// Maximum connection interval in units of 1.25 ms
+    pub interval_max: u16,
+    pub max_latency: u16,
+    /// Supervision timeout in units of 10 ms
+    pub supervision_timeout: u16,
+    pub min_ce_length: u16,
+    pub max_ce_length: u16,
+}
+
+impl CreateConnectionParameters {
+    /// Parameters to connect to the given peer with reasonable defaults
+    pub fn new(peer_address_type: PeerAddressType, peer_address: [u8; 6]) -> Self {
+        Self {
+            scan_interval: 0x0060,
+            scan_window: 0x0030,
+            filter_policy: InitiatorFilterPolicy::PeerAddress,
+            peer_address_type,
+            peer_address,
+            own_address_type: OwnAddressType::Public,
+            interval_min: 0x0018,
+            interval_max: 0x0028,
+            max_latency: 0,
+            supervision_timeout: 0x01f4,
+            min_ce_length: 0,
+            max_ce_length: 0,
+        }
+    }
+}
+
+/// Connection parameters requested via the LE Connection Update command or the L2CAP
+/// Connection Parameter Update Request
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConnectionUpdateParameters {
+    /// Minimum connection interval in units of 1.25 ms
+    pub interval_min: u16,
+    /// Maximum connection interval in units of 1.25 ms
+    pub interval_max: u16,
+    pub max_latency: u16,
+    /// Supervision timeout in units of 10 ms
+    pub supervision_timeout: u16,
+    pub min_ce_length: u16,
+    pub max_ce_length: u16,
+}
+
+impl ConnectionUpdateParameters {
+    /// Parameters for the given range of the connection interval in units of 1.25 ms
+    pub fn new(interval_min: u16, interval_max: u16) -> Self {
+        Self {
+            interval_min,
+            interval_max,
+            max_latency: 0,
+            supervision_timeout: 0x01f4,
+            min_ce_length: 0,
+            max_ce_length: 0,
+        }
+    }
+
+    /// Checks the ranges given in [Vol 4] Part E, Section 7.8.18
+    ///
+    /// The supervision timeout needs to be longer than `(1 + max_latency) * interval_max * 2`.
+    pub fn is_valid(&self) -> bool {
+        (0x0006..=0x0c80).contains(&self.interval_min)
+            && (0x0006..=0x0c80).contains(&self.interval_max)
+            && self.interval_min <= self.interval_max
+            && self.max_latency <= 0x01f3
+            && (0x000a..=0x0c80).contains(&self.supervision_timeout)
+            && self.supervision_timeout as u32 * 4
+                > (1 + self.max_latency as u32) * self.interval_max as u32
+    }
+}
+
+/// Maximum payload and air time of data channel PDUs ([Vol 6] Part B, Section 4.5.10)
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DataLength {
+    /// Maximum payload in bytes, 27 to 251
+    pub tx_octets: u16,
+    /// Maximum time to transmit a PDU in microseconds, 328 to 17040
+    pub tx_time: u16,
+}
+
+impl DataLength {
+    /// The largest PDUs, the controller limits the time to the PHY in use
+    pub const MAX: DataLength = DataLength {
+        tx_octets: 251,
+        tx_time: 17040,
+    };
+
+    /// The PDUs every controller supports
+    pub const MIN: DataLength = DataLength {
+        tx_octets: 27,
+        tx_time: 328,
+    };
+}
+
+/// PHYs for LE Set PHY, several can be combined
+pub const PHY_LE_1M: u8 = 0x01;
+pub const PHY_LE_2M: u8 = 0x02;
+pub const PHY_LE_CODED: u8 = 0x04;
+
+/// Coding preferred when transmitting on the LE Coded PHY
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CodedPhyOptions {
+    NoPreference = 0x00,
+    /// 500 kbit/s
+    S2 = 0x01,
+    /// 125 kbit/s for the longest range
+    S8 = 0x02,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Role {
+    Central,
+    Peripheral,
+}
+
+impl Role {
+    pub fn from_u8(value: u8) -> Role {
+        match value {
+            0x00 => Role::Central,
+            _ => Role::Peripheral,
+        }
+    }
+}
+
+/// An established LE connection
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Connection {
+    pub handle: u16,
+    pub role: Role,
+    pub peer_address: Addr,
+    /// Connection interval in units of 1.25 ms
+    pub interval: u16,
+    pub latency: u16,
+    /// Supervision timeout in units of 10 ms
+    pub timeout: u16,
+}
+
+impl Connection {
+    /// Creates a connection from a successful (Enhanced) Connection Complete event
+    pub fn from_event(event: &EventType) -> Option<Connection> {
+        match *event {
+            EventType::ConnectionComplete {
+                status: 0,
+                handle,
+                role,
+                peer_address,
+                interval,
+                latency,
+                timeout,
+            }
+            | EventType::EnhancedConnectionComplete {
+                status: 0,
+                handle,
+                role,
+                peer_address,
+                interval,
+                latency,
+                timeout,
+                ..
+            } => Some(Connection {
+                handle,
+                role: Role::from_u8(role),
+                peer_address,
+                interval,
+                latency,
+                timeout,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Applies new parameters from a Connection Update Complete event for this connection
+    pub fn update(&mut self, event: &EventType) {
+        if let EventType::ConnectionUpdateComplete {
+            status: 0,
+            handle,
+            interval,
+            latency,
+            timeout,
+        } = *event
+        {
+            if handle == self.handle {
+                self.interval = interval;
+                self.latency = latency;
+                self.timeout = timeout;
+            }
+        }
+    }
+
+    /// Returns true if the event terminates this connection
+    pub fn is_disconnected_by(&self, event: &EventType) -> bool {
+        matches!(event, EventType::DisconnectComplete { handle, .. } if *handle == self.handle)
+    }
+}
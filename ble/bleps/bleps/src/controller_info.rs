@@ -0,0 +1,97 @@
+//! Information about the controller and the quality of its links
+//!
+//! `Ble::init` reads the [ControllerInfo], e.g. to only use features the controller
+//! supports. `Ble::link_quality` reports the RSSI and channel map of a connection.
+
+/// Result of Read Local Version Information ([Vol 4] Part E, Section 7.4.1)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LocalVersion {
+    /// Core specification version of the HCI, e.g. 0x0c for 5.3
+    pub hci_version: u8,
+    pub hci_subversion: u16,
+    /// Core specification version of the link layer
+    pub lmp_version: u8,
+    /// Bluetooth SIG company identifier of the manufacturer
+    pub company_identifier: u16,
+    pub lmp_subversion: u16,
+}
+
+impl LocalVersion {
+    pub(crate) fn from_return_parameters(data: &[u8]) -> Self {
+        LocalVersion {
+            hci_version: data[1],
+            hci_subversion: u16::from_le_bytes([data[2], data[3]]),
+            lmp_version: data[4],
+            company_identifier: u16::from_le_bytes([data[5], data[6]]),
+            lmp_subversion: u16::from_le_bytes([data[7], data[8]]),
+        }
+    }
+}
+
+/// Bit numbers of the LE features ([Vol 6] Part B, Section 4.6)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LeFeature {
+    Encryption = 0,
+    ConnectionParametersRequest = 1,
+    ExtendedRejectIndication = 2,
+    PeripheralInitiatedFeaturesExchange = 3,
+    Ping = 4,
+    DataPacketLengthExtension = 5,
+    LlPrivacy = 6,
+    ExtendedScanningFilterPolicies = 7,
+    Le2mPhy = 8,
+    StableModulationIndexTransmitter = 9,
+    StableModulationIndexReceiver = 10,
+    LeCodedPhy = 11,
+    ExtendedAdvertising = 12,
+    PeriodicAdvertising = 13,
+    ChannelSelectionAlgorithm2 = 14,
+    PowerClass1 = 15,
+    MinimumNumberOfUsedChannels = 16,
+}
+
+/// Bit mask of the LE features supported by the local controller or a peer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LeFeatures(pub u64);
+
+impl LeFeatures {
+    pub fn supports(&self, feature: LeFeature) -> bool {
+        self.0 & (1 << feature as u8) != 0
+    }
+}
+
+/// Data channels used by a connection, one bit per channel 0 to 36
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelMap(pub [u8; 5]);
+
+impl ChannelMap {
+    pub fn is_used(&self, channel: u8) -> bool {
+        channel < 37 && self.0[channel as usize / 8] & (1 << (channel % 8)) != 0
+    }
+
+    /// Number of data channels in use, few channels hint at a noisy environment
+    pub fn used_channels(&self) -> u8 {
+        (0..37).filter(|channel| self.is_used(*channel)).count() as u8
+    }
+}
+
+/// What the controller reported about itself during `Ble::init`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ControllerInfo {
+    pub version: LocalVersion,
+    pub le_features: LeFeatures,
+}
+
+/// Health of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkQuality {
+    /// Received signal strength in dBm, 127 if not available
+    pub rssi: i8,
+    pub channel_map: ChannelMap,
+}
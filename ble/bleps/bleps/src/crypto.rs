@@ -263,6 +263,20 @@ impl PublicKey {
         }
     }
 
+    /// The key as sent in a Pairing Public Key PDU, both coordinates in little-endian byte order
+    pub fn to_bytes(&self) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        let (x, y) = bytes.split_at_mut(32);
+
+        x.copy_from_slice(self.x.0.as_be_bytes());
+        y.copy_from_slice(self.y.as_be_bytes());
+
+        x.reverse();
+        y.reverse();
+
+        bytes
+    }
+
     /// Returns the public key X coordinate.
     #[inline(always)]
     pub const fn x(&self) -> &PublicKeyX {
@@ -363,6 +377,90 @@ impl DHKey {
     }
 }
 
+/// Identity Resolving Key used to generate and resolve resolvable private
+/// addresses ([Vol 3] Part H, Section 2.4.2.1).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[must_use]
+#[repr(transparent)]
+pub struct Irk(pub u128);
+
+impl Irk {
+    /// Creates a random IRK.
+    #[inline]
+    pub fn new<T: RngCore + CryptoRng>(rng: &mut T) -> Self {
+        let mut b = [0; core::mem::size_of::<u128>()];
+        rng.fill_bytes(b.as_mut_slice());
+        Self(u128::from_ne_bytes(b))
+    }
+
+    /// Random address hash function `ah` ([Vol 3] Part H, Section 2.2.2).
+    /// Only the lower 24 bits of `r` and of the result are used.
+    #[inline]
+    #[must_use]
+    pub fn ah(&self, r: u32) -> u32 {
+        let r = u128::from(r & 0x00ff_ffff);
+        (e(&Key::new(self.0), r) & 0x00ff_ffff) as u32
+    }
+
+    /// Generates a new resolvable private address ([Vol 6] Part B, Section
+    /// 1.3.2.2).
+    pub fn generate_rpa<T: RngCore>(&self, rng: &mut T) -> Addr {
+        // the random part of prand must not be all zeros or all ones
+        let random = loop {
+            let random = rng.next_u32() & 0x003f_ffff;
+            if random != 0 && random != 0x003f_ffff {
+                break random;
+            }
+        };
+        let prand = 0x0040_0000 | random;
+        let hash = self.ah(prand);
+
+        let mut a = [0; 7];
+        a[0] = 1;
+        a[1..4].copy_from_slice(&prand.to_be_bytes()[1..]);
+        a[4..].copy_from_slice(&hash.to_be_bytes()[1..]);
+        Addr(a)
+    }
+
+    /// Returns true if `addr` is a resolvable private address generated from
+    /// this IRK ([Vol 6] Part B, Section 1.3.2.3).
+    #[must_use]
+    pub fn resolves(&self, addr: &Addr) -> bool {
+        if addr.kind() != crate::AddressKind::ResolvablePrivate {
+            return false;
+        }
+
+        let prand = u32::from_be_bytes([0, addr.0[1], addr.0[2], addr.0[3]]);
+        let hash = u32::from_be_bytes([0, addr.0[4], addr.0[5], addr.0[6]]);
+        self.ah(prand) == hash
+    }
+}
+
+/// AES-128 encryption of a single block with the byte order of the security
+/// functions.
+///
+/// This is a software implementation, like the one the security functions use
+/// since they are computed while handling a pairing request. `Ble::cmd_le_encrypt`
+/// computes the same with the controller's AES engine.
+#[inline]
+#[must_use]
+pub fn aes128(key: u128, plaintext: u128) -> u128 {
+    e(&Key::new(key), plaintext)
+}
+
+/// Security function `e`, AES-128 encryption of a single block ([Vol 3] Part
+/// H, Section 2.2.1).
+#[inline]
+#[must_use]
+pub(super) fn e(k: &Key, plaintext: u128) -> u128 {
+    use aes::cipher::{BlockEncrypt, KeyInit};
+
+    let cipher = aes::Aes128::new(&k.0);
+    let mut block = plaintext.to_be_bytes().into();
+    cipher.encrypt_block(&mut block);
+    u128::from_be_bytes(block.into())
+}
+
 /// Combines `hi` and `lo` values into a big-endian byte array.
 #[allow(clippy::redundant_pub_crate)]
 #[cfg(test)]
@@ -382,6 +480,37 @@ mod tests {
     use super::*;
     extern crate std;
 
+    /// Random address hash function ah ([Vol 3] Part H, Appendix D.7).
+    #[test]
+    fn irk_ah() {
+        let irk = Irk(0xec0234a3_57c8ad05_341010a6_0a397d9b);
+        assert_eq!(irk.ah(0x708194), 0x0dfbaa);
+
+        let rpa = Addr([0x01, 0x70, 0x81, 0x94, 0x0d, 0xfb, 0xaa]);
+        assert!(irk.resolves(&rpa));
+        assert!(!Irk(0).resolves(&rpa));
+    }
+
+    #[test]
+    fn irk_generate_rpa() {
+        let irk = Irk::new(&mut OsRng);
+        let rpa = irk.generate_rpa(&mut OsRng);
+        assert_eq!(rpa.kind(), crate::AddressKind::ResolvablePrivate);
+        assert!(irk.resolves(&rpa));
+    }
+
+    #[test]
+    fn aes128_fips_197() {
+        // FIPS-197, Appendix C.1
+        assert_eq!(
+            aes128(
+                0x00010203_04050607_08090a0b_0c0d0e0f,
+                0x00112233_44556677_8899aabb_ccddeeff
+            ),
+            0x69c4e0d8_6a7b0430_d8cdb780_70b4c55a
+        );
+    }
+
     #[test]
     fn sizes() {
         assert_eq!(core::mem::size_of::<Coord>(), 32);
@@ -7,7 +7,7 @@ pub struct Event {
     data: Data,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum EventType {
     CommandComplete {
@@ -15,15 +15,35 @@ pub enum EventType {
         opcode: u16,
         data: Data,
     },
+    /// The controller received a command and started to process it
+    CommandStatus {
+        status: u8,
+        num_packets: u8,
+        opcode: u16,
+    },
     DisconnectComplete {
         handle: u16,
         status: ErrorCode,
         reason: ErrorCode,
     },
+    EncryptionChange {
+        status: u8,
+        handle: u16,
+        enabled: bool,
+    },
+    EncryptionKeyRefreshComplete {
+        status: u8,
+        handle: u16,
+    },
+    HardwareError {
+        code: u8,
+    },
+    /// The controller ran out of buffers, data or events got lost
+    DataBufferOverflow {
+        link_type: u8,
+    },
     NumberOfCompletedPackets {
-        number_of_connection_handles: u8,
-        connection_handles: u16, // should be list
-        completed_packets: u16,  // should be list
+        completed: CompletedPackets,
     },
     ConnectionComplete {
         status: u8,
@@ -34,14 +54,243 @@ pub enum EventType {
         latency: u16,
         timeout: u16,
     },
+    /// Like [EventType::ConnectionComplete], but also reports the resolvable private
+    /// addresses used if address resolution is enabled
+    EnhancedConnectionComplete {
+        status: u8,
+        handle: u16,
+        role: u8,
+        peer_address: Addr,
+        /// Local resolvable private address in little-endian byte order, zero if not used
+        local_resolvable_private_address: [u8; 6],
+        /// Peer resolvable private address in little-endian byte order, zero if not used
+        peer_resolvable_private_address: [u8; 6],
+        interval: u16,
+        latency: u16,
+        timeout: u16,
+    },
+    ConnectionUpdateComplete {
+        status: u8,
+        handle: u16,
+        /// Connection interval in units of 1.25 ms
+        interval: u16,
+        latency: u16,
+        /// Supervision timeout in units of 10 ms
+        timeout: u16,
+    },
+    ReadRemoteFeaturesComplete {
+        status: u8,
+        handle: u16,
+        /// Bit mask of the LE features supported by the peer
+        features: u64,
+    },
     LongTermKeyRequest {
         handle: u16,
         random: u64,
         diversifier: u16,
     },
+    /// The peer requests new connection parameters, to be answered with a reply or
+    /// negative reply
+    RemoteConnectionParameterRequest {
+        handle: u16,
+        interval_min: u16,
+        interval_max: u16,
+        max_latency: u16,
+        timeout: u16,
+    },
+    DataLengthChange {
+        handle: u16,
+        max_tx_octets: u16,
+        max_tx_time: u16,
+        max_rx_octets: u16,
+        max_rx_time: u16,
+    },
+    /// PHYs are reported as 1: LE 1M, 2: LE 2M, 3: LE Coded
+    PhyUpdateComplete {
+        status: u8,
+        handle: u16,
+        tx_phy: u8,
+        rx_phy: u8,
+    },
+    AdvertisingReport {
+        reports: AdvertisingReports,
+    },
+    /// An advertising set stopped, because of a connection or because its duration or
+    /// number of events ran out
+    AdvertisingSetTerminated {
+        status: u8,
+        advertising_handle: u8,
+        /// The connection created, only valid if `status` is 0
+        connection_handle: u16,
+        num_completed_events: u8,
+    },
     Unknown,
 }
 
+/// Event type of a single advertising report
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AdvertisingReportType {
+    AdvInd,
+    AdvDirectInd,
+    AdvScanInd,
+    AdvNonConnInd,
+    ScanRsp,
+    Unknown(u8),
+}
+
+impl AdvertisingReportType {
+    pub fn from_u8(value: u8) -> AdvertisingReportType {
+        match value {
+            0x00 => AdvertisingReportType::AdvInd,
+            0x01 => AdvertisingReportType::AdvDirectInd,
+            0x02 => AdvertisingReportType::AdvScanInd,
+            0x03 => AdvertisingReportType::AdvNonConnInd,
+            0x04 => AdvertisingReportType::ScanRsp,
+            _ => AdvertisingReportType::Unknown(value),
+        }
+    }
+}
+
+/// Address type as reported by the controller
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AddressType {
+    Public,
+    Random,
+    PublicIdentity,
+    RandomIdentity,
+    Unknown(u8),
+}
+
+impl AddressType {
+    pub fn from_u8(value: u8) -> AddressType {
+        match value {
+            0x00 => AddressType::Public,
+            0x01 => AddressType::Random,
+            0x02 => AddressType::PublicIdentity,
+            0x03 => AddressType::RandomIdentity,
+            _ => AddressType::Unknown(value),
+        }
+    }
+
+    pub fn is_random(&self) -> bool {
+        matches!(self, AddressType::Random | AddressType::RandomIdentity)
+    }
+}
+
+/// The reports contained in an LE Advertising Report event
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AdvertisingReports {
+    pub num_reports: u8,
+    data: Data,
+}
+
+impl AdvertisingReports {
+    pub fn iter(&self) -> AdvertisingReportIter<'_> {
+        AdvertisingReportIter {
+            remaining: self.num_reports,
+            data: self.data.as_slice(),
+        }
+    }
+}
+
+/// The connection handles and number of completed packets contained in a
+/// Number Of Completed Packets event
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CompletedPackets {
+    pub number_of_handles: u8,
+    data: Data,
+}
+
+impl CompletedPackets {
+    /// Iterate over `(connection handle, number of completed packets)`
+    pub fn iter(&self) -> CompletedPacketsIter<'_> {
+        CompletedPacketsIter {
+            remaining: self.number_of_handles,
+            data: self.data.as_slice(),
+        }
+    }
+}
+
+pub struct CompletedPacketsIter<'a> {
+    remaining: u8,
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for CompletedPacketsIter<'a> {
+    type Item = (u16, u16);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 || self.data.len() < 4 {
+            return None;
+        }
+
+        let handle = u16::from_le_bytes([self.data[0], self.data[1]]) & 0xfff;
+        let count = u16::from_le_bytes([self.data[2], self.data[3]]);
+        self.data = &self.data[4..];
+        self.remaining -= 1;
+
+        Some((handle, count))
+    }
+}
+
+/// A single advertising report, borrowing its AD payload from the event
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AdvertisingReport<'a> {
+    pub event_type: AdvertisingReportType,
+    pub address_type: AddressType,
+    /// Address in little-endian byte order as sent by the controller
+    pub address: [u8; 6],
+    pub data: &'a [u8],
+    pub rssi: i8,
+}
+
+impl<'a> AdvertisingReport<'a> {
+    pub fn addr(&self) -> Addr {
+        Addr::from_le_bytes(self.address_type.is_random(), self.address)
+    }
+}
+
+pub struct AdvertisingReportIter<'a> {
+    remaining: u8,
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for AdvertisingReportIter<'a> {
+    type Item = AdvertisingReport<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // event type, address type, address, data length
+        if self.remaining == 0 || self.data.len() < 9 {
+            return None;
+        }
+
+        let data_len = self.data[8] as usize;
+        if self.data.len() < 9 + data_len + 1 {
+            log::warn!("Truncated advertising report {:02x?}", self.data);
+            self.remaining = 0;
+            return None;
+        }
+
+        let report = AdvertisingReport {
+            event_type: AdvertisingReportType::from_u8(self.data[0]),
+            address_type: AddressType::from_u8(self.data[1]),
+            address: self.data[2..][..6].try_into().unwrap(),
+            data: &self.data[9..][..data_len],
+            rssi: self.data[9 + data_len] as i8,
+        };
+
+        self.data = &self.data[9 + data_len + 1..];
+        self.remaining -= 1;
+
+        Some(report)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ErrorCode {
@@ -84,47 +333,274 @@ impl ErrorCode {
     }
 }
 
+/// Events the controller reports, see Set Event Mask ([Vol 4] Part E, Section 7.3.1)
+///
+/// Command Complete, Command Status and Number Of Completed Packets are always reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EventMask(pub u64);
+
+impl EventMask {
+    pub const DISCONNECTION_COMPLETE: Self = Self(1 << 4);
+    pub const ENCRYPTION_CHANGE: Self = Self(1 << 7);
+    pub const READ_REMOTE_VERSION_INFORMATION_COMPLETE: Self = Self(1 << 11);
+    pub const HARDWARE_ERROR: Self = Self(1 << 15);
+    pub const DATA_BUFFER_OVERFLOW: Self = Self(1 << 25);
+    pub const ENCRYPTION_KEY_REFRESH_COMPLETE: Self = Self(1 << 47);
+    /// Needed for any of the events in [LeEventMask]
+    pub const LE_META: Self = Self(1 << 61);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// LE meta events, disconnections and errors of the controller
+    pub const fn connections() -> Self {
+        Self::LE_META
+            .with(Self::DISCONNECTION_COMPLETE)
+            .with(Self::HARDWARE_ERROR)
+            .with(Self::DATA_BUFFER_OVERFLOW)
+    }
+
+    /// Changes of the encryption of connections
+    pub const fn with_encryption(self) -> Self {
+        self.with(Self::ENCRYPTION_CHANGE)
+            .with(Self::ENCRYPTION_KEY_REFRESH_COMPLETE)
+    }
+
+    pub const fn with(self, events: Self) -> Self {
+        Self(self.0 | events.0)
+    }
+
+    pub const fn without(self, events: Self) -> Self {
+        Self(self.0 & !events.0)
+    }
+
+    pub const fn contains(self, events: Self) -> bool {
+        self.0 & events.0 == events.0
+    }
+}
+
+impl Default for EventMask {
+    /// All events the stack handles
+    fn default() -> Self {
+        Self::connections().with_encryption()
+    }
+}
+
+impl core::ops::BitOr for EventMask {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.with(rhs)
+    }
+}
+
+/// LE meta events the controller reports, see LE Set Event Mask ([Vol 4] Part E,
+/// Section 7.8.1)
+///
+/// Only reported if [EventMask::LE_META] is set, too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LeEventMask(pub u64);
+
+impl LeEventMask {
+    pub const CONNECTION_COMPLETE: Self = Self(1 << 0);
+    pub const ADVERTISING_REPORT: Self = Self(1 << 1);
+    pub const CONNECTION_UPDATE_COMPLETE: Self = Self(1 << 2);
+    pub const READ_REMOTE_FEATURES_COMPLETE: Self = Self(1 << 3);
+    pub const LONG_TERM_KEY_REQUEST: Self = Self(1 << 4);
+    pub const REMOTE_CONNECTION_PARAMETER_REQUEST: Self = Self(1 << 5);
+    pub const DATA_LENGTH_CHANGE: Self = Self(1 << 6);
+    pub const ENHANCED_CONNECTION_COMPLETE: Self = Self(1 << 9);
+    pub const PHY_UPDATE_COMPLETE: Self = Self(1 << 11);
+    pub const EXTENDED_ADVERTISING_REPORT: Self = Self(1 << 12);
+    pub const SCAN_TIMEOUT: Self = Self(1 << 16);
+    pub const ADVERTISING_SET_TERMINATED: Self = Self(1 << 17);
+    pub const SCAN_REQUEST_RECEIVED: Self = Self(1 << 18);
+    pub const CHANNEL_SELECTION_ALGORITHM: Self = Self(1 << 19);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Connection complete, connection update and remote features events
+    pub const fn connections() -> Self {
+        Self::CONNECTION_COMPLETE
+            .with(Self::ENHANCED_CONNECTION_COMPLETE)
+            .with(Self::CONNECTION_UPDATE_COMPLETE)
+            .with(Self::READ_REMOTE_FEATURES_COMPLETE)
+            .with(Self::REMOTE_CONNECTION_PARAMETER_REQUEST)
+    }
+
+    pub const fn with_scanning(self) -> Self {
+        self.with(Self::ADVERTISING_REPORT)
+    }
+
+    pub const fn with_extended_advertising(self) -> Self {
+        self.with(Self::ADVERTISING_SET_TERMINATED)
+    }
+
+    pub const fn with_data_length(self) -> Self {
+        self.with(Self::DATA_LENGTH_CHANGE)
+    }
+
+    pub const fn with_phy(self) -> Self {
+        self.with(Self::PHY_UPDATE_COMPLETE)
+    }
+
+    /// Requests for the LTK when a central starts encryption
+    pub const fn with_encryption(self) -> Self {
+        self.with(Self::LONG_TERM_KEY_REQUEST)
+    }
+
+    pub const fn with(self, events: Self) -> Self {
+        Self(self.0 | events.0)
+    }
+
+    pub const fn without(self, events: Self) -> Self {
+        Self(self.0 & !events.0)
+    }
+
+    pub const fn contains(self, events: Self) -> bool {
+        self.0 & events.0 == events.0
+    }
+}
+
+impl Default for LeEventMask {
+    /// All LE meta events the stack handles
+    fn default() -> Self {
+        Self::connections()
+            .with_scanning()
+            .with_extended_advertising()
+            .with_data_length()
+            .with_phy()
+            .with_encryption()
+    }
+}
+
+impl core::ops::BitOr for LeEventMask {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.with(rhs)
+    }
+}
+
 const EVENT_COMMAND_COMPLETE: u8 = 0x0e;
+const EVENT_COMMAND_STATUS: u8 = 0x0f;
 const EVENT_DISCONNECTION_COMPLETE: u8 = 0x05;
+const EVENT_ENCRYPTION_CHANGE: u8 = 0x08;
+const EVENT_HARDWARE_ERROR: u8 = 0x10;
 const EVENT_NUMBER_OF_COMPLETED_PACKETS: u8 = 0x13;
+const EVENT_DATA_BUFFER_OVERFLOW: u8 = 0x1a;
+const EVENT_ENCRYPTION_KEY_REFRESH_COMPLETE: u8 = 0x30;
 const EVENT_LE_META: u8 = 0x3e;
 const EVENT_LE_META_CONNECTION_COMPLETE: u8 = 0x01;
-// TODO ENHANCED_CONNECTION_COMPLETE
+const EVENT_LE_META_ADVERTISING_REPORT: u8 = 0x02;
+const EVENT_LE_META_CONNECTION_UPDATE_COMPLETE: u8 = 0x03;
+const EVENT_LE_META_READ_REMOTE_FEATURES_COMPLETE: u8 = 0x04;
 const EVENT_LE_META_LONG_TERM_KEY_REQUEST: u8 = 0x05;
+const EVENT_LE_META_REMOTE_CONNECTION_PARAMETER_REQUEST: u8 = 0x06;
+const EVENT_LE_META_DATA_LENGTH_CHANGE: u8 = 0x07;
+const EVENT_LE_META_ENHANCED_CONNECTION_COMPLETE: u8 = 0x0a;
+const EVENT_LE_META_PHY_UPDATE_COMPLETE: u8 = 0x0c;
+const EVENT_LE_META_ADVERTISING_SET_TERMINATED: u8 = 0x12;
 
 impl EventType {
+    /// Fails if a Command Complete or Command Status event reports an error
     pub fn check_command_completed(self) -> Result<Self, Error> {
-        if let Self::CommandComplete {
-            num_packets: _,
-            opcode: _,
-            data,
-        } = self
-        {
-            let status = data.as_slice()[0];
-            if status != 0 {
-                return Err(Error::Failed(status));
+        let (opcode, status) = match &self {
+            Self::CommandComplete { opcode, data, .. } => {
+                (*opcode, data.as_slice().first().copied().unwrap_or(0))
             }
+            Self::CommandStatus { opcode, status, .. } => (*opcode, *status),
+            _ => (0, 0),
+        };
+
+        if status != 0 {
+            return Err(Error::CommandFailed { opcode, status });
         }
 
         Ok(self)
     }
 
+    /// The number of commands the controller is able to accept, if this event reports it
+    pub fn command_credits(&self) -> Option<u8> {
+        match self {
+            Self::CommandComplete { num_packets, .. } | Self::CommandStatus { num_packets, .. } => {
+                Some(*num_packets)
+            }
+            _ => None,
+        }
+    }
+
     /// Reads and decodes an event and assumes the packet type (0x04) is already read.
     pub fn read(connector: &dyn HciConnection) -> Self {
-        let event = Event::read(connector);
+        Self::decode(Event::read(connector))
+    }
+
+    #[cfg(feature = "async")]
+    /// Reads and decodes an event and assumes the packet type (0x04) is already read.
+    pub async fn async_read<T>(connector: &mut T) -> Self
+    where
+        T: embedded_io_async::Read,
+    {
+        Self::decode(Event::async_read(connector).await)
+    }
+
+    fn decode(event: Event) -> Self {
+        if event.data.len() < Self::parameters_len(event.code, event.data.as_slice()) {
+            log::warn!(
+                "Ignoring truncated event {:02x} data = {:02x?}",
+                event.code,
+                event.data.as_slice()
+            );
+            return Self::Unknown;
+        }
 
         match event.code {
             EVENT_COMMAND_COMPLETE => {
-                let data = event.data.as_slice();
-                let num_packets = data[0];
-                let opcode = ((data[2] as u16) << 8) + data[1] as u16;
-                let data = event.data.subdata_from(3);
+                let header = event.data.as_slice();
+                let num_packets = header[0];
+                let opcode = ((header[2] as u16) << 8) + header[1] as u16;
+                let mut data = event.data;
+                data.advance(3);
                 Self::CommandComplete {
                     num_packets,
                     opcode,
                     data,
                 }
             }
+            EVENT_COMMAND_STATUS => {
+                let data = event.data.as_slice();
+                Self::CommandStatus {
+                    status: data[0],
+                    num_packets: data[1],
+                    opcode: ((data[3] as u16) << 8) + data[2] as u16,
+                }
+            }
+            EVENT_ENCRYPTION_CHANGE => {
+                let data = event.data.as_slice();
+                Self::EncryptionChange {
+                    status: data[0],
+                    handle: ((data[2] as u16) << 8) + data[1] as u16,
+                    enabled: data[3] != 0,
+                }
+            }
+            EVENT_ENCRYPTION_KEY_REFRESH_COMPLETE => {
+                let data = event.data.as_slice();
+                Self::EncryptionKeyRefreshComplete {
+                    status: data[0],
+                    handle: ((data[2] as u16) << 8) + data[1] as u16,
+                }
+            }
+            EVENT_HARDWARE_ERROR => Self::HardwareError {
+                code: event.data.as_slice()[0],
+            },
+            EVENT_DATA_BUFFER_OVERFLOW => Self::DataBufferOverflow {
+                link_type: event.data.as_slice()[0],
+            },
             EVENT_DISCONNECTION_COMPLETE => {
                 let data = event.data.as_slice();
                 let status = data[0];
@@ -140,13 +616,11 @@ impl EventType {
             }
             EVENT_NUMBER_OF_COMPLETED_PACKETS => {
                 let data = event.data.as_slice();
-                let num_handles = data[0];
-                let connection_handle = ((data[2] as u16) << 8) + data[1] as u16;
-                let completed_packet = ((data[4] as u16) << 8) + data[3] as u16;
                 Self::NumberOfCompletedPackets {
-                    number_of_connection_handles: num_handles,
-                    connection_handles: connection_handle,
-                    completed_packets: completed_packet,
+                    completed: CompletedPackets {
+                        number_of_handles: data[0],
+                        data: Data::new(&data[1..]),
+                    },
                 }
             }
             EVENT_LE_META => {
@@ -160,9 +634,9 @@ impl EventType {
                         let role = data[3];
                         let peer_address =
                             Addr::from_le_bytes(data[4] != 0, data[5..][..6].try_into().unwrap());
-                        let interval = ((data[2] as u16) << 8) + data[1] as u16;
-                        let latency = ((data[2] as u16) << 8) + data[1] as u16;
-                        let timeout = ((data[2] as u16) << 8) + data[1] as u16;
+                        let interval = ((data[12] as u16) << 8) + data[11] as u16;
+                        let latency = ((data[14] as u16) << 8) + data[13] as u16;
+                        let timeout = ((data[16] as u16) << 8) + data[15] as u16;
 
                         Self::ConnectionComplete {
                             status,
@@ -174,106 +648,75 @@ impl EventType {
                             timeout,
                         }
                     }
-                    EVENT_LE_META_LONG_TERM_KEY_REQUEST => {
-                        let handle = ((data[1] as u16) << 8) + data[0] as u16;
-                        let random = u64::from_be_bytes((&data[2..][..8]).try_into().unwrap());
-                        let diversifier = ((data[11] as u16) << 8) + data[10] as u16;
-                        Self::LongTermKeyRequest {
-                            handle,
-                            random,
-                            diversifier,
-                        }
-                    }
-                    _ => {
-                        log::warn!(
-                            "Ignoring unknown le-meta event {:02x} data = {:02x?}",
-                            sub_event,
-                            data
-                        );
-                        Self::Unknown
-                    }
-                }
-            }
-            _ => {
-                log::warn!(
-                    "Ignoring unknown event {:02x} data = {:02x?}",
-                    event.code,
-                    event.data.as_slice()
-                );
-                Self::Unknown
-            }
-        }
-    }
-
-    #[cfg(feature = "async")]
-    /// Reads and decodes an event and assumes the packet type (0x04) is already read.
-    pub async fn async_read<T>(connector: &mut T) -> Self
-    where
-        T: embedded_io_async::Read,
-    {
-        let event = Event::async_read(connector).await;
-
-        match event.code {
-            EVENT_COMMAND_COMPLETE => {
-                let data = event.data.as_slice();
-                let num_packets = data[0];
-                let opcode = ((data[2] as u16) << 8) + data[1] as u16;
-                let data = event.data.subdata_from(3);
-                Self::CommandComplete {
-                    num_packets,
-                    opcode,
-                    data,
-                }
-            }
-            EVENT_DISCONNECTION_COMPLETE => {
-                let data = event.data.as_slice();
-                let status = data[0];
-                let handle = ((data[2] as u16) << 8) + data[1] as u16;
-                let reason = data[3];
-                let status = ErrorCode::from_u8(status);
-                let reason = ErrorCode::from_u8(reason);
-                Self::DisconnectComplete {
-                    handle,
-                    status,
-                    reason,
-                }
-            }
-            EVENT_NUMBER_OF_COMPLETED_PACKETS => {
-                let data = event.data.as_slice();
-                let num_handles = data[0];
-                let connection_handle = ((data[2] as u16) << 8) + data[1] as u16;
-                let completed_packet = ((data[4] as u16) << 8) + data[3] as u16;
-                Self::NumberOfCompletedPackets {
-                    number_of_connection_handles: num_handles,
-                    connection_handles: connection_handle,
-                    completed_packets: completed_packet,
-                }
-            }
-            EVENT_LE_META => {
-                let sub_event = event.data.as_slice()[0];
-                let data = &event.data.as_slice()[1..];
-
-                match sub_event {
-                    EVENT_LE_META_CONNECTION_COMPLETE => {
+                    EVENT_LE_META_ENHANCED_CONNECTION_COMPLETE => {
                         let status = data[0];
                         let handle = ((data[2] as u16) << 8) + data[1] as u16;
                         let role = data[3];
-                        let peer_address =
-                            Addr::from_le_bytes(data[4] != 0, data[5..][..6].try_into().unwrap());
-                        let interval = ((data[2] as u16) << 8) + data[1] as u16;
-                        let latency = ((data[2] as u16) << 8) + data[1] as u16;
-                        let timeout = ((data[2] as u16) << 8) + data[1] as u16;
+                        // 0x01 and 0x03 are random (identity) addresses
+                        let peer_address = Addr::from_le_bytes(
+                            data[4] & 0x01 != 0,
+                            data[5..][..6].try_into().unwrap(),
+                        );
+                        let local_resolvable_private_address = data[11..][..6].try_into().unwrap();
+                        let peer_resolvable_private_address = data[17..][..6].try_into().unwrap();
+                        let interval = ((data[24] as u16) << 8) + data[23] as u16;
+                        let latency = ((data[26] as u16) << 8) + data[25] as u16;
+                        let timeout = ((data[28] as u16) << 8) + data[27] as u16;
 
-                        Self::ConnectionComplete {
+                        Self::EnhancedConnectionComplete {
                             status,
                             handle,
                             role,
                             peer_address,
+                            local_resolvable_private_address,
+                            peer_resolvable_private_address,
                             interval,
                             latency,
                             timeout,
                         }
                     }
+                    EVENT_LE_META_CONNECTION_UPDATE_COMPLETE => Self::ConnectionUpdateComplete {
+                        status: data[0],
+                        handle: ((data[2] as u16) << 8) + data[1] as u16,
+                        interval: ((data[4] as u16) << 8) + data[3] as u16,
+                        latency: ((data[6] as u16) << 8) + data[5] as u16,
+                        timeout: ((data[8] as u16) << 8) + data[7] as u16,
+                    },
+                    EVENT_LE_META_READ_REMOTE_FEATURES_COMPLETE => {
+                        Self::ReadRemoteFeaturesComplete {
+                            status: data[0],
+                            handle: ((data[2] as u16) << 8) + data[1] as u16,
+                            features: u64::from_le_bytes(data[3..][..8].try_into().unwrap()),
+                        }
+                    }
+                    EVENT_LE_META_REMOTE_CONNECTION_PARAMETER_REQUEST => {
+                        Self::RemoteConnectionParameterRequest {
+                            handle: ((data[1] as u16) << 8) + data[0] as u16,
+                            interval_min: ((data[3] as u16) << 8) + data[2] as u16,
+                            interval_max: ((data[5] as u16) << 8) + data[4] as u16,
+                            max_latency: ((data[7] as u16) << 8) + data[6] as u16,
+                            timeout: ((data[9] as u16) << 8) + data[8] as u16,
+                        }
+                    }
+                    EVENT_LE_META_DATA_LENGTH_CHANGE => Self::DataLengthChange {
+                        handle: ((data[1] as u16) << 8) + data[0] as u16,
+                        max_tx_octets: ((data[3] as u16) << 8) + data[2] as u16,
+                        max_tx_time: ((data[5] as u16) << 8) + data[4] as u16,
+                        max_rx_octets: ((data[7] as u16) << 8) + data[6] as u16,
+                        max_rx_time: ((data[9] as u16) << 8) + data[8] as u16,
+                    },
+                    EVENT_LE_META_PHY_UPDATE_COMPLETE => Self::PhyUpdateComplete {
+                        status: data[0],
+                        handle: ((data[2] as u16) << 8) + data[1] as u16,
+                        tx_phy: data[3],
+                        rx_phy: data[4],
+                    },
+                    EVENT_LE_META_ADVERTISING_REPORT => Self::AdvertisingReport {
+                        reports: AdvertisingReports {
+                            num_reports: data[0],
+                            data: Data::new(&data[1..]),
+                        },
+                    },
                     EVENT_LE_META_LONG_TERM_KEY_REQUEST => {
                         let handle = ((data[1] as u16) << 8) + data[0] as u16;
                         let random = u64::from_be_bytes((&data[2..][..8]).try_into().unwrap());
@@ -284,6 +727,15 @@ impl EventType {
                             diversifier,
                         }
                     }
+                    EVENT_LE_META_ADVERTISING_SET_TERMINATED => {
+                        let connection_handle = ((data[3] as u16) << 8) + data[2] as u16;
+                        Self::AdvertisingSetTerminated {
+                            status: data[0],
+                            advertising_handle: data[1],
+                            connection_handle,
+                            num_completed_events: data[4],
+                        }
+                    }
                     _ => {
                         log::warn!(
                             "Ignoring unknown le-meta event {:02x} data = {:02x?}",
@@ -304,6 +756,37 @@ impl EventType {
             }
         }
     }
+
+    /// Number of parameter bytes the decoder of an event reads, 0 for unknown events
+    fn parameters_len(code: u8, parameters: &[u8]) -> usize {
+        match code {
+            EVENT_COMMAND_COMPLETE => 3,
+            EVENT_COMMAND_STATUS => 4,
+            EVENT_ENCRYPTION_CHANGE => 4,
+            EVENT_ENCRYPTION_KEY_REFRESH_COMPLETE => 3,
+            EVENT_HARDWARE_ERROR => 1,
+            EVENT_DATA_BUFFER_OVERFLOW => 1,
+            EVENT_DISCONNECTION_COMPLETE => 4,
+            EVENT_NUMBER_OF_COMPLETED_PACKETS => 1,
+            EVENT_LE_META => {
+                let sub_event_len = match parameters.first() {
+                    Some(&EVENT_LE_META_CONNECTION_COMPLETE) => 17,
+                    Some(&EVENT_LE_META_ENHANCED_CONNECTION_COMPLETE) => 29,
+                    Some(&EVENT_LE_META_CONNECTION_UPDATE_COMPLETE) => 9,
+                    Some(&EVENT_LE_META_READ_REMOTE_FEATURES_COMPLETE) => 11,
+                    Some(&EVENT_LE_META_REMOTE_CONNECTION_PARAMETER_REQUEST) => 10,
+                    Some(&EVENT_LE_META_DATA_LENGTH_CHANGE) => 10,
+                    Some(&EVENT_LE_META_PHY_UPDATE_COMPLETE) => 5,
+                    Some(&EVENT_LE_META_ADVERTISING_REPORT) => 1,
+                    Some(&EVENT_LE_META_LONG_TERM_KEY_REQUEST) => 12,
+                    Some(&EVENT_LE_META_ADVERTISING_SET_TERMINATED) => 5,
+                    _ => 0,
+                };
+                1 + sub_event_len
+            }
+            _ => 0,
+        }
+    }
 }
 
 impl Event {
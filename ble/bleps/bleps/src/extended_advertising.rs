@@ -0,0 +1,129 @@
+use crate::{AdvertisingFilterPolicy, OwnAddressType, PeerAddressType};
+
+/// The advertising is connectable
+pub const ADV_EVENT_PROP_CONNECTABLE: u16 = 0b0000001;
+/// The advertising is scannable
+pub const ADV_EVENT_PROP_SCANNABLE: u16 = 0b0000010;
+/// The advertising is directed to the peer address
+pub const ADV_EVENT_PROP_DIRECTED: u16 = 0b0000100;
+/// High duty cycle directed connectable advertising
+pub const ADV_EVENT_PROP_HIGH_DUTY_CYCLE: u16 = 0b0001000;
+/// Use legacy advertising PDUs, the data is limited to 31 bytes
+pub const ADV_EVENT_PROP_LEGACY: u16 = 0b0010000;
+/// Omit the advertiser's address from all PDUs
+pub const ADV_EVENT_PROP_ANONYMOUS: u16 = 0b0100000;
+/// Include the TX power in the extended header
+pub const ADV_EVENT_PROP_INCLUDE_TX_POWER: u16 = 0b1000000;
+
+/// Maximum length of the data of an advertising set supported by [crate::ad_structure::create_extended_advertising_data]
+pub const MAX_EXTENDED_ADVERTISING_DATA_LEN: usize = 251;
+
+/// Largest data fragment sent in a single command, all data fits into one fragment as the
+/// controller doesn't accept fragmented data for enabled sets
+pub(crate) const MAX_ADVERTISING_DATA_FRAGMENT_LEN: usize = MAX_EXTENDED_ADVERTISING_DATA_LEN;
+
+/// Tell the controller that the host has no TX power preference
+pub const TX_POWER_NO_PREFERENCE: i8 = 0x7f;
+
+#[derive(Debug, Clone, Copy)]
+pub enum AdvertisingPhy {
+    Le1M = 0x01,
+    Le2M = 0x02,
+    LeCoded = 0x03,
+}
+
+/// Parameters of the LE Set Extended Advertising Parameters command
+#[derive(Debug, Clone, Copy)]
+pub struct ExtendedAdvertisingParameters {
+    /// Identifies the advertising set, 0x00 to 0xef
+    pub handle: u8,
+    /// Bit field of `ADV_EVENT_PROP_*`
+    pub properties: u16,
+    /// Minimum advertising interval in units of 0.625 ms (24 bits)
+    pub interval_min: u32,
+    /// Maximum advertising interval in units of 0.625 ms (24 bits)
+    pub interval_max: u32,
+    /// Bit field of [crate::AdvertisingChannelMapBits]
+    pub channel_map: u8,
+    pub own_address_type: OwnAddressType,
+    pub peer_address_type: PeerAddressType,
+    /// Peer address in little-endian byte order
+    pub peer_address: [u8; 6],
+    pub filter_policy: AdvertisingFilterPolicy,
+    /// Maximum TX power in dBm, or [TX_POWER_NO_PREFERENCE]
+    pub tx_power: i8,
+    /// PHY of the primary advertising channels, either `Le1M` or `LeCoded`
+    pub primary_phy: AdvertisingPhy,
+    pub secondary_max_skip: u8,
+    pub secondary_phy: AdvertisingPhy,
+    /// Advertising SID sent in the extended header
+    pub sid: u8,
+    pub scan_request_notification: bool,
+}
+
+impl ExtendedAdvertisingParameters {
+    /// Connectable and undirected advertising using extended PDUs
+    pub fn connectable(handle: u8) -> Self {
+        Self {
+            handle,
+            properties: ADV_EVENT_PROP_CONNECTABLE,
+            interval_min: 0x0000a0,
+            interval_max: 0x0000a0,
+            channel_map: 0b111,
+            own_address_type: OwnAddressType::Public,
+            peer_address_type: PeerAddressType::Public,
+            peer_address: [0u8; 6],
+            filter_policy: AdvertisingFilterPolicy::All,
+            tx_power: TX_POWER_NO_PREFERENCE,
+            primary_phy: AdvertisingPhy::Le1M,
+            secondary_max_skip: 0,
+            secondary_phy: AdvertisingPhy::Le1M,
+            sid: handle & 0x0f,
+            scan_request_notification: false,
+        }
+    }
+
+    /// Non-connectable and non-scannable advertising using extended PDUs, e.g. for a beacon
+    ///
+    /// Usually used with a random address set via `cmd_set_le_advertising_set_random_address`.
+    pub fn non_connectable(handle: u8) -> Self {
+        Self {
+            properties: 0,
+            interval_min: 0x000640,
+            interval_max: 0x000640,
+            own_address_type: OwnAddressType::Random,
+            ..Self::connectable(handle)
+        }
+    }
+}
+
+/// How a fragment relates to the whole advertising or scan response data
+#[derive(Debug, Clone, Copy)]
+pub enum AdvertisingDataOperation {
+    IntermediateFragment = 0x00,
+    FirstFragment = 0x01,
+    LastFragment = 0x02,
+    Complete = 0x03,
+    Unchanged = 0x04,
+}
+
+/// An advertising set to enable or disable via LE Set Extended Advertising Enable
+#[derive(Debug, Clone, Copy)]
+pub struct AdvertisingSet {
+    pub handle: u8,
+    /// Advertise for this long in units of 10 ms, 0 advertises until disabled
+    pub duration: u16,
+    /// Stop after this many extended advertising events, 0 means no limit
+    pub max_events: u8,
+}
+
+impl AdvertisingSet {
+    /// Advertise until disabled
+    pub fn new(handle: u8) -> Self {
+        Self {
+            handle,
+            duration: 0,
+            max_events: 0,
+        }
+    }
+}
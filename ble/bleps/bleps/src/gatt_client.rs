@@ -0,0 +1,563 @@
+use crate::{
+    att::{Att, AttErrorCode, AttResponse, Uuid},
+    attribute_server::{
+        WorkResult, BASE_MTU, CHARACTERISTIC_UUID16, CLIENT_CHARACTERISTIC_CONFIGURATION_UUID16,
+        MTU, PRIMARY_SERVICE_UUID16,
+    },
+    event::EventType,
+    l2cap::L2capPacket,
+    signaling::LE_SIGNALING_CHANNEL,
+    Ble, Data, Error, PollResult,
+};
+
+/// How long to wait for the response to a request ([Vol 3] Part F, Section 3.3.3)
+const ATT_TIMEOUT_MILLIS: u64 = 30_000;
+
+/// A primary service discovered on the peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Service {
+    pub start_handle: u16,
+    pub end_handle: u16,
+    pub uuid: Uuid,
+}
+
+impl Default for Service {
+    fn default() -> Self {
+        Self {
+            start_handle: 0,
+            end_handle: 0,
+            uuid: Uuid::Uuid16(0),
+        }
+    }
+}
+
+/// A characteristic discovered on the peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Characteristic {
+    pub declaration_handle: u16,
+    pub properties: u8,
+    pub value_handle: u16,
+    pub uuid: Uuid,
+}
+
+impl Default for Characteristic {
+    fn default() -> Self {
+        Self {
+            declaration_handle: 0,
+            properties: 0,
+            value_handle: 0,
+            uuid: Uuid::Uuid16(0),
+        }
+    }
+}
+
+/// A characteristic descriptor discovered on the peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Descriptor {
+    pub handle: u16,
+    pub uuid: Uuid,
+}
+
+impl Default for Descriptor {
+    fn default() -> Self {
+        Self {
+            handle: 0,
+            uuid: Uuid::Uuid16(0),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GattClientError {
+    Hci(Error),
+    /// The server answered with an error response
+    ErrorResponse {
+        handle: u16,
+        code: u8,
+    },
+    UnexpectedResponse,
+    /// The server sent an ATT PDU which couldn't be decoded
+    InvalidPdu,
+    /// The value doesn't fit into a single PDU with the current MTU
+    InvalidLength,
+    Disconnected,
+}
+
+impl From<Error> for GattClientError {
+    fn from(err: Error) -> Self {
+        GattClientError::Hci(err)
+    }
+}
+
+impl GattClientError {
+    fn from_response(response: AttResponse) -> Self {
+        match response {
+            AttResponse::Error { handle, code, .. } => {
+                GattClientError::ErrorResponse { handle, code }
+            }
+            _ => GattClientError::UnexpectedResponse,
+        }
+    }
+}
+
+/// Receives the attribute handle and value of notifications and indications
+pub type NotificationCallback<'a> = &'a mut dyn FnMut(u16, &[u8]);
+
+fn is_attribute_not_found(response: &AttResponse) -> bool {
+    matches!(response, AttResponse::Error { code, .. } if *code == AttErrorCode::AttributeNotFound as u8)
+}
+
+pub struct GattClient<'a> {
+    ble: &'a mut Ble<'a>,
+    handle: u16,
+    mtu: u16,
+    notification_callback: Option<NotificationCallback<'a>>,
+}
+
+impl<'a> GattClient<'a> {
+    /// Create a client for the ATT bearer of the given connection handle
+    pub fn new(ble: &'a mut Ble<'a>, handle: u16) -> GattClient<'a> {
+        GattClient {
+            ble,
+            handle,
+            mtu: BASE_MTU,
+            notification_callback: None,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+pub struct AsyncGattClient<'a, T, C>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    C: crate::clock::Clock + embedded_hal_async::delay::DelayNs,
+{
+    ble: &'a mut crate::asynch::Ble<T, C>,
+    handle: u16,
+    mtu: u16,
+    notification_callback: Option<NotificationCallback<'a>>,
+}
+
+#[cfg(feature = "async")]
+impl<'a, T, C> AsyncGattClient<'a, T, C>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    C: crate::clock::Clock + embedded_hal_async::delay::DelayNs,
+{
+    /// Create a client for the ATT bearer of the given connection handle
+    pub fn new(ble: &'a mut crate::asynch::Ble<T, C>, handle: u16) -> AsyncGattClient<'a, T, C> {
+        AsyncGattClient {
+            ble,
+            handle,
+            mtu: BASE_MTU,
+            notification_callback: None,
+        }
+    }
+}
+
+bleps_dedup::dedup! {
+    impl<'a> SYNC GattClient<'a>
+    impl<'a, T, C> ASYNC AsyncGattClient<'a, T, C>
+        where
+            T: embedded_io_async::Read + embedded_io_async::Write,
+            C: crate::clock::Clock + embedded_hal_async::delay::DelayNs,
+    {
+        /// The callback receives the attribute handle and value of every notification and
+        /// indication. Indications are confirmed automatically.
+        pub fn set_notification_callback(
+            &mut self,
+            notification_callback: Option<NotificationCallback<'a>>,
+        ) {
+            self.notification_callback = notification_callback;
+        }
+
+        pub fn mtu(&self) -> u16 {
+            self.mtu
+        }
+
+        /// Negotiates the MTU, at most [MTU] is requested as larger PDUs don't fit into our
+        /// buffers
+        pub async fn exchange_mtu(&mut self, mtu: u16) -> Result<u16, GattClientError> {
+            let mtu = mtu.clamp(BASE_MTU, MTU);
+            match self.request(Data::new_att_exchange_mtu_request(mtu)).await? {
+                AttResponse::ExchangeMtu { mtu: server_mtu } => {
+                    self.mtu = mtu.min(server_mtu).max(BASE_MTU);
+                    Ok(self.mtu)
+                }
+                other => Err(GattClientError::from_response(other)),
+            }
+        }
+
+        /// Discover primary services, returns the number of services stored in `services`
+        pub async fn discover_services(
+            &mut self,
+            services: &mut [Service],
+        ) -> Result<usize, GattClientError> {
+            let mut count = 0;
+            let mut start = 0x0001;
+            while count < services.len() {
+                let response = self
+                    .request(Data::new_att_read_by_group_type_request(
+                        start,
+                        0xffff,
+                        &PRIMARY_SERVICE_UUID16,
+                    ))
+                    .await?;
+                let (length, data) = match response {
+                    AttResponse::ReadByGroupType { length, data } if length == 6 || length == 20 => {
+                        (length as usize, data)
+                    }
+                    ref other if is_attribute_not_found(other) => break,
+                    other => return Err(GattClientError::from_response(other)),
+                };
+
+                let mut end = 0xffff;
+                for entry in data.as_slice().chunks_exact(length) {
+                    if count == services.len() {
+                        break;
+                    }
+                    end = u16::from_le_bytes([entry[2], entry[3]]);
+                    services[count] = Service {
+                        start_handle: u16::from_le_bytes([entry[0], entry[1]]),
+                        end_handle: end,
+                        uuid: Uuid::from(&entry[4..]),
+                    };
+                    count += 1;
+                }
+
+                if end == 0xffff {
+                    break;
+                }
+                start = end + 1;
+            }
+
+            Ok(count)
+        }
+
+        /// Discover the characteristics of a service, returns the number stored in `characteristics`
+        pub async fn discover_characteristics(
+            &mut self,
+            service: &Service,
+            characteristics: &mut [Characteristic],
+        ) -> Result<usize, GattClientError> {
+            let mut count = 0;
+            let mut start = service.start_handle;
+            while count < characteristics.len() && start <= service.end_handle {
+                let response = self
+                    .request(Data::new_att_read_by_type_request(
+                        start,
+                        service.end_handle,
+                        &CHARACTERISTIC_UUID16,
+                    ))
+                    .await?;
+                let (length, data) = match response {
+                    AttResponse::ReadByType { length, data } if length == 7 || length == 21 => {
+                        (length as usize, data)
+                    }
+                    ref other if is_attribute_not_found(other) => break,
+                    other => return Err(GattClientError::from_response(other)),
+                };
+
+                let mut last = service.end_handle;
+                for entry in data.as_slice().chunks_exact(length) {
+                    if count == characteristics.len() {
+                        break;
+                    }
+                    last = u16::from_le_bytes([entry[0], entry[1]]);
+                    characteristics[count] = Characteristic {
+                        declaration_handle: last,
+                        properties: entry[2],
+                        value_handle: u16::from_le_bytes([entry[3], entry[4]]),
+                        uuid: Uuid::from(&entry[5..]),
+                    };
+                    count += 1;
+                }
+
+                if last >= service.end_handle {
+                    break;
+                }
+                start = last + 1;
+            }
+
+            Ok(count)
+        }
+
+        /// Discover the attributes in the given range, returns the number stored in `descriptors`
+        pub async fn discover_descriptors(
+            &mut self,
+            start: u16,
+            end: u16,
+            descriptors: &mut [Descriptor],
+        ) -> Result<usize, GattClientError> {
+            let mut count = 0;
+            let mut start = start;
+            while count < descriptors.len() && start <= end {
+                let response = self
+                    .request(Data::new_att_find_information_request(start, end))
+                    .await?;
+                let (length, data) = match response {
+                    AttResponse::FindInformation { format: 0x01, data } => (4, data),
+                    AttResponse::FindInformation { format: 0x02, data } => (18, data),
+                    ref other if is_attribute_not_found(other) => break,
+                    other => return Err(GattClientError::from_response(other)),
+                };
+
+                let mut last = end;
+                for entry in data.as_slice().chunks_exact(length) {
+                    if count == descriptors.len() {
+                        break;
+                    }
+                    last = u16::from_le_bytes([entry[0], entry[1]]);
+                    descriptors[count] = Descriptor {
+                        handle: last,
+                        uuid: Uuid::from(&entry[2..]),
+                    };
+                    count += 1;
+                }
+
+                if last >= end {
+                    break;
+                }
+                start = last + 1;
+            }
+
+            Ok(count)
+        }
+
+        /// Find the Client Characteristic Configuration descriptor of a characteristic
+        ///
+        /// `end_handle` is usually the end handle of the containing service.
+        pub async fn find_cccd(
+            &mut self,
+            characteristic: &Characteristic,
+            end_handle: u16,
+        ) -> Result<Option<u16>, GattClientError> {
+            let mut start = characteristic.value_handle + 1;
+            while start <= end_handle {
+                let mut descriptors = [Descriptor::default(); 4];
+                let count = self
+                    .discover_descriptors(start, end_handle, &mut descriptors)
+                    .await?;
+                for descriptor in &descriptors[..count] {
+                    if descriptor.uuid == CHARACTERISTIC_UUID16 {
+                        // the next characteristic starts here
+                        return Ok(None);
+                    }
+                    if descriptor.uuid == CLIENT_CHARACTERISTIC_CONFIGURATION_UUID16 {
+                        return Ok(Some(descriptor.handle));
+                    }
+                }
+
+                if count < descriptors.len() {
+                    break;
+                }
+                start = descriptors[count - 1].handle + 1;
+            }
+
+            Ok(None)
+        }
+
+        /// Read an attribute value, using Read Blob requests for values longer than `MTU - 1`
+        pub async fn read(&mut self, handle: u16, buffer: &mut [u8]) -> Result<usize, GattClientError> {
+            let mut len = match self.request(Data::new_att_read_request(handle)).await? {
+                AttResponse::Read { data } => {
+                    let len = data.len().min(buffer.len());
+                    buffer[..len].copy_from_slice(&data.as_slice()[..len]);
+                    if data.len() < self.mtu as usize - 1 {
+                        return Ok(len);
+                    }
+                    len
+                }
+                other => return Err(GattClientError::from_response(other)),
+            };
+
+            while len < buffer.len() {
+                let response = self
+                    .request(Data::new_att_read_blob_request(handle, len as u16))
+                    .await?;
+                let data = match response {
+                    AttResponse::ReadBlob { data } => data,
+                    AttResponse::Error { code, .. }
+                        if code == AttErrorCode::AttributeNotLong as u8
+                            || code == AttErrorCode::InvalidOffset as u8 =>
+                    {
+                        break;
+                    }
+                    other => return Err(GattClientError::from_response(other)),
+                };
+
+                let chunk = data.len().min(buffer.len() - len);
+                buffer[len..][..chunk].copy_from_slice(&data.as_slice()[..chunk]);
+                len += chunk;
+
+                if data.len() < self.mtu as usize - 1 {
+                    break;
+                }
+            }
+
+            Ok(len)
+        }
+
+        pub async fn write(&mut self, handle: u16, value: &[u8]) -> Result<(), GattClientError> {
+            if value.len() > self.mtu as usize - 3 {
+                return Err(GattClientError::InvalidLength);
+            }
+
+            match self.request(Data::new_att_write_request(handle, value)).await? {
+                AttResponse::Write => Ok(()),
+                other => Err(GattClientError::from_response(other)),
+            }
+        }
+
+        pub async fn write_without_response(
+            &mut self,
+            handle: u16,
+            value: &[u8],
+        ) -> Result<(), GattClientError> {
+            if value.len() > self.mtu as usize - 3 {
+                return Err(GattClientError::InvalidLength);
+            }
+
+            self.write_att(Data::new_att_write_cmd(handle, value)).await
+        }
+
+        /// Enable notifications, or indications if `indicate` is set, by writing the CCCD
+        pub async fn subscribe(&mut self, cccd_handle: u16, indicate: bool) -> Result<(), GattClientError> {
+            let value: u16 = if indicate { 0x0002 } else { 0x0001 };
+            self.write(cccd_handle, &value.to_le_bytes()).await
+        }
+
+        pub async fn unsubscribe(&mut self, cccd_handle: u16) -> Result<(), GattClientError> {
+            self.write(cccd_handle, &[0x00, 0x00]).await
+        }
+
+        /// Process incoming packets, delivering notifications and indications to the callback
+        pub async fn do_work(&mut self) -> Result<WorkResult, GattClientError> {
+            let packet = self.ble.poll().await;
+            match self.handle_packet(packet).await {
+                Ok(Some(response)) => {
+                    log::warn!("Unexpected response {:?}", response);
+                    Ok(WorkResult::DidWork)
+                }
+                Ok(None) => Ok(WorkResult::DidWork),
+                Err(GattClientError::Disconnected) => Ok(WorkResult::GotDisconnected),
+                Err(err) => Err(err),
+            }
+        }
+
+        async fn request(&mut self, data: Data) -> Result<AttResponse, GattClientError> {
+            self.write_att(data).await?;
+
+            let timeout_at = self.ble.millis() + ATT_TIMEOUT_MILLIS;
+            loop {
+                let packet = self.ble.poll_until(timeout_at).await;
+                if let Some(response) = self.handle_packet(packet).await? {
+                    return Ok(response);
+                }
+
+                if self.ble.millis() > timeout_at {
+                    return Err(GattClientError::Hci(Error::Timeout));
+                }
+            }
+        }
+
+        /// Handles a polled packet, returns the ATT response if it was one
+        async fn handle_packet(
+            &mut self,
+            packet: Option<PollResult>,
+        ) -> Result<Option<AttResponse>, GattClientError> {
+            match packet {
+                Some(PollResult::Event(EventType::DisconnectComplete { handle, .. }))
+                    if handle == self.handle =>
+                {
+                    Err(GattClientError::Disconnected)
+                }
+                Some(PollResult::AsyncData(packet)) if packet.handle == self.handle => {
+                    let (_, l2cap_packet) = match L2capPacket::decode(packet) {
+                        Ok(decoded) => decoded,
+                        Err(_) => return Ok(None),
+                    };
+                    if l2cap_packet.channel == LE_SIGNALING_CHANNEL {
+                        self.ble
+                            .handle_signaling(self.handle, l2cap_packet.payload.as_slice())
+                            .await?;
+                        return Ok(None);
+                    }
+                    if l2cap_packet.channel != 4 {
+                        log::debug!("Ignoring L2CAP channel {}", l2cap_packet.channel);
+                        return Ok(None);
+                    }
+
+                    match AttResponse::decode(&l2cap_packet) {
+                        Ok(AttResponse::Notification { handle, data }) => {
+                            if let Some(callback) = &mut self.notification_callback {
+                                callback(handle, data.as_slice());
+                            }
+                            Ok(None)
+                        }
+                        Ok(AttResponse::Indication { handle, data }) => {
+                            if let Some(callback) = &mut self.notification_callback {
+                                callback(handle, data.as_slice());
+                            }
+                            self.write_att(Data::new_att_value_cfm()).await?;
+                            Ok(None)
+                        }
+                        Ok(response) => Ok(Some(response)),
+                        Err(_) => {
+                            self.handle_server_request(l2cap_packet.payload).await?;
+                            Ok(None)
+                        }
+                    }
+                }
+                Some(packet) => {
+                    log::trace!("Ignoring {:?}", packet);
+                    Ok(None)
+                }
+                None => Ok(None),
+            }
+        }
+
+        /// We don't have an attribute server, but must answer requests the peer sends us
+        async fn handle_server_request(&mut self, payload: Data) -> Result<(), GattClientError> {
+            let Some(&opcode) = payload.as_slice().first() else {
+                return Err(GattClientError::InvalidPdu);
+            };
+            let l2cap_packet = L2capPacket {
+                length: payload.len() as u16,
+                channel: 4,
+                payload,
+            };
+            match Att::decode(&l2cap_packet) {
+                Ok(Att::ExchangeMtu { mtu }) => {
+                    self.mtu = mtu.clamp(BASE_MTU, crate::attribute_server::MTU);
+                    self.write_att(Data::new_att_exchange_mtu_response(self.mtu))
+                        .await
+                }
+                Ok(Att::WriteCmd { .. }) => Ok(()),
+                _ => {
+                    log::warn!("Rejecting ATT request {:02x}", opcode);
+                    self.write_att(Data::new_att_error_response(
+                        opcode,
+                        0,
+                        AttErrorCode::RequestNotSupported,
+                    ))
+                    .await
+                }
+            }
+        }
+
+        async fn write_att(&mut self, data: Data) -> Result<(), GattClientError> {
+            log::debug!("data {:x?}", data.as_slice());
+
+            let res = L2capPacket::encode(data);
+            log::trace!("encoded_l2cap {:x?}", res.as_slice());
+
+            self.ble.write_l2cap(self.handle, res).await?;
+            Ok(())
+        }
+    }
+}
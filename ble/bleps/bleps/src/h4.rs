@@ -0,0 +1,331 @@
+//! H4 (UART) transport for controllers attached via a serial port or a TCP socket
+//!
+//! [H4Transport] frames the byte stream received from the controller by packet indicator
+//! and length and only hands out complete packets. Bytes which don't start a valid packet,
+//! e.g. boot messages or a handshake byte, are skipped until the stream is in sync again.
+
+use core::cell::RefCell;
+
+use embedded_io_blocking::{Read, ReadReady, Write};
+
+use crate::{clock::Clock, HciConnection, MAX_L2CAP_FRAME_LEN};
+
+/// Largest packet which can be received, an ACL packet carrying an L2CAP frame of
+/// [MAX_L2CAP_FRAME_LEN] after the packet indicator and the ACL header
+pub const H4_MAX_PACKET_LEN: usize = 5 + MAX_L2CAP_FRAME_LEN;
+
+/// Time to wait for the remaining bytes of a packet before dropping it
+pub const H4_TIMEOUT_MILLIS: u64 = 100;
+
+const H4_ACL: u8 = 0x02;
+const H4_EVENT: u8 = 0x04;
+
+/// Size of the chunks read from the underlying transport
+const RX_CHUNK_LEN: usize = 32;
+
+enum Frame {
+    Incomplete,
+    Complete,
+    Invalid,
+}
+
+/// Assembles packets from the received bytes and resynchronizes on invalid headers
+struct Framer {
+    packet: [u8; H4_MAX_PACKET_LEN],
+    len: usize,
+    /// Position of the next byte of a complete packet to hand out
+    pos: usize,
+    complete: bool,
+    /// Bytes to scan again after an invalid header
+    replay: [u8; 5],
+    replay_len: usize,
+    /// Bytes read from the transport but not processed yet
+    rx: [u8; RX_CHUNK_LEN],
+    rx_pos: usize,
+    rx_len: usize,
+    dropped: usize,
+}
+
+impl Framer {
+    const fn new() -> Self {
+        Self {
+            packet: [0u8; H4_MAX_PACKET_LEN],
+            len: 0,
+            pos: 0,
+            complete: false,
+            replay: [0u8; 5],
+            replay_len: 0,
+            rx: [0u8; RX_CHUNK_LEN],
+            rx_pos: 0,
+            rx_len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn check(&self) -> Frame {
+        match &self.packet[..self.len] {
+            [] => Frame::Incomplete,
+            [H4_EVENT, 0x00, ..] => Frame::Invalid,
+            [H4_EVENT, _, len, ..] if self.len == 3 + *len as usize => Frame::Complete,
+            [H4_EVENT, ..] => Frame::Incomplete,
+            [H4_ACL, _, _, lo, hi, ..] => {
+                let len = 5 + u16::from_le_bytes([*lo, *hi]) as usize;
+                if len > H4_MAX_PACKET_LEN {
+                    Frame::Invalid
+                } else if self.len == len {
+                    Frame::Complete
+                } else {
+                    Frame::Incomplete
+                }
+            }
+            [H4_ACL, ..] => Frame::Incomplete,
+            _ => Frame::Invalid,
+        }
+    }
+
+    /// Next byte to scan, either from the replay buffer or the last chunk read
+    fn next_byte(&mut self) -> Option<u8> {
+        if self.replay_len > 0 {
+            let byte = self.replay[0];
+            self.replay.copy_within(1..self.replay_len, 0);
+            self.replay_len -= 1;
+            return Some(byte);
+        }
+
+        if self.rx_pos < self.rx_len {
+            let byte = self.rx[self.rx_pos];
+            self.rx_pos += 1;
+            return Some(byte);
+        }
+
+        None
+    }
+
+    /// Returns the buffer to read the next chunk into
+    fn rx_buffer(&mut self) -> &mut [u8] {
+        self.rx_pos = 0;
+        self.rx_len = 0;
+        &mut self.rx
+    }
+
+    fn received(&mut self, len: usize) {
+        self.rx_len = len;
+    }
+
+    /// Scan the available bytes, returns true once a packet is complete
+    fn process(&mut self) -> bool {
+        while !self.complete {
+            let Some(byte) = self.next_byte() else {
+                return false;
+            };
+
+            self.packet[self.len] = byte;
+            self.len += 1;
+
+            match self.check() {
+                Frame::Incomplete => (),
+                Frame::Complete => {
+                    self.complete = true;
+                    self.pos = 0;
+                }
+                Frame::Invalid => {
+                    // drop the first byte and scan the rest of the header again
+                    log::warn!("Dropping unexpected byte {:02x}", self.packet[0]);
+                    self.dropped += 1;
+                    let rest = self.len - 1;
+                    self.replay.copy_within(0..self.replay_len, rest);
+                    self.replay[..rest].copy_from_slice(&self.packet[1..self.len]);
+                    self.replay_len += rest;
+                    self.len = 0;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// If a packet is partially received
+    fn in_progress(&self) -> bool {
+        self.len > 0 && !self.complete
+    }
+
+    /// Drop a partially received packet
+    fn reset(&mut self) {
+        log::warn!("Dropping incomplete packet of {} bytes", self.len);
+        self.dropped += self.len;
+        self.len = 0;
+    }
+
+    /// Copy bytes of the complete packet, returns the number of bytes copied
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        if !self.complete {
+            return 0;
+        }
+
+        let len = usize::min(buf.len(), self.len - self.pos);
+        buf[..len].copy_from_slice(&self.packet[self.pos..][..len]);
+        self.pos += len;
+
+        if self.pos == self.len {
+            self.complete = false;
+            self.len = 0;
+        }
+
+        len
+    }
+}
+
+/// H4 transport over any `embedded_io` Read/Write, e.g. a UART or a TCP socket
+///
+/// Implements [HciConnection] for [crate::Ble] if `T` implements the blocking traits including
+/// `ReadReady`, which lets it poll without blocking, and `embedded_io_async::Read`/`Write` for
+/// `asynch::Ble` if `T` implements the async ones.
+pub struct H4Transport<T, C> {
+    hci: RefCell<T>,
+    clock: C,
+    framer: RefCell<Framer>,
+}
+
+impl<T, C> H4Transport<T, C>
+where
+    C: Clock,
+{
+    /// The clock is used to time out incomplete packets of blocking transports
+    pub fn new(hci: T, clock: C) -> Self {
+        Self {
+            hci: RefCell::new(hci),
+            clock,
+            framer: RefCell::new(Framer::new()),
+        }
+    }
+
+    /// Number of bytes dropped to resynchronize with the packet boundaries
+    pub fn dropped_bytes(&self) -> usize {
+        self.framer.borrow().dropped
+    }
+
+    pub fn into_inner(self) -> T {
+        self.hci.into_inner()
+    }
+}
+
+impl<T, C> H4Transport<T, C>
+where
+    T: Read + ReadReady + Write,
+    C: Clock,
+{
+    /// Read from the transport until a packet is complete, gives up if nothing is received
+    /// or the rest of a started packet doesn't arrive in time
+    fn receive(&self) -> Result<bool, T::Error> {
+        let mut framer = self.framer.borrow_mut();
+        let mut hci = self.hci.borrow_mut();
+        let mut timeout_at = None;
+
+        loop {
+            if framer.process() {
+                return Ok(true);
+            }
+
+            if hci.read_ready()? {
+                let len = hci.read(framer.rx_buffer())?;
+                framer.received(len);
+                if len > 0 {
+                    continue;
+                }
+            }
+
+            if !framer.in_progress() {
+                return Ok(false);
+            }
+
+            let now = self.clock.now_millis();
+            if now > *timeout_at.get_or_insert(now + H4_TIMEOUT_MILLIS) {
+                framer.reset();
+                return Ok(false);
+            }
+        }
+    }
+}
+
+impl<T, C> HciConnection for H4Transport<T, C>
+where
+    T: Read + ReadReady + Write,
+    C: Clock,
+{
+    fn read(&self) -> Option<u8> {
+        let mut byte = [0u8];
+        if self.framer.borrow_mut().take(&mut byte) == 1 {
+            return Some(byte[0]);
+        }
+
+        match self.receive() {
+            Ok(true) if self.framer.borrow_mut().take(&mut byte) == 1 => Some(byte[0]),
+            Ok(_) => None,
+            Err(err) => {
+                log::warn!("Failed to read from the transport {:?}", err);
+                let mut framer = self.framer.borrow_mut();
+                if framer.in_progress() {
+                    framer.reset();
+                }
+                None
+            }
+        }
+    }
+
+    fn write(&self, data: u8) {
+        if let Err(err) = self.hci.borrow_mut().write_all(&[data]) {
+            log::warn!("Failed to write to the transport {:?}", err);
+        }
+    }
+
+    fn millis(&self) -> u64 {
+        self.clock.now_millis()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T, C> embedded_io_async::ErrorType for H4Transport<T, C>
+where
+    T: embedded_io_async::ErrorType,
+{
+    type Error = T::Error;
+}
+
+#[cfg(feature = "async")]
+impl<T, C> embedded_io_async::Read for H4Transport<T, C>
+where
+    T: embedded_io_async::Read,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let framer = self.framer.get_mut();
+        let hci = self.hci.get_mut();
+
+        while !framer.process() {
+            let len = hci.read(framer.rx_buffer()).await?;
+            if len == 0 {
+                // the transport reached its end, a started packet won't be completed anymore
+                if framer.in_progress() {
+                    framer.reset();
+                }
+                return Ok(0);
+            }
+            framer.received(len);
+        }
+
+        Ok(framer.take(buf))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T, C> embedded_io_async::Write for H4Transport<T, C>
+where
+    T: embedded_io_async::Write,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.hci.get_mut().write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.hci.get_mut().flush().await
+    }
+}
@@ -1,4 +1,10 @@
-use crate::{acl::AclPacket, Data};
+use crate::{
+    acl::{AclPacket, BoundaryFlag},
+    Data,
+};
+
+/// Number of connections which can receive a fragmented L2CAP frame at the same time
+pub(crate) const MAX_REASSEMBLY_CONNECTIONS: usize = 3;
 
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -11,6 +17,8 @@ pub struct L2capPacket {
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum L2capDecodeError {
+    /// The packet is shorter than the L2CAP header or the length given in the header
+    InvalidLength,
     Other,
 }
 
@@ -18,9 +26,19 @@ impl L2capPacket {
     pub fn decode(packet: AclPacket) -> Result<(u16, Self), L2capDecodeError> {
         let data = packet.data.as_slice();
         log::debug!("L2CAP {:02x?}", data);
+        if data.len() < 4 {
+            return Err(L2capDecodeError::InvalidLength);
+        }
+
         let length = (data[0] as u16) + ((data[1] as u16) << 8);
         let channel = (data[2] as u16) + ((data[3] as u16) << 8);
-        let payload = Data::new(&data[4..]);
+        if data.len() < 4 + length as usize {
+            return Err(L2capDecodeError::InvalidLength);
+        }
+
+        let mut payload = packet.data;
+        payload.advance(4);
+        payload.limit_len(length as usize);
 
         Ok((
             packet.handle,
@@ -33,30 +51,90 @@ impl L2capPacket {
     }
 
     pub fn encode(att_data: Data) -> Data {
-        let mut data = Data::new(&[
-            0, 0, // len set later
-            0x04, 0x00, // channel
-        ]);
-        data.append(att_data.as_slice());
+        Self::encode_channel(0x0004, att_data)
+    }
 
-        let len = data.len - 4;
-        data.set(0, (len & 0xff) as u8);
-        data.set(1, ((len >> 8) & 0xff) as u8);
+    pub fn encode_sm(att_data: Data) -> Data {
+        Self::encode_channel(0x0006, att_data)
+    }
 
-        data
+    /// Prepends the L2CAP header for the given channel to the payload
+    pub fn encode_channel(channel: u16, mut payload: Data) -> Data {
+        let len = payload.len as u16;
+        let [len_lo, len_hi] = len.to_le_bytes();
+        let [channel_lo, channel_hi] = channel.to_le_bytes();
+        payload.prepend(&[len_lo, len_hi, channel_lo, channel_hi]);
+        payload
     }
+}
 
-    pub fn encode_sm(att_data: Data) -> Data {
-        let mut data = Data::new(&[
-            0, 0, // len set later
-            0x06, 0x00, // channel
-        ]);
-        data.append(att_data.as_slice());
+/// Collects the fragments of L2CAP frames which span multiple ACL packets
+pub(crate) struct L2capReassembly {
+    frames: [Option<AclPacket>; MAX_REASSEMBLY_CONNECTIONS],
+}
+
+impl L2capReassembly {
+    pub(crate) fn new() -> Self {
+        Self {
+            frames: core::array::from_fn(|_| None),
+        }
+    }
 
-        let len = data.len - 4;
-        data.set(0, (len & 0xff) as u8);
-        data.set(1, ((len >> 8) & 0xff) as u8);
+    /// Process a received ACL packet, returns the L2CAP frame once it is complete
+    pub(crate) fn process(&mut self, packet: AclPacket) -> Option<AclPacket> {
+        let pending = self
+            .frames
+            .iter_mut()
+            .find(|frame| matches!(frame, Some(frame) if frame.handle == packet.handle));
 
-        data
+        if let BoundaryFlag::Continuing = packet.boundary_flag {
+            let Some(slot) = pending else {
+                log::warn!("Unexpected continuing fragment for {}", packet.handle);
+                return None;
+            };
+
+            let frame = slot.as_mut().unwrap();
+            if frame.data.len + packet.data.len > frame.data.capacity() {
+                log::warn!("L2CAP frame too large, dropping it");
+                *slot = None;
+                return None;
+            }
+
+            frame.data.append(packet.data.as_slice());
+            return if Self::is_complete(frame) {
+                slot.take()
+            } else {
+                None
+            };
+        }
+
+        if let Some(slot) = pending {
+            log::warn!("Incomplete L2CAP frame for {} dropped", packet.handle);
+            *slot = None;
+        }
+
+        if Self::is_complete(&packet) {
+            return Some(packet);
+        }
+
+        match self.frames.iter_mut().find(|frame| frame.is_none()) {
+            Some(slot) => *slot = Some(packet),
+            None => log::warn!("No space to reassemble L2CAP frame, dropping it"),
+        }
+        None
+    }
+
+    /// Drop incomplete frames of a disconnected connection
+    pub(crate) fn disconnected(&mut self, handle: u16) {
+        for slot in self.frames.iter_mut() {
+            if matches!(slot, Some(frame) if frame.handle == handle) {
+                *slot = None;
+            }
+        }
+    }
+
+    fn is_complete(packet: &AclPacket) -> bool {
+        let data = packet.data.as_slice();
+        data.len() >= 4 && data.len() >= u16::from_le_bytes([data[0], data[1]]) as usize + 4
     }
 }
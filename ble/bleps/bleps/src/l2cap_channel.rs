@@ -0,0 +1,684 @@
+//! LE Credit Based Connection-oriented Channels ([Vol 3] Part A, Section 10.1)
+//!
+//! A [L2capChannel] is opened with [L2capChannel::connect] or accepted from the peer with
+//! [L2capChannel::accept] and then used as a byte stream via `embedded_io`. Written data is
+//! sent as SDUs which are segmented into K-frames of at most the MPS of the peer, received
+//! SDUs are reassembled into the receive buffer. The peer gets credits as long as the
+//! buffer has room for all the K-frames it may send.
+//!
+//! While waiting for data or credits the channel answers the LE signaling channel of the
+//! connection. Other packets, e.g. ATT requests or events of other connections, are held back
+//! and returned by the next `poll` once the channel is no longer used.
+
+use crate::{
+    acl::AclPacket,
+    attribute_server::BASE_MTU,
+    event::EventType,
+    l2cap::L2capPacket,
+    signaling::{CreditBasedConnectionResult, SignalingCommand, LE_SIGNALING_CHANNEL},
+    Ble, Data, Error, PollResult,
+};
+
+/// The channel identifier used for our end of the channel
+pub const L2CAP_CHANNEL_CID: u16 = 0x0040;
+
+/// The largest SDU the peer may send
+pub const L2CAP_CHANNEL_MTU: u16 = 512;
+
+/// The largest K-frame payload we send or receive, a K-frame with its L2CAP header still
+/// fits into a single [Data]
+pub const L2CAP_CHANNEL_MPS: u16 = 247;
+
+/// How long to wait for the response to a signaling request ([Vol 3] Part A, Section 6.2.1)
+const SIGNALING_TIMEOUT_MILLIS: u64 = 30_000;
+
+/// Range of the channel identifiers of credit based channels on LE
+const DYNAMIC_CIDS: core::ops::RangeInclusive<u16> = 0x0040..=0x007f;
+
+/// MTU and MPS of the peer must be at least 23 bytes ([Vol 3] Part A, Section 4.22)
+fn acceptable_parameters(mtu: u16, mps: u16) -> bool {
+    mtu >= BASE_MTU && (BASE_MTU..=65533).contains(&mps)
+}
+
+/// Whether the packet belongs to the LE signaling channel or our channel, other packets are
+/// held back for the next `poll`
+fn is_channel_packet(packet: &AclPacket) -> bool {
+    match packet.data.as_slice() {
+        [_, _, channel_lo, channel_hi, ..] => matches!(
+            u16::from_le_bytes([*channel_lo, *channel_hi]),
+            LE_SIGNALING_CHANNEL | L2CAP_CHANNEL_CID
+        ),
+        _ => true,
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum L2capChannelError {
+    Hci(Error),
+    /// The peer refused to open the channel
+    Refused(CreditBasedConnectionResult),
+    /// The channel or the connection was closed
+    Disconnected,
+    /// The receive buffer is smaller than the minimum MTU of 23 bytes
+    BufferTooSmall,
+}
+
+impl From<Error> for L2capChannelError {
+    fn from(err: Error) -> Self {
+        L2capChannelError::Hci(err)
+    }
+}
+
+impl embedded_io_blocking::Error for L2capChannelError {
+    fn kind(&self) -> embedded_io_blocking::ErrorKind {
+        match self {
+            L2capChannelError::Hci(Error::Timeout | Error::CommandTimeout { .. }) => {
+                embedded_io_blocking::ErrorKind::TimedOut
+            }
+            L2capChannelError::Hci(_) => embedded_io_blocking::ErrorKind::Other,
+            L2capChannelError::Refused(_) => embedded_io_blocking::ErrorKind::ConnectionRefused,
+            L2capChannelError::Disconnected => embedded_io_blocking::ErrorKind::NotConnected,
+            L2capChannelError::BufferTooSmall => embedded_io_blocking::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// Flow control and reassembly state of a channel
+struct ChannelState<'a> {
+    handle: u16,
+    open: bool,
+    remote_cid: u16,
+    remote_mtu: u16,
+    remote_mps: u16,
+    /// K-frames we may still send
+    tx_credits: u16,
+    /// K-frames the peer may still send
+    rx_credits: u16,
+    /// Bytes of the SDU being received which are still missing
+    sdu_remaining: usize,
+    /// Ring buffer of received SDU bytes
+    buffer: &'a mut [u8],
+    read_pos: usize,
+    len: usize,
+}
+
+impl<'a> ChannelState<'a> {
+    fn new(handle: u16, buffer: &'a mut [u8]) -> Self {
+        Self {
+            handle,
+            open: false,
+            remote_cid: 0,
+            remote_mtu: 0,
+            remote_mps: 0,
+            tx_credits: 0,
+            rx_credits: 0,
+            sdu_remaining: 0,
+            buffer,
+            read_pos: 0,
+            len: 0,
+        }
+    }
+
+    fn mps(&self) -> u16 {
+        (self.buffer.len() as u16).min(L2CAP_CHANNEL_MPS)
+    }
+
+    /// As many credits as K-frames of the maximum size fit into the buffer
+    fn initial_credits(&self) -> u16 {
+        (self.buffer.len() / self.mps() as usize) as u16
+    }
+
+    fn open(&mut self, remote_cid: u16, remote_mtu: u16, remote_mps: u16, tx_credits: u16) {
+        self.open = true;
+        self.remote_cid = remote_cid;
+        self.remote_mtu = remote_mtu;
+        self.remote_mps = remote_mps;
+        self.tx_credits = tx_credits;
+        self.rx_credits = self.initial_credits();
+        self.sdu_remaining = 0;
+    }
+
+    /// Stores the SDU bytes of a received K-frame, fails if the peer violated the protocol
+    fn receive_frame(&mut self, payload: &[u8]) -> Result<(), ()> {
+        if self.rx_credits == 0 || payload.len() > self.mps() as usize {
+            return Err(());
+        }
+        self.rx_credits -= 1;
+
+        let data = if self.sdu_remaining == 0 {
+            let [len_lo, len_hi, data @ ..] = payload else {
+                return Err(());
+            };
+            let sdu_len = u16::from_le_bytes([*len_lo, *len_hi]);
+            if sdu_len > L2CAP_CHANNEL_MTU {
+                return Err(());
+            }
+            self.sdu_remaining = sdu_len as usize;
+            data
+        } else {
+            payload
+        };
+        if data.len() > self.sdu_remaining {
+            return Err(());
+        }
+        self.sdu_remaining -= data.len();
+
+        // the credits given to the peer never exceed the free space
+        for byte in data {
+            let index = (self.read_pos + self.len) % self.buffer.len();
+            self.buffer[index] = *byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.len);
+        for byte in buf[..count].iter_mut() {
+            *byte = self.buffer[self.read_pos];
+            self.read_pos = (self.read_pos + 1) % self.buffer.len();
+        }
+        self.len -= count;
+        count
+    }
+
+    /// Credits which can be given to the peer without overrunning the buffer
+    fn grantable_credits(&self) -> u16 {
+        let free_frames = ((self.buffer.len() - self.len) / self.mps() as usize) as u16;
+        free_frames.saturating_sub(self.rx_credits)
+    }
+}
+
+pub struct L2capChannel<'a> {
+    ble: &'a mut Ble<'a>,
+    state: ChannelState<'a>,
+}
+
+impl<'a> L2capChannel<'a> {
+    /// Create a channel on the given connection handle, received data is buffered in `buffer`
+    ///
+    /// The channel needs to be opened by [Self::connect] or [Self::accept].
+    pub fn new(ble: &'a mut Ble<'a>, handle: u16, buffer: &'a mut [u8]) -> L2capChannel<'a> {
+        L2capChannel {
+            ble,
+            state: ChannelState::new(handle, buffer),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+pub struct AsyncL2capChannel<'a, T, C>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    C: crate::clock::Clock + embedded_hal_async::delay::DelayNs,
+{
+    ble: &'a mut crate::asynch::Ble<T, C>,
+    state: ChannelState<'a>,
+}
+
+#[cfg(feature = "async")]
+impl<'a, T, C> AsyncL2capChannel<'a, T, C>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    C: crate::clock::Clock + embedded_hal_async::delay::DelayNs,
+{
+    /// Create a channel on the given connection handle, received data is buffered in `buffer`
+    ///
+    /// The channel needs to be opened by [Self::connect] or [Self::accept].
+    pub fn new(
+        ble: &'a mut crate::asynch::Ble<T, C>,
+        handle: u16,
+        buffer: &'a mut [u8],
+    ) -> AsyncL2capChannel<'a, T, C> {
+        AsyncL2capChannel {
+            ble,
+            state: ChannelState::new(handle, buffer),
+        }
+    }
+}
+
+bleps_dedup::dedup! {
+    impl<'a> SYNC L2capChannel<'a>
+    impl<'a, T, C> ASYNC AsyncL2capChannel<'a, T, C>
+        where
+            T: embedded_io_async::Read + embedded_io_async::Write,
+            C: crate::clock::Clock + embedded_hal_async::delay::DelayNs,
+    {
+        pub fn is_open(&self) -> bool {
+            self.state.open
+        }
+
+        /// The largest SDU the peer accepts
+        pub fn remote_mtu(&self) -> u16 {
+            self.state.remote_mtu
+        }
+
+        /// Open a channel to the given LE_PSM of the peer
+        pub async fn connect(&mut self, le_psm: u16) -> Result<(), L2capChannelError> {
+            if self.state.buffer.len() < BASE_MTU as usize {
+                return Err(L2capChannelError::BufferTooSmall);
+            }
+
+            let identifier = self.ble.next_signaling_identifier();
+            self.ble
+                .write_signaling(
+                    self.state.handle,
+                    SignalingCommand::LeCreditBasedConnectionRequest {
+                        identifier,
+                        le_psm,
+                        source_cid: L2CAP_CHANNEL_CID,
+                        mtu: L2CAP_CHANNEL_MTU,
+                        mps: self.state.mps(),
+                        initial_credits: self.state.initial_credits(),
+                    },
+                )
+                .await?;
+
+            let timeout_at = self.ble.millis() + SIGNALING_TIMEOUT_MILLIS;
+            loop {
+                let packet = self.ble.poll_hci_until(timeout_at).await;
+                match self.handle_packet(packet).await? {
+                    Some(SignalingCommand::LeCreditBasedConnectionResponse {
+                        identifier: response_identifier,
+                        destination_cid,
+                        mtu,
+                        mps,
+                        initial_credits,
+                        result,
+                    }) if response_identifier == identifier => {
+                        if result != CreditBasedConnectionResult::Success {
+                            return Err(L2capChannelError::Refused(result));
+                        }
+                        if !acceptable_parameters(mtu, mps) {
+                            // The peer opened its end already, close it again
+                            self.state.remote_cid = destination_cid;
+                            self.write_disconnection_request().await?;
+                            return Err(L2capChannelError::Refused(
+                                CreditBasedConnectionResult::UnacceptableParameters,
+                            ));
+                        }
+                        self.state.open(destination_cid, mtu, mps, initial_credits);
+                        return Ok(());
+                    }
+                    Some(SignalingCommand::LeCreditBasedConnectionRequest { identifier, .. }) => {
+                        self.refuse(identifier, CreditBasedConnectionResult::NoResourcesAvailable)
+                            .await?;
+                    }
+                    _ => (),
+                }
+
+                if self.ble.millis() > timeout_at {
+                    return Err(L2capChannelError::Hci(Error::Timeout));
+                }
+            }
+        }
+
+        /// Wait for the peer to open a channel to the given LE_PSM
+        ///
+        /// Requests for other LE_PSMs are refused.
+        pub async fn accept(&mut self, le_psm: u16) -> Result<(), L2capChannelError> {
+            if self.state.buffer.len() < BASE_MTU as usize {
+                return Err(L2capChannelError::BufferTooSmall);
+            }
+
+            loop {
+                let packet = self.ble.poll_hci().await;
+                let Some(SignalingCommand::LeCreditBasedConnectionRequest {
+                    identifier,
+                    le_psm: requested_psm,
+                    source_cid,
+                    mtu,
+                    mps,
+                    initial_credits,
+                }) = self.handle_packet(packet).await?
+                else {
+                    continue;
+                };
+
+                let result = if requested_psm != le_psm {
+                    CreditBasedConnectionResult::SpsmNotSupported
+                } else if !DYNAMIC_CIDS.contains(&source_cid) {
+                    CreditBasedConnectionResult::InvalidSourceCid
+                } else if !acceptable_parameters(mtu, mps) {
+                    CreditBasedConnectionResult::UnacceptableParameters
+                } else {
+                    CreditBasedConnectionResult::Success
+                };
+                if result != CreditBasedConnectionResult::Success {
+                    self.refuse(identifier, result).await?;
+                    continue;
+                }
+
+                self.ble
+                    .write_signaling(
+                        self.state.handle,
+                        SignalingCommand::LeCreditBasedConnectionResponse {
+                            identifier,
+                            destination_cid: L2CAP_CHANNEL_CID,
+                            mtu: L2CAP_CHANNEL_MTU,
+                            mps: self.state.mps(),
+                            initial_credits: self.state.initial_credits(),
+                            result,
+                        },
+                    )
+                    .await?;
+                self.state.open(source_cid, mtu, mps, initial_credits);
+                return Ok(());
+            }
+        }
+
+        /// Close the channel, waits for the peer to confirm
+        pub async fn disconnect(&mut self) -> Result<(), L2capChannelError> {
+            if !self.state.open {
+                return Ok(());
+            }
+
+            self.write_disconnection_request().await?;
+            let timeout_at = self.ble.millis() + SIGNALING_TIMEOUT_MILLIS;
+            while self.state.open {
+                let packet = self.ble.poll_hci_until(timeout_at).await;
+                match self.handle_packet(packet).await {
+                    Ok(_) | Err(L2capChannelError::Disconnected) => (),
+                    Err(err) => return Err(err),
+                }
+
+                if self.ble.millis() > timeout_at {
+                    self.state.open = false;
+                    return Err(L2capChannelError::Hci(Error::Timeout));
+                }
+            }
+            Ok(())
+        }
+
+        /// Read received data, waits until at least one byte is available
+        ///
+        /// Returns 0 once the channel is closed and all received data was read.
+        pub async fn receive(&mut self, buf: &mut [u8]) -> Result<usize, L2capChannelError> {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+
+            while self.state.len == 0 {
+                if !self.state.open {
+                    return Ok(0);
+                }
+
+                let packet = self.ble.poll_hci().await;
+                self.handle_channel_packet(packet).await?;
+            }
+
+            let count = self.state.read(buf);
+            let credits = self.state.grantable_credits();
+            if credits > 0 && self.state.open {
+                self.state.rx_credits += credits;
+                let identifier = self.ble.next_signaling_identifier();
+                self.ble
+                    .write_signaling(
+                        self.state.handle,
+                        SignalingCommand::FlowControlCreditInd {
+                            identifier,
+                            cid: L2CAP_CHANNEL_CID,
+                            credits,
+                        },
+                    )
+                    .await?;
+            }
+            Ok(count)
+        }
+
+        /// Send the data as a single SDU, waits for credits if needed
+        ///
+        /// Returns the number of bytes sent which is limited to the MTU of the peer.
+        pub async fn send(&mut self, data: &[u8]) -> Result<usize, L2capChannelError> {
+            if data.is_empty() {
+                return Ok(0);
+            }
+
+            let sdu_len = data.len().min(self.state.remote_mtu as usize);
+            let mps = self.state.remote_mps.min(L2CAP_CHANNEL_MPS) as usize;
+            let mut sent = 0;
+            while sent < sdu_len {
+                while self.state.tx_credits == 0 {
+                    if !self.state.open {
+                        return Err(L2capChannelError::Disconnected);
+                    }
+
+                    let packet = self.ble.poll_hci().await;
+                    self.handle_channel_packet(packet).await?;
+                }
+                if !self.state.open {
+                    return Err(L2capChannelError::Disconnected);
+                }
+
+                let mut frame = Data::new(&[]);
+                if sent == 0 {
+                    frame.append(&(sdu_len as u16).to_le_bytes());
+                }
+                let chunk = (mps - frame.len()).min(sdu_len - sent);
+                frame.append(&data[sent..][..chunk]);
+                self.ble
+                    .write_l2cap(
+                        self.state.handle,
+                        L2capPacket::encode_channel(self.state.remote_cid, frame),
+                    )
+                    .await?;
+                self.state.tx_credits -= 1;
+                sent += chunk;
+            }
+
+            Ok(sdu_len)
+        }
+
+        /// Handles a packet while the channel is in use, requests for other channels are refused
+        async fn handle_channel_packet(
+            &mut self,
+            packet: Option<PollResult>,
+        ) -> Result<(), L2capChannelError> {
+            match self.handle_packet(packet).await {
+                Ok(Some(SignalingCommand::LeCreditBasedConnectionRequest { identifier, .. })) => {
+                    self.refuse(identifier, CreditBasedConnectionResult::NoResourcesAvailable)
+                        .await?;
+                    Ok(())
+                }
+                Ok(_) | Err(L2capChannelError::Disconnected) => Ok(()),
+                Err(err) => Err(err),
+            }
+        }
+
+        /// Handles a polled packet, returns credit based connection requests and responses
+        /// for the caller to process
+        async fn handle_packet(
+            &mut self,
+            packet: Option<PollResult>,
+        ) -> Result<Option<SignalingCommand>, L2capChannelError> {
+            match packet {
+                Some(res @ PollResult::Event(EventType::DisconnectComplete { handle, .. }))
+                    if handle == self.state.handle =>
+                {
+                    self.state.open = false;
+                    self.ble.hold_back(res);
+                    Err(L2capChannelError::Disconnected)
+                }
+                Some(PollResult::AsyncData(packet))
+                    if packet.handle == self.state.handle && is_channel_packet(&packet) =>
+                {
+                    let (_, l2cap_packet) = match L2capPacket::decode(packet) {
+                        Ok(decoded) => decoded,
+                        Err(_) => return Ok(None),
+                    };
+
+                    if l2cap_packet.channel == LE_SIGNALING_CHANNEL {
+                        return self.handle_signaling(l2cap_packet.payload.as_slice()).await;
+                    }
+                    if l2cap_packet.channel == L2CAP_CHANNEL_CID && self.state.open {
+                        if self.state.receive_frame(l2cap_packet.payload.as_slice()).is_err() {
+                            log::warn!("Invalid K-frame, closing the channel");
+                            self.write_disconnection_request().await?;
+                            self.state.open = false;
+                        }
+                        return Ok(None);
+                    }
+
+                    log::debug!("Ignoring K-frame of the closed channel");
+                    Ok(None)
+                }
+                Some(res) => {
+                    self.ble.hold_back(res);
+                    Ok(None)
+                }
+                None => Ok(None),
+            }
+        }
+
+        async fn handle_signaling(
+            &mut self,
+            payload: &[u8],
+        ) -> Result<Option<SignalingCommand>, L2capChannelError> {
+            let command = match SignalingCommand::decode(payload) {
+                Ok(command) => command,
+                Err(_) => {
+                    self.ble.handle_signaling(self.state.handle, payload).await?;
+                    return Ok(None);
+                }
+            };
+
+            match command {
+                SignalingCommand::LeCreditBasedConnectionRequest { .. }
+                | SignalingCommand::LeCreditBasedConnectionResponse { .. } => Ok(Some(command)),
+                SignalingCommand::FlowControlCreditInd { cid, credits, .. }
+                    if self.state.open && cid == self.state.remote_cid =>
+                {
+                    self.state.tx_credits = self.state.tx_credits.saturating_add(credits);
+                    Ok(None)
+                }
+                SignalingCommand::DisconnectionRequest {
+                    identifier,
+                    destination_cid,
+                    source_cid,
+                } if self.state.open
+                    && destination_cid == L2CAP_CHANNEL_CID
+                    && source_cid == self.state.remote_cid =>
+                {
+                    self.ble
+                        .write_signaling(
+                            self.state.handle,
+                            SignalingCommand::DisconnectionResponse {
+                                identifier,
+                                destination_cid,
+                                source_cid,
+                            },
+                        )
+                        .await?;
+                    self.state.open = false;
+                    Ok(None)
+                }
+                SignalingCommand::DisconnectionResponse {
+                    destination_cid,
+                    source_cid,
+                    ..
+                } if destination_cid == self.state.remote_cid
+                    && source_cid == L2CAP_CHANNEL_CID =>
+                {
+                    self.state.open = false;
+                    Ok(None)
+                }
+                _ => {
+                    self.ble.handle_signaling(self.state.handle, payload).await?;
+                    Ok(None)
+                }
+            }
+        }
+
+        async fn refuse(
+            &mut self,
+            identifier: u8,
+            result: CreditBasedConnectionResult,
+        ) -> Result<(), L2capChannelError> {
+            self.ble
+                .write_signaling(
+                    self.state.handle,
+                    SignalingCommand::LeCreditBasedConnectionResponse {
+                        identifier,
+                        destination_cid: 0,
+                        mtu: 0,
+                        mps: 0,
+                        initial_credits: 0,
+                        result,
+                    },
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn write_disconnection_request(&mut self) -> Result<(), L2capChannelError> {
+            let identifier = self.ble.next_signaling_identifier();
+            self.ble
+                .write_signaling(
+                    self.state.handle,
+                    SignalingCommand::DisconnectionRequest {
+                        identifier,
+                        destination_cid: self.state.remote_cid,
+                        source_cid: L2CAP_CHANNEL_CID,
+                    },
+                )
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+impl embedded_io_blocking::ErrorType for L2capChannel<'_> {
+    type Error = L2capChannelError;
+}
+
+impl embedded_io_blocking::Read for L2capChannel<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.receive(buf)
+    }
+}
+
+impl embedded_io_blocking::Write for L2capChannel<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.send(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T, C> embedded_io_async::ErrorType for AsyncL2capChannel<'_, T, C>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    C: crate::clock::Clock + embedded_hal_async::delay::DelayNs,
+{
+    type Error = L2capChannelError;
+}
+
+#[cfg(feature = "async")]
+impl<T, C> embedded_io_async::Read for AsyncL2capChannel<'_, T, C>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    C: crate::clock::Clock + embedded_hal_async::delay::DelayNs,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.receive(buf).await
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T, C> embedded_io_async::Write for AsyncL2capChannel<'_, T, C>
+where
+    T: embedded_io_async::Read + embedded_io_async::Write,
+    C: crate::clock::Clock + embedded_hal_async::delay::DelayNs,
+{
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.send(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
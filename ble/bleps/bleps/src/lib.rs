@@ -2,29 +2,83 @@
 
 use core::cell::RefCell;
 
-use acl::AclPacket;
+use acl::{AclFlowControl, AclPacket, BoundaryFlag, HostBroadcastFlag, ACL_TIMEOUT_MILLIS};
+use clock::Clock;
 use command::{
-    opcode, Command, INFORMATIONAL_OGF, LONG_TERM_KEY_REQUEST_REPLY_OCF, READ_BD_ADDR_OCF,
-    SET_ADVERTISE_ENABLE_OCF, SET_ADVERTISING_DATA_OCF, SET_EVENT_MASK_OCF, SET_SCAN_RSP_DATA_OCF,
+    opcode, Command, CommandHeader, CommandTimeouts, CustomCommand, ReturnParameters,
+    ADD_DEVICE_TO_FILTER_ACCEPT_LIST_OCF, ADD_DEVICE_TO_RESOLVING_LIST_OCF,
+    CLEAR_ADVERTISING_SETS_OCF, CLEAR_FILTER_ACCEPT_LIST_OCF, CLEAR_RESOLVING_LIST_OCF,
+    COMMAND_QUEUE_SIZE, CONNECTION_UPDATE_OCF, CREATE_CONNECTION_CANCEL_OCF, CREATE_CONNECTION_OCF,
+    DISCONNECT_OCF, ENCRYPT_OCF, INFORMATIONAL_OGF, LE_READ_BUFFER_SIZE_OCF, LINK_CONTROL_OGF,
+    LONG_TERM_KEY_REQUEST_NEGATIVE_REPLY_OCF, LONG_TERM_KEY_REQUEST_REPLY_OCF,
+    MAX_COMMAND_PARAMETERS_LEN, RAND_OCF, READ_ADVERTISING_PHYSICAL_CHANNEL_TX_POWER_OCF,
+    READ_BD_ADDR_OCF, READ_BUFFER_SIZE_OCF, READ_CHANNEL_MAP_OCF, READ_FILTER_ACCEPT_LIST_SIZE_OCF,
+    READ_LOCAL_SUPPORTED_FEATURES_OCF, READ_LOCAL_VERSION_INFORMATION_OCF, READ_PHY_OCF,
+    READ_REMOTE_FEATURES_OCF, READ_RSSI_OCF, READ_SUGGESTED_DEFAULT_DATA_LENGTH_OCF,
+    REMOVE_ADVERTISING_SET_OCF, REMOVE_DEVICE_FROM_FILTER_ACCEPT_LIST_OCF,
+    REMOVE_DEVICE_FROM_RESOLVING_LIST_OCF, SET_ADDRESS_RESOLUTION_ENABLE_OCF,
+    SET_ADVERTISE_ENABLE_OCF, SET_ADVERTISING_DATA_OCF, SET_ADVERTISING_SET_RANDOM_ADDRESS_OCF,
+    SET_DATA_LENGTH_OCF, SET_EVENT_MASK_OCF, SET_EXTENDED_ADVERTISING_DATA_OCF,
+    SET_EXTENDED_ADVERTISING_ENABLE_OCF, SET_EXTENDED_ADVERTISING_PARAMETERS_OCF,
+    SET_EXTENDED_SCAN_RSP_DATA_OCF, SET_PHY_OCF, SET_RANDOM_ADDRESS_OCF,
+    SET_RESOLVABLE_PRIVATE_ADDRESS_TIMEOUT_OCF, SET_SCAN_ENABLE_OCF, SET_SCAN_PARAMETERS_OCF,
+    SET_SCAN_RSP_DATA_OCF, START_ENCRYPTION_OCF, STATUS_PARAMETERS_OGF,
+    WRITE_SUGGESTED_DEFAULT_DATA_LENGTH_OCF,
 };
-use command::{LE_OGF, SET_ADVERTISING_PARAMETERS_OCF};
+use command::{LE_OGF, LE_SET_EVENT_MASK_OCF, SET_ADVERTISING_PARAMETERS_OCF};
+use connection::{
+    CodedPhyOptions, Connection, ConnectionUpdateParameters, CreateConnectionParameters, DataLength,
+};
+use controller_info::{ChannelMap, ControllerInfo, LeFeatures, LinkQuality, LocalVersion};
 use embedded_io_blocking::{Read, Write};
-use event::EventType;
+use event::{EventMask, EventType, LeEventMask};
+use extended_advertising::{
+    AdvertisingDataOperation, AdvertisingSet, ExtendedAdvertisingParameters,
+    MAX_ADVERTISING_DATA_FRAGMENT_LEN,
+};
+use l2cap::{L2capPacket, L2capReassembly};
+use recovery::{
+    AdvertisingConfig, AdvertisingSetConfig, ControllerFault, PausedAdvertising,
+    MAX_COMMAND_TIMEOUTS_IN_A_ROW,
+};
+use signaling::{
+    CreditBasedConnectionResult, RejectReason, SignalingCommand, SignalingDecodeError,
+    LE_SIGNALING_CHANNEL,
+};
 
 pub mod acl;
 pub mod att;
 pub mod l2cap;
+pub mod l2cap_channel;
+pub mod signaling;
 
 pub mod command;
 pub mod event;
 
+pub mod connection;
+pub mod controller_info;
+
 pub mod ad_structure;
+pub mod extended_advertising;
 
 pub mod attribute;
 pub mod attribute_server;
 
+pub mod gatt_client;
+
+pub mod capture;
+pub mod clock;
+pub mod h4;
+pub mod virtual_controller;
+
 #[cfg(feature = "crypto")]
 pub mod crypto;
+pub mod recovery;
+
+#[cfg(feature = "crypto")]
+pub mod privacy;
+#[cfg(feature = "crypto")]
+pub mod rng;
 #[cfg(feature = "crypto")]
 pub mod sm;
 
@@ -37,12 +91,26 @@ pub use bleps_macros::gatt;
 use command::CONTROLLER_OGF;
 use command::RESET_OCF;
 
-const TIMEOUT_MILLIS: u64 = 1000;
-
 #[derive(Debug)]
 pub enum Error {
+    /// Timed out waiting for the controller, e.g. for free ACL buffers or a connection
     Timeout,
+    /// Failed with the given HCI status code
     Failed(u8),
+    /// The controller didn't answer the command with the given opcode in time
+    CommandTimeout { opcode: u16 },
+    /// The controller rejected the command with the given opcode
+    CommandFailed { opcode: u16, status: u8 },
+}
+
+impl Error {
+    /// The opcode of the command which failed or timed out, if any
+    pub fn opcode(&self) -> Option<u16> {
+        match self {
+            Error::CommandTimeout { opcode } | Error::CommandFailed { opcode, .. } => Some(*opcode),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(feature = "defmt")]
@@ -55,6 +123,12 @@ impl defmt::Format for Error {
             Error::Failed(value) => {
                 defmt::write!(fmt, "Failed({})", value)
             }
+            Error::CommandTimeout { opcode } => {
+                defmt::write!(fmt, "CommandTimeout({:04x})", opcode)
+            }
+            Error::CommandFailed { opcode, status } => {
+                defmt::write!(fmt, "CommandFailed({:04x}, {})", opcode, status)
+            }
         }
     }
 }
@@ -77,6 +151,57 @@ impl Addr {
         a[1..].copy_from_slice(&v);
         Self(a)
     }
+
+    /// Returns the address as little-endian byte array as used by HCI.
+    #[inline]
+    pub fn to_le_bytes(&self) -> [u8; 6] {
+        let mut v: [u8; 6] = self.0[1..].try_into().unwrap();
+        v.reverse();
+        v
+    }
+
+    #[inline]
+    pub fn is_random(&self) -> bool {
+        self.0[0] != 0
+    }
+
+    /// Classifies the address by its type and, for random addresses, the two most
+    /// significant bits ([Vol 6] Part B, Section 1.3).
+    pub fn kind(&self) -> AddressKind {
+        if !self.is_random() {
+            return AddressKind::Public;
+        }
+
+        match self.0[1] >> 6 {
+            0b11 => AddressKind::StaticRandom,
+            0b01 => AddressKind::ResolvablePrivate,
+            0b00 => AddressKind::NonResolvablePrivate,
+            _ => AddressKind::Reserved,
+        }
+    }
+
+    /// Returns true if the address changes over time and can't be used to identify a device
+    /// without resolving it.
+    #[inline]
+    pub fn is_private(&self) -> bool {
+        matches!(
+            self.kind(),
+            AddressKind::ResolvablePrivate | AddressKind::NonResolvablePrivate
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AddressKind {
+    Public,
+    /// A random address which stays the same at least until the next power cycle
+    StaticRandom,
+    /// A private address which can be resolved with the IRK of the device
+    ResolvablePrivate,
+    NonResolvablePrivate,
+    /// A random address with the reserved most significant bits `0b10`
+    Reserved,
 }
 
 #[derive(Debug)]
@@ -86,37 +211,74 @@ pub enum PollResult {
     AsyncData(AclPacket),
 }
 
-#[derive(Clone, Copy)]
-#[cfg_attr(feature = "defmt", derive(defmt::Format))]
-pub struct Data {
-    pub data: [u8; 256],
+/// Space reserved in front of the payload of [Data] for the HCI packet type and the ACL and
+/// L2CAP headers, so these can be prepended without moving the payload
+pub const DATA_HEADROOM: usize = 9;
+
+/// Largest L2CAP frame which can be received, an ATT PDU of the largest MTU (256 bytes) plus
+/// the L2CAP header
+pub const MAX_L2CAP_FRAME_LEN: usize = 256 + 4;
+
+/// Default capacity of [Data], a frame of [MAX_L2CAP_FRAME_LEN] plus [DATA_HEADROOM]
+pub const DATA_CAPACITY: usize = MAX_L2CAP_FRAME_LEN + DATA_HEADROOM;
+
+/// A packet buffer holding up to `N` bytes
+///
+/// The payload starts after [DATA_HEADROOM] bytes. Lower layers prepend their headers into
+/// the headroom and upper layers strip them by advancing the start, so the same buffer is
+/// passed through all layers.
+#[derive(Clone)]
+pub struct Data<const N: usize = DATA_CAPACITY> {
+    data: [u8; N],
+    start: usize,
     pub len: usize,
 }
 
 impl Data {
     pub fn new(bytes: &[u8]) -> Data {
-        let mut data = [0u8; 256];
-        data[..bytes.len()].copy_from_slice(bytes);
+        Self::from_bytes(bytes)
+    }
+}
+
+impl<const N: usize> Data<N> {
+    /// Create a buffer of any capacity, leaving headroom if the capacity allows it
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let start = if N >= DATA_HEADROOM + bytes.len() {
+            DATA_HEADROOM
+        } else {
+            0
+        };
+
+        let mut data = [0u8; N];
+        data[start..][..bytes.len()].copy_from_slice(bytes);
         Data {
             data,
+            start,
             len: bytes.len(),
         }
     }
 
     pub fn as_slice(&self) -> &[u8] {
-        &self.data[0..self.len]
+        &self.data[self.start..][..self.len]
     }
 
     pub fn as_slice_mut(&mut self) -> &mut [u8] {
-        &mut self.data[self.len..]
+        &mut self.data[self.start..][..self.len]
+    }
+
+    /// The unused space after the payload, use [Data::append_len] to make bytes written to it
+    /// part of the payload
+    pub fn spare_capacity_mut(&mut self) -> &mut [u8] {
+        &mut self.data[self.start + self.len..]
+    }
+
+    /// Number of bytes the payload can grow to
+    pub fn capacity(&self) -> usize {
+        N - self.start
     }
 
     pub fn set_len(&mut self, new_len: usize) {
-        self.len = if new_len > self.data.len() {
-            self.data.len()
-        } else {
-            new_len
-        };
+        self.len = usize::min(new_len, self.capacity());
     }
 
     pub fn append_len(&mut self, extra_len: usize) {
@@ -129,15 +291,29 @@ impl Data {
         }
     }
 
-    pub fn subdata_from(&self, from: usize) -> Data {
-        let mut data = [0u8; 256];
-        let new_len = self.len - from;
-        data[..new_len].copy_from_slice(&self.data[from..(from + new_len)]);
-        Data { data, len: new_len }
+    /// Strip `count` bytes from the front, e.g. a header which was decoded
+    pub fn advance(&mut self, count: usize) {
+        let count = usize::min(count, self.len);
+        self.start += count;
+        self.len -= count;
+    }
+
+    /// Put bytes in front of the payload, using the headroom if there is enough of it
+    pub fn prepend(&mut self, bytes: &[u8]) {
+        if self.start < bytes.len() {
+            let shift = bytes.len() - self.start;
+            self.data
+                .copy_within(self.start..self.start + self.len, self.start + shift);
+            self.start += shift;
+        }
+
+        self.start -= bytes.len();
+        self.len += bytes.len();
+        self.data[self.start..][..bytes.len()].copy_from_slice(bytes);
     }
 
     pub fn append(&mut self, bytes: &[u8]) {
-        self.data[self.len..(self.len + bytes.len())].copy_from_slice(bytes);
+        self.spare_capacity_mut()[..bytes.len()].copy_from_slice(bytes);
         self.len += bytes.len();
     }
 
@@ -153,14 +329,14 @@ impl Data {
         {
             let top = slice.len() - 1;
             for (index, byte) in slice.iter().enumerate() {
-                self.set(top - index, *byte);
+                self.set(self.len + top - index, *byte);
             }
             self.append_len(slice.len());
         }
     }
 
     pub fn set(&mut self, index: usize, byte: u8) {
-        self.data[index] = byte;
+        self.data[self.start + index] = byte;
     }
 
     pub fn len(&self) -> usize {
@@ -168,19 +344,148 @@ impl Data {
     }
 }
 
-impl Default for Data {
+/// Encoded packets waiting to be sent until the controller is able to accept them
+pub(crate) struct PacketQueue<const N: usize> {
+    packets: [Data; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> PacketQueue<N> {
+    pub(crate) fn new() -> Self {
+        Self {
+            packets: core::array::from_fn(|_| Data::default()),
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Index in the ring of the packet at `position` in the queue
+    fn slot(&self, position: usize) -> usize {
+        (self.head + position) % N
+    }
+
+    /// Appends a packet, callers have to make sure the queue isn't full
+    pub(crate) fn push(&mut self, packet: Data) {
+        let slot = self.slot(self.len);
+        self.packets[slot] = packet;
+        self.len += 1;
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Data> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let packet = core::mem::take(&mut self.packets[self.head]);
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(packet)
+    }
+
+    /// Drops all packets not matching the predicate
+    pub(crate) fn retain(&mut self, f: impl Fn(&Data) -> bool) {
+        let mut kept = 0;
+        for position in 0..self.len {
+            let slot = self.slot(position);
+            if f(&self.packets[slot]) {
+                let kept_slot = self.slot(kept);
+                self.packets.swap(kept_slot, slot);
+                kept += 1;
+            }
+        }
+        self.len = kept;
+    }
+}
+
+/// Number of polled packets held back while waiting for command credits or ACL buffers
+pub(crate) const PENDING_QUEUE_SIZE: usize = 4;
+
+/// Packets polled while waiting for the controller, returned first by the next polls
+pub(crate) struct PendingQueue {
+    results: [Option<PollResult>; PENDING_QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl PendingQueue {
+    pub(crate) fn new() -> Self {
+        Self {
+            results: core::array::from_fn(|_| None),
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.len == PENDING_QUEUE_SIZE
+    }
+
+    /// Index in the ring of the packet at `position` in the queue
+    fn slot(&self, position: usize) -> usize {
+        (self.head + position) % PENDING_QUEUE_SIZE
+    }
+
+    /// Appends a packet, callers have to make sure the queue isn't full
+    pub(crate) fn push(&mut self, result: PollResult) {
+        let slot = self.slot(self.len);
+        self.results[slot] = Some(result);
+        self.len += 1;
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<PollResult> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let result = self.results[self.head].take();
+        self.head = (self.head + 1) % PENDING_QUEUE_SIZE;
+        self.len -= 1;
+        result
+    }
+
+    /// Removes the first packet matching the predicate, keeping the order of the others
+    pub(crate) fn take(&mut self, f: impl Fn(&PollResult) -> bool) -> Option<PollResult> {
+        let position = (0..self.len)
+            .position(|position| self.results[self.slot(position)].as_ref().is_some_and(&f))?;
+        let result = self.results[self.slot(position)].take();
+        for position in position..self.len - 1 {
+            let (slot, next) = (self.slot(position), self.slot(position + 1));
+            self.results.swap(slot, next);
+        }
+        self.len -= 1;
+        result
+    }
+}
+
+impl<const N: usize> Default for Data<N> {
     fn default() -> Self {
-        Data::new(&[])
+        Data::from_bytes(&[])
     }
 }
 
-impl core::fmt::Debug for Data {
+impl<const N: usize> core::fmt::Debug for Data<N> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{:x?}", &self.data[..self.len]).expect("Failed to format Data");
+        write!(f, "{:x?}", self.as_slice()).expect("Failed to format Data");
         Ok(())
     }
 }
 
+#[cfg(feature = "defmt")]
+impl<const N: usize> defmt::Format for Data<N> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=[u8]:x}", self.as_slice())
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum AdvertisingType {
     AdvInd = 0x00,
@@ -231,25 +536,112 @@ pub struct AdvertisingParameters {
     pub filter_policy: AdvertisingFilterPolicy,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum ScanType {
+    Passive = 0x00,
+    Active = 0x01,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ScanFilterPolicy {
+    All = 0x00,
+    Filtered = 0x01,
+    AllDirectedRpa = 0x02,
+    FilteredDirectedRpa = 0x03,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScanParameters {
+    pub scan_type: ScanType,
+    /// Time between the start of two scan windows in units of 0.625 ms
+    pub scan_interval: u16,
+    /// Duration of a scan window in units of 0.625 ms, must not exceed the interval
+    pub scan_window: u16,
+    pub own_address_type: OwnAddressType,
+    pub filter_policy: ScanFilterPolicy,
+}
+
+impl Default for ScanParameters {
+    fn default() -> Self {
+        Self {
+            scan_type: ScanType::Passive,
+            scan_interval: 0x0010,
+            scan_window: 0x0010,
+            own_address_type: OwnAddressType::Public,
+            filter_policy: ScanFilterPolicy::All,
+        }
+    }
+}
+
 const PACKET_TYPE_COMMAND: u8 = 0x01;
 const PACKET_TYPE_ASYNC_DATA: u8 = 0x02;
 const PACKET_TYPE_EVENT: u8 = 0x04;
 
 pub struct Ble<'a> {
     connector: &'a dyn HciConnection,
+    // Num_HCI_Command_Packets granted by the controller, initially one command is allowed
+    command_credits: u8,
+    command_queue: PacketQueue<COMMAND_QUEUE_SIZE>,
+    command_timeouts: CommandTimeouts,
+    acl_flow_control: AclFlowControl,
+    l2cap_reassembly: L2capReassembly,
+    pending: PendingQueue,
+    // identifier of the last request sent on the LE signaling channel
+    signaling_identifier: u8,
+    controller_info: ControllerInfo,
+    event_masks: (EventMask, LeEventMask),
+    advertising_config: AdvertisingConfig,
+    command_timeouts_in_a_row: u8,
+    fault: Option<ControllerFault>,
 }
 
 impl<'a> Ble<'a> {
     pub fn new(connector: &'a dyn HciConnection) -> Ble<'a> {
-        Ble { connector }
+        Ble {
+            connector,
+            command_credits: 1,
+            command_queue: PacketQueue::new(),
+            command_timeouts: CommandTimeouts::new(),
+            acl_flow_control: AclFlowControl::new(),
+            l2cap_reassembly: L2capReassembly::new(),
+            pending: PendingQueue::new(),
+            signaling_identifier: 0,
+            controller_info: ControllerInfo::default(),
+            event_masks: (EventMask::default(), LeEventMask::default()),
+            advertising_config: AdvertisingConfig::new(),
+            command_timeouts_in_a_row: 0,
+            fault: None,
+        }
+    }
+
+    fn millis(&self) -> u64 {
+        self.connector.millis()
     }
 
+    /// Reset the controller and enable all events the stack handles
     pub fn init(&mut self) -> Result<(), Error>
     where
         Self: Sized,
     {
+        self.init_with_event_masks(EventMask::default(), LeEventMask::default())
+    }
+
+    /// Reset the controller and only enable the given events, e.g.
+    /// `LeEventMask::connections().with_data_length()` if the application doesn't scan
+    pub fn init_with_event_masks(
+        &mut self,
+        events: EventMask,
+        le_events: LeEventMask,
+    ) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        self.event_masks = (events, le_events);
         self.cmd_reset()?;
-        self.cmd_set_event_mask([0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])?;
+        self.cmd_set_event_mask(events)?;
+        self.cmd_le_set_event_mask(le_events)?;
+        self.read_acl_buffer_size()?;
+        self.read_controller_info()?;
         Ok(())
     }
 
@@ -257,25 +649,34 @@ impl<'a> Ble<'a> {
     where
         Self: Sized,
     {
-        self.write_bytes(Command::Reset.encode().as_slice());
+        self.send_command(Command::Reset)?;
         self.wait_for_command_complete(CONTROLLER_OGF, RESET_OCF)?
             .check_command_completed()
     }
 
-    pub fn cmd_set_event_mask(&mut self, events: [u8; 8]) -> Result<EventType, Error>
+    pub fn cmd_set_event_mask(&mut self, events: EventMask) -> Result<EventType, Error>
     where
         Self: Sized,
     {
-        self.write_bytes(Command::SetEventMask { events }.encode().as_slice());
+        self.send_command(Command::SetEventMask { events })?;
         self.wait_for_command_complete(CONTROLLER_OGF, SET_EVENT_MASK_OCF)?
             .check_command_completed()
     }
 
+    pub fn cmd_le_set_event_mask(&mut self, events: LeEventMask) -> Result<EventType, Error>
+    where
+        Self: Sized,
+    {
+        self.send_command(Command::LeSetEventMask { events })?;
+        self.wait_for_command_complete(LE_OGF, LE_SET_EVENT_MASK_OCF)?
+            .check_command_completed()
+    }
+
     pub fn cmd_set_le_advertising_parameters(&mut self) -> Result<EventType, Error>
     where
         Self: Sized,
     {
-        self.write_bytes(Command::LeSetAdvertisingParameters.encode().as_slice());
+        self.send_command(Command::LeSetAdvertisingParameters)?;
         self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF)?
             .check_command_completed()
     }
@@ -287,11 +688,7 @@ impl<'a> Ble<'a> {
     where
         Self: Sized,
     {
-        self.write_bytes(
-            Command::LeSetAdvertisingParametersCustom(params)
-                .encode()
-                .as_slice(),
-        );
+        self.send_command(Command::LeSetAdvertisingParametersCustom(params))?;
         self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF)?
             .check_command_completed()
     }
@@ -300,7 +697,7 @@ impl<'a> Ble<'a> {
     where
         Self: Sized,
     {
-        self.write_bytes(Command::LeSetAdvertisingData { data }.encode().as_slice());
+        self.send_command(Command::LeSetAdvertisingData { data })?;
         self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_DATA_OCF)?
             .check_command_completed()
     }
@@ -309,7 +706,7 @@ impl<'a> Ble<'a> {
     where
         Self: Sized,
     {
-        self.write_bytes(Command::LeSetScanRspData { data }.encode().as_slice());
+        self.send_command(Command::LeSetScanRspData { data })?;
         self.wait_for_command_complete(LE_OGF, SET_SCAN_RSP_DATA_OCF)?
             .check_command_completed()
     }
@@ -318,7 +715,7 @@ impl<'a> Ble<'a> {
     where
         Self: Sized,
     {
-        self.write_bytes(Command::LeSetAdvertiseEnable(enable).encode().as_slice());
+        self.send_command(Command::LeSetAdvertiseEnable(enable))?;
         self.wait_for_command_complete(LE_OGF, SET_ADVERTISE_ENABLE_OCF)?
             .check_command_completed()
     }
@@ -332,11 +729,7 @@ impl<'a> Ble<'a> {
         Self: Sized,
     {
         log::trace!("before, key = {:x}, hanlde = {:x}", ltk, handle);
-        self.write_bytes(
-            Command::LeLongTermKeyRequestReply { handle, ltk }
-                .encode()
-                .as_slice(),
-        );
+        self.send_command(Command::LeLongTermKeyRequestReply { handle, ltk })?;
         log::trace!("done writing command");
         let res = self
             .wait_for_command_complete(LE_OGF, LONG_TERM_KEY_REQUEST_REPLY_OCF)?
@@ -350,7 +743,7 @@ impl<'a> Ble<'a> {
     where
         Self: Sized,
     {
-        self.write_bytes(Command::ReadBrAddr.encode().as_slice());
+        self.send_command(Command::ReadBrAddr)?;
         let res = self
             .wait_for_command_complete(INFORMATIONAL_OGF, READ_BD_ADDR_OCF)?
             .check_command_completed()?;
@@ -364,29 +757,28 @@ impl<'a> Ble<'a> {
         }
     }
 
+    /// Waits for the Command Complete or Command Status event of the given command
     fn wait_for_command_complete(&mut self, ogf: u8, ocf: u16) -> Result<EventType, Error>
     where
         Self: Sized,
     {
-        let timeout_at = self.connector.millis() + TIMEOUT_MILLIS;
+        let code = opcode(ogf, ocf);
+        let timeout_at = self.connector.millis() + self.command_timeouts.get(code);
         loop {
-            let res = self.poll();
-            if res.is_some() {
-                log::debug!("polled while waiting {:?}", res);
-            }
-
-            match res {
-                Some(PollResult::Event(event)) => match event {
-                    EventType::CommandComplete { opcode: code, .. } if code == opcode(ogf, ocf) => {
-                        return Ok(event);
-                    }
-                    _ => (),
-                },
-                _ => (),
+            match self.poll_hci() {
+                Some(PollResult::Event(
+                    event @ (EventType::CommandComplete { opcode, .. }
+                    | EventType::CommandStatus { opcode, .. }),
+                )) if opcode == code => {
+                    self.command_timeouts_in_a_row = 0;
+                    return Ok(event);
+                }
+                Some(res) => self.hold_back(res),
+                None => (),
             }
 
             if self.connector.millis() > timeout_at {
-                return Err(Error::Timeout);
+                return Err(self.command_timed_out(code));
             }
         }
     }
@@ -395,6 +787,11 @@ impl<'a> Ble<'a> {
     where
         Self: Sized,
     {
+        self.pending.pop().or_else(|| self.poll_hci())
+    }
+
+    /// Polls the controller, skipping the packets held back while waiting for it
+    pub(crate) fn poll_hci(&mut self) -> Option<PollResult> {
         // poll & process input
         let packet_type = self.connector.read();
 
@@ -402,32 +799,15 @@ impl<'a> Ble<'a> {
             Some(packet_type) => match packet_type {
                 PACKET_TYPE_COMMAND => {}
                 PACKET_TYPE_ASYNC_DATA => {
-                    let mut acl_packet = AclPacket::read(self.connector);
-                    let wanted =
-                        u16::from_le_bytes(acl_packet.data.as_slice()[..2].try_into().unwrap())
-                            as usize;
-
-                    // somewhat dirty way to handle re-assembling fragmented packets
-                    loop {
-                        log::debug!("Wanted = {}, actual = {}", wanted, acl_packet.data.len());
-
-                        if wanted == acl_packet.data.len() - 4 {
-                            break;
-                        }
-
-                        log::debug!("Need more!");
-                        if self.connector.read() != Some(PACKET_TYPE_ASYNC_DATA) {
-                            log::error!("Expected async data");
-                        }
-
-                        let next_acl_packet = AclPacket::read(self.connector);
-                        acl_packet.data.append(next_acl_packet.data.as_slice());
-                    }
-
-                    return Some(PollResult::AsyncData(acl_packet));
+                    let acl_packet = AclPacket::read(self.connector);
+                    return self
+                        .l2cap_reassembly
+                        .process(acl_packet)
+                        .map(PollResult::AsyncData);
                 }
                 PACKET_TYPE_EVENT => {
                     let event = EventType::read(self.connector);
+                    self.process_event(&event);
                     return Some(PollResult::Event(event));
                 }
                 _ => {
@@ -441,167 +821,1466 @@ impl<'a> Ble<'a> {
         None
     }
 
-    fn write_bytes(&mut self, bytes: &[u8]) {
-        for b in bytes {
-            self.connector.write(*b);
-        }
-    }
-}
-
-impl Data {
-    fn read(connector: &dyn HciConnection, len: usize) -> Self {
-        let mut data = [0u8; 256];
-        for i in 0..len {
-            loop {
-                match connector.read() {
-                    Some(byte) => {
-                        data[i] = byte;
-                        break;
-                    }
-                    None => {
-                        // TODO timeout?
-                    }
-                };
-            }
-        }
-        let mut data = Self::new(&data);
-        data.len = len;
-        data
+    /// Polls once, only `asynch::Ble` waits for a packet until `timeout_at`
+    pub(crate) fn poll_until(&mut self, _timeout_at: u64) -> Option<PollResult> {
+        self.poll()
     }
-}
-
-pub trait HciConnection {
-    fn read(&self) -> Option<u8>;
-
-    fn write(&self, data: u8);
-
-    fn millis(&self) -> u64;
-}
-
-pub struct HciConnector<T>
-where
-    T: Read + Write,
-{
-    hci: RefCell<T>,
-    get_millis: fn() -> u64,
-}
 
-impl<T> HciConnector<T>
-where
-    T: Read + Write,
-{
-    pub fn new(hci: T, get_millis: fn() -> u64) -> HciConnector<T> {
-        HciConnector {
-            hci: RefCell::new(hci),
-            get_millis,
-        }
+    pub(crate) fn poll_hci_until(&mut self, _timeout_at: u64) -> Option<PollResult> {
+        self.poll_hci()
     }
-}
 
-impl<T> HciConnection for HciConnector<T>
-where
-    T: Read + Write,
-{
-    fn read(&self) -> Option<u8> {
-        let mut buf = [0u8];
-        let res = self.hci.borrow_mut().read(&mut buf);
-        match res {
-            Ok(len) if len == 1 => Some(buf[0]),
-            _ => None,
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.connector.write(*b);
         }
     }
-
-    fn write(&self, data: u8) {
-        self.hci.borrow_mut().write(&[data]).ok();
-    }
-
-    fn millis(&self) -> u64 {
-        (self.get_millis)()
-    }
 }
 
-#[cfg(feature = "async")]
-pub mod asynch {
-    use super::*;
-
-    pub struct Ble<T>
-    where
-        T: embedded_io_async::Read + embedded_io_async::Write,
-    {
-        hci: RefCell<T>,
-        get_millis: fn() -> u64,
-    }
-
-    impl<T> Ble<T>
-    where
-        T: embedded_io_async::Read + embedded_io_async::Write,
+// Using the bleps-dedup proc-macro to de-duplicate the async/sync code
+// The macro will remove async/await for the SYNC implementation
+bleps_dedup::dedup! {
+    impl<'a> SYNC Ble<'a>
+    impl<T, C> ASYNC asynch::Ble<T, C>
+        where
+            T: embedded_io_async::Read + embedded_io_async::Write,
+            C: Clock + embedded_hal_async::delay::DelayNs,
     {
-        pub fn new(hci: T, get_millis: fn() -> u64) -> Ble<T> {
-            Ble {
-                hci: RefCell::new(hci),
-                get_millis,
-            }
+        pub async fn cmd_set_le_scan_parameters(
+            &mut self,
+            params: &ScanParameters,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeSetScanParameters(params)).await?;
+            self.wait_for_command_complete(LE_OGF, SET_SCAN_PARAMETERS_OCF)
+                .await?
+                .check_command_completed()
         }
 
-        fn millis(&self) -> u64 {
-            (self.get_millis)()
+        /// Start or stop scanning
+        ///
+        /// While scanning is enabled, [EventType::AdvertisingReport] events are
+        /// delivered via `poll`.
+        pub async fn cmd_set_le_scan_enable(
+            &mut self,
+            enable: bool,
+            filter_duplicates: bool,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeSetScanEnable {
+                enable,
+                filter_duplicates,
+            })
+            .await?;
+            self.wait_for_command_complete(LE_OGF, SET_SCAN_ENABLE_OCF)
+                .await?
+                .check_command_completed()
         }
 
-        pub async fn init(&mut self) -> Result<EventType, Error>
-        where
-            Self: Sized,
-        {
-            let res = self.cmd_reset().await?;
-            self.cmd_set_event_mask([0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])
-                .await?;
-            Ok(res)
+        /// Start initiating a connection
+        ///
+        /// Returns once the controller acknowledged the command with a [EventType::CommandStatus],
+        /// the outcome is reported by a [EventType::ConnectionComplete] event.
+        pub async fn cmd_le_create_connection(
+            &mut self,
+            params: &CreateConnectionParameters,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeCreateConnection(params)).await?;
+            self.wait_for_command_complete(LE_OGF, CREATE_CONNECTION_OCF)
+                .await?
+                .check_command_completed()
         }
 
-        pub async fn cmd_reset(&mut self) -> Result<EventType, Error>
-        where
-            Self: Sized,
-        {
-            self.write_bytes(Command::Reset.encode().as_slice()).await;
-            self.wait_for_command_complete(CONTROLLER_OGF, RESET_OCF)
+        pub async fn cmd_le_create_connection_cancel(&mut self) -> Result<EventType, Error> {
+            self.send_command(Command::LeCreateConnectionCancel).await?;
+            self.wait_for_command_complete(LE_OGF, CREATE_CONNECTION_CANCEL_OCF)
                 .await?
                 .check_command_completed()
         }
 
-        pub async fn cmd_set_event_mask(&mut self, events: [u8; 8]) -> Result<EventType, Error>
-        where
-            Self: Sized,
-        {
-            self.write_bytes(Command::SetEventMask { events }.encode().as_slice())
-                .await;
-            self.wait_for_command_complete(CONTROLLER_OGF, SET_EVENT_MASK_OCF)
+        /// Terminate a connection
+        ///
+        /// Returns once the controller acknowledged the command with a [EventType::CommandStatus],
+        /// the outcome is reported by a [EventType::DisconnectComplete] event.
+        pub async fn cmd_disconnect(&mut self, handle: u16, reason: u8) -> Result<EventType, Error> {
+            self.send_command(Command::Disconnect {
+                connection_handle: handle,
+                reason,
+            })
+            .await?;
+            self.wait_for_command_complete(LINK_CONTROL_OGF, DISCONNECT_OCF)
                 .await?
                 .check_command_completed()
         }
 
-        pub async fn cmd_set_le_advertising_parameters(&mut self) -> Result<EventType, Error>
-        where
-            Self: Sized,
-        {
-            self.write_bytes(Command::LeSetAdvertisingParameters.encode().as_slice())
-                .await;
-            self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF)
+        /// Change the parameters of a connection, as central or as peripheral if both sides
+        /// support the Connection Parameters Request procedure
+        ///
+        /// Returns once the controller acknowledged the command with a [EventType::CommandStatus],
+        /// the new parameters are reported by a [EventType::ConnectionUpdateComplete] event.
+        pub async fn cmd_le_connection_update(
+            &mut self,
+            handle: u16,
+            params: &ConnectionUpdateParameters,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeConnectionUpdate { handle, params }).await?;
+            self.wait_for_command_complete(LE_OGF, CONNECTION_UPDATE_OCF)
                 .await?
                 .check_command_completed()
         }
 
-        pub async fn cmd_set_le_advertising_parameters_custom(
+        /// Set the maximum payload and air time of PDUs the controller sends on the connection
+        ///
+        /// A [EventType::DataLengthChange] event reports the new lengths if they changed.
+        pub async fn cmd_le_set_data_length(
+            &mut self,
+            handle: u16,
+            length: DataLength,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeSetDataLength { handle, length }).await?;
+            self.wait_for_command_complete(LE_OGF, SET_DATA_LENGTH_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// The data length the controller uses for new connections
+        pub async fn cmd_le_read_suggested_default_data_length(
+            &mut self,
+        ) -> Result<DataLength, Error> {
+            self.send_command(Command::LeReadSuggestedDefaultDataLength).await?;
+            match self
+                .wait_for_command_complete(LE_OGF, READ_SUGGESTED_DEFAULT_DATA_LENGTH_OCF)
+                .await?
+                .check_command_completed()?
+            {
+                EventType::CommandComplete { data, .. } if data.len() >= 5 => {
+                    let data = data.as_slice();
+                    Ok(DataLength {
+                        tx_octets: u16::from_le_bytes([data[1], data[2]]),
+                        tx_time: u16::from_le_bytes([data[3], data[4]]),
+                    })
+                }
+                _ => Err(Error::Failed(0)),
+            }
+        }
+
+        /// Set the data length the controller uses for new connections, e.g. [DataLength::MAX]
+        pub async fn cmd_le_write_suggested_default_data_length(
+            &mut self,
+            length: DataLength,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeWriteSuggestedDefaultDataLength(length)).await?;
+            self.wait_for_command_complete(LE_OGF, WRITE_SUGGESTED_DEFAULT_DATA_LENGTH_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// The PHYs used to transmit and receive on the connection
+        ///
+        /// PHYs are reported as 1: LE 1M, 2: LE 2M, 3: LE Coded.
+        pub async fn cmd_le_read_phy(&mut self, handle: u16) -> Result<(u8, u8), Error> {
+            self.send_command(Command::LeReadPhy { handle }).await?;
+            match self
+                .wait_for_command_complete(LE_OGF, READ_PHY_OCF)
+                .await?
+                .check_command_completed()?
+            {
+                EventType::CommandComplete { data, .. } if data.len() >= 5 => {
+                    Ok((data.as_slice()[3], data.as_slice()[4]))
+                }
+                _ => Err(Error::Failed(0)),
+            }
+        }
+
+        /// Request the PHYs for the connection
+        ///
+        /// `tx_phys` and `rx_phys` combine [connection::PHY_LE_1M], [connection::PHY_LE_2M]
+        /// and [connection::PHY_LE_CODED], 0 leaves the choice to the controller. Returns once
+        /// the controller acknowledged the command with a [EventType::CommandStatus], the PHYs
+        /// in use are reported by a [EventType::PhyUpdateComplete] event.
+        pub async fn cmd_le_set_phy(
+            &mut self,
+            handle: u16,
+            tx_phys: u8,
+            rx_phys: u8,
+            coded_options: CodedPhyOptions,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeSetPhy {
+                handle,
+                tx_phys,
+                rx_phys,
+                coded_options,
+            })
+            .await?;
+            self.wait_for_command_complete(LE_OGF, SET_PHY_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Set the random address used with [OwnAddressType::Random], in little-endian byte
+        /// order
+        ///
+        /// The controller rejects a new address while legacy advertising, scanning or
+        /// connecting with the random address is enabled.
+        pub async fn cmd_le_set_random_address(
+            &mut self,
+            address: [u8; 6],
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeSetRandomAddress(address)).await?;
+            self.wait_for_command_complete(LE_OGF, SET_RANDOM_ADDRESS_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Switch to a new resolvable private address if the current one expired
+        ///
+        /// Returns the new address, it's applied with [Self::cmd_le_set_random_address] so
+        /// advertising with [OwnAddressType::Random] needs to be disabled meanwhile.
+        #[cfg(feature = "crypto")]
+        pub async fn rotate_private_address<R: rand_core::RngCore>(
+            &mut self,
+            rotation: &mut privacy::RpaRotation,
+            rng: &mut R,
+        ) -> Result<Option<Addr>, Error> {
+            let Some(address) = rotation.poll(self.millis(), rng) else {
+                return Ok(None);
+            };
+            if let Err(err) = self.cmd_le_set_random_address(address.to_le_bytes()).await {
+                // try again next time
+                rotation.expire();
+                return Err(err);
+            }
+            Ok(Some(address))
+        }
+
+        /// Number of devices the filter accept list of the controller can hold
+        pub async fn cmd_le_read_filter_accept_list_size(&mut self) -> Result<u8, Error> {
+            self.send_command(Command::LeReadFilterAcceptListSize).await?;
+            match self
+                .wait_for_command_complete(LE_OGF, READ_FILTER_ACCEPT_LIST_SIZE_OCF)
+                .await?
+                .check_command_completed()?
+            {
+                EventType::CommandComplete { data, .. } if data.len() >= 2 => {
+                    Ok(data.as_slice()[1])
+                }
+                _ => Err(Error::Failed(0)),
+            }
+        }
+
+        pub async fn cmd_le_clear_filter_accept_list(&mut self) -> Result<EventType, Error> {
+            self.send_command(Command::LeClearFilterAcceptList).await?;
+            self.wait_for_command_complete(LE_OGF, CLEAR_FILTER_ACCEPT_LIST_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Add a device to the filter accept list, used by the advertising, scanning and
+        /// initiator filter policies
+        pub async fn cmd_le_add_device_to_filter_accept_list(
+            &mut self,
+            address_type: PeerAddressType,
+            address: [u8; 6],
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeAddDeviceToFilterAcceptList {
+                address_type,
+                address,
+            })
+            .await?;
+            self.wait_for_command_complete(LE_OGF, ADD_DEVICE_TO_FILTER_ACCEPT_LIST_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        pub async fn cmd_le_remove_device_from_filter_accept_list(
+            &mut self,
+            address_type: PeerAddressType,
+            address: [u8; 6],
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeRemoveDeviceFromFilterAcceptList {
+                address_type,
+                address,
+            })
+            .await?;
+            self.wait_for_command_complete(LE_OGF, REMOVE_DEVICE_FROM_FILTER_ACCEPT_LIST_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Add a peer to the resolving list of the controller, the IRKs are the ones
+        /// exchanged during bonding
+        ///
+        /// The list can't be changed while address resolution is enabled and advertising,
+        /// scanning or connecting.
+        pub async fn cmd_le_add_device_to_resolving_list(
+            &mut self,
+            peer_address_type: PeerAddressType,
+            peer_address: [u8; 6],
+            peer_irk: u128,
+            local_irk: u128,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeAddDeviceToResolvingList {
+                peer_address_type,
+                peer_address,
+                peer_irk,
+                local_irk,
+            })
+            .await?;
+            self.wait_for_command_complete(LE_OGF, ADD_DEVICE_TO_RESOLVING_LIST_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        pub async fn cmd_le_remove_device_from_resolving_list(
+            &mut self,
+            peer_address_type: PeerAddressType,
+            peer_address: [u8; 6],
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeRemoveDeviceFromResolvingList {
+                peer_address_type,
+                peer_address,
+            })
+            .await?;
+            self.wait_for_command_complete(LE_OGF, REMOVE_DEVICE_FROM_RESOLVING_LIST_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        pub async fn cmd_le_clear_resolving_list(&mut self) -> Result<EventType, Error> {
+            self.send_command(Command::LeClearResolvingList).await?;
+            self.wait_for_command_complete(LE_OGF, CLEAR_RESOLVING_LIST_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Let the controller resolve peer addresses and generate local resolvable private
+        /// addresses with the IRKs in the resolving list
+        pub async fn cmd_le_set_address_resolution_enable(
+            &mut self,
+            enable: bool,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeSetAddressResolutionEnable(enable)).await?;
+            self.wait_for_command_complete(LE_OGF, SET_ADDRESS_RESOLUTION_ENABLE_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Set the interval in seconds in which the controller generates new resolvable
+        /// private addresses, 900 by default
+        pub async fn cmd_le_set_resolvable_private_address_timeout(
+            &mut self,
+            timeout_secs: u16,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeSetResolvablePrivateAddressTimeout(timeout_secs))
+                .await?;
+            self.wait_for_command_complete(LE_OGF, SET_RESOLVABLE_PRIVATE_ADDRESS_TIMEOUT_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Ask the central for new connection parameters via the LE signaling channel
+        ///
+        /// Returns the identifier of the request. The answer is returned by
+        /// [Self::handle_signaling] as [SignalingCommand::ConnectionParameterUpdateResponse],
+        /// the attribute server passes it on as [attribute_server::WorkResult::GotSignaling].
+        pub async fn request_connection_parameter_update(
+            &mut self,
+            handle: u16,
+            params: &ConnectionUpdateParameters,
+        ) -> Result<u8, Error> {
+            let identifier = self.next_signaling_identifier();
+            self.write_signaling(
+                handle,
+                SignalingCommand::ConnectionParameterUpdateRequest {
+                    identifier,
+                    parameters: *params,
+                },
+            )
+            .await?;
+            Ok(identifier)
+        }
+
+        /// Handle a command received on the LE signaling channel
+        ///
+        /// Requests are answered right away. A Connection Parameter Update Request with valid
+        /// parameters is accepted and applied via LE Connection Update, commands which are not
+        /// understood are rejected. Credit based channels are refused, they are only accepted
+        /// by [crate::l2cap_channel::L2capChannel]. Returns the received command.
+        pub async fn handle_signaling(
+            &mut self,
+            handle: u16,
+            payload: &[u8],
+        ) -> Result<Option<SignalingCommand>, Error> {
+            let command = match SignalingCommand::decode(payload) {
+                Ok(command) => command,
+                Err(
+                    SignalingDecodeError::InvalidLength { identifier }
+                    | SignalingDecodeError::UnknownCommand { identifier, .. },
+                ) if identifier != 0 => {
+                    log::warn!("Rejecting signaling command {:02x?}", payload);
+                    self.write_signaling(
+                        handle,
+                        SignalingCommand::CommandReject {
+                            identifier,
+                            reason: RejectReason::CommandNotUnderstood,
+                        },
+                    )
+                    .await?;
+                    return Ok(None);
+                }
+                Err(err) => {
+                    log::warn!("Dropping signaling command {:?}", err);
+                    return Ok(None);
+                }
+            };
+
+            match command {
+                SignalingCommand::ConnectionParameterUpdateRequest {
+                    identifier,
+                    parameters,
+                } => {
+                    let accepted = parameters.is_valid();
+                    self.write_signaling(
+                        handle,
+                        SignalingCommand::ConnectionParameterUpdateResponse {
+                            identifier,
+                            accepted,
+                        },
+                    )
+                    .await?;
+                    if accepted {
+                        self.cmd_le_connection_update(handle, &parameters).await?;
+                    }
+                }
+                SignalingCommand::DisconnectionRequest {
+                    identifier,
+                    destination_cid,
+                    source_cid,
+                } => {
+                    // there are no channels which could be disconnected
+                    self.write_signaling(
+                        handle,
+                        SignalingCommand::CommandReject {
+                            identifier,
+                            reason: RejectReason::InvalidCid {
+                                local_cid: destination_cid,
+                                remote_cid: source_cid,
+                            },
+                        },
+                    )
+                    .await?;
+                }
+                SignalingCommand::LeCreditBasedConnectionRequest { identifier, .. } => {
+                    self.write_signaling(
+                        handle,
+                        SignalingCommand::LeCreditBasedConnectionResponse {
+                            identifier,
+                            destination_cid: 0,
+                            mtu: 0,
+                            mps: 0,
+                            initial_credits: 0,
+                            result: CreditBasedConnectionResult::SpsmNotSupported,
+                        },
+                    )
+                    .await?;
+                }
+                SignalingCommand::CommandReject { identifier, reason } => {
+                    log::warn!("Signaling request {} rejected: {:?}", identifier, reason);
+                }
+                _ => (),
+            }
+
+            Ok(Some(command))
+        }
+
+        /// Identifier for a new signaling request, 0 is never used
+        fn next_signaling_identifier(&mut self) -> u8 {
+            self.signaling_identifier = self.signaling_identifier.wrapping_add(1).max(1);
+            self.signaling_identifier
+        }
+
+        async fn write_signaling(
+            &mut self,
+            handle: u16,
+            command: SignalingCommand,
+        ) -> Result<(), Error> {
+            let frame = L2capPacket::encode_channel(LE_SIGNALING_CHANNEL, command.encode());
+            self.write_l2cap(handle, frame).await
+        }
+
+        /// Start encrypting a connection as central, using a previously distributed LTK
+        ///
+        /// Returns once the controller acknowledged the command with a [EventType::CommandStatus],
+        /// the outcome is reported by a [EventType::EncryptionChange] event.
+        pub async fn cmd_le_start_encryption(
+            &mut self,
+            handle: u16,
+            random: u64,
+            diversifier: u16,
+            ltk: u128,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeStartEncryption {
+                handle,
+                random,
+                diversifier,
+                ltk,
+            })
+            .await?;
+            self.wait_for_command_complete(LE_OGF, START_ENCRYPTION_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Refuse to encrypt a connection as peripheral, because there's no LTK for the peer
+        pub async fn cmd_long_term_key_request_negative_reply(
+            &mut self,
+            handle: u16,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeLongTermKeyRequestNegativeReply { handle })
+                .await?;
+            self.wait_for_command_complete(LE_OGF, LONG_TERM_KEY_REQUEST_NEGATIVE_REPLY_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Timeouts for the controller to answer commands
+        pub fn command_timeouts_mut(&mut self) -> &mut CommandTimeouts {
+            &mut self.command_timeouts
+        }
+
+        /// Sends a command, or queues it until the controller is able to accept more commands
+        ///
+        /// If the queue is full this waits for the controller to grant more credits.
+        pub(crate) async fn send_command(&mut self, command: Command<'_>) -> Result<(), Error> {
+            let packet = command.encode();
+            let header = CommandHeader::from_bytes(&packet.as_slice()[1..]);
+            self.advertising_config
+                .sent(header.ogf(), header.ocf(), &packet.as_slice()[4..]);
+
+            if self.command_queue.is_full() {
+                let code = header.opcode;
+                let timeout_at = self.millis() + self.command_timeouts.get(code);
+                while self.command_queue.is_full() {
+                    // polling flushes the queue as soon as there are credits
+                    if let Some(res) = self.poll_hci_until(timeout_at).await {
+                        self.hold_back(res);
+                    }
+
+                    if self.millis() > timeout_at {
+                        return Err(self.command_timed_out(code));
+                    }
+                }
+            }
+
+            self.command_queue.push(packet);
+            self.flush_command_queue().await;
+            Ok(())
+        }
+
+        /// Keeps a packet polled while waiting for the controller for the next poll, only the
+        /// flow control events are consumed right away
+        fn hold_back(&mut self, res: PollResult) {
+            match res {
+                PollResult::Event(
+                    EventType::NumberOfCompletedPackets { .. }
+                    | EventType::CommandComplete { .. }
+                    | EventType::CommandStatus { .. },
+                ) => log::debug!("polled while waiting for the controller {:?}", res),
+                res if self.pending.is_full() => {
+                    log::warn!("Too many packets pending, dropping {:?}", res)
+                }
+                res => self.pending.push(res),
+            }
+        }
+
+        /// Sends queued commands for as long as the controller grants credits
+        async fn flush_command_queue(&mut self) {
+            while self.command_credits > 0 {
+                let Some(packet) = self.command_queue.pop() else {
+                    break;
+                };
+                self.command_credits -= 1;
+                self.write_bytes(packet.as_slice()).await;
+            }
+        }
+
+        /// Sends an encoded ACL packet, or queues it until the controller frees a buffer
+        ///
+        /// If the queue is full this waits for the controller to complete packets.
+        pub async fn write_acl(&mut self, packet: Data) -> Result<(), Error> {
+            if self.acl_flow_control.queue.is_full() {
+                let timeout_at = self.millis() + ACL_TIMEOUT_MILLIS;
+                while self.acl_flow_control.queue.is_full() {
+                    // polling flushes the queue as soon as buffers are freed
+                    if let Some(res) = self.poll_hci_until(timeout_at).await {
+                        self.hold_back(res);
+                    }
+
+                    if self.millis() > timeout_at {
+                        return Err(Error::Timeout);
+                    }
+                }
+            }
+
+            self.acl_flow_control.queue.push(packet);
+            self.flush_acl_queue().await;
+            Ok(())
+        }
+
+        /// Sends an L2CAP frame, fragmented to the ACL buffer size of the controller
+        ///
+        /// Fragments are written straight from the frame while the controller has free
+        /// buffers, only fragments which have to wait are copied into the ACL queue.
+        pub async fn write_l2cap(&mut self, handle: u16, frame: Data) -> Result<(), Error> {
+            let fragment_len = self.acl_flow_control.fragment_len();
+            if frame.len() <= fragment_len {
+                // the ACL header goes into the headroom of the frame
+                let packet = AclPacket::encode(
+                    handle,
+                    BoundaryFlag::FirstAutoFlushable,
+                    HostBroadcastFlag::NoBroadcast,
+                    frame,
+                );
+                return self.write_acl(packet).await;
+            }
+
+            let mut boundary_flag = BoundaryFlag::FirstAutoFlushable;
+            for fragment in frame.as_slice().chunks(fragment_len) {
+                if self.acl_flow_control.queue.is_empty() && self.acl_flow_control.can_send() {
+                    let header = AclPacket::header(
+                        handle,
+                        boundary_flag,
+                        HostBroadcastFlag::NoBroadcast,
+                        fragment.len(),
+                    );
+                    self.acl_flow_control.sent(handle);
+                    self.write_bytes(&header).await;
+                    self.write_bytes(fragment).await;
+                } else {
+                    let packet = AclPacket::encode(
+                        handle,
+                        boundary_flag,
+                        HostBroadcastFlag::NoBroadcast,
+                        Data::new(fragment),
+                    );
+                    self.write_acl(packet).await?;
+                }
+                boundary_flag = BoundaryFlag::Continuing;
+            }
+            Ok(())
+        }
+
+        /// Sends queued ACL packets for as long as the controller has free buffers
+        async fn flush_acl_queue(&mut self) {
+            while self.acl_flow_control.can_send() {
+                let Some(packet) = self.acl_flow_control.queue.pop() else {
+                    break;
+                };
+                self.acl_flow_control.sent(acl::packet_handle(&packet));
+                self.write_bytes(packet.as_slice()).await;
+            }
+        }
+
+        /// Update command credits, ACL buffers and reassembly from a received event
+        async fn process_event(&mut self, event: &EventType) {
+            if let Some(credits) = event.command_credits() {
+                self.command_credits = credits;
+                self.flush_command_queue().await;
+            }
+
+            match event {
+                EventType::NumberOfCompletedPackets { completed } => {
+                    for (handle, count) in completed.iter() {
+                        self.acl_flow_control.completed(handle, count);
+                    }
+                    self.flush_acl_queue().await;
+                }
+                EventType::DisconnectComplete { handle, .. } => {
+                    self.acl_flow_control.disconnected(*handle);
+                    self.l2cap_reassembly.disconnected(*handle);
+                    self.flush_acl_queue().await;
+                }
+                EventType::CommandComplete { opcode, data, .. } => {
+                    let status = data.as_slice().first().copied().unwrap_or(0);
+                    self.advertising_config
+                        .completed((opcode >> 10) as u8, opcode & 0x3ff, status);
+                }
+                EventType::AdvertisingSetTerminated {
+                    advertising_handle, ..
+                } => {
+                    self.advertising_config.terminated(*advertising_handle);
+                }
+                EventType::HardwareError { code } => {
+                    log::warn!("Hardware error {}", code);
+                    self.fault = Some(ControllerFault::HardwareError(*code));
+                }
+                EventType::DataBufferOverflow { link_type } => {
+                    log::warn!("Data buffer overflow on link type {}", link_type);
+                    self.fault = Some(ControllerFault::DataBufferOverflow);
+                }
+                _ => (),
+            }
+        }
+
+        fn command_timed_out(&mut self, opcode: u16) -> Error {
+            self.command_timeouts_in_a_row = self.command_timeouts_in_a_row.saturating_add(1);
+            if self.command_timeouts_in_a_row >= MAX_COMMAND_TIMEOUTS_IN_A_ROW {
+                self.fault.get_or_insert(ControllerFault::CommandTimeouts);
+            }
+            Error::CommandTimeout { opcode }
+        }
+
+        /// The fault the controller needs to be reset for, if any, see [Self::recover]
+        pub fn fault(&self) -> Option<ControllerFault> {
+            self.fault
+        }
+
+        /// Reset the controller after a fault and restore the event masks and the advertising
+        /// set up before
+        ///
+        /// Connections and queued packets are lost.
+        pub async fn recover(&mut self) -> Result<(), Error> {
+            self.reset_after_fault().await?;
+            self.restore_advertising().await
+        }
+
+        /// Forget all state of the controller and initialize it again
+        pub(crate) async fn reset_after_fault(&mut self) -> Result<(), Error> {
+            log::warn!("Resetting the controller after {:?}", self.fault);
+            self.command_credits = 1;
+            self.command_queue = PacketQueue::new();
+            self.command_timeouts_in_a_row = 0;
+            self.acl_flow_control = AclFlowControl::new();
+            self.l2cap_reassembly = L2capReassembly::new();
+            self.pending = PendingQueue::new();
+
+            let (events, le_events) = self.event_masks;
+            self.init_with_event_masks(events, le_events).await?;
+            self.fault = None;
+            Ok(())
+        }
+
+        /// Repeat the advertising commands the controller accepted before the reset
+        pub(crate) async fn restore_advertising(&mut self) -> Result<(), Error> {
+            let legacy = self.advertising_config.legacy;
+            for (ocf, params) in legacy.commands() {
+                self.cmd_raw(LE_OGF, ocf, params).await?;
+            }
+
+            let sets = self.advertising_config.sets;
+            for set in sets.iter().flatten() {
+                self.restore_advertising_set(set).await?;
+            }
+            Ok(())
+        }
+
+        async fn restore_advertising_set(
+            &mut self,
+            set: &AdvertisingSetConfig,
+        ) -> Result<(), Error> {
+            self.cmd_raw(LE_OGF, SET_EXTENDED_ADVERTISING_PARAMETERS_OCF, &set.parameters)
+                .await?;
+            if let Some(address) = &set.random_address {
+                self.cmd_raw(LE_OGF, SET_ADVERTISING_SET_RANDOM_ADDRESS_OCF, address)
+                    .await?;
+            }
+            if let Some(data) = &set.data {
+                self.set_le_extended_data(false, set.handle, data.as_slice())
+                    .await?;
+            }
+            if let Some(scan_response) = &set.scan_response {
+                self.set_le_extended_data(true, set.handle, scan_response.as_slice())
+                    .await?;
+            }
+            if let Some([duration_lo, duration_hi, max_events]) = set.enabled {
+                let params = [1, 1, set.handle, duration_lo, duration_hi, max_events];
+                self.cmd_raw(LE_OGF, SET_EXTENDED_ADVERTISING_ENABLE_OCF, &params)
+                    .await?;
+            }
+            Ok(())
+        }
+
+        /// Disables the enabled advertising for a change the controller refuses while
+        /// advertising, e.g. of the filter accept list
+        pub(crate) async fn pause_advertising(&mut self) -> Result<PausedAdvertising, Error> {
+            let paused = self.advertising_config.enabled();
+            if paused.legacy {
+                self.cmd_set_le_advertise_enable(false).await?;
+            }
+            if paused.sets.iter().any(Option::is_some) {
+                // no sets given disables all of them
+                self.cmd_raw(LE_OGF, SET_EXTENDED_ADVERTISING_ENABLE_OCF, &[0, 0])
+                    .await?;
+            }
+            Ok(paused)
+        }
+
+        /// Enables the advertising disabled by [Self::pause_advertising] again
+        pub(crate) async fn resume_advertising(&mut self, paused: PausedAdvertising) -> Result<(), Error> {
+            if paused.legacy {
+                self.cmd_set_le_advertise_enable(true).await?;
+            }
+            for (handle, [duration_lo, duration_hi, max_events]) in paused.sets.into_iter().flatten() {
+                let params = [1, 1, handle, duration_lo, duration_hi, max_events];
+                self.cmd_raw(LE_OGF, SET_EXTENDED_ADVERTISING_ENABLE_OCF, &params)
+                    .await?;
+            }
+            Ok(())
+        }
+
+        /// Returns the maximum length of ACL data and the number of ACL packets the
+        /// controller can buffer for LE, a count of 0 means the buffers are shared with BR/EDR
+        pub async fn cmd_le_read_buffer_size(&mut self) -> Result<(u16, u8), Error> {
+            self.send_command(Command::LeReadBufferSize).await?;
+            let res = self
+                .wait_for_command_complete(LE_OGF, LE_READ_BUFFER_SIZE_OCF)
+                .await?
+                .check_command_completed()?;
+            match res {
+                EventType::CommandComplete { data, .. } if data.len >= 4 => {
+                    let data = data.as_slice();
+                    Ok((u16::from_le_bytes([data[1], data[2]]), data[3]))
+                }
+                _ => Err(Error::Failed(0)),
+            }
+        }
+
+        /// Returns the maximum length of ACL data and the number of ACL packets the
+        /// controller can buffer, shared between BR/EDR and LE
+        pub async fn cmd_read_buffer_size(&mut self) -> Result<(u16, u16), Error> {
+            self.send_command(Command::ReadBufferSize).await?;
+            let res = self
+                .wait_for_command_complete(INFORMATIONAL_OGF, READ_BUFFER_SIZE_OCF)
+                .await?
+                .check_command_completed()?;
+            match res {
+                EventType::CommandComplete { data, .. } if data.len >= 8 => {
+                    let data = data.as_slice();
+                    Ok((
+                        u16::from_le_bytes([data[1], data[2]]),
+                        u16::from_le_bytes([data[4], data[5]]),
+                    ))
+                }
+                _ => Err(Error::Failed(0)),
+            }
+        }
+
+        /// Read the ACL buffers of the controller to limit the number of outstanding packets
+        async fn read_acl_buffer_size(&mut self) -> Result<(), Error> {
+            let (len, count) = match self.cmd_le_read_buffer_size().await? {
+                (len, count) if count > 0 => (len, count as u16),
+                _ => self.cmd_read_buffer_size().await?,
+            };
+            log::debug!("ACL buffers: {} packets of {} bytes", count, len);
+            self.acl_flow_control.set_buffer_size(len, count);
+            Ok(())
+        }
+
+        /// Versions, LE features and advertising transmit power, read during `init`
+        pub fn controller_info(&self) -> &ControllerInfo {
+            &self.controller_info
+        }
+
+        async fn read_controller_info(&mut self) -> Result<(), Error> {
+            self.controller_info = ControllerInfo {
+                version: self.cmd_read_local_version_information().await?,
+                le_features: self.cmd_le_read_local_supported_features().await?,
+            };
+            log::debug!("{:?}", self.controller_info);
+            Ok(())
+        }
+
+        pub async fn cmd_read_local_version_information(&mut self) -> Result<LocalVersion, Error> {
+            self.send_command(Command::ReadLocalVersionInformation).await?;
+            match self
+                .wait_for_command_complete(INFORMATIONAL_OGF, READ_LOCAL_VERSION_INFORMATION_OCF)
+                .await?
+                .check_command_completed()?
+            {
+                EventType::CommandComplete { data, .. } if data.len() >= 9 => {
+                    Ok(LocalVersion::from_return_parameters(data.as_slice()))
+                }
+                _ => Err(Error::Failed(0)),
+            }
+        }
+
+        pub async fn cmd_le_read_local_supported_features(&mut self) -> Result<LeFeatures, Error> {
+            self.send_command(Command::LeReadLocalSupportedFeatures).await?;
+            match self
+                .wait_for_command_complete(LE_OGF, READ_LOCAL_SUPPORTED_FEATURES_OCF)
+                .await?
+                .check_command_completed()?
+            {
+                EventType::CommandComplete { data, .. } if data.len() >= 9 => Ok(LeFeatures(
+                    u64::from_le_bytes(data.as_slice()[1..9].try_into().unwrap()),
+                )),
+                _ => Err(Error::Failed(0)),
+            }
+        }
+
+        /// Transmit power of legacy advertising in dBm
+        ///
+        /// This is a legacy advertising command, afterwards the controller rejects extended
+        /// advertising commands until it is reset.
+        pub async fn cmd_le_read_advertising_physical_channel_tx_power(
+            &mut self,
+        ) -> Result<i8, Error> {
+            self.send_command(Command::LeReadAdvertisingPhysicalChannelTxPower).await?;
+            match self
+                .wait_for_command_complete(LE_OGF, READ_ADVERTISING_PHYSICAL_CHANNEL_TX_POWER_OCF)
+                .await?
+                .check_command_completed()?
+            {
+                EventType::CommandComplete { data, .. } if data.len() >= 2 => {
+                    Ok(data.as_slice()[1] as i8)
+                }
+                _ => Err(Error::Failed(0)),
+            }
+        }
+
+        /// Received signal strength of the connection in dBm, 127 if not available
+        pub async fn cmd_read_rssi(&mut self, handle: u16) -> Result<i8, Error> {
+            self.send_command(Command::ReadRssi { handle }).await?;
+            match self
+                .wait_for_command_complete(STATUS_PARAMETERS_OGF, READ_RSSI_OCF)
+                .await?
+                .check_command_completed()?
+            {
+                EventType::CommandComplete { data, .. } if data.len() >= 4 => {
+                    Ok(data.as_slice()[3] as i8)
+                }
+                _ => Err(Error::Failed(0)),
+            }
+        }
+
+        pub async fn cmd_le_read_channel_map(&mut self, handle: u16) -> Result<ChannelMap, Error> {
+            self.send_command(Command::LeReadChannelMap { handle }).await?;
+            match self
+                .wait_for_command_complete(LE_OGF, READ_CHANNEL_MAP_OCF)
+                .await?
+                .check_command_completed()?
+            {
+                EventType::CommandComplete { data, .. } if data.len() >= 8 => Ok(ChannelMap(
+                    data.as_slice()[3..8].try_into().unwrap(),
+                )),
+                _ => Err(Error::Failed(0)),
+            }
+        }
+
+        /// Request the LE features supported by the peer
+        ///
+        /// Returns once the controller acknowledged the command with a
+        /// [EventType::CommandStatus], the features are reported by a
+        /// [EventType::ReadRemoteFeaturesComplete] event.
+        pub async fn cmd_le_read_remote_features(
+            &mut self,
+            handle: u16,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeReadRemoteFeatures { handle }).await?;
+            self.wait_for_command_complete(LE_OGF, READ_REMOTE_FEATURES_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Send any command and wait for its Command Complete or Command Status event
+        ///
+        /// Fails with the Invalid HCI Command Parameters status (0x12) without sending
+        /// anything if there are more than [command::MAX_COMMAND_PARAMETERS_LEN] bytes of
+        /// parameters, or with [Error::Failed] if the OGF or OCF don't fit into an opcode.
+        pub async fn cmd_raw(
+            &mut self,
+            ogf: u8,
+            ocf: u16,
+            params: &[u8],
+        ) -> Result<EventType, Error> {
+            if ogf > 0x3f || ocf > 0x3ff {
+                return Err(Error::Failed(0x12));
+            }
+            if params.len() > MAX_COMMAND_PARAMETERS_LEN {
+                return Err(Error::CommandFailed {
+                    opcode: opcode(ogf, ocf),
+                    status: 0x12,
+                });
+            }
+
+            self.send_command(Command::Raw { ogf, ocf, params }).await?;
+            self.wait_for_command_complete(ogf, ocf)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Send a command `bleps` doesn't know about and decode its return parameters
+        pub async fn cmd_custom<Cmd: CustomCommand>(
+            &mut self,
+            command: &Cmd,
+        ) -> Result<Cmd::ReturnParameters, Error> {
+            let mut params = Data::new(&[]);
+            command.write_parameters(&mut params);
+            let event = self.cmd_raw(Cmd::OGF, Cmd::OCF, params.as_slice()).await?;
+            let return_parameters = match event {
+                EventType::CommandComplete { data, .. } if data.len() >= 1 => {
+                    Cmd::ReturnParameters::decode(&data.as_slice()[1..])
+                }
+                EventType::CommandStatus { .. } => Cmd::ReturnParameters::decode(&[]),
+                _ => None,
+            };
+            return_parameters.ok_or(Error::Failed(0))
+        }
+
+        /// AES-128 encryption of a single block by the controller
+        ///
+        /// Key, plaintext and result are numbers like in the security functions, the
+        /// controller computes the same as the software `crypto::aes128`. The security
+        /// manager doesn't use it, its security functions are computed in software.
+        pub async fn cmd_le_encrypt(&mut self, key: u128, plaintext: u128) -> Result<u128, Error> {
+            self.send_command(Command::LeEncrypt { key, plaintext }).await?;
+            match self
+                .wait_for_command_complete(LE_OGF, ENCRYPT_OCF)
+                .await?
+                .check_command_completed()?
+            {
+                EventType::CommandComplete { data, .. } if data.len() >= 17 => Ok(
+                    u128::from_le_bytes(data.as_slice()[1..17].try_into().unwrap()),
+                ),
+                _ => Err(Error::Failed(0)),
+            }
+        }
+
+        /// 64 random bits generated by the controller
+        pub async fn cmd_le_rand(&mut self) -> Result<u64, Error> {
+            self.send_command(Command::LeRand).await?;
+            match self
+                .wait_for_command_complete(LE_OGF, RAND_OCF)
+                .await?
+                .check_command_completed()?
+            {
+                EventType::CommandComplete { data, .. } if data.len() >= 9 => Ok(
+                    u64::from_le_bytes(data.as_slice()[1..9].try_into().unwrap()),
+                ),
+                _ => Err(Error::Failed(0)),
+            }
+        }
+
+        /// Seed a random number generator for the security manager with random bits of the
+        /// controller, for targets without an entropy source of their own
+        #[cfg(feature = "crypto")]
+        pub async fn controller_rng(&mut self) -> Result<rng::ControllerRng, Error> {
+            let key = ((self.cmd_le_rand().await? as u128) << 64)
+                | self.cmd_le_rand().await? as u128;
+            let nonce = self.cmd_le_rand().await?;
+            Ok(rng::ControllerRng::from_seed(key, nonce))
+        }
+
+        /// RSSI and channel map of the connection
+        pub async fn link_quality(&mut self, handle: u16) -> Result<LinkQuality, Error> {
+            Ok(LinkQuality {
+                rssi: self.cmd_read_rssi(handle).await?,
+                channel_map: self.cmd_le_read_channel_map(handle).await?,
+            })
+        }
+
+        pub async fn cmd_set_le_extended_advertising_parameters(
+            &mut self,
+            params: &ExtendedAdvertisingParameters,
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeSetExtendedAdvertisingParameters(params)).await?;
+            self.wait_for_command_complete(LE_OGF, SET_EXTENDED_ADVERTISING_PARAMETERS_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Set the advertising data of an advertising set
+        ///
+        /// Data which doesn't fit into a single command is sent in fragments, which requires
+        /// the set to be disabled.
+        pub async fn cmd_set_le_extended_advertising_data(
+            &mut self,
+            handle: u8,
+            data: Data,
+        ) -> Result<EventType, Error> {
+            self.set_le_extended_data(false, handle, data.as_slice())
+                .await
+        }
+
+        /// Set the scan response data of a scannable advertising set
+        pub async fn cmd_set_le_extended_scan_rsp_data(
+            &mut self,
+            handle: u8,
+            data: Data,
+        ) -> Result<EventType, Error> {
+            self.set_le_extended_data(true, handle, data.as_slice())
+                .await
+        }
+
+        async fn set_le_extended_data(
+            &mut self,
+            scan_response: bool,
+            handle: u8,
+            data: &[u8],
+        ) -> Result<EventType, Error> {
+            let mut offset = 0;
+            loop {
+                let len = (data.len() - offset).min(MAX_ADVERTISING_DATA_FRAGMENT_LEN);
+                let last = offset + len == data.len();
+                let operation = match (offset == 0, last) {
+                    (true, true) => AdvertisingDataOperation::Complete,
+                    (true, false) => AdvertisingDataOperation::FirstFragment,
+                    (false, true) => AdvertisingDataOperation::LastFragment,
+                    (false, false) => AdvertisingDataOperation::IntermediateFragment,
+                };
+                let fragment = &data[offset..][..len];
+
+                let (command, ocf) = if scan_response {
+                    (
+                        Command::LeSetExtendedScanRspData {
+                            handle,
+                            operation,
+                            data: fragment,
+                        },
+                        SET_EXTENDED_SCAN_RSP_DATA_OCF,
+                    )
+                } else {
+                    (
+                        Command::LeSetExtendedAdvertisingData {
+                            handle,
+                            operation,
+                            data: fragment,
+                        },
+                        SET_EXTENDED_ADVERTISING_DATA_OCF,
+                    )
+                };
+                self.send_command(command).await?;
+                let res = self
+                    .wait_for_command_complete(LE_OGF, ocf)
+                    .await?
+                    .check_command_completed()?;
+
+                offset += len;
+                if last {
+                    return Ok(res);
+                }
+            }
+        }
+
+        /// Enable or disable the given advertising sets
+        ///
+        /// Disabling with no sets given disables all advertising sets.
+        pub async fn cmd_set_le_extended_advertising_enable(
+            &mut self,
+            enable: bool,
+            sets: &[AdvertisingSet],
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeSetExtendedAdvertisingEnable { enable, sets }).await?;
+            self.wait_for_command_complete(LE_OGF, SET_EXTENDED_ADVERTISING_ENABLE_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Set the random address used by an advertising set
+        ///
+        /// The address is given in little-endian byte order.
+        pub async fn cmd_set_le_advertising_set_random_address(
+            &mut self,
+            handle: u8,
+            address: [u8; 6],
+        ) -> Result<EventType, Error> {
+            self.send_command(Command::LeSetAdvertisingSetRandomAddress { handle, address }).await?;
+            self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_SET_RANDOM_ADDRESS_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        pub async fn cmd_le_remove_advertising_set(&mut self, handle: u8) -> Result<EventType, Error> {
+            self.send_command(Command::LeRemoveAdvertisingSet { handle }).await?;
+            self.wait_for_command_complete(LE_OGF, REMOVE_ADVERTISING_SET_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        pub async fn cmd_le_clear_advertising_sets(&mut self) -> Result<EventType, Error> {
+            self.send_command(Command::LeClearAdvertisingSets).await?;
+            self.wait_for_command_complete(LE_OGF, CLEAR_ADVERTISING_SETS_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        /// Connect to a peripheral as central
+        ///
+        /// Waits up to `timeout_millis` for the connection to be established, otherwise the
+        /// connection attempt is cancelled. A connection which got established while cancelling
+        /// is still returned, other packets polled meanwhile are kept for the next `poll`.
+        pub async fn connect(
+            &mut self,
+            params: &CreateConnectionParameters,
+            timeout_millis: u64,
+        ) -> Result<Connection, Error> {
+            self.cmd_le_create_connection(params).await?;
+
+            let timeout_at = self.millis() + timeout_millis;
+            while self.millis() <= timeout_at {
+                match self.poll_hci_until(timeout_at).await {
+                    Some(PollResult::Event(
+                        event @ (EventType::ConnectionComplete { status, .. }
+                        | EventType::EnhancedConnectionComplete { status, .. }),
+                    )) => {
+                        return Connection::from_event(&event).ok_or(Error::Failed(status));
+                    }
+                    Some(res) => self.hold_back(res),
+                    None => (),
+                }
+            }
+
+            // the controller reports the cancelled connection, or the one which got established
+            // in the meantime, with a Connection Complete event
+            let cancelled = self.cmd_le_create_connection_cancel().await;
+            let cancel_timeout_at = self.millis()
+                + self
+                    .command_timeouts
+                    .get(opcode(LE_OGF, CREATE_CONNECTION_CANCEL_OCF));
+            let mut completed = self.pending.take(
+                |res| matches!(res, PollResult::Event(event) if is_connection_complete(event)),
+            );
+            while completed.is_none() && self.millis() <= cancel_timeout_at {
+                match self.poll_hci_until(cancel_timeout_at).await {
+                    Some(PollResult::Event(event)) if is_connection_complete(&event) => {
+                        completed = Some(PollResult::Event(event))
+                    }
+                    Some(res) => self.hold_back(res),
+                    None => (),
+                }
+            }
+
+            match completed {
+                Some(PollResult::Event(event)) => {
+                    Connection::from_event(&event).ok_or(Error::Timeout)
+                }
+                _ => cancelled.and(Err(Error::Timeout)),
+            }
+        }
+    }
+}
+
+fn is_connection_complete(event: &EventType) -> bool {
+    matches!(
+        event,
+        EventType::ConnectionComplete { .. } | EventType::EnhancedConnectionComplete { .. }
+    )
+}
+
+impl Data {
+    fn read(connector: &dyn HciConnection, len: usize) -> Self {
+        let mut data = Self::default();
+        for byte in data.spare_capacity_mut()[..len].iter_mut() {
+            loop {
+                match connector.read() {
+                    Some(read) => {
+                        *byte = read;
+                        break;
+                    }
+                    None => {
+                        // TODO timeout?
+                    }
+                };
+            }
+        }
+        data.append_len(len);
+        data
+    }
+}
+
+pub trait HciConnection {
+    fn read(&self) -> Option<u8>;
+
+    fn write(&self, data: u8);
+
+    fn millis(&self) -> u64;
+}
+
+pub struct HciConnector<T, C>
+where
+    T: Read + Write,
+    C: Clock,
+{
+    hci: RefCell<T>,
+    clock: C,
+}
+
+impl<T, C> HciConnector<T, C>
+where
+    T: Read + Write,
+    C: Clock,
+{
+    pub fn new(hci: T, clock: C) -> HciConnector<T, C> {
+        HciConnector {
+            hci: RefCell::new(hci),
+            clock,
+        }
+    }
+}
+
+impl<T, C> HciConnection for HciConnector<T, C>
+where
+    T: Read + Write,
+    C: Clock,
+{
+    fn read(&self) -> Option<u8> {
+        let mut buf = [0u8];
+        let res = self.hci.borrow_mut().read(&mut buf);
+        match res {
+            Ok(len) if len == 1 => Some(buf[0]),
+            _ => None,
+        }
+    }
+
+    fn write(&self, data: u8) {
+        self.hci.borrow_mut().write(&[data]).ok();
+    }
+
+    fn millis(&self) -> u64 {
+        self.clock.now_millis()
+    }
+}
+
+#[cfg(feature = "async")]
+pub mod asynch {
+    use core::pin::pin;
+
+    use embedded_hal_async::delay::DelayNs;
+    use futures::future::{select, Either};
+
+    use super::*;
+
+    pub struct Ble<T, C>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+        C: Clock + DelayNs,
+    {
+        hci: RefCell<T>,
+        clock: C,
+        pub(crate) command_credits: u8,
+        pub(crate) command_queue: PacketQueue<COMMAND_QUEUE_SIZE>,
+        pub(crate) command_timeouts: CommandTimeouts,
+        pub(crate) acl_flow_control: AclFlowControl,
+        pub(crate) l2cap_reassembly: L2capReassembly,
+        pub(crate) pending: PendingQueue,
+        pub(crate) signaling_identifier: u8,
+        pub(crate) controller_info: ControllerInfo,
+        pub(crate) event_masks: (EventMask, LeEventMask),
+        pub(crate) advertising_config: AdvertisingConfig,
+        pub(crate) command_timeouts_in_a_row: u8,
+        pub(crate) fault: Option<ControllerFault>,
+    }
+
+    impl<T, C> Ble<T, C>
+    where
+        T: embedded_io_async::Read + embedded_io_async::Write,
+        C: Clock + DelayNs,
+    {
+        /// The clock is used to sleep while waiting for the controller, see
+        /// [crate::clock::ClockWithDelay]
+        pub fn new(hci: T, clock: C) -> Ble<T, C> {
+            Ble {
+                hci: RefCell::new(hci),
+                clock,
+                command_credits: 1,
+                command_queue: PacketQueue::new(),
+                command_timeouts: CommandTimeouts::new(),
+                acl_flow_control: AclFlowControl::new(),
+                l2cap_reassembly: L2capReassembly::new(),
+                pending: PendingQueue::new(),
+                signaling_identifier: 0,
+                controller_info: ControllerInfo::default(),
+                event_masks: (EventMask::default(), LeEventMask::default()),
+                advertising_config: AdvertisingConfig::new(),
+                command_timeouts_in_a_row: 0,
+                fault: None,
+            }
+        }
+
+        pub(crate) fn millis(&self) -> u64 {
+            self.clock.now_millis()
+        }
+
+        /// Reset the controller and enable all events the stack handles
+        pub async fn init(&mut self) -> Result<EventType, Error>
+        where
+            Self: Sized,
+        {
+            self.init_with_event_masks(EventMask::default(), LeEventMask::default())
+                .await
+        }
+
+        /// Reset the controller and only enable the given events, e.g.
+        /// `LeEventMask::connections().with_data_length()` if the application doesn't scan
+        pub async fn init_with_event_masks(
+            &mut self,
+            events: EventMask,
+            le_events: LeEventMask,
+        ) -> Result<EventType, Error>
+        where
+            Self: Sized,
+        {
+            self.event_masks = (events, le_events);
+            let res = self.cmd_reset().await?;
+            self.cmd_set_event_mask(events).await?;
+            self.cmd_le_set_event_mask(le_events).await?;
+            self.read_acl_buffer_size().await?;
+            self.read_controller_info().await?;
+            Ok(res)
+        }
+
+        pub async fn cmd_reset(&mut self) -> Result<EventType, Error>
+        where
+            Self: Sized,
+        {
+            self.send_command(Command::Reset).await?;
+            self.wait_for_command_complete(CONTROLLER_OGF, RESET_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        pub async fn cmd_set_event_mask(&mut self, events: EventMask) -> Result<EventType, Error>
+        where
+            Self: Sized,
+        {
+            self.send_command(Command::SetEventMask { events }).await?;
+            self.wait_for_command_complete(CONTROLLER_OGF, SET_EVENT_MASK_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        pub async fn cmd_le_set_event_mask(
+            &mut self,
+            events: LeEventMask,
+        ) -> Result<EventType, Error>
+        where
+            Self: Sized,
+        {
+            self.send_command(Command::LeSetEventMask { events })
+                .await?;
+            self.wait_for_command_complete(LE_OGF, LE_SET_EVENT_MASK_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        pub async fn cmd_set_le_advertising_parameters(&mut self) -> Result<EventType, Error>
+        where
+            Self: Sized,
+        {
+            self.send_command(Command::LeSetAdvertisingParameters)
+                .await?;
+            self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF)
+                .await?
+                .check_command_completed()
+        }
+
+        pub async fn cmd_set_le_advertising_parameters_custom(
             &mut self,
             params: &AdvertisingParameters,
         ) -> Result<EventType, Error>
         where
             Self: Sized,
         {
-            self.write_bytes(
-                Command::LeSetAdvertisingParametersCustom(params)
-                    .encode()
-                    .as_slice(),
-            )
-            .await;
+            self.send_command(Command::LeSetAdvertisingParametersCustom(params))
+                .await?;
             self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_PARAMETERS_OCF)
                 .await?
                 .check_command_completed()
@@ -611,8 +2290,8 @@ pub mod asynch {
         where
             Self: Sized,
         {
-            self.write_bytes(Command::LeSetAdvertisingData { data }.encode().as_slice())
-                .await;
+            self.send_command(Command::LeSetAdvertisingData { data })
+                .await?;
             self.wait_for_command_complete(LE_OGF, SET_ADVERTISING_DATA_OCF)
                 .await?
                 .check_command_completed()
@@ -625,8 +2304,8 @@ pub mod asynch {
         where
             Self: Sized,
         {
-            self.write_bytes(Command::LeSetAdvertiseEnable(enable).encode().as_slice())
-                .await;
+            self.send_command(Command::LeSetAdvertiseEnable(enable))
+                .await?;
             self.wait_for_command_complete(LE_OGF, SET_ADVERTISE_ENABLE_OCF)
                 .await?
                 .check_command_completed()
@@ -641,12 +2320,8 @@ pub mod asynch {
             Self: Sized,
         {
             log::trace!("before, key = {:x}, handle = {:x}", ltk, handle);
-            self.write_bytes(
-                Command::LeLongTermKeyRequestReply { handle, ltk }
-                    .encode()
-                    .as_slice(),
-            )
-            .await;
+            self.send_command(Command::LeLongTermKeyRequestReply { handle, ltk })
+                .await?;
             log::trace!("done writing command");
             let res = self
                 .wait_for_command_complete(LE_OGF, LONG_TERM_KEY_REQUEST_REPLY_OCF)
@@ -661,8 +2336,7 @@ pub mod asynch {
         where
             Self: Sized,
         {
-            self.write_bytes(Command::ReadBrAddr.encode().as_slice())
-                .await;
+            self.send_command(Command::ReadBrAddr).await?;
             let res = self
                 .wait_for_command_complete(INFORMATIONAL_OGF, READ_BD_ADDR_OCF)
                 .await?
@@ -677,6 +2351,7 @@ pub mod asynch {
             }
         }
 
+        /// Waits for the Command Complete or Command Status event of the given command
         pub(crate) async fn wait_for_command_complete(
             &mut self,
             ogf: u8,
@@ -685,24 +2360,23 @@ pub mod asynch {
         where
             Self: Sized,
         {
-            let timeout_at = self.millis() + TIMEOUT_MILLIS;
+            let code = opcode(ogf, ocf);
+            let timeout_at = self.millis() + self.command_timeouts.get(code);
             loop {
-                let res = self.poll().await;
-
-                match res {
-                    Some(PollResult::Event(event)) => match event {
-                        EventType::CommandComplete { opcode: code, .. }
-                            if code == opcode(ogf, ocf) =>
-                        {
-                            return Ok(event);
-                        }
-                        _ => (),
-                    },
-                    _ => (),
+                match self.poll_hci_until(timeout_at).await {
+                    Some(PollResult::Event(
+                        event @ (EventType::CommandComplete { opcode, .. }
+                        | EventType::CommandStatus { opcode, .. }),
+                    )) if opcode == code => {
+                        self.command_timeouts_in_a_row = 0;
+                        return Ok(event);
+                    }
+                    Some(res) => self.hold_back(res),
+                    None => (),
                 }
 
                 if self.millis() > timeout_at {
-                    return Err(Error::Timeout);
+                    return Err(self.command_timed_out(code));
                 }
             }
         }
@@ -711,6 +2385,14 @@ pub mod asynch {
         where
             Self: Sized,
         {
+            match self.pending.pop() {
+                Some(res) => Some(res),
+                None => self.poll_hci().await,
+            }
+        }
+
+        /// Polls the controller, skipping the packets held back while waiting for it
+        pub(crate) async fn poll_hci(&mut self) -> Option<PollResult> {
             // poll & process input
             let packet_type = {
                 let mut buffer = [0u8];
@@ -718,44 +2400,55 @@ pub mod asynch {
                 Some(buffer[0])
             };
 
+            self.process_packet(packet_type).await
+        }
+
+        /// Waits for a packet until `timeout_at` has passed, sleeping instead of polling the
+        /// clock
+        pub(crate) async fn poll_until(&mut self, timeout_at: u64) -> Option<PollResult> {
+            match self.pending.pop() {
+                Some(res) => Some(res),
+                None => self.poll_hci_until(timeout_at).await,
+            }
+        }
+
+        /// Like [Self::poll_until], skipping the packets held back while waiting for the
+        /// controller
+        pub(crate) async fn poll_hci_until(&mut self, timeout_at: u64) -> Option<PollResult> {
+            let millis = (timeout_at + 1).saturating_sub(self.millis());
+            let millis = u32::try_from(millis).unwrap_or(u32::MAX);
+
+            let packet_type = {
+                let hci = &self.hci;
+                let read = pin!(async {
+                    let mut buffer = [0u8];
+                    hci.borrow_mut().read(&mut buffer).await.unwrap();
+                    buffer[0]
+                });
+                let delay = pin!(self.clock.delay_ms(millis));
+                match select(read, delay).await {
+                    Either::Left((packet_type, _)) => packet_type,
+                    Either::Right(_) => return None,
+                }
+            };
+
+            self.process_packet(Some(packet_type)).await
+        }
+
+        async fn process_packet(&mut self, packet_type: Option<u8>) -> Option<PollResult> {
             match packet_type {
                 Some(packet_type) => match packet_type {
                     PACKET_TYPE_COMMAND => {}
                     PACKET_TYPE_ASYNC_DATA => {
-                        let mut acl_packet =
-                            AclPacket::async_read(&mut *self.hci.borrow_mut()).await;
-
-                        let wanted =
-                            u16::from_le_bytes(acl_packet.data.as_slice()[..2].try_into().unwrap())
-                                as usize;
-
-                        // somewhat dirty way to handle re-assembling fragmented packets
-                        loop {
-                            log::debug!("Wanted = {}, actual = {}", wanted, acl_packet.data.len());
-
-                            if wanted == acl_packet.data.len() - 4 {
-                                break;
-                            }
-
-                            log::debug!("Need more!");
-                            let mut buffer = [0u8; 1];
-                            (&mut *self.hci.borrow_mut())
-                                .read(&mut buffer)
-                                .await
-                                .unwrap();
-                            if buffer[0] != PACKET_TYPE_ASYNC_DATA {
-                                log::error!("Expected async data");
-                            }
-
-                            let next_acl_packet =
-                                AclPacket::async_read(&mut *self.hci.borrow_mut()).await;
-                            acl_packet.data.append(next_acl_packet.data.as_slice());
-                        }
-
-                        return Some(PollResult::AsyncData(acl_packet));
+                        let acl_packet = AclPacket::async_read(&mut *self.hci.borrow_mut()).await;
+                        return self
+                            .l2cap_reassembly
+                            .process(acl_packet)
+                            .map(PollResult::AsyncData);
                     }
                     PACKET_TYPE_EVENT => {
                         let event = EventType::async_read(&mut *self.hci.borrow_mut()).await;
+                        self.process_event(&event).await;
                         return Some(PollResult::Event(event));
                     }
                     _ => {
@@ -780,20 +2473,16 @@ pub mod asynch {
             T: embedded_io_async::Read,
         {
             let mut idx = 0;
-            let mut data = [0u8; 256];
-            loop {
-                let l = connector.read(&mut data[idx..][..len]).await.unwrap();
+            let mut data = Self::default();
+            let buffer = &mut data.spare_capacity_mut()[..len];
+            while idx < len {
+                let l = connector.read(&mut buffer[idx..]).await.unwrap();
                 idx += l;
 
-                if idx >= len {
-                    break;
-                }
-
                 // TODO timeout?
             }
 
-            let mut data = Self::new(&data);
-            data.len = len;
+            data.append_len(len);
             data
         }
     }
@@ -0,0 +1,65 @@
+//! Host based LE privacy ([Vol 3] Part C, Section 10.7)
+//!
+//! [RpaRotation] generates a new resolvable private address from the local IRK whenever the
+//! current one expired, `Ble::rotate_private_address` applies it with LE Set Random Address.
+//! Alternatively the controller generates and resolves addresses itself once the peers are
+//! added to the resolving list and address resolution is enabled.
+
+use rand_core::RngCore;
+
+use crate::{crypto::Irk, Addr};
+
+/// Interval recommended for changing the resolvable private address ([Vol 3] Part C,
+/// Appendix A)
+pub const DEFAULT_RPA_TIMEOUT_MILLIS: u64 = 15 * 60 * 1000;
+
+/// Generates a new resolvable private address once the current one expired
+pub struct RpaRotation {
+    irk: Irk,
+    timeout_millis: u64,
+    address: Option<Addr>,
+    rotate_at: u64,
+}
+
+impl RpaRotation {
+    /// Rotate the addresses generated from the local IRK every
+    /// [DEFAULT_RPA_TIMEOUT_MILLIS]
+    pub fn new(irk: Irk) -> Self {
+        Self::with_timeout(irk, DEFAULT_RPA_TIMEOUT_MILLIS)
+    }
+
+    pub fn with_timeout(irk: Irk, timeout_millis: u64) -> Self {
+        Self {
+            irk,
+            timeout_millis,
+            address: None,
+            rotate_at: 0,
+        }
+    }
+
+    pub fn irk(&self) -> Irk {
+        self.irk
+    }
+
+    /// The address currently in use, if one was generated yet
+    pub fn address(&self) -> Option<Addr> {
+        self.address
+    }
+
+    /// Force a new address on the next call of [Self::poll], e.g. after a disconnection
+    pub fn expire(&mut self) {
+        self.address = None;
+    }
+
+    /// Returns a new address if there is none yet or the current one expired
+    pub fn poll<T: RngCore>(&mut self, now_millis: u64, rng: &mut T) -> Option<Addr> {
+        if self.address.is_some() && now_millis < self.rotate_at {
+            return None;
+        }
+
+        let address = self.irk.generate_rpa(rng);
+        self.address = Some(address);
+        self.rotate_at = now_millis + self.timeout_millis;
+        Some(address)
+    }
+}
@@ -0,0 +1,308 @@
+//! Detecting controller faults and restoring the stack afterwards
+//!
+//! `Ble` reports a [ControllerFault] after a Hardware Error or Data Buffer Overflow event or
+//! once [MAX_COMMAND_TIMEOUTS_IN_A_ROW] commands timed out. `Ble::recover` then resets the
+//! controller and restores the event masks and the advertising set up before, the
+//! `AttributeServer` does so on its own in `do_work`.
+
+use crate::{
+    command::{
+        CLEAR_ADVERTISING_SETS_OCF, LE_OGF, REMOVE_ADVERTISING_SET_OCF, SET_ADVERTISE_ENABLE_OCF,
+        SET_ADVERTISING_DATA_OCF, SET_ADVERTISING_PARAMETERS_OCF,
+        SET_ADVERTISING_SET_RANDOM_ADDRESS_OCF, SET_EXTENDED_ADVERTISING_DATA_OCF,
+        SET_EXTENDED_ADVERTISING_ENABLE_OCF, SET_EXTENDED_ADVERTISING_PARAMETERS_OCF,
+        SET_EXTENDED_SCAN_RSP_DATA_OCF, SET_SCAN_RSP_DATA_OCF,
+    },
+    extended_advertising::{AdvertisingDataOperation, MAX_EXTENDED_ADVERTISING_DATA_LEN},
+    Data,
+};
+
+/// Number of commands which time out in a row before the controller is considered stuck
+pub const MAX_COMMAND_TIMEOUTS_IN_A_ROW: u8 = 3;
+
+/// Number of extended advertising sets restored after a fault
+pub const MAX_RESTORED_ADVERTISING_SETS: usize = 2;
+
+/// Why the controller needs to be reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ControllerFault {
+    /// Hardware Error event with a vendor specific code
+    HardwareError(u8),
+    /// The controller ran out of buffers, data or events got lost
+    DataBufferOverflow,
+    /// The controller stopped answering commands
+    CommandTimeouts,
+}
+
+/// Parameters of the last legacy advertising commands the controller accepted
+#[derive(Clone, Copy)]
+pub(crate) struct LegacyAdvertisingConfig {
+    parameters: Option<[u8; 15]>,
+    data: Option<[u8; 32]>,
+    scan_response: Option<[u8; 32]>,
+    enable: Option<[u8; 1]>,
+}
+
+impl LegacyAdvertisingConfig {
+    /// The recorded commands in the order to repeat them, enabling advertising last
+    pub(crate) fn commands(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        [
+            (
+                SET_ADVERTISING_PARAMETERS_OCF,
+                self.parameters.as_ref().map(|p| &p[..]),
+            ),
+            (SET_ADVERTISING_DATA_OCF, self.data.as_ref().map(|p| &p[..])),
+            (
+                SET_SCAN_RSP_DATA_OCF,
+                self.scan_response.as_ref().map(|p| &p[..]),
+            ),
+            (
+                SET_ADVERTISE_ENABLE_OCF,
+                self.enable.as_ref().map(|p| &p[..]),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(ocf, params)| Some((ocf, params?)))
+    }
+}
+
+/// Advertising or scan response data of a set, put together from its fragments
+#[derive(Clone, Copy)]
+pub(crate) struct AdvertisingData {
+    data: [u8; MAX_EXTENDED_ADVERTISING_DATA_LEN],
+    len: usize,
+}
+
+impl AdvertisingData {
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+/// Set up of an extended advertising set the controller accepted
+#[derive(Clone, Copy)]
+pub(crate) struct AdvertisingSetConfig {
+    pub(crate) handle: u8,
+    /// Parameters of LE Set Extended Advertising Parameters
+    pub(crate) parameters: [u8; 25],
+    /// Parameters of LE Set Advertising Set Random Address
+    pub(crate) random_address: Option<[u8; 7]>,
+    pub(crate) data: Option<AdvertisingData>,
+    pub(crate) scan_response: Option<AdvertisingData>,
+    /// Duration and maximum number of events while the set is enabled
+    pub(crate) enabled: Option<[u8; 3]>,
+}
+
+/// Advertising which was enabled before it got paused
+#[derive(Clone, Copy)]
+pub(crate) struct PausedAdvertising {
+    pub(crate) legacy: bool,
+    /// Handle, duration and maximum number of events of the extended advertising sets
+    pub(crate) sets: [Option<(u8, [u8; 3])>; MAX_RESTORED_ADVERTISING_SETS],
+}
+
+/// Advertising set up to repeat after a reset
+///
+/// Commands are only recorded once the controller completed them successfully.
+pub(crate) struct AdvertisingConfig {
+    pub(crate) legacy: LegacyAdvertisingConfig,
+    pub(crate) sets: [Option<AdvertisingSetConfig>; MAX_RESTORED_ADVERTISING_SETS],
+    /// The last advertising command sent, waiting for its Command Complete event
+    sent: Option<(u16, Data)>,
+}
+
+impl AdvertisingConfig {
+    pub(crate) const fn new() -> Self {
+        Self {
+            legacy: LegacyAdvertisingConfig {
+                parameters: None,
+                data: None,
+                scan_response: None,
+                enable: None,
+            },
+            sets: [None; MAX_RESTORED_ADVERTISING_SETS],
+            sent: None,
+        }
+    }
+
+    /// Remembers the parameters if the command configures advertising, until the controller
+    /// completed it
+    pub(crate) fn sent(&mut self, ogf: u8, ocf: u16, params: &[u8]) {
+        if ogf != LE_OGF {
+            return;
+        }
+
+        match ocf {
+            SET_ADVERTISING_PARAMETERS_OCF
+            | SET_ADVERTISING_DATA_OCF
+            | SET_SCAN_RSP_DATA_OCF
+            | SET_ADVERTISE_ENABLE_OCF
+            | SET_ADVERTISING_SET_RANDOM_ADDRESS_OCF
+            | SET_EXTENDED_ADVERTISING_PARAMETERS_OCF
+            | SET_EXTENDED_ADVERTISING_DATA_OCF
+            | SET_EXTENDED_SCAN_RSP_DATA_OCF
+            | SET_EXTENDED_ADVERTISING_ENABLE_OCF
+            | REMOVE_ADVERTISING_SET_OCF
+            | CLEAR_ADVERTISING_SETS_OCF => self.sent = Some((ocf, Data::new(params))),
+            _ => (),
+        }
+    }
+
+    /// Records the command sent last once the controller completed it successfully
+    pub(crate) fn completed(&mut self, ogf: u8, ocf: u16, status: u8) {
+        if ogf != LE_OGF || !matches!(&self.sent, Some((sent, _)) if *sent == ocf) {
+            return;
+        }
+
+        let Some((_, params)) = self.sent.take() else {
+            return;
+        };
+        if status == 0 {
+            self.record(ocf, params.as_slice());
+        }
+    }
+
+    /// The advertising enabled at the moment
+    pub(crate) fn enabled(&self) -> PausedAdvertising {
+        PausedAdvertising {
+            legacy: self.legacy.enable == Some([1]),
+            sets: core::array::from_fn(|i| {
+                let set = self.sets[i].as_ref()?;
+                Some((set.handle, set.enabled?))
+            }),
+        }
+    }
+
+    /// A set stopped advertising on its own, e.g. because a connection was created
+    pub(crate) fn terminated(&mut self, handle: u8) {
+        if let Some(set) = self.set_mut(handle) {
+            set.enabled = None;
+        }
+    }
+
+    fn record(&mut self, ocf: u16, params: &[u8]) {
+        match ocf {
+            SET_ADVERTISING_PARAMETERS_OCF => self.legacy.parameters = params.try_into().ok(),
+            SET_ADVERTISING_DATA_OCF => self.legacy.data = params.try_into().ok(),
+            SET_SCAN_RSP_DATA_OCF => self.legacy.scan_response = params.try_into().ok(),
+            SET_ADVERTISE_ENABLE_OCF => self.legacy.enable = params.try_into().ok(),
+            SET_EXTENDED_ADVERTISING_PARAMETERS_OCF => {
+                let Ok(parameters) = params.try_into() else {
+                    return;
+                };
+                self.record_parameters(parameters);
+            }
+            SET_ADVERTISING_SET_RANDOM_ADDRESS_OCF => {
+                let Ok(address) = <[u8; 7]>::try_from(params) else {
+                    return;
+                };
+                if let Some(set) = self.set_mut(address[0]) {
+                    set.random_address = Some(address);
+                }
+            }
+            SET_EXTENDED_ADVERTISING_DATA_OCF | SET_EXTENDED_SCAN_RSP_DATA_OCF => {
+                let [handle, operation, _, len, fragment @ ..] = params else {
+                    return;
+                };
+                let Some(set) = self.set_mut(*handle) else {
+                    return;
+                };
+                let data = if ocf == SET_EXTENDED_ADVERTISING_DATA_OCF {
+                    &mut set.data
+                } else {
+                    &mut set.scan_response
+                };
+                append_fragment(
+                    data,
+                    *operation,
+                    &fragment[..usize::min(*len as usize, fragment.len())],
+                );
+            }
+            SET_EXTENDED_ADVERTISING_ENABLE_OCF => {
+                let [enable, num_sets, sets @ ..] = params else {
+                    return;
+                };
+                if *enable == 0 && *num_sets == 0 {
+                    for set in self.sets.iter_mut().flatten() {
+                        set.enabled = None;
+                    }
+                }
+                let (entries, _) = sets.as_chunks::<4>();
+                for [handle, duration_lo, duration_hi, max_events] in
+                    entries.iter().take(*num_sets as usize)
+                {
+                    if let Some(set) = self.set_mut(*handle) {
+                        set.enabled =
+                            (*enable == 1).then_some([*duration_lo, *duration_hi, *max_events]);
+                    }
+                }
+            }
+            REMOVE_ADVERTISING_SET_OCF => {
+                for slot in self.sets.iter_mut() {
+                    if matches!(slot, Some(set) if Some(&set.handle) == params.first()) {
+                        *slot = None;
+                    }
+                }
+            }
+            CLEAR_ADVERTISING_SETS_OCF => self.sets = [None; MAX_RESTORED_ADVERTISING_SETS],
+            _ => (),
+        }
+    }
+
+    fn record_parameters(&mut self, parameters: [u8; 25]) {
+        let handle = parameters[0];
+        if let Some(set) = self.set_mut(handle) {
+            set.parameters = parameters;
+            return;
+        }
+
+        match self.sets.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(AdvertisingSetConfig {
+                    handle,
+                    parameters,
+                    random_address: None,
+                    data: None,
+                    scan_response: None,
+                    enabled: None,
+                })
+            }
+            None => log::warn!("Advertising set {} won't be restored after a fault", handle),
+        }
+    }
+
+    fn set_mut(&mut self, handle: u8) -> Option<&mut AdvertisingSetConfig> {
+        self.sets
+            .iter_mut()
+            .flatten()
+            .find(|set| set.handle == handle)
+    }
+}
+
+/// Puts the fragments of LE Set Extended Advertising/Scan Response Data together
+fn append_fragment(data: &mut Option<AdvertisingData>, operation: u8, fragment: &[u8]) {
+    let first = operation == AdvertisingDataOperation::FirstFragment as u8
+        || operation == AdvertisingDataOperation::Complete as u8;
+    if operation == AdvertisingDataOperation::Unchanged as u8 {
+        return;
+    }
+
+    if first {
+        *data = Some(AdvertisingData {
+            data: [0u8; MAX_EXTENDED_ADVERTISING_DATA_LEN],
+            len: 0,
+        });
+    }
+
+    let Some(current) = data else {
+        return;
+    };
+    if current.len + fragment.len() > MAX_EXTENDED_ADVERTISING_DATA_LEN {
+        log::warn!("Advertising data too long to be restored after a fault");
+        *data = None;
+        return;
+    }
+    current.data[current.len..][..fragment.len()].copy_from_slice(fragment);
+    current.len += fragment.len();
+}
@@ -0,0 +1,57 @@
+//! Random numbers for targets without an entropy source of their own
+//!
+//! [ControllerRng] is seeded with random bits of the controller, see
+//! `Ble::controller_rng`, and expands them with AES-128 in counter mode. The key is replaced
+//! after every request, so earlier output can't be recovered from the state. The expansion
+//! uses the software AES of [crate::crypto::aes128], the controller is only asked for the seed.
+
+use rand_core::{CryptoRng, RngCore};
+
+use crate::crypto::aes128;
+
+/// AES-128 CTR generator seeded by the controller's LE Rand
+pub struct ControllerRng {
+    key: u128,
+    counter: u128,
+}
+
+impl ControllerRng {
+    /// `key` and `nonce` need to be random, e.g. read with `Ble::cmd_le_rand`
+    pub fn from_seed(key: u128, nonce: u64) -> Self {
+        Self {
+            key,
+            counter: (nonce as u128) << 64,
+        }
+    }
+
+    fn next_block(&mut self) -> [u8; 16] {
+        let block = aes128(self.key, self.counter);
+        self.counter = self.counter.wrapping_add(1);
+        block.to_be_bytes()
+    }
+}
+
+impl RngCore for ControllerRng {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(16) {
+            let block = self.next_block();
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        self.key = u128::from_be_bytes(self.next_block());
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for ControllerRng {}
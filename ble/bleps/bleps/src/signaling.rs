@@ -0,0 +1,360 @@
+//! L2CAP LE signaling channel ([Vol 3] Part A, Section 4)
+//!
+//! Each C-frame on the LE signaling channel carries a single command. Requests are
+//! answered by `Ble::handle_signaling`, commands it doesn't understand are rejected.
+
+use crate::{connection::ConnectionUpdateParameters, Data};
+
+/// Channel identifier of the LE signaling channel
+pub const LE_SIGNALING_CHANNEL: u16 = 0x0005;
+
+pub const COMMAND_REJECT_CODE: u8 = 0x01;
+pub const DISCONNECTION_REQUEST_CODE: u8 = 0x06;
+pub const DISCONNECTION_RESPONSE_CODE: u8 = 0x07;
+pub const CONNECTION_PARAMETER_UPDATE_REQUEST_CODE: u8 = 0x12;
+pub const CONNECTION_PARAMETER_UPDATE_RESPONSE_CODE: u8 = 0x13;
+pub const LE_CREDIT_BASED_CONNECTION_REQUEST_CODE: u8 = 0x14;
+pub const LE_CREDIT_BASED_CONNECTION_RESPONSE_CODE: u8 = 0x15;
+pub const FLOW_CONTROL_CREDIT_IND_CODE: u8 = 0x16;
+
+const CONNECTION_PARAMETERS_ACCEPTED: u16 = 0x0000;
+const CONNECTION_PARAMETERS_REJECTED: u16 = 0x0001;
+
+/// Reason of a Command Reject
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RejectReason {
+    CommandNotUnderstood,
+    SignalingMtuExceeded,
+    /// The channel given in a request doesn't exist
+    InvalidCid {
+        local_cid: u16,
+        remote_cid: u16,
+    },
+    Unknown(u16),
+}
+
+/// Result of a LE Credit Based Connection Request
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CreditBasedConnectionResult {
+    Success,
+    SpsmNotSupported,
+    NoResourcesAvailable,
+    InsufficientAuthentication,
+    InsufficientAuthorization,
+    InsufficientEncryption,
+    InvalidSourceCid,
+    SourceCidAlreadyAllocated,
+    UnacceptableParameters,
+    Unknown(u16),
+}
+
+impl CreditBasedConnectionResult {
+    fn from_u16(value: u16) -> Self {
+        match value {
+            0x0000 => Self::Success,
+            0x0002 => Self::SpsmNotSupported,
+            0x0004 => Self::NoResourcesAvailable,
+            0x0005 => Self::InsufficientAuthentication,
+            0x0006 => Self::InsufficientAuthorization,
+            0x0008 => Self::InsufficientEncryption,
+            0x0009 => Self::InvalidSourceCid,
+            0x000a => Self::SourceCidAlreadyAllocated,
+            0x000b => Self::UnacceptableParameters,
+            value => Self::Unknown(value),
+        }
+    }
+
+    fn to_u16(self) -> u16 {
+        match self {
+            Self::Success => 0x0000,
+            Self::SpsmNotSupported => 0x0002,
+            Self::NoResourcesAvailable => 0x0004,
+            Self::InsufficientAuthentication => 0x0005,
+            Self::InsufficientAuthorization => 0x0006,
+            Self::InsufficientEncryption => 0x0008,
+            Self::InvalidSourceCid => 0x0009,
+            Self::SourceCidAlreadyAllocated => 0x000a,
+            Self::UnacceptableParameters => 0x000b,
+            Self::Unknown(value) => value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SignalingCommand {
+    CommandReject {
+        identifier: u8,
+        reason: RejectReason,
+    },
+    DisconnectionRequest {
+        identifier: u8,
+        destination_cid: u16,
+        source_cid: u16,
+    },
+    DisconnectionResponse {
+        identifier: u8,
+        destination_cid: u16,
+        source_cid: u16,
+    },
+    /// Sent by the peripheral to ask the central for new connection parameters
+    ConnectionParameterUpdateRequest {
+        identifier: u8,
+        parameters: ConnectionUpdateParameters,
+    },
+    ConnectionParameterUpdateResponse {
+        identifier: u8,
+        accepted: bool,
+    },
+    /// Opens a credit based channel to the given LE_PSM, `source_cid` is the channel of the
+    /// sender
+    LeCreditBasedConnectionRequest {
+        identifier: u8,
+        le_psm: u16,
+        source_cid: u16,
+        mtu: u16,
+        mps: u16,
+        initial_credits: u16,
+    },
+    LeCreditBasedConnectionResponse {
+        identifier: u8,
+        destination_cid: u16,
+        mtu: u16,
+        mps: u16,
+        initial_credits: u16,
+        result: CreditBasedConnectionResult,
+    },
+    /// Allows the receiver to send `credits` more K-frames on the channel `cid` of the sender
+    FlowControlCreditInd {
+        identifier: u8,
+        cid: u16,
+        credits: u16,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SignalingDecodeError {
+    /// The command is shorter than its header or the length given in the header
+    InvalidLength {
+        identifier: u8,
+    },
+    UnknownCommand {
+        identifier: u8,
+        code: u8,
+    },
+    /// Identifier 0 is never used, such commands are dropped
+    InvalidIdentifier,
+}
+
+impl SignalingCommand {
+    pub fn decode(payload: &[u8]) -> Result<Self, SignalingDecodeError> {
+        let [code, identifier, len_lo, len_hi, data @ ..] = payload else {
+            return Err(SignalingDecodeError::InvalidLength { identifier: 0 });
+        };
+        let identifier = *identifier;
+        if identifier == 0 {
+            return Err(SignalingDecodeError::InvalidIdentifier);
+        }
+
+        let len = u16::from_le_bytes([*len_lo, *len_hi]) as usize;
+        if data.len() < len {
+            return Err(SignalingDecodeError::InvalidLength { identifier });
+        }
+        let data = &data[..len];
+        let value = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+
+        let expected_len = match *code {
+            COMMAND_REJECT_CODE => 2,
+            DISCONNECTION_REQUEST_CODE | DISCONNECTION_RESPONSE_CODE => 4,
+            CONNECTION_PARAMETER_UPDATE_REQUEST_CODE => 8,
+            CONNECTION_PARAMETER_UPDATE_RESPONSE_CODE => 2,
+            LE_CREDIT_BASED_CONNECTION_REQUEST_CODE => 10,
+            LE_CREDIT_BASED_CONNECTION_RESPONSE_CODE => 10,
+            FLOW_CONTROL_CREDIT_IND_CODE => 4,
+            code => return Err(SignalingDecodeError::UnknownCommand { identifier, code }),
+        };
+        if len < expected_len {
+            return Err(SignalingDecodeError::InvalidLength { identifier });
+        }
+
+        Ok(match *code {
+            COMMAND_REJECT_CODE => {
+                let reason = match value(0) {
+                    0x0000 => RejectReason::CommandNotUnderstood,
+                    0x0001 => RejectReason::SignalingMtuExceeded,
+                    0x0002 if len >= 6 => RejectReason::InvalidCid {
+                        local_cid: value(1),
+                        remote_cid: value(2),
+                    },
+                    reason => RejectReason::Unknown(reason),
+                };
+                SignalingCommand::CommandReject { identifier, reason }
+            }
+            DISCONNECTION_REQUEST_CODE => SignalingCommand::DisconnectionRequest {
+                identifier,
+                destination_cid: value(0),
+                source_cid: value(1),
+            },
+            DISCONNECTION_RESPONSE_CODE => SignalingCommand::DisconnectionResponse {
+                identifier,
+                destination_cid: value(0),
+                source_cid: value(1),
+            },
+            CONNECTION_PARAMETER_UPDATE_REQUEST_CODE => {
+                SignalingCommand::ConnectionParameterUpdateRequest {
+                    identifier,
+                    parameters: ConnectionUpdateParameters {
+                        interval_min: value(0),
+                        interval_max: value(1),
+                        max_latency: value(2),
+                        supervision_timeout: value(3),
+                        min_ce_length: 0,
+                        max_ce_length: 0,
+                    },
+                }
+            }
+            CONNECTION_PARAMETER_UPDATE_RESPONSE_CODE => {
+                SignalingCommand::ConnectionParameterUpdateResponse {
+                    identifier,
+                    accepted: value(0) == CONNECTION_PARAMETERS_ACCEPTED,
+                }
+            }
+            LE_CREDIT_BASED_CONNECTION_REQUEST_CODE => {
+                SignalingCommand::LeCreditBasedConnectionRequest {
+                    identifier,
+                    le_psm: value(0),
+                    source_cid: value(1),
+                    mtu: value(2),
+                    mps: value(3),
+                    initial_credits: value(4),
+                }
+            }
+            LE_CREDIT_BASED_CONNECTION_RESPONSE_CODE => {
+                SignalingCommand::LeCreditBasedConnectionResponse {
+                    identifier,
+                    destination_cid: value(0),
+                    mtu: value(1),
+                    mps: value(2),
+                    initial_credits: value(3),
+                    result: CreditBasedConnectionResult::from_u16(value(4)),
+                }
+            }
+            _ => SignalingCommand::FlowControlCreditInd {
+                identifier,
+                cid: value(0),
+                credits: value(1),
+            },
+        })
+    }
+
+    /// Encodes the command, the L2CAP header still needs to be prepended
+    pub fn encode(&self) -> Data {
+        let (code, identifier) = match *self {
+            SignalingCommand::CommandReject { identifier, .. } => (COMMAND_REJECT_CODE, identifier),
+            SignalingCommand::DisconnectionRequest { identifier, .. } => {
+                (DISCONNECTION_REQUEST_CODE, identifier)
+            }
+            SignalingCommand::DisconnectionResponse { identifier, .. } => {
+                (DISCONNECTION_RESPONSE_CODE, identifier)
+            }
+            SignalingCommand::ConnectionParameterUpdateRequest { identifier, .. } => {
+                (CONNECTION_PARAMETER_UPDATE_REQUEST_CODE, identifier)
+            }
+            SignalingCommand::ConnectionParameterUpdateResponse { identifier, .. } => {
+                (CONNECTION_PARAMETER_UPDATE_RESPONSE_CODE, identifier)
+            }
+            SignalingCommand::LeCreditBasedConnectionRequest { identifier, .. } => {
+                (LE_CREDIT_BASED_CONNECTION_REQUEST_CODE, identifier)
+            }
+            SignalingCommand::LeCreditBasedConnectionResponse { identifier, .. } => {
+                (LE_CREDIT_BASED_CONNECTION_RESPONSE_CODE, identifier)
+            }
+            SignalingCommand::FlowControlCreditInd { identifier, .. } => {
+                (FLOW_CONTROL_CREDIT_IND_CODE, identifier)
+            }
+        };
+
+        let mut data = Data::new(&[code, identifier, 0, 0]);
+        match *self {
+            SignalingCommand::CommandReject { reason, .. } => match reason {
+                RejectReason::CommandNotUnderstood => data.append(&0x0000u16.to_le_bytes()),
+                RejectReason::SignalingMtuExceeded => {
+                    data.append(&0x0001u16.to_le_bytes());
+                    // the signaling MTU of LE is fixed
+                    data.append(&23u16.to_le_bytes());
+                }
+                RejectReason::InvalidCid {
+                    local_cid,
+                    remote_cid,
+                } => {
+                    data.append(&0x0002u16.to_le_bytes());
+                    data.append(&local_cid.to_le_bytes());
+                    data.append(&remote_cid.to_le_bytes());
+                }
+                RejectReason::Unknown(reason) => data.append(&reason.to_le_bytes()),
+            },
+            SignalingCommand::DisconnectionRequest {
+                destination_cid,
+                source_cid,
+                ..
+            }
+            | SignalingCommand::DisconnectionResponse {
+                destination_cid,
+                source_cid,
+                ..
+            } => {
+                data.append(&destination_cid.to_le_bytes());
+                data.append(&source_cid.to_le_bytes());
+            }
+            SignalingCommand::ConnectionParameterUpdateRequest { parameters, .. } => {
+                data.append(&parameters.interval_min.to_le_bytes());
+                data.append(&parameters.interval_max.to_le_bytes());
+                data.append(&parameters.max_latency.to_le_bytes());
+                data.append(&parameters.supervision_timeout.to_le_bytes());
+            }
+            SignalingCommand::ConnectionParameterUpdateResponse { accepted, .. } => {
+                let result = if accepted {
+                    CONNECTION_PARAMETERS_ACCEPTED
+                } else {
+                    CONNECTION_PARAMETERS_REJECTED
+                };
+                data.append(&result.to_le_bytes());
+            }
+            SignalingCommand::LeCreditBasedConnectionRequest {
+                le_psm,
+                source_cid,
+                mtu,
+                mps,
+                initial_credits,
+                ..
+            } => {
+                for value in [le_psm, source_cid, mtu, mps, initial_credits] {
+                    data.append(&value.to_le_bytes());
+                }
+            }
+            SignalingCommand::LeCreditBasedConnectionResponse {
+                destination_cid,
+                mtu,
+                mps,
+                initial_credits,
+                result,
+                ..
+            } => {
+                for value in [destination_cid, mtu, mps, initial_credits, result.to_u16()] {
+                    data.append(&value.to_le_bytes());
+                }
+            }
+            SignalingCommand::FlowControlCreditInd { cid, credits, .. } => {
+                data.append(&cid.to_le_bytes());
+                data.append(&credits.to_le_bytes());
+            }
+        }
+
+        let len = (data.len() - 4) as u16;
+        data.as_slice_mut()[2..4].copy_from_slice(&len.to_le_bytes());
+        data
+    }
+}
@@ -68,7 +68,9 @@ const SM_PAIRING_FAILED: u8 = 0x05;
 const SM_PAIRING_PUBLIC_KEY: u8 = 0x0c;
 const SM_PAIRING_DHKEY_CHECK: u8 = 0x0d;
 
-pub struct SecurityManager<'a, B, R: CryptoRng> {
+/// Pairing state and keys of a single connection
+#[derive(Default)]
+pub struct PairingState {
     ioa: Option<IoCap>,
 
     skb: Option<SecretKey>,
@@ -87,8 +89,41 @@ pub struct SecurityManager<'a, B, R: CryptoRng> {
 
     eb: Option<Check>,
 
+    /// The LTK used to encrypt the connection
+    pub ltk: Option<u128>,
+}
+
+impl PairingState {
+    /// A connection which didn't pair yet, encrypted with the given LTK of a bonded peer
+    pub fn new(ltk: Option<u128>) -> Self {
+        Self {
+            ltk,
+            ..Self::default()
+        }
+    }
+}
+
+impl core::fmt::Debug for PairingState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PairingState")
+            .field("has_ltk", &self.ltk.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for PairingState {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "PairingState {{ has_ltk: {} }}", self.ltk.is_some())
+    }
+}
+
+/// Pairs connections as responder using LE Secure Connections
+///
+/// The state of each pairing is kept in a [PairingState] per connection.
+pub struct SecurityManager<'a, B, R: CryptoRng> {
     pub local_address: Option<Addr>,
-    pub peer_address: Option<Addr>,
+    /// The LTK of the bonded peer, or of the connection which paired last
     pub ltk: Option<u128>,
 
     rng: &'a mut R,
@@ -101,25 +136,16 @@ pub trait BleWriter {
 
 impl<'a> BleWriter for Ble<'a> {
     fn write_bytes(&mut self, bytes: &[u8]) {
-        self.write_bytes(bytes);
+        if let Err(err) = self.write_acl(Data::new(bytes)) {
+            log::warn!("Failed to write ACL packet {:?}", err);
+        }
     }
 }
 
 impl<'a, B, R: CryptoRng> SecurityManager<'a, B, R> {
     pub fn new(rng: &'a mut R) -> Self {
         Self {
-            ioa: None,
-            skb: None,
-            pkb: None,
-            pka: None,
-            confirm: None,
-            na: None,
-            nb: None,
-            dh_key: None,
-            mac_key: None,
-            eb: None,
             local_address: None,
-            peer_address: None,
             ltk: None,
             rng,
             phantom: PhantomData::default(),
@@ -129,25 +155,8 @@ impl<'a, B, R: CryptoRng> SecurityManager<'a, B, R> {
 
 #[cfg(feature = "async")]
 pub struct AsyncSecurityManager<'a, B, R: CryptoRng> {
-    ioa: Option<IoCap>,
-
-    skb: Option<SecretKey>,
-    pkb: Option<PublicKey>,
-
-    pka: Option<PublicKey>,
-
-    confirm: Option<Confirm>,
-
-    na: Option<Nonce>,
-    nb: Option<Nonce>,
-
-    dh_key: Option<DHKey>,
-
-    mac_key: Option<MacKey>,
-    eb: Option<Check>,
-
     pub local_address: Option<Addr>,
-    pub peer_address: Option<Addr>,
+    /// The LTK of the bonded peer, or of the connection which paired last
     pub ltk: Option<u128>,
 
     rng: &'a mut R,
@@ -160,12 +169,15 @@ pub trait AsyncBleWriter {
 }
 
 #[cfg(feature = "async")]
-impl<T> AsyncBleWriter for crate::asynch::Ble<T>
+impl<T, C> AsyncBleWriter for crate::asynch::Ble<T, C>
 where
     T: embedded_io_async::Read + embedded_io_async::Write,
+    C: crate::clock::Clock + embedded_hal_async::delay::DelayNs,
 {
     async fn write_bytes(&mut self, bytes: &[u8]) {
-        self.write_bytes(bytes).await
+        if let Err(err) = self.write_acl(Data::new(bytes)).await {
+            log::warn!("Failed to write ACL packet {:?}", err);
+        }
     }
 }
 
@@ -173,18 +185,7 @@ where
 impl<'a, B, R: CryptoRng> AsyncSecurityManager<'a, B, R> {
     pub fn new(rng: &'a mut R) -> Self {
         Self {
-            ioa: None,
-            skb: None,
-            pkb: None,
-            pka: None,
-            confirm: None,
-            na: None,
-            nb: None,
-            dh_key: None,
-            mac_key: None,
-            eb: None,
             local_address: None,
-            peer_address: None,
             ltk: None,
             rng,
             phantom: PhantomData::default(),
@@ -206,7 +207,7 @@ bleps_dedup::dedup! {
 impl<'a, B, R> SYNC SecurityManager<'a, B, R> where B: BleWriter, R: CryptoRng + RngCore
 impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R: CryptoRng + RngCore
  {
-    pub(crate) async fn handle(&mut self, ble: &mut B, src_handle: u16, payload: crate::Data, pin_callback: &mut Option<&mut dyn FnMut(u32)>) -> Result<(), AttributeServerError> {
+    pub(crate) async fn handle(&mut self, ble: &mut B, src_handle: u16, pairing: &mut PairingState, peer_address: Option<Addr>, payload: crate::Data, pin_callback: &mut Option<&mut dyn FnMut(u32)>) -> Result<(), AttributeServerError> {
         log::debug!("SM packet {:02x?}", payload.as_slice());
 
         let data = &payload.as_slice()[1..];
@@ -214,16 +215,16 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
 
         match command {
             SM_PAIRING_REQUEST => {
-                self.handle_pairing_request(ble, src_handle, data).await;
+                self.handle_pairing_request(ble, src_handle, pairing, data).await;
             }
             SM_PAIRING_PUBLIC_KEY => {
-                self.handle_pairing_public_key(ble, src_handle, data).await?;
+                self.handle_pairing_public_key(ble, src_handle, pairing, data).await?;
             }
             SM_PAIRING_RANDOM => {
-                self.handle_pairing_random(ble, src_handle, data, pin_callback).await?;
+                self.handle_pairing_random(ble, src_handle, pairing, peer_address, data, pin_callback).await?;
             }
             SM_PAIRING_DHKEY_CHECK => {
-                self.handle_pairing_dhkey_check(ble, src_handle, data).await?;
+                self.handle_pairing_dhkey_check(ble, src_handle, pairing, peer_address, data).await?;
             }
             _ => {
                 // handle FAILURE
@@ -236,8 +237,8 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
         Ok(())
     }
 
-    async fn handle_pairing_request(&mut self, ble: &mut B, src_handle: u16, data: &[u8]) {
-        self.ioa = Some(IoCap::new(data[2], data[1] != 0, data[0]));
+    async fn handle_pairing_request(&mut self, ble: &mut B, src_handle: u16, pairing: &mut PairingState, data: &[u8]) {
+        pairing.ioa = Some(IoCap::new(data[2], data[1] != 0, data[0]));
         log::debug!("got pairing request");
 
         let mut data = Data::new(&[SM_PAIRING_RESPONSE]);
@@ -251,7 +252,7 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
         self.write_sm(ble, src_handle, data).await;
     }
 
-    async fn handle_pairing_public_key(&mut self, ble: &mut B, src_handle: u16, pka: &[u8]) -> Result<(), AttributeServerError> {
+    async fn handle_pairing_public_key(&mut self, ble: &mut B, src_handle: u16, pairing: &mut PairingState, pka: &[u8]) -> Result<(), AttributeServerError> {
         log::debug!("got public key");
 
         log::debug!("key len = {} {:02x?}", pka.len(), pka);
@@ -265,15 +266,7 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
         let skb = SecretKey::new(self.rng);
         let pkb = skb.public_key();
 
-        let mut x = [0u8; 32];
-        let mut y = [0u8; 32];
-        x.copy_from_slice(pkb.x.as_be_bytes());
-        y.copy_from_slice(pkb.y.as_be_bytes());
-        x.reverse();
-        y.reverse();
-
-        data.append(&x);
-        data.append(&y);
+        data.append(&pkb.to_bytes());
         self.write_sm(ble, src_handle, data).await;
 
         let dh_key = match skb.dh_key(pka) {
@@ -291,54 +284,54 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
         data.append(&confirm_value);
         self.write_sm(ble, src_handle, data).await;
 
-        self.pka = Some(pka);
-        self.pkb = Some(pkb);
-        self.skb = Some(skb);
-        self.confirm = Some(cb);
-        self.nb = Some(nb);
-        self.dh_key = Some(dh_key);
+        pairing.pka = Some(pka);
+        pairing.pkb = Some(pkb);
+        pairing.skb = Some(skb);
+        pairing.confirm = Some(cb);
+        pairing.nb = Some(nb);
+        pairing.dh_key = Some(dh_key);
 
         Ok(())
     }
 
-    async fn handle_pairing_random(&mut self, ble: &mut B, src_handle: u16, random: &[u8], pin_callback: &mut Option<&mut dyn FnMut(u32)>) -> Result<(), AttributeServerError> {
+    async fn handle_pairing_random(&mut self, ble: &mut B, src_handle: u16, pairing: &mut PairingState, peer_address: Option<Addr>, random: &[u8], pin_callback: &mut Option<&mut dyn FnMut(u32)>) -> Result<(), AttributeServerError> {
         log::debug!("got pairing random {:02x?}", random);
 
-        if *&(self.nb).is_none() {
+        if pairing.nb.is_none() {
             self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
             return Err(AttributeServerError::SecurityManagerError);
         }
 
-        if *&(self.pka).is_none() {
+        if pairing.pka.is_none() {
             self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
             return Err(AttributeServerError::SecurityManagerError);
         }
 
-        if *&(self.pkb).is_none() {
+        if pairing.pkb.is_none() {
             self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
             return Err(AttributeServerError::SecurityManagerError);
         }
 
-        if *&(self.peer_address).is_none() {
+        if peer_address.is_none() {
             self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
             return Err(AttributeServerError::SecurityManagerError);
         }
 
-        if *&(self.local_address).is_none() {
+        if self.local_address.is_none() {
             self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
             return Err(AttributeServerError::SecurityManagerError);
         }
 
         let mut data = Data::new(&[SM_PAIRING_RANDOM]);
-        data.append(&self.nb.unwrap().0.to_le_bytes());
+        data.append(&pairing.nb.unwrap().0.to_le_bytes());
         self.write_sm(ble, src_handle, data).await;
 
         let na = Nonce(u128::from_le_bytes(random.try_into().unwrap()));
-        self.na = Some(na);
-        let nb = self.nb.unwrap();
+        pairing.na = Some(na);
+        let nb = pairing.nb.unwrap();
         let vb = na.g2(
-            self.pka.as_ref().unwrap().x(),
-            self.pkb.as_ref().unwrap().x(),
+            pairing.pka.as_ref().unwrap().x(),
+            pairing.pkb.as_ref().unwrap().x(),
             &nb,
         );
 
@@ -352,7 +345,7 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
         // Authentication stage 2 and long term key calculation
         // ([Vol 3] Part H, Section 2.3.5.6.5 and C.2.2.4).
 
-        let a = self.peer_address.unwrap();
+        let a = peer_address.unwrap();
         let b = self.local_address.unwrap();
         let ra = 0;
         log::trace!("a = {:02x?}", a.0);
@@ -360,56 +353,57 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
 
         let io_cap = IoCapability::DisplayYesNo as u8;
         let iob = IoCap::new(make_auth_req().0, false, io_cap);
-        let dh_key = self.dh_key.as_ref().unwrap();
+        let dh_key = pairing.dh_key.as_ref().unwrap();
 
         let (mac_key, ltk) = dh_key.f5(na, nb, a, b);
         let eb = mac_key.f6(nb, na, ra, iob, b, a);
 
-        self.mac_key = Some(mac_key);
+        pairing.mac_key = Some(mac_key);
+        pairing.ltk = Some(ltk.0);
         self.ltk = Some(ltk.0);
-        self.eb = Some(eb);
+        pairing.eb = Some(eb);
 
         Ok(())
     }
 
-    async fn handle_pairing_dhkey_check(&mut self, ble: &mut B, src_handle: u16, ea: &[u8]) -> Result<(), AttributeServerError> {
+    async fn handle_pairing_dhkey_check(&mut self, ble: &mut B, src_handle: u16, pairing: &mut PairingState, peer_address: Option<Addr>, ea: &[u8]) -> Result<(), AttributeServerError> {
         log::debug!("got dhkey_check {:02x?}", ea);
 
-        if *&(self.na).is_none() {
+        if pairing.na.is_none() {
             self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
             return Err(AttributeServerError::SecurityManagerError);
         }
 
-        if *&(self.nb).is_none() {
+        if pairing.nb.is_none() {
             self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
             return Err(AttributeServerError::SecurityManagerError);
         }
 
-        if *&(self.ioa).is_none() {
+        if pairing.ioa.is_none() {
             self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
             return Err(AttributeServerError::SecurityManagerError);
         }
 
-        if *&(self.peer_address).is_none() {
+        if peer_address.is_none() {
             self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
             return Err(AttributeServerError::SecurityManagerError);
         }
 
-        if *&(self.local_address).is_none() {
+        if self.local_address.is_none() {
             self.report_error(ble, src_handle, SecurityManagerError::UnspecifiedReason).await;
             return Err(AttributeServerError::SecurityManagerError);
         }
 
-        let expected = self
+        let expected = pairing
             .mac_key
             .as_ref()
             .unwrap()
             .f6(
-                self.na.unwrap(),
-                self.nb.unwrap(),
+                pairing.na.unwrap(),
+                pairing.nb.unwrap(),
                 0,
-                self.ioa.unwrap(),
-                self.peer_address.unwrap(),
+                pairing.ioa.unwrap(),
+                peer_address.unwrap(),
                 self.local_address.unwrap(),
             )
             .0
@@ -419,7 +413,7 @@ impl<'a, B, R> ASYNC AsyncSecurityManager<'a, B, R> where B: AsyncBleWriter, R:
         }
 
         let mut data = Data::new(&[SM_PAIRING_DHKEY_CHECK]);
-        data.append(&self.eb.as_ref().unwrap().0.to_le_bytes());
+        data.append(&pairing.eb.as_ref().unwrap().0.to_le_bytes());
         self.write_sm(ble, src_handle, data).await;
 
         Ok(())
@@ -0,0 +1,622 @@
+//! An in-memory controller to run two stacks against each other, e.g. in tests
+//!
+//! A [VirtualLink] provides two [VirtualController]s. Each of them answers the HCI commands
+//! sent by its host and implements [HciConnection] as well as `embedded_io_async::Read` and
+//! `Write`. When one side creates a connection while the other one advertises, both hosts
+//! get a connection complete event. ACL data is relayed to the peer and disconnection,
+//! connection updates and encryption are reported to both sides.
+//!
+//! ```ignore
+//! let link = VirtualLink::new();
+//! let (peripheral, central) = link.controllers();
+//! let mut peripheral = Ble::new(&peripheral);
+//! let mut central = Ble::new(&central);
+//! ```
+
+use core::cell::{Cell, RefCell};
+
+use crate::{capture::PacketAssembler, Data, HciConnection};
+
+/// Number of bytes buffered for each host
+pub const VIRTUAL_CONTROLLER_BUFFER_SIZE: usize = 2048;
+
+/// Number of ACL packets each controller reports to be able to buffer
+const ACL_BUFFERS: u8 = 8;
+/// Largest ACL payload each controller reports to accept
+const ACL_MAX_LEN: u16 = 251;
+/// LE features of each controller: encryption, connection parameters request, data length
+/// extension and LE 2M PHY
+const LE_FEATURES: u64 = 0x0123;
+/// Signal strength in dBm each controller reports for the connection
+const RSSI: i8 = -50;
+
+const STATUS_SUCCESS: u8 = 0x00;
+const STATUS_UNKNOWN_CONNECTION_IDENTIFIER: u8 = 0x02;
+const STATUS_PIN_OR_KEY_MISSING: u8 = 0x06;
+const STATUS_COMMAND_DISALLOWED: u8 = 0x0c;
+const REASON_LOCAL_HOST_TERMINATED: u8 = 0x16;
+
+const EVENT_DISCONNECTION_COMPLETE: u8 = 0x05;
+const EVENT_ENCRYPTION_CHANGE: u8 = 0x08;
+const EVENT_COMMAND_COMPLETE: u8 = 0x0e;
+const EVENT_COMMAND_STATUS: u8 = 0x0f;
+const EVENT_NUMBER_OF_COMPLETED_PACKETS: u8 = 0x13;
+const EVENT_LE_META: u8 = 0x3e;
+const EVENT_LE_META_CONNECTION_COMPLETE: u8 = 0x01;
+const EVENT_LE_META_CONNECTION_UPDATE_COMPLETE: u8 = 0x03;
+const EVENT_LE_META_READ_REMOTE_FEATURES_COMPLETE: u8 = 0x04;
+const EVENT_LE_META_LONG_TERM_KEY_REQUEST: u8 = 0x05;
+
+const OPCODE_DISCONNECT: u16 = 0x0406;
+const OPCODE_RESET: u16 = 0x0c03;
+const OPCODE_READ_LOCAL_VERSION_INFORMATION: u16 = 0x1001;
+const OPCODE_READ_BUFFER_SIZE: u16 = 0x1005;
+const OPCODE_READ_BD_ADDR: u16 = 0x1009;
+const OPCODE_READ_RSSI: u16 = 0x1405;
+const OPCODE_LE_READ_BUFFER_SIZE: u16 = 0x2002;
+const OPCODE_LE_READ_LOCAL_SUPPORTED_FEATURES: u16 = 0x2003;
+const OPCODE_LE_READ_ADVERTISING_PHYSICAL_CHANNEL_TX_POWER: u16 = 0x2007;
+const OPCODE_LE_SET_ADVERTISE_ENABLE: u16 = 0x200a;
+const OPCODE_LE_CREATE_CONNECTION: u16 = 0x200d;
+const OPCODE_LE_CREATE_CONNECTION_CANCEL: u16 = 0x200e;
+const OPCODE_LE_CONNECTION_UPDATE: u16 = 0x2013;
+const OPCODE_LE_READ_CHANNEL_MAP: u16 = 0x2015;
+const OPCODE_LE_READ_REMOTE_FEATURES: u16 = 0x2016;
+#[cfg(feature = "crypto")]
+const OPCODE_LE_ENCRYPT: u16 = 0x2017;
+const OPCODE_LE_RAND: u16 = 0x2018;
+const OPCODE_LE_START_ENCRYPTION: u16 = 0x2019;
+const OPCODE_LE_LONG_TERM_KEY_REQUEST_REPLY: u16 = 0x201a;
+const OPCODE_LE_LONG_TERM_KEY_REQUEST_NEGATIVE_REPLY: u16 = 0x201b;
+const OPCODE_LE_SET_EXTENDED_ADVERTISING_ENABLE: u16 = 0x2039;
+
+/// Bytes waiting to be read by a host
+struct ByteQueue {
+    buffer: [u8; VIRTUAL_CONTROLLER_BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl ByteQueue {
+    const fn new() -> Self {
+        Self {
+            buffer: [0u8; VIRTUAL_CONTROLLER_BUFFER_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        if self.len + bytes.len() > VIRTUAL_CONTROLLER_BUFFER_SIZE {
+            log::warn!("Virtual controller buffer full, dropping packet");
+            return;
+        }
+
+        for byte in bytes {
+            self.buffer[(self.start + self.len) % VIRTUAL_CONTROLLER_BUFFER_SIZE] = *byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.buffer[self.start];
+        self.start = (self.start + 1) % VIRTUAL_CONTROLLER_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// State of one of the two controllers
+struct Side {
+    address: [u8; 6],
+    to_host: ByteQueue,
+    from_host: PacketAssembler,
+    advertising: bool,
+    initiating: bool,
+    /// Whether legacy (`Some(true)`) or extended advertising commands were used since the
+    /// last reset, the other kind is disallowed ([Vol 4] Part E, Section 3.1.1)
+    legacy_advertising: Option<bool>,
+}
+
+impl Side {
+    const fn new(address: [u8; 6]) -> Self {
+        Self {
+            address,
+            to_host: ByteQueue::new(),
+            from_host: PacketAssembler::new(),
+            advertising: false,
+            initiating: false,
+            legacy_advertising: None,
+        }
+    }
+
+    fn event(&mut self, code: u8, params: &[u8]) {
+        self.to_host.push(&[0x04, code, params.len() as u8]);
+        self.to_host.push(params);
+    }
+
+    fn command_complete(&mut self, opcode: u16, return_params: &[u8]) {
+        let mut params = Data::new(&[1]);
+        params.append(&opcode.to_le_bytes());
+        params.append(return_params);
+        self.event(EVENT_COMMAND_COMPLETE, params.as_slice());
+    }
+
+    fn command_status(&mut self, opcode: u16, status: u8) {
+        let [lo, hi] = opcode.to_le_bytes();
+        self.event(EVENT_COMMAND_STATUS, &[status, 1, lo, hi]);
+    }
+
+    fn connection_complete(&mut self, status: u8, handle: u16, role: u8, peer: [u8; 6]) {
+        let mut params = Data::new(&[EVENT_LE_META_CONNECTION_COMPLETE, status]);
+        params.append(&handle.to_le_bytes());
+        params.append(&[role, 0x00]);
+        params.append(&peer);
+        // interval, latency, supervision timeout and clock accuracy
+        params.append(&[0x18, 0x00, 0x00, 0x00, 0xc8, 0x00, 0x00]);
+        self.event(EVENT_LE_META, params.as_slice());
+    }
+
+    fn disconnection_complete(&mut self, handle: u16, reason: u8) {
+        let [lo, hi] = handle.to_le_bytes();
+        self.event(
+            EVENT_DISCONNECTION_COMPLETE,
+            &[STATUS_SUCCESS, lo, hi, reason],
+        );
+    }
+
+    fn encryption_change(&mut self, status: u8, handle: u16, enabled: bool) {
+        let [lo, hi] = handle.to_le_bytes();
+        self.event(EVENT_ENCRYPTION_CHANGE, &[status, lo, hi, enabled as u8]);
+    }
+}
+
+/// `Some(true)` for legacy advertising commands, `Some(false)` for extended ones
+fn advertising_command_kind(opcode: u16) -> Option<bool> {
+    match opcode {
+        0x2006..=0x200a => Some(true),
+        0x2035..=0x203d => Some(false),
+        _ => None,
+    }
+}
+
+/// A connection between the two sides
+#[derive(Clone, Copy)]
+struct Link {
+    handle: u16,
+    central: usize,
+    /// LTK given by the central when starting encryption
+    ltk: Option<u128>,
+}
+
+struct LinkState {
+    sides: [Side; 2],
+    connection: Option<Link>,
+    next_handle: u16,
+    /// xorshift state for LE Rand, predictable and only meant for tests
+    rand_state: u64,
+}
+
+impl LinkState {
+    fn process_packet(&mut self, side: usize, packet: &[u8]) {
+        match packet {
+            [0x01, lo, hi, _, params @ ..] => {
+                self.process_command(side, u16::from_le_bytes([*lo, *hi]), params)
+            }
+            [0x02, lo, hi, _, _, ..] => {
+                self.process_acl(side, u16::from_le_bytes([*lo, *hi]) & 0x0fff, packet)
+            }
+            _ => log::warn!("Virtual controller ignores packet {:02x?}", packet),
+        }
+    }
+
+    fn process_command(&mut self, side: usize, opcode: u16, params: &[u8]) {
+        let peer = 1 - side;
+
+        if let Some(legacy) = advertising_command_kind(opcode) {
+            if *self.sides[side].legacy_advertising.get_or_insert(legacy) != legacy {
+                self.sides[side].command_complete(opcode, &[STATUS_COMMAND_DISALLOWED]);
+                return;
+            }
+        }
+
+        match opcode {
+            OPCODE_RESET => {
+                if let Some(link) = self.connection.take() {
+                    self.sides[peer].disconnection_complete(link.handle, 0x08);
+                }
+                self.sides[side].advertising = false;
+                self.sides[side].initiating = false;
+                self.sides[side].legacy_advertising = None;
+                self.sides[side].command_complete(opcode, &[STATUS_SUCCESS]);
+            }
+            OPCODE_READ_BD_ADDR => {
+                let mut res = [STATUS_SUCCESS; 7];
+                res[1..].copy_from_slice(&self.sides[side].address);
+                self.sides[side].command_complete(opcode, &res);
+            }
+            OPCODE_LE_READ_BUFFER_SIZE => {
+                let [lo, hi] = ACL_MAX_LEN.to_le_bytes();
+                self.sides[side].command_complete(opcode, &[STATUS_SUCCESS, lo, hi, ACL_BUFFERS]);
+            }
+            OPCODE_READ_BUFFER_SIZE => {
+                let [lo, hi] = ACL_MAX_LEN.to_le_bytes();
+                self.sides[side]
+                    .command_complete(opcode, &[STATUS_SUCCESS, lo, hi, 0, ACL_BUFFERS, 0, 0, 0]);
+            }
+            OPCODE_READ_LOCAL_VERSION_INFORMATION => {
+                // Core 5.3, no company identifier assigned
+                self.sides[side].command_complete(
+                    opcode,
+                    &[STATUS_SUCCESS, 0x0c, 0, 0, 0x0c, 0xff, 0xff, 0, 0],
+                );
+            }
+            OPCODE_LE_READ_LOCAL_SUPPORTED_FEATURES => {
+                let mut res = [STATUS_SUCCESS; 9];
+                res[1..].copy_from_slice(&LE_FEATURES.to_le_bytes());
+                self.sides[side].command_complete(opcode, &res);
+            }
+            OPCODE_LE_READ_ADVERTISING_PHYSICAL_CHANNEL_TX_POWER => {
+                self.sides[side].command_complete(opcode, &[STATUS_SUCCESS, 0]);
+            }
+            OPCODE_READ_RSSI => match self.link(params) {
+                Some(_) => self.sides[side]
+                    .command_complete(opcode, &[STATUS_SUCCESS, params[0], params[1], RSSI as u8]),
+                None => self.sides[side]
+                    .command_complete(opcode, &[STATUS_UNKNOWN_CONNECTION_IDENTIFIER]),
+            },
+            OPCODE_LE_READ_CHANNEL_MAP => match self.link(params) {
+                Some(_) => {
+                    // all 37 data channels are in use
+                    let map = [0xff, 0xff, 0xff, 0xff, 0x1f];
+                    let mut res = Data::new(&[STATUS_SUCCESS, params[0], params[1]]);
+                    res.append(&map);
+                    self.sides[side].command_complete(opcode, res.as_slice());
+                }
+                None => self.sides[side]
+                    .command_complete(opcode, &[STATUS_UNKNOWN_CONNECTION_IDENTIFIER]),
+            },
+            OPCODE_LE_READ_REMOTE_FEATURES => match self.link(params) {
+                Some(_) => {
+                    self.sides[side].command_status(opcode, STATUS_SUCCESS);
+
+                    let mut event = Data::new(&[
+                        EVENT_LE_META_READ_REMOTE_FEATURES_COMPLETE,
+                        STATUS_SUCCESS,
+                        params[0],
+                        params[1],
+                    ]);
+                    event.append(&LE_FEATURES.to_le_bytes());
+                    self.sides[side].event(EVENT_LE_META, event.as_slice());
+                }
+                None => {
+                    self.sides[side].command_status(opcode, STATUS_UNKNOWN_CONNECTION_IDENTIFIER)
+                }
+            },
+            OPCODE_LE_RAND => {
+                self.rand_state ^= self.rand_state << 13;
+                self.rand_state ^= self.rand_state >> 7;
+                self.rand_state ^= self.rand_state << 17;
+                let mut res = [STATUS_SUCCESS; 9];
+                res[1..].copy_from_slice(&self.rand_state.to_le_bytes());
+                self.sides[side].command_complete(opcode, &res);
+            }
+            #[cfg(feature = "crypto")]
+            OPCODE_LE_ENCRYPT if params.len() >= 32 => {
+                let key = u128::from_le_bytes(params[..16].try_into().unwrap());
+                let plaintext = u128::from_le_bytes(params[16..32].try_into().unwrap());
+                let mut res = [STATUS_SUCCESS; 17];
+                res[1..].copy_from_slice(&crate::crypto::aes128(key, plaintext).to_le_bytes());
+                self.sides[side].command_complete(opcode, &res);
+            }
+            OPCODE_LE_SET_ADVERTISE_ENABLE | OPCODE_LE_SET_EXTENDED_ADVERTISING_ENABLE => {
+                self.sides[side].advertising = params.first() == Some(&1);
+                self.sides[side].command_complete(opcode, &[STATUS_SUCCESS]);
+                self.try_connect();
+            }
+            OPCODE_LE_CREATE_CONNECTION => {
+                if self.connection.is_some() {
+                    self.sides[side].command_status(opcode, STATUS_COMMAND_DISALLOWED);
+                } else {
+                    self.sides[side].initiating = true;
+                    self.sides[side].command_status(opcode, STATUS_SUCCESS);
+                    self.try_connect();
+                }
+            }
+            OPCODE_LE_CREATE_CONNECTION_CANCEL => {
+                if self.sides[side].initiating {
+                    self.sides[side].initiating = false;
+                    self.sides[side].command_complete(opcode, &[STATUS_SUCCESS]);
+                    self.sides[side].connection_complete(0x02, 0, 0, [0u8; 6]);
+                } else {
+                    self.sides[side].command_complete(opcode, &[STATUS_COMMAND_DISALLOWED]);
+                }
+            }
+            OPCODE_DISCONNECT => match self.link(params) {
+                Some(link) => {
+                    self.connection = None;
+                    self.sides[side].command_status(opcode, STATUS_SUCCESS);
+                    self.sides[side]
+                        .disconnection_complete(link.handle, REASON_LOCAL_HOST_TERMINATED);
+                    let reason = params
+                        .get(2)
+                        .copied()
+                        .unwrap_or(REASON_LOCAL_HOST_TERMINATED);
+                    self.sides[peer].disconnection_complete(link.handle, reason);
+                }
+                None => {
+                    self.sides[side].command_status(opcode, STATUS_UNKNOWN_CONNECTION_IDENTIFIER)
+                }
+            },
+            OPCODE_LE_CONNECTION_UPDATE => match self.link(params) {
+                Some(_) if params.len() >= 14 => {
+                    self.sides[side].command_status(opcode, STATUS_SUCCESS);
+
+                    // handle, the maximum interval, latency and supervision timeout
+                    let mut event =
+                        Data::new(&[EVENT_LE_META_CONNECTION_UPDATE_COMPLETE, STATUS_SUCCESS]);
+                    event.append(&params[..2]);
+                    event.append(&params[4..10]);
+                    for side in self.sides.iter_mut() {
+                        side.event(EVENT_LE_META, event.as_slice());
+                    }
+                }
+                _ => self.sides[side].command_status(opcode, STATUS_UNKNOWN_CONNECTION_IDENTIFIER),
+            },
+            OPCODE_LE_START_ENCRYPTION => match self.link(params) {
+                Some(link) if link.central == side && params.len() >= 28 => {
+                    let ltk = u128::from_le_bytes(params[12..28].try_into().unwrap());
+                    self.connection = Some(Link {
+                        ltk: Some(ltk),
+                        ..link
+                    });
+                    self.sides[side].command_status(opcode, STATUS_SUCCESS);
+
+                    // handle, random and diversifier
+                    let mut event = Data::new(&[EVENT_LE_META_LONG_TERM_KEY_REQUEST]);
+                    event.append(&params[..12]);
+                    self.sides[peer].event(EVENT_LE_META, event.as_slice());
+                }
+                Some(_) => self.sides[side].command_status(opcode, STATUS_COMMAND_DISALLOWED),
+                None => {
+                    self.sides[side].command_status(opcode, STATUS_UNKNOWN_CONNECTION_IDENTIFIER)
+                }
+            },
+            OPCODE_LE_LONG_TERM_KEY_REQUEST_REPLY
+            | OPCODE_LE_LONG_TERM_KEY_REQUEST_NEGATIVE_REPLY => match self.link(params) {
+                Some(link) if link.central == peer => {
+                    self.sides[side]
+                        .command_complete(opcode, &[STATUS_SUCCESS, params[0], params[1]]);
+
+                    let ltk = params
+                        .get(2..18)
+                        .map(|ltk| u128::from_le_bytes(ltk.try_into().unwrap()));
+                    let matching = opcode == OPCODE_LE_LONG_TERM_KEY_REQUEST_REPLY
+                        && ltk.is_some()
+                        && ltk == link.ltk;
+                    let status = if matching {
+                        STATUS_SUCCESS
+                    } else {
+                        STATUS_PIN_OR_KEY_MISSING
+                    };
+
+                    self.sides[peer].encryption_change(status, link.handle, matching);
+                    if matching {
+                        self.sides[side].encryption_change(status, link.handle, true);
+                    }
+                }
+                _ => self.sides[side]
+                    .command_complete(opcode, &[STATUS_UNKNOWN_CONNECTION_IDENTIFIER]),
+            },
+            _ => {
+                log::debug!("Virtual controller accepts command {:04x}", opcode);
+                self.sides[side].command_complete(opcode, &[STATUS_SUCCESS]);
+            }
+        }
+    }
+
+    /// The connection addressed by the handle at the start of the parameters
+    fn link(&self, params: &[u8]) -> Option<Link> {
+        let handle = u16::from_le_bytes([*params.first()?, *params.get(1)?]);
+        self.connection.filter(|link| link.handle == handle)
+    }
+
+    fn try_connect(&mut self) {
+        if self.connection.is_some() {
+            return;
+        }
+
+        for central in 0..2 {
+            let peripheral = 1 - central;
+            if self.sides[central].initiating && self.sides[peripheral].advertising {
+                let handle = self.next_handle;
+                self.next_handle += 1;
+                self.connection = Some(Link {
+                    handle,
+                    central,
+                    ltk: None,
+                });
+                self.sides[central].initiating = false;
+                self.sides[peripheral].advertising = false;
+
+                let central_address = self.sides[central].address;
+                let peripheral_address = self.sides[peripheral].address;
+                self.sides[central].connection_complete(
+                    STATUS_SUCCESS,
+                    handle,
+                    0x00,
+                    peripheral_address,
+                );
+                self.sides[peripheral].connection_complete(
+                    STATUS_SUCCESS,
+                    handle,
+                    0x01,
+                    central_address,
+                );
+                return;
+            }
+        }
+    }
+
+    fn process_acl(&mut self, side: usize, handle: u16, packet: &[u8]) {
+        if !matches!(self.connection, Some(link) if link.handle == handle) {
+            log::warn!(
+                "Virtual controller drops ACL data for unknown handle {}",
+                handle
+            );
+            return;
+        }
+
+        let mut relayed = Data::new(packet);
+        // the first fragment is always flushable from controller to host
+        if packet[2] & 0x30 == 0x00 {
+            relayed.set(2, packet[2] | 0x20);
+        }
+        self.sides[1 - side].to_host.push(relayed.as_slice());
+
+        let [lo, hi] = handle.to_le_bytes();
+        self.sides[side].event(EVENT_NUMBER_OF_COMPLETED_PACKETS, &[1, lo, hi, 1, 0]);
+    }
+}
+
+/// Two connected virtual controllers
+pub struct VirtualLink {
+    state: RefCell<LinkState>,
+    millis: Cell<u64>,
+}
+
+impl VirtualLink {
+    pub const fn new() -> Self {
+        Self {
+            state: RefCell::new(LinkState {
+                sides: [
+                    Side::new([0x01, 0x00, 0x00, 0x00, 0x00, 0xc0]),
+                    Side::new([0x02, 0x00, 0x00, 0x00, 0x00, 0xc0]),
+                ],
+                connection: None,
+                next_handle: 1,
+                rand_state: 0x2545_f491_4f6c_dd1d,
+            }),
+            millis: Cell::new(0),
+        }
+    }
+
+    /// The controllers of both sides of the link
+    pub fn controllers(&self) -> (VirtualController<'_>, VirtualController<'_>) {
+        (
+            VirtualController {
+                link: self,
+                side: 0,
+            },
+            VirtualController {
+                link: self,
+                side: 1,
+            },
+        )
+    }
+
+    /// Handle of the current connection
+    pub fn connection_handle(&self) -> Option<u16> {
+        self.state.borrow().connection.map(|link| link.handle)
+    }
+
+    /// Virtual time, advancing by a millisecond every time it's read
+    pub fn millis(&self) -> u64 {
+        let millis = self.millis.get() + 1;
+        self.millis.set(millis);
+        millis
+    }
+}
+
+impl Default for VirtualLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One side of a [VirtualLink]
+pub struct VirtualController<'a> {
+    link: &'a VirtualLink,
+    side: usize,
+}
+
+impl<'a> VirtualController<'a> {
+    /// Public address of this controller, in little-endian byte order
+    pub fn address(&self) -> [u8; 6] {
+        self.link.state.borrow().sides[self.side].address
+    }
+
+    fn write_bytes(&self, bytes: &[u8]) {
+        let mut state = self.link.state.borrow_mut();
+        for byte in bytes {
+            let side = &mut state.sides[self.side];
+            if let Some((packet, _)) = side.from_host.push(*byte) {
+                let packet = Data::new(packet);
+                state.process_packet(self.side, packet.as_slice());
+            }
+        }
+    }
+}
+
+impl<'a> HciConnection for VirtualController<'a> {
+    fn read(&self) -> Option<u8> {
+        self.link.state.borrow_mut().sides[self.side].to_host.pop()
+    }
+
+    fn write(&self, data: u8) {
+        self.write_bytes(&[data]);
+    }
+
+    fn millis(&self) -> u64 {
+        self.link.millis()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a> embedded_io_async::ErrorType for VirtualController<'a> {
+    type Error = core::convert::Infallible;
+}
+
+#[cfg(feature = "async")]
+impl<'a> embedded_io_async::Read for VirtualController<'a> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        // yield to other tasks, e.g. the peer's stack, until there is something to read
+        core::future::poll_fn(|cx| {
+            let mut state = self.link.state.borrow_mut();
+            let to_host = &mut state.sides[self.side].to_host;
+
+            let mut len = 0;
+            while len < buf.len() {
+                let Some(byte) = to_host.pop() else {
+                    break;
+                };
+                buf[len] = byte;
+                len += 1;
+            }
+
+            if len == 0 && !buf.is_empty() {
+                cx.waker().wake_by_ref();
+                core::task::Poll::Pending
+            } else {
+                core::task::Poll::Ready(Ok(len))
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a> embedded_io_async::Write for VirtualController<'a> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.write_bytes(buf);
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
 #![feature(assert_matches)]
 
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{assert_matches::assert_matches, cell::RefCell};
 
 extern crate std;
@@ -8,21 +10,47 @@ use bleps::ad_structure::AdvertisementDataError;
 use bleps::{
     acl::{AclPacket, BoundaryFlag, ControllerBroadcastFlag, HostBroadcastFlag},
     ad_structure::{
-        create_advertising_data, AdStructure, BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE,
+        create_advertising_data, create_extended_advertising_data, AdStructure,
+        BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE,
+    },
+    att::{
+        Att, AttDecodeError, AttErrorCode, AttResponse, Uuid, ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE,
     },
-    att::{Att, AttErrorCode, Uuid, ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE},
     attribute::Attribute,
-    attribute_server::{AttributeServer, CHARACTERISTIC_UUID16, PRIMARY_SERVICE_UUID16},
-    command::{Command, CommandHeader},
-    event::{ErrorCode, EventType},
-    l2cap::L2capPacket,
-    Ble, Data, HciConnection, PollResult,
+    attribute_server::{
+        AttributeServer, NotificationData, WorkResult, CHARACTERISTIC_UUID16,
+        CLIENT_CHARACTERISTIC_CONFIGURATION_UUID16, MAX_PREPARED_WRITES, PRIMARY_SERVICE_UUID16,
+    },
+    capture::{CaptureFormat, HciCapture},
+    command::{
+        Command, CommandHeader, CommandTimeouts, CustomCommand, ReturnParameters,
+        DEFAULT_COMMAND_TIMEOUT_MILLIS, MAX_COMMAND_TIMEOUTS, VENDOR_OGF,
+    },
+    connection::{
+        CodedPhyOptions, Connection, ConnectionUpdateParameters, CreateConnectionParameters,
+        DataLength, Role, PHY_LE_1M, PHY_LE_2M, PHY_LE_CODED,
+    },
+    controller_info::{ChannelMap, LeFeature, LeFeatures, LinkQuality, LocalVersion},
+    event::{AddressType, AdvertisingReportType, ErrorCode, EventMask, EventType, LeEventMask},
+    extended_advertising::{AdvertisingSet, ExtendedAdvertisingParameters},
+    gatt_client::{GattClient, GattClientError, Service},
+    h4::H4Transport,
+    l2cap::{L2capDecodeError, L2capPacket},
+    l2cap_channel::{L2capChannel, L2capChannelError},
+    recovery::{ControllerFault, MAX_COMMAND_TIMEOUTS_IN_A_ROW},
+    signaling::{
+        CreditBasedConnectionResult, RejectReason, SignalingCommand, SignalingDecodeError,
+        LE_SIGNALING_CHANNEL,
+    },
+    virtual_controller::VirtualLink,
+    Addr, AddressKind, Ble, Data, HciConnection, HciConnector, PeerAddressType, PollResult,
+    ScanParameters, ScanType,
 };
 use p256::elliptic_curve::rand_core::OsRng;
 
 struct TestConnector {
-    to_read: RefCell<[u8; 128]>,
-    to_write: RefCell<[u8; 128]>,
+    to_read: RefCell<[u8; 512]>,
+    to_write: RefCell<[u8; 512]>,
     read_idx: RefCell<usize>,
     read_max: RefCell<usize>,
     write_idx: RefCell<usize>,
@@ -111,8 +139,8 @@ impl HciConnection for TestConnector {
 
 fn connector() -> TestConnector {
     TestConnector {
-        to_read: RefCell::new([0u8; 128]),
-        to_write: RefCell::new([0u8; 128]),
+        to_read: RefCell::new([0u8; 512]),
+        to_write: RefCell::new([0u8; 512]),
         read_idx: RefCell::new(0),
         read_max: RefCell::new(0),
         write_idx: RefCell::new(0),
@@ -121,6 +149,34 @@ fn connector() -> TestConnector {
     }
 }
 
+/// CommandComplete for Read Local Version Information (5.3) and LE Read Local Supported Features
+/// (encryption, connection parameters request, data length extension and LE 2M PHY), which
+/// finish `Ble::init`
+fn provide_controller_info(connector: &TestConnector) {
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x0c, 0x05, 0x01, 0x10, 0x00, 0x0c, 0x34, 0x12, 0x0c, 0x59, 0x00, 0x78, 0x56,
+        0x04, 0x0e, 0x0c, 0x05, 0x03, 0x20, 0x00, 0x23, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ]);
+}
+
+/// CommandComplete for all commands sent by `Ble::init`
+fn provide_init_responses(connector: &TestConnector) {
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x01, 0x0c, 0x00, 0x04,
+        0x0e, 0x04, 0x05, 0x01, 0x20, 0x00, 0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0xfb, 0x00,
+        0x02,
+    ]);
+    provide_controller_info(connector);
+}
+
+/// Let the next command time out, see `init_fails_timeout`
+fn time_out_next_command(connector: &TestConnector) {
+    let idx = connector.get_current_millis_idx();
+    connector.set_current_millis_at(idx, 0);
+    connector.set_current_millis_at(idx + 1, 100);
+    connector.set_current_millis_at(idx + 2, 2000);
+}
+
 #[test]
 fn testing_will_work() {
     let connector = connector();
@@ -160,31 +216,87 @@ fn init_works() {
     let mut ble = Ble::new(&connector);
 
     connector.provide_data_to_read(&[
-        0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x01, 0x0c, 0x00,
+        0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x01, 0x0c, 0x00, 0x04,
+        0x0e, 0x04, 0x05, 0x01, 0x20, 0x00, 0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0xfb, 0x00,
+        0x02,
     ]);
+    provide_controller_info(&connector);
 
     let res = ble.init();
 
     assert_matches!(res, Ok(()));
 
-    assert_eq!(connector.get_write_idx(), 16);
+    assert_eq!(connector.get_write_idx(), 40);
     assert_eq!(connector.get_to_write_at(0), 0x01);
     assert_eq!(connector.get_to_write_at(1), 0x03);
     assert_eq!(connector.get_to_write_at(2), 0x0c);
     assert_eq!(connector.get_to_write_at(3), 0x00);
 
-    assert_eq!(connector.get_to_write_at(4), 0x01);
-    assert_eq!(connector.get_to_write_at(5), 0x01);
-    assert_eq!(connector.get_to_write_at(6), 0x0c);
-    assert_eq!(connector.get_to_write_at(7), 0x08);
-    assert_eq!(connector.get_to_write_at(8), 0xff);
-    assert_eq!(connector.get_to_write_at(9), 0xff);
-    assert_eq!(connector.get_to_write_at(10), 0xff);
-    assert_eq!(connector.get_to_write_at(11), 0xff);
-    assert_eq!(connector.get_to_write_at(12), 0xff);
-    assert_eq!(connector.get_to_write_at(13), 0xff);
-    assert_eq!(connector.get_to_write_at(14), 0xff);
-    assert_eq!(connector.get_to_write_at(15), 0xff);
+    // disconnection complete, encryption change, hardware error, data buffer overflow,
+    // encryption key refresh complete and LE meta
+    assert_eq!(
+        (4..16)
+            .map(|i| connector.get_to_write_at(i))
+            .collect::<Vec<u8>>(),
+        [0x01, 0x01, 0x0c, 0x08, 0x90, 0x80, 0x00, 0x02, 0x00, 0x80, 0x00, 0x20]
+    );
+
+    // all LE meta events the stack handles
+    assert_eq!(
+        (16..28)
+            .map(|i| connector.get_to_write_at(i))
+            .collect::<Vec<u8>>(),
+        [0x01, 0x01, 0x20, 0x08, 0x7f, 0x0a, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00]
+    );
+
+    assert_eq!(connector.get_to_write_at(28), 0x01);
+    assert_eq!(connector.get_to_write_at(29), 0x02);
+    assert_eq!(connector.get_to_write_at(30), 0x20);
+    assert_eq!(connector.get_to_write_at(31), 0x00);
+
+    assert_eq!(
+        (32..40)
+            .map(|i| connector.get_to_write_at(i))
+            .collect::<Vec<u8>>(),
+        [0x01, 0x01, 0x10, 0x00, 0x01, 0x03, 0x20, 0x00]
+    );
+
+    let info = ble.controller_info();
+    assert_eq!(
+        info.version,
+        LocalVersion {
+            hci_version: 0x0c,
+            hci_subversion: 0x1234,
+            lmp_version: 0x0c,
+            company_identifier: 0x0059,
+            lmp_subversion: 0x5678,
+        }
+    );
+    assert!(info.le_features.supports(LeFeature::Encryption));
+    assert!(info.le_features.supports(LeFeature::Le2mPhy));
+    assert!(!info.le_features.supports(LeFeature::LeCodedPhy));
+}
+
+#[test]
+fn event_masks_are_built() {
+    let events = EventMask::connections();
+    assert!(events.contains(EventMask::LE_META | EventMask::DISCONNECTION_COMPLETE));
+    assert!(!events.contains(EventMask::ENCRYPTION_CHANGE));
+    assert_eq!(events.with_encryption(), EventMask::default());
+
+    let le_events = LeEventMask::connections().with_data_length().with_phy();
+    assert!(le_events.contains(LeEventMask::CONNECTION_COMPLETE));
+    assert!(le_events.contains(LeEventMask::DATA_LENGTH_CHANGE));
+    assert!(!le_events.contains(LeEventMask::ADVERTISING_REPORT));
+    assert!(!le_events
+        .without(LeEventMask::PHY_UPDATE_COMPLETE)
+        .contains(LeEventMask::PHY_UPDATE_COMPLETE));
+
+    let data = Command::LeSetEventMask { events: le_events }.encode();
+    assert_eq!(
+        data.as_slice(),
+        &[0x01, 0x01, 0x20, 0x08, 0x6d, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+    );
 }
 
 #[test]
@@ -198,10 +310,45 @@ fn init_fails_timeout() {
 
     let res = ble.init();
 
-    assert_matches!(res, Err(bleps::Error::Timeout));
+    assert_matches!(res, Err(bleps::Error::CommandTimeout { opcode: 0x0c03 }));
     assert_eq!(connector.get_current_millis_idx(), 3);
 }
 
+#[test]
+fn commands_use_their_own_timeout() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    assert!(ble.command_timeouts_mut().set(0x0c03, 5000));
+
+    connector.set_current_millis_at(0, 0);
+    connector.set_current_millis_at(1, 100);
+    connector.set_current_millis_at(2, 2000);
+    connector.set_current_millis_at(3, 6000);
+
+    let res = ble.cmd_reset();
+
+    let err = res.unwrap_err();
+    assert_matches!(err, bleps::Error::CommandTimeout { opcode: 0x0c03 });
+    assert_eq!(err.opcode(), Some(0x0c03));
+    assert_eq!(connector.get_current_millis_idx(), 4);
+}
+
+#[test]
+fn command_timeouts_are_limited() {
+    let mut timeouts = CommandTimeouts::new();
+    for opcode in 0..MAX_COMMAND_TIMEOUTS as u16 {
+        assert!(timeouts.set(opcode, 10 + opcode as u64));
+    }
+    assert!(!timeouts.set(0x0c03, 5000));
+    assert!(timeouts.set(1, 100));
+    assert_eq!(timeouts.get(1), 100);
+
+    timeouts.remove(1);
+    assert_eq!(timeouts.get(1), DEFAULT_COMMAND_TIMEOUT_MILLIS);
+    assert!(timeouts.set(0x0c03, 5000));
+    assert_eq!(timeouts.get(0x0c03), 5000);
+}
+
 #[test]
 fn init_fails() {
     let connector = connector();
@@ -211,7 +358,13 @@ fn init_fails() {
 
     let res = ble.init();
 
-    assert_matches!(res, Err(bleps::Error::Failed(255)));
+    assert_matches!(
+        res,
+        Err(bleps::Error::CommandFailed {
+            opcode: 0x0c03,
+            status: 255
+        })
+    );
 
     assert_eq!(connector.get_write_idx(), 4);
     assert_eq!(connector.get_to_write_at(0), 0x01);
@@ -258,11 +411,400 @@ pub fn command_header_set_adv_param_works() {
     assert_eq!(header.len, 0x0f);
 }
 
+#[test]
+fn controller_faults_are_detected() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // a successful command starts counting again
+    for _ in 0..MAX_COMMAND_TIMEOUTS_IN_A_ROW - 1 {
+        time_out_next_command(&connector);
+        assert_matches!(
+            ble.cmd_reset(),
+            Err(bleps::Error::CommandTimeout { opcode: 0x0c03 })
+        );
+    }
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00]);
+    assert_matches!(ble.cmd_reset(), Ok(_));
+    for _ in 0..MAX_COMMAND_TIMEOUTS_IN_A_ROW - 1 {
+        time_out_next_command(&connector);
+        assert_matches!(
+            ble.cmd_reset(),
+            Err(bleps::Error::CommandTimeout { opcode: 0x0c03 })
+        );
+    }
+    assert_eq!(ble.fault(), None);
+
+    time_out_next_command(&connector);
+    assert_matches!(
+        ble.cmd_reset(),
+        Err(bleps::Error::CommandTimeout { opcode: 0x0c03 })
+    );
+    assert_eq!(ble.fault(), Some(ControllerFault::CommandTimeouts));
+
+    // HardwareError { code: 0x2a }
+    connector.provide_data_to_read(&[0x04, 0x10, 0x01, 0x2a]);
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::HardwareError { code: 0x2a }))
+    );
+    assert_eq!(ble.fault(), Some(ControllerFault::HardwareError(0x2a)));
+
+    // DataBufferOverflow { link_type: ACL }
+    connector.provide_data_to_read(&[0x04, 0x1a, 0x01, 0x01]);
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::DataBufferOverflow { .. }))
+    );
+    assert_eq!(ble.fault(), Some(ControllerFault::DataBufferOverflow));
+}
+
+#[test]
+fn ble_recovers_after_fault() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x0a, 0x20, 0x00]);
+    assert_matches!(ble.cmd_set_le_advertise_enable(true), Ok(_));
+    connector.provide_data_to_read(&[0x04, 0x10, 0x01, 0x2a]);
+    assert_matches!(ble.poll(), Some(_));
+    assert_eq!(ble.fault(), Some(ControllerFault::HardwareError(0x2a)));
+
+    connector.reset();
+    provide_init_responses(&connector);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x0a, 0x20, 0x00]);
+    assert_matches!(ble.recover(), Ok(()));
+    assert_eq!(ble.fault(), None);
+
+    // init again, then advertising is enabled again
+    let written = connector.get_written_data();
+    assert_eq!(written.len(), 45);
+    assert_eq!(&written.as_slice()[..4], &[0x01, 0x03, 0x0c, 0x00]);
+    assert_eq!(&written.as_slice()[40..], &[0x01, 0x0a, 0x20, 0x01, 0x01]);
+}
+
+#[test]
+fn rejected_advertising_commands_are_not_restored() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // Command Disallowed
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x0a, 0x20, 0x0c]);
+    assert_matches!(
+        ble.cmd_set_le_advertise_enable(true),
+        Err(bleps::Error::CommandFailed {
+            opcode: 0x200a,
+            status: 0x0c
+        })
+    );
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x36, 0x20, 0x0c]);
+    assert_matches!(
+        ble.cmd_set_le_extended_advertising_parameters(
+            &ExtendedAdvertisingParameters::connectable(0)
+        ),
+        Err(bleps::Error::CommandFailed { .. })
+    );
+    connector.provide_data_to_read(&[0x04, 0x10, 0x01, 0x2a]);
+    assert_matches!(ble.poll(), Some(_));
+
+    connector.reset();
+    provide_init_responses(&connector);
+    assert_matches!(ble.recover(), Ok(()));
+
+    // only init is repeated
+    assert_eq!(connector.get_written_data().len(), 40);
+}
+
+#[test]
+fn ble_restores_extended_advertising_after_fault() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x36, 0x20, 0x00]);
+    assert_matches!(
+        ble.cmd_set_le_extended_advertising_parameters(
+            &ExtendedAdvertisingParameters::connectable(0)
+        ),
+        Ok(_)
+    );
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x37, 0x20, 0x00]);
+    assert_matches!(
+        ble.cmd_set_le_extended_advertising_data(0, Data::new(&[0x02, 0x01, 0x06])),
+        Ok(_)
+    );
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x39, 0x20, 0x00]);
+    assert_matches!(
+        ble.cmd_set_le_extended_advertising_enable(true, &[AdvertisingSet::new(0)]),
+        Ok(_)
+    );
+    let parameters = connector.get_written_data().as_slice()[4..29].to_vec();
+    connector.provide_data_to_read(&[0x04, 0x10, 0x01, 0x2a]);
+    assert_matches!(ble.poll(), Some(_));
+
+    connector.reset();
+    provide_init_responses(&connector);
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x04, 0x05, 0x36, 0x20, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x37, 0x20, 0x00, 0x04,
+        0x0e, 0x04, 0x05, 0x39, 0x20, 0x00,
+    ]);
+    assert_matches!(ble.recover(), Ok(()));
+
+    // init again, then the set is configured and enabled again
+    let written = connector.get_written_data();
+    let written = &written.as_slice()[40..];
+    assert_eq!(&written[..4], &[0x01, 0x36, 0x20, 0x19]);
+    assert_eq!(&written[4..29], parameters.as_slice());
+    assert_eq!(
+        &written[29..40],
+        &[0x01, 0x37, 0x20, 0x07, 0x00, 0x03, 0x01, 0x03, 0x02, 0x01, 0x06]
+    );
+    assert_eq!(
+        &written[40..],
+        &[0x01, 0x39, 0x20, 0x06, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00]
+    );
+}
+
+#[test]
+fn data_headers_are_prepended_and_stripped_in_place() {
+    let mut data = Data::new(&[1, 2, 3]);
+    data.as_slice_mut()[0] = 0x10;
+    assert_eq!(data.as_slice(), &[0x10, 2, 3]);
+
+    data.prepend(&[0xaa, 0xbb]);
+    assert_eq!(data.as_slice(), &[0xaa, 0xbb, 0x10, 2, 3]);
+
+    data.advance(2);
+    assert_eq!(data.as_slice(), &[0x10, 2, 3]);
+
+    data.spare_capacity_mut()[0] = 4;
+    data.append_len(1);
+    assert_eq!(data.as_slice(), &[0x10, 2, 3, 4]);
+}
+
+#[test]
+fn data_with_custom_capacity_works() {
+    let mut data = Data::<8>::from_bytes(&[1, 2, 3, 4]);
+    assert_eq!(data.capacity(), 8);
+
+    // without headroom the payload is moved to make space for the header
+    data.prepend(&[0xaa]);
+    assert_eq!(data.as_slice(), &[0xaa, 1, 2, 3, 4]);
+
+    data.append(&[5, 6, 7]);
+    assert_eq!(data.as_slice(), &[0xaa, 1, 2, 3, 4, 5, 6, 7]);
+}
+
+struct VecSink(Vec<u8>);
+
+impl embedded_io_blocking::ErrorType for VecSink {
+    type Error = core::convert::Infallible;
+}
+
+impl embedded_io_blocking::Write for VecSink {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn btsnoop_capture_works() {
+    let connector = connector();
+    connector.set_current_millis_at(0, 5);
+    let capture = HciCapture::new(&connector, VecSink(Vec::new()), CaptureFormat::BtSnoop);
+    let mut ble = Ble::new(&capture);
+
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00]);
+    ble.cmd_reset().unwrap();
+
+    let capture = capture.into_inner().0;
+    assert_eq!(&capture[..16], b"btsnoop\0\0\0\0\x01\0\0\x03\xea");
+
+    // the reset command
+    let record = &capture[16..];
+    assert_eq!(&record[..12], &[0, 0, 0, 4, 0, 0, 0, 4, 0, 0, 0, 2]);
+    assert_eq!(
+        u64::from_be_bytes(record[16..24].try_into().unwrap()),
+        0x00dc_ddb3_0f2f_8000 + 5000
+    );
+    assert_eq!(&record[24..28], &[0x01, 0x03, 0x0c, 0x00]);
+
+    // the command complete event
+    let record = &record[28..];
+    assert_eq!(&record[..12], &[0, 0, 0, 7, 0, 0, 0, 7, 0, 0, 0, 3]);
+    assert_eq!(&record[24..], &[0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00]);
+}
+
+#[test]
+fn pcap_capture_works() {
+    let connector = connector();
+    let capture = HciCapture::new(&connector, VecSink(Vec::new()), CaptureFormat::Pcap);
+
+    // an ACL packet from the controller
+    connector.provide_data_to_read(&[0x02, 0x00, 0x20, 0x01, 0x00, 0xaa]);
+    while capture.read().is_some() {}
+
+    let capture = capture.into_inner().0;
+    assert_eq!(capture.len(), 24 + 16 + 4 + 6);
+    assert_eq!(&capture[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+    assert_eq!(&capture[20..24], &[201, 0, 0, 0]);
+
+    let record = &capture[24..];
+    assert_eq!(&record[8..16], &[10, 0, 0, 0, 10, 0, 0, 0]);
+    assert_eq!(
+        &record[16..],
+        &[0, 0, 0, 1, 0x02, 0x00, 0x20, 0x01, 0x00, 0xaa]
+    );
+}
+
+/// A serial port delivering the received bytes in small chunks
+struct MockSerial {
+    rx: Vec<u8>,
+    rx_pos: usize,
+    tx: Vec<u8>,
+}
+
+impl MockSerial {
+    fn new(rx: &[u8]) -> Self {
+        Self {
+            rx: rx.to_vec(),
+            rx_pos: 0,
+            tx: Vec::new(),
+        }
+    }
+}
+
+impl embedded_io_blocking::ErrorType for MockSerial {
+    type Error = core::convert::Infallible;
+}
+
+impl embedded_io_blocking::Read for MockSerial {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = usize::min(usize::min(buf.len(), 3), self.rx.len() - self.rx_pos);
+        buf[..len].copy_from_slice(&self.rx[self.rx_pos..][..len]);
+        self.rx_pos += len;
+        Ok(len)
+    }
+}
+
+impl embedded_io_blocking::ReadReady for MockSerial {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.rx_pos < self.rx.len())
+    }
+}
+
+#[cfg(feature = "async")]
+impl embedded_io_async::Read for MockSerial {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        embedded_io_blocking::Read::read(self, buf)
+    }
+}
+
+impl embedded_io_blocking::Write for MockSerial {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.tx.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+fn no_millis() -> u64 {
+    0
+}
+
+#[test]
+fn h4_transport_skips_garbage() {
+    // boot message, the handshake byte and an event with an invalid event code
+    let serial = MockSerial::new(&[
+        b'b', b'o', b'o', b't', b'\n', 0xff, 0x04, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00,
+    ]);
+    let transport = H4Transport::new(serial, no_millis);
+    let mut ble = Ble::new(&transport);
+
+    let res = ble.cmd_reset();
+    assert_matches!(res, Ok(EventType::CommandComplete { opcode: 0x0c03, .. }));
+    assert_eq!(transport.dropped_bytes(), 8);
+    assert_eq!(transport.into_inner().tx, [0x01, 0x03, 0x0c, 0x00]);
+}
+
+#[test]
+fn h4_transport_frames_acl_packets() {
+    let serial = MockSerial::new(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
+        0x28, 0x04, 0x13, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00,
+    ]);
+    let transport = H4Transport::new(serial, no_millis);
+    let mut ble = Ble::new(&transport);
+
+    assert_matches!(ble.poll(), Some(PollResult::AsyncData(packet)) if packet.data.len() == 11);
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(
+            EventType::NumberOfCompletedPackets { .. }
+        ))
+    );
+    assert_matches!(ble.poll(), None);
+    assert_eq!(transport.dropped_bytes(), 0);
+}
+
+#[test]
+fn h4_transport_drops_incomplete_packets() {
+    static MILLIS: AtomicU64 = AtomicU64::new(0);
+    fn millis() -> u64 {
+        MILLIS.fetch_add(50, Ordering::Relaxed)
+    }
+
+    let serial = MockSerial::new(&[0x04, 0x0e, 0x04, 0x05]);
+    let transport = H4Transport::new(serial, millis);
+
+    assert_eq!(transport.read(), None);
+    assert_eq!(transport.dropped_bytes(), 4);
+}
+
+#[test]
+fn h4_transport_drops_oversized_acl_packets() {
+    // an ACL packet longer than the largest L2CAP frame, followed by an event
+    let serial = MockSerial::new(&[
+        0x02, 0x00, 0x20, 0x05, 0x01, 0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00,
+    ]);
+    let transport = H4Transport::new(serial, no_millis);
+    let mut ble = Ble::new(&transport);
+
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::CommandComplete {
+            opcode: 0x0c03,
+            ..
+        }))
+    );
+    assert_eq!(transport.dropped_bytes(), 5);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_h4_transport_returns_at_end_of_stream() {
+    let mut transport = H4Transport::new(MockSerial::new(&[0x04, 0x0e, 0x04, 0x05]), no_millis);
+
+    let mut buf = [0u8; 8];
+    assert_matches!(
+        block_on(embedded_io_async::Read::read(&mut transport, &mut buf)),
+        Ok(0)
+    );
+    assert_eq!(transport.dropped_bytes(), 4);
+}
+
 #[test]
 fn create_reset_command_works() {
     let data = Command::Reset.encode();
     assert_eq!(data.len, 4);
-    assert_eq!(data.data[0..4], [0x01, 0x03, 0x0c, 0x00]);
+    assert_eq!(data.as_slice()[0..4], [0x01, 0x03, 0x0c, 0x00]);
 }
 
 #[test]
@@ -270,7 +812,7 @@ fn create_le_set_advertising_parameters_works() {
     let data = Command::LeSetAdvertisingParameters.encode();
     assert_eq!(data.len, 19);
     assert_eq!(
-        data.data[..19],
+        data.as_slice()[..19],
         [0x01, 0x06, 0x20, 0x0f, 0x00, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7, 0]
     );
 }
@@ -294,7 +836,10 @@ fn create_le_set_advertising_data_works() {
     }
     .encode();
     assert_eq!(data.len, 9);
-    assert_eq!(data.data[..9], [0x01, 0x08, 0x20, 0x05, 1, 2, 3, 4, 5]);
+    assert_eq!(
+        data.as_slice()[..9],
+        [0x01, 0x08, 0x20, 0x05, 1, 2, 3, 4, 5]
+    );
 }
 
 #[test]
@@ -313,7 +858,7 @@ fn le_set_advertising_data_works() {
 fn create_le_set_advertise_enable_works() {
     let data = Command::LeSetAdvertiseEnable(true).encode();
     assert_eq!(data.len, 5);
-    assert_eq!(data.data[..5], [0x01, 0x0a, 0x20, 0x01, 0x01]);
+    assert_eq!(data.as_slice()[..5], [0x01, 0x0a, 0x20, 0x01, 0x01]);
 }
 
 #[test]
@@ -329,429 +874,3496 @@ fn le_set_advertise_enable_works() {
 }
 
 #[test]
-fn receiving_async_data_works() {
-    let connector = connector();
-    let mut ble = Ble::new(&connector);
-
-    connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
-        0x28,
-    ]);
-
-    let res = ble.poll();
-
-    assert_matches!(res,
-        Some(PollResult::AsyncData(AclPacket {
-            handle: 0,
-            boundary_flag: BoundaryFlag::FirstAutoFlushable,
-            bc_flag: ControllerBroadcastFlag::PointToPoint,
-            data,
-        })) if data.as_slice() == &[0x7, 0x0, 0x4, 0x0, 0x10, 0x1, 0x0, 0xff, 0xff, 0x0, 0x28]
+fn create_le_set_scan_parameters_works() {
+    let params = ScanParameters {
+        scan_type: ScanType::Active,
+        scan_interval: 0x0060,
+        scan_window: 0x0030,
+        ..Default::default()
+    };
+    let data = Command::LeSetScanParameters(&params).encode();
+    assert_eq!(
+        data.as_slice(),
+        &[0x01, 0x0b, 0x20, 0x07, 0x01, 0x60, 0x00, 0x30, 0x00, 0x00, 0x00]
     );
 }
 
 #[test]
-fn receiving_disconnection_complete_works() {
+fn le_set_scan_enable_works() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
-    connector.provide_data_to_read(&[0x04, 0x05, 0x04, 0x00, 0x00, 0x00, 0x13]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x0c, 0x20, 0x00]);
 
-    let res = ble.poll();
+    let res = ble.cmd_set_le_scan_enable(true, true);
 
-    assert_matches!(
-        res,
-        Some(PollResult::Event(EventType::DisconnectComplete {
-            handle: 0,
-            status: ErrorCode::Okay,
-            reason: ErrorCode::RemoteUserTerminatedConnection
-        }))
+    assert_matches!(res, Ok(EventType::CommandComplete{ num_packets: 5, opcode: 0x200c, data}) if data.as_slice() == &[0]);
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x01, 0x0c, 0x20, 0x02, 0x01, 0x01]
     );
 }
 
 #[test]
-fn receiving_number_of_completed_packets_works() {
+fn receiving_advertising_report_works() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
-    connector.provide_data_to_read(&[0x04, 0x13, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00]);
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x19, 0x02, 0x02, // two reports
+        0x00, 0x01, 0x11, 0x22, 0x33, 0x44, 0x55, 0xc6, 0x03, 0x02, 0x01, 0x06,
+        0xc4, // ADV_IND
+        0x04, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x00, 0x80, // SCAN_RSP, no data
+    ]);
 
     let res = ble.poll();
 
-    assert_matches!(
-        res,
-        Some(PollResult::Event(EventType::NumberOfCompletedPackets {
-            number_of_connection_handles: 1,
-            connection_handles: 0,
-            completed_packets: 1,
-        }))
-    );
+    let Some(PollResult::Event(EventType::AdvertisingReport { reports })) = res else {
+        panic!("Expected advertising report, got {:?}", res);
+    };
+    let mut reports = reports.iter();
+
+    let report = reports.next().unwrap();
+    assert_eq!(report.event_type, AdvertisingReportType::AdvInd);
+    assert_eq!(report.address_type, AddressType::Random);
+    assert_eq!(report.address, [0x11, 0x22, 0x33, 0x44, 0x55, 0xc6]);
+    assert_eq!(report.data, &[0x02, 0x01, 0x06]);
+    assert_eq!(report.rssi, -60);
+    assert_eq!(report.addr().0, [1, 0xc6, 0x55, 0x44, 0x33, 0x22, 0x11]);
+
+    let report = reports.next().unwrap();
+    assert_eq!(report.event_type, AdvertisingReportType::ScanRsp);
+    assert_eq!(report.address_type, AddressType::Public);
+    assert_eq!(report.data, &[]);
+    assert_eq!(report.rssi, -128);
+
+    assert!(reports.next().is_none());
 }
 
 #[test]
-fn receiving_read_by_group_type_works() {
-    let connector = connector();
-    let mut ble = Ble::new(&connector);
+fn create_le_set_extended_advertising_parameters_works() {
+    let params = ExtendedAdvertisingParameters::connectable(1);
+    let data = Command::LeSetExtendedAdvertisingParameters(&params).encode();
 
-    connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
-        0x28,
-    ]);
-
-    let res = ble.poll();
-    match res {
-        Some(res) => match res {
-            PollResult::Event(_) => assert!(true, "Expected async data"),
-            PollResult::AsyncData(res) => {
-                let res = Att::decode(L2capPacket::decode(res).unwrap().1);
-                assert_matches!(
-                    res,
-                    Ok(Att::ReadByGroupTypeReq {
-                        start: 0x0001,
-                        end: 0xffff,
-                        group_type: Uuid::Uuid16(0x2800),
-                    })
-                )
-            }
-        },
-        None => assert!(true, "Expected result"),
-    }
+    assert_eq!(
+        data.as_slice(),
+        &[
+            0x01, 0x36, 0x20, 0x19, 0x01, 0x01, 0x00, 0xa0, 0x00, 0x00, 0xa0, 0x00, 0x00, 0x07,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7f, 0x01, 0x00, 0x01, 0x01,
+            0x00,
+        ]
+    );
 }
 
 #[test]
-fn create_read_by_group_type_resp_works() {
-    let mut res = Data::new_att_read_by_group_type_response();
-    res.append_att_read_by_group_type_response(0x0001, 0x0010, &Uuid::Uuid16(0x1801));
-    res.append_att_read_by_group_type_response(0x0020, 0x0030, &Uuid::Uuid16(0x1802));
+fn create_le_set_extended_advertising_enable_works() {
+    let sets = [
+        AdvertisingSet::new(0),
+        AdvertisingSet {
+            handle: 1,
+            duration: 0x0064,
+            max_events: 0,
+        },
+    ];
+    let data = Command::LeSetExtendedAdvertisingEnable {
+        enable: true,
+        sets: &sets,
+    }
+    .encode();
 
-    assert_matches!(
-        res.as_slice(),
-        [0x11, 0x06, 0x01, 0x00, 0x10, 0x00, 0x01, 0x18, 0x20, 0x00, 0x30, 0x00, 0x02, 0x18]
+    assert_eq!(
+        data.as_slice(),
+        &[0x01, 0x39, 0x20, 0x0a, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x64, 0x00, 0x00]
     );
 }
 
 #[test]
-fn create_read_by_group_type_resp_acl_works() {
-    let mut res = Data::new_att_read_by_group_type_response();
-    res.append_att_read_by_group_type_response(0x0001, 0x0010, &Uuid::Uuid16(0x1801));
-    res.append_att_read_by_group_type_response(0x0020, 0x0030, &Uuid::Uuid16(0x1802));
-    let res = L2capPacket::encode(res);
-    let res = AclPacket::encode(
-        0x0000,
-        BoundaryFlag::FirstAutoFlushable,
-        HostBroadcastFlag::NoBroadcast,
-        res,
-    );
+fn le_set_extended_advertising_data_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    assert_matches!(
-        res.as_slice(),
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x37, 0x20, 0x00]);
+
+    let data = create_extended_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::CompleteLocalName("bleps"),
+    ])
+    .unwrap();
+    let res = ble.cmd_set_le_extended_advertising_data(0, data);
+
+    assert_matches!(res, Ok(EventType::CommandComplete { opcode: 0x2037, .. }));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
         &[
-            0x02, 0x00, 0x20, 0x12, 0x00, 0x0e, 0x00, 0x04, 0x00, 0x11, 0x06, 0x01, 0x00, 0x10,
-            0x00, 0x01, 0x18, 0x20, 0x00, 0x30, 0x00, 0x02, 0x18,
+            0x01, 0x37, 0x20, 0x0e, 0x00, 0x03, 0x01, 0x0a, 0x02, 0x01, 0x06, 0x06, 0x09, 0x62,
+            0x6c, 0x65, 0x70, 0x73
         ]
     );
 }
 
 #[test]
-fn create_error_resp_works() {
-    let res = Data::new_att_error_response(
-        ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE,
-        0x1234,
-        AttErrorCode::AttributeNotFound,
-    );
+fn le_set_extended_advertising_data_of_enabled_set_is_not_fragmented() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    assert_matches!(res.as_slice(), &[0x01, 0x10, 0x34, 0x12, 0x0a,]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x39, 0x20, 0x00]);
+    ble.cmd_set_le_extended_advertising_enable(true, &[AdvertisingSet::new(0)])
+        .unwrap();
+
+    // the largest data of a set, updated while the set is enabled
+    let payload = [0x55u8; 244];
+    let data = create_extended_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::ManufacturerSpecificData {
+            company_identifier: 0xffff,
+            payload: &payload,
+        },
+    ])
+    .unwrap();
+    assert_eq!(data.len(), 251);
+
+    connector.reset();
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x05, 0x37, 0x20, 0x00]);
+    let res = ble.cmd_set_le_extended_advertising_data(0, data);
+
+    assert_matches!(res, Ok(EventType::CommandComplete { opcode: 0x2037, .. }));
+    let written = connector.get_written_data();
+    assert_eq!(written.len(), 4 + 255);
+    // a single command with the Complete operation
+    assert_eq!(
+        &written.as_slice()[..12],
+        &[0x01, 0x37, 0x20, 0xff, 0x00, 0x03, 0x01, 0xfb, 0x02, 0x01, 0x06, 0xf7]
+    );
 }
 
 #[test]
-fn receiving_read_by_type_works() {
-    let connector = connector();
-    let mut ble = Ble::new(&connector);
-
-    connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x08, 0x01, 0x00, 0x02, 0x00, 0x02,
-        0x28,
+fn create_extended_advertising_data_fails_if_too_long() {
+    let payload = [0u8; 246];
+    let res = create_extended_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::ManufacturerSpecificData {
+            company_identifier: 0xffff,
+            payload: &payload[..240],
+        },
     ]);
+    assert_matches!(res, Ok(data) if data.len() == 247);
 
-    let res = ble.poll();
-    match res {
-        Some(res) => match res {
-            PollResult::Event(_) => assert!(true, "Expected async data"),
-            PollResult::AsyncData(res) => {
-                let res = Att::decode(L2capPacket::decode(res).unwrap().1);
-                assert_matches!(
-                    res,
-                    Ok(Att::ReadByTypeReq {
-                        start: 0x0001,
-                        end: 0x0002,
-                        attribute_type: Uuid::Uuid16(0x2802),
-                    })
-                )
-            }
+    let res = create_extended_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::ManufacturerSpecificData {
+            company_identifier: 0xffff,
+            payload: &payload,
         },
-        None => assert!(true, "Expected result"),
-    }
+    ]);
+    assert_matches!(res, Err(AdvertisementDataError::TooLong));
 }
 
 #[test]
-fn create_read_by_type_resp_works() {
-    let mut res = Data::new_att_read_by_type_response();
-    res.append_value(0x0002u16);
-    res.append(&[1u8, 2u8, 3u8, 4u8]);
-    res.append_att_read_by_type_response();
+fn receiving_advertising_set_terminated_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x3e, 0x06, 0x12, 0x00, 0x01, 0x40, 0x00, 0x05]);
 
     assert_matches!(
-        res.as_slice(),
-        [0x09, 0x06, 0x02, 0x00, 0x01, 0x02, 0x03, 0x04]
+        ble.poll(),
+        Some(PollResult::Event(EventType::AdvertisingSetTerminated {
+            status: 0,
+            advertising_handle: 1,
+            connection_handle: 0x0040,
+            num_completed_events: 5,
+        }))
     );
 }
 
-// TODO test EXCHANGE_MTU
+#[test]
+fn receiving_command_status_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-// TODO test FIND_TYPE_VALUE
+    connector.provide_data_to_read(&[0x04, 0x0f, 0x04, 0x00, 0x01, 0x0d, 0x20]);
+
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::CommandStatus {
+            status: 0,
+            num_packets: 1,
+            opcode: 0x200d,
+        }))
+    );
+}
 
 #[test]
-fn receiving_read_works() {
+fn receiving_enhanced_connection_complete_works() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
     connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0a, 0x03, 0x00,
+        0x04, 0x3e, 0x1f, 0x0a, 0x00, 0x40, 0x00, 0x01, 0x03, 0x01, 0x02, 0x03, 0x04, 0x05, 0xc6,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x11, 0x12, 0x13, 0x14, 0x15, 0x56, 0x18, 0x00, 0x02,
+        0x00, 0xf4, 0x01, 0x00,
     ]);
 
-    let res = ble.poll();
-    match res {
-        Some(res) => match res {
-            PollResult::Event(_) => assert!(true, "Expected async data"),
-            PollResult::AsyncData(res) => {
-                let res = Att::decode(L2capPacket::decode(res).unwrap().1);
-                assert_matches!(res, Ok(Att::ReadReq { handle: 0x03 }))
-            }
-        },
-        None => assert!(true, "Expected result"),
-    }
-}
-
-#[test]
-fn create_read_resp_works() {
-    let mut res = Data::new_att_read_response();
-    res.append(&[0x01, 0x02, 0x03, 0x04]);
+    let event = match ble.poll() {
+        Some(PollResult::Event(event)) => event,
+        other => panic!("unexpected {:?}", other),
+    };
+    assert_matches!(
+        event,
+        EventType::EnhancedConnectionComplete {
+            status: 0,
+            handle: 0x0040,
+            role: 1,
+            local_resolvable_private_address: [0, 0, 0, 0, 0, 0],
+            peer_resolvable_private_address: [0x11, 0x12, 0x13, 0x14, 0x15, 0x56],
+            ..
+        }
+    );
 
-    assert_matches!(res.as_slice(), &[0x0b, 0x01, 0x02, 0x03, 0x04,]);
+    let connection = Connection::from_event(&event).unwrap();
+    assert_eq!(connection.role, Role::Peripheral);
+    assert_eq!(connection.peer_address.0, [1, 0xc6, 5, 4, 3, 2, 1]);
+    assert_eq!(connection.interval, 0x0018);
+    assert_eq!(connection.latency, 2);
+    assert_eq!(connection.timeout, 0x01f4);
 }
 
 #[test]
-fn receiving_write_works() {
+fn receiving_link_events_works() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
+    connector.provide_data_to_read(&[0x04, 0x08, 0x04, 0x00, 0x40, 0x00, 0x01]);
+    connector.provide_data_to_read(&[0x04, 0x30, 0x03, 0x00, 0x40, 0x00]);
+    connector.provide_data_to_read(&[0x04, 0x10, 0x01, 0x2a]);
+    connector.provide_data_to_read(&[0x04, 0x1a, 0x01, 0x01]);
     connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x08, 0x00, 0x04, 0x00, 0x04, 0x00, 0x12, 0x03, 0x00, 0x0ff,
+        0x04, 0x3e, 0x0a, 0x03, 0x00, 0x40, 0x00, 0x28, 0x00, 0x01, 0x00, 0xc8, 0x00,
+    ]);
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x0c, 0x04, 0x00, 0x40, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ]);
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x0b, 0x06, 0x40, 0x00, 0x06, 0x00, 0x0c, 0x00, 0x00, 0x00, 0xc8, 0x00,
+    ]);
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x0b, 0x07, 0x40, 0x00, 0xfb, 0x00, 0x48, 0x08, 0xfb, 0x00, 0x48, 0x08,
     ]);
+    connector.provide_data_to_read(&[0x04, 0x3e, 0x06, 0x0c, 0x00, 0x40, 0x00, 0x02, 0x02]);
 
-    let res = ble.poll();
-    match res {
-        Some(res) => match res {
-            PollResult::Event(_) => assert!(true, "Expected async data"),
-            PollResult::AsyncData(res) => {
-                let res = Att::decode(L2capPacket::decode(res).unwrap().1);
-                assert_matches!(
-                    res,
-                    Ok(Att::WriteReq {
-                        handle: 0x03,
-                        data
-                    }) if data.as_slice() == &[0xff]
-                )
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::EncryptionChange {
+            status: 0,
+            handle: 0x0040,
+            enabled: true,
+        }))
+    );
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::EncryptionKeyRefreshComplete {
+            status: 0,
+            handle: 0x0040,
+        }))
+    );
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::HardwareError { code: 0x2a }))
+    );
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::DataBufferOverflow {
+            link_type: 1
+        }))
+    );
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::ConnectionUpdateComplete {
+            status: 0,
+            handle: 0x0040,
+            interval: 0x0028,
+            latency: 1,
+            timeout: 0x00c8,
+        }))
+    );
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::ReadRemoteFeaturesComplete {
+            status: 0,
+            handle: 0x0040,
+            features: 1,
+        }))
+    );
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(
+            EventType::RemoteConnectionParameterRequest {
+                handle: 0x0040,
+                interval_min: 6,
+                interval_max: 12,
+                max_latency: 0,
+                timeout: 0x00c8,
             }
-        },
-        None => assert!(true, "Expected result"),
-    }
+        ))
+    );
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::DataLengthChange {
+            handle: 0x0040,
+            max_tx_octets: 251,
+            max_tx_time: 2120,
+            max_rx_octets: 251,
+            max_rx_time: 2120,
+        }))
+    );
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::PhyUpdateComplete {
+            status: 0,
+            handle: 0x0040,
+            tx_phy: 2,
+            rx_phy: 2,
+        }))
+    );
 }
 
 #[test]
-fn create_write_resp_works() {
-    let res = Data::new_att_write_response();
-
-    assert_matches!(res.as_slice(), &[0x13]);
+fn create_le_create_connection_works() {
+    let params = CreateConnectionParameters::new(PeerAddressType::Random, [1, 2, 3, 4, 5, 0xc6]);
+    let data = Command::LeCreateConnection(&params).encode();
+    assert_eq!(
+        data.as_slice(),
+        &[
+            0x01, 0x0d, 0x20, 0x19, 0x60, 0x00, 0x30, 0x00, 0x00, 0x01, 0x01, 0x02, 0x03, 0x04,
+            0x05, 0xc6, 0x00, 0x18, 0x00, 0x28, 0x00, 0x00, 0x00, 0xf4, 0x01, 0x00, 0x00, 0x00,
+            0x00
+        ]
+    );
 }
 
 #[test]
-fn create_advertising_data_works() {
-    let res = create_advertising_data(&[
-        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-        AdStructure::ServiceUuids16(&[Uuid::Uuid16(0x1809)]),
-        AdStructure::CompleteLocalName("Ble-Example!"),
-    ])
-    .unwrap();
+fn create_data_length_commands_works() {
+    let data = Command::LeSetDataLength {
+        handle: 0x0040,
+        length: DataLength::MAX,
+    }
+    .encode();
+    assert_eq!(
+        data.as_slice(),
+        &[0x01, 0x22, 0x20, 0x06, 0x40, 0x00, 0xfb, 0x00, 0x90, 0x42]
+    );
 
-    println!("{:x?}", res);
+    let data = Command::LeWriteSuggestedDefaultDataLength(DataLength::MIN).encode();
+    assert_eq!(
+        data.as_slice(),
+        &[0x01, 0x24, 0x20, 0x04, 0x1b, 0x00, 0x48, 0x01]
+    );
+}
 
-    assert_matches!(
-        res.as_slice(),
-        &[
-            21, 2, 1, 6, 3, 2, 9, 24, 13, 9, 66, 108, 101, 45, 69, 120, 97, 109, 112, 108, 101, 33,
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0
-        ]
+#[test]
+fn create_le_set_phy_works() {
+    let data = Command::LeSetPhy {
+        handle: 0x0040,
+        tx_phys: PHY_LE_2M,
+        rx_phys: 0,
+        coded_options: CodedPhyOptions::NoPreference,
+    }
+    .encode();
+    assert_eq!(
+        data.as_slice(),
+        &[0x01, 0x32, 0x20, 0x07, 0x40, 0x00, 0x02, 0x02, 0x00, 0x00, 0x00]
+    );
+
+    let data = Command::LeSetPhy {
+        handle: 0x0040,
+        tx_phys: PHY_LE_CODED,
+        rx_phys: PHY_LE_1M | PHY_LE_CODED,
+        coded_options: CodedPhyOptions::S8,
+    }
+    .encode();
+    assert_eq!(
+        data.as_slice(),
+        &[0x01, 0x32, 0x20, 0x07, 0x40, 0x00, 0x00, 0x04, 0x05, 0x02, 0x00]
     );
 }
 
 #[test]
-fn create_advertising_data_fails() {
-    let res = create_advertising_data(&[
-        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-        AdStructure::ServiceUuids16(&[Uuid::Uuid16(0x1809)]),
-        AdStructure::CompleteLocalName(
-            "Ble-Example!Ble-Example!Ble-Example!Ble-Example!Ble-Example!Ble-Example!Ble-Example!",
-        ),
+fn read_suggested_default_data_length_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x08, 0x01, 0x23, 0x20, 0x00, 0x1b, 0x00, 0x48, 0x01,
     ]);
 
-    assert_matches!(res, Err(AdvertisementDataError::TooLong));
+    assert_eq!(
+        ble.cmd_le_read_suggested_default_data_length().unwrap(),
+        DataLength::MIN
+    );
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x01, 0x23, 0x20, 0x00]
+    );
 }
 
 #[test]
-fn attribute_server_discover_two_services() {
+fn read_phy_works() {
     let connector = connector();
     let mut ble = Ble::new(&connector);
 
-    let mut rf1 = |_offset: usize, data: &mut [u8]| -> usize {
-        data[0] = 0;
-        1
-    };
-    let mut wf1 = |_offset: usize, _data: &[u8]| {};
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x08, 0x01, 0x30, 0x20, 0x00, 0x40, 0x00, 0x02, 0x01,
+    ]);
 
-    let mut rf2 = |_offset: usize, data: &mut [u8]| -> usize {
-        data[0] = 0;
-        1
-    };
-    let mut wf2 = |_offset: usize, _data: &[u8]| {};
+    assert_matches!(ble.cmd_le_read_phy(0x0040), Ok((2, 1)));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x01, 0x30, 0x20, 0x02, 0x40, 0x00]
+    );
+}
 
-    let srv_uuid: [u8; 16] = [
-        0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62, 0x6A,
-        0xA8,
-    ];
-    let mut srv_uuid_att_data = &srv_uuid[..];
-    let primaray_srv = Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_uuid_att_data);
+#[test]
+fn set_phy_waits_for_command_status() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    let char_data = [
-        0x02, // 1 byte properties: READ = 0x02
-        0x03, 0x00, // 2 bytes handle = 0x0007
-        0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62, 0x6A,
-        0xA8, // 128 bit UUID like above
-    ];
-    let mut char_att_data = &char_data;
-    let char = Attribute::new(CHARACTERISTIC_UUID16, &mut char_att_data);
+    connector.provide_data_to_read(&[0x04, 0x0f, 0x04, 0x00, 0x01, 0x32, 0x20]);
 
-    let mut custom_char_att_data = (&mut rf1, &mut wf1, ());
-    let custom_char_att_data_attr = Attribute::new(
-        Uuid::Uuid128([
-            0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62,
-            0x6A, 0xA8,
-        ]),
-        &mut custom_char_att_data,
+    assert_matches!(
+        ble.cmd_le_set_phy(0x0040, PHY_LE_2M, PHY_LE_2M, CodedPhyOptions::NoPreference),
+        Ok(EventType::CommandStatus {
+            status: 0,
+            opcode: 0x2032,
+            ..
+        })
     );
+}
 
-    let srv_uuid2: [u8; 16] = [
-        0xC8, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62, 0x6A,
-        0xA8,
-    ];
-    let mut srv_uuid_att_data2 = &srv_uuid2[..];
-    let primaray_srv2 = Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_uuid_att_data2);
+#[test]
+fn addresses_are_classified() {
+    let address = |is_random, msb| Addr::from_le_bytes(is_random, [1, 2, 3, 4, 5, msb]);
 
-    let char_data2 = [
-        0x02 | 0x08, // 1 byte properties: READ = 0x02, WRITE WITH RESPONSE = 0x08
-        0x06,
-        0x00, // 2 bytes handle = 0x0007
-        0xC8,
-        0x15,
-        0x15,
-        0x96,
-        0x54,
-        0x56,
-        0x64,
-        0xB3,
-        0x38,
-        0x45,
-        0x26,
-        0x5D,
-        0xF1,
-        0x62,
-        0x6A,
-        0xA8, // 128 bit UUID like above
-    ];
-    let mut char_att_data2 = &char_data2;
-    let char2 = Attribute::new(CHARACTERISTIC_UUID16, &mut char_att_data2);
+    assert_eq!(address(false, 0xc0).kind(), AddressKind::Public);
+    assert_eq!(address(true, 0xc0).kind(), AddressKind::StaticRandom);
+    assert_eq!(address(true, 0x40).kind(), AddressKind::ResolvablePrivate);
+    assert_eq!(
+        address(true, 0x3f).kind(),
+        AddressKind::NonResolvablePrivate
+    );
+    assert_eq!(address(true, 0x80).kind(), AddressKind::Reserved);
+    assert!(address(true, 0x40).is_private());
+    assert!(!address(true, 0xc0).is_private());
+    assert_eq!(address(true, 0x40).to_le_bytes(), [1, 2, 3, 4, 5, 0x40]);
+}
 
-    let mut custom_char_att_data2 = (&mut rf2, &mut wf2, ());
-    let custom_char_att_data_attr2 = Attribute::new(
-        Uuid::Uuid128([
-            0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62,
-            0x6A, 0xA8,
-        ]),
-        &mut custom_char_att_data2,
+#[test]
+fn create_resolving_list_commands_works() {
+    let data = Command::LeAddDeviceToResolvingList {
+        peer_address_type: PeerAddressType::Random,
+        peer_address: [1, 2, 3, 4, 5, 0xc6],
+        peer_irk: 0x0f0e0d0c_0b0a0908_07060504_03020100,
+        local_irk: 0,
+    }
+    .encode();
+    assert_eq!(
+        &data.as_slice()[..11],
+        &[0x01, 0x27, 0x20, 0x27, 0x01, 1, 2, 3, 4, 5, 0xc6]
     );
+    assert_eq!(
+        &data.as_slice()[11..27],
+        &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+    );
+    assert_eq!(&data.as_slice()[27..], &[0; 16]);
 
-    let mut val_att_data = &(32u32,);
-    let val = Attribute::new(CHARACTERISTIC_UUID16, &mut val_att_data);
+    let data = Command::LeSetAddressResolutionEnable(true).encode();
+    assert_eq!(data.as_slice(), &[0x01, 0x2d, 0x20, 0x01, 0x01]);
 
-    let attributes = &mut [
-        primaray_srv,
-        char,
-        custom_char_att_data_attr,
-        primaray_srv2,
-        char2,
-        custom_char_att_data_attr2,
-        val,
-    ];
+    let data = Command::LeSetResolvablePrivateAddressTimeout(900).encode();
+    assert_eq!(data.as_slice(), &[0x01, 0x2e, 0x20, 0x02, 0x84, 0x03]);
+}
 
-    let mut rng = OsRng::default();
-    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+#[test]
+fn create_controller_info_commands_works() {
+    let data = Command::ReadLocalVersionInformation.encode();
+    assert_eq!(data.as_slice(), &[0x01, 0x01, 0x10, 0x00]);
+
+    let data = Command::LeReadLocalSupportedFeatures.encode();
+    assert_eq!(data.as_slice(), &[0x01, 0x03, 0x20, 0x00]);
+
+    let data = Command::LeReadAdvertisingPhysicalChannelTxPower.encode();
+    assert_eq!(data.as_slice(), &[0x01, 0x07, 0x20, 0x00]);
+
+    let data = Command::ReadRssi { handle: 0x0040 }.encode();
+    assert_eq!(data.as_slice(), &[0x01, 0x05, 0x14, 0x02, 0x40, 0x00]);
+
+    let data = Command::LeReadChannelMap { handle: 0x0040 }.encode();
+    assert_eq!(data.as_slice(), &[0x01, 0x15, 0x20, 0x02, 0x40, 0x00]);
+
+    let data = Command::LeReadRemoteFeatures { handle: 0x0040 }.encode();
+    assert_eq!(data.as_slice(), &[0x01, 0x16, 0x20, 0x02, 0x40, 0x00]);
+}
+
+#[test]
+fn channel_map_counts_used_channels() {
+    let map = ChannelMap([0x0f, 0x00, 0x00, 0x00, 0x10]);
+    assert!(map.is_used(0));
+    assert!(!map.is_used(4));
+    assert!(map.is_used(36));
+    assert!(!map.is_used(37));
+    assert_eq!(map.used_channels(), 5);
+}
+
+#[test]
+fn create_rand_and_encrypt_commands_works() {
+    let data = Command::LeRand.encode();
+    assert_eq!(data.as_slice(), &[0x01, 0x18, 0x20, 0x00]);
+
+    let data = Command::LeEncrypt {
+        key: 0x00010203_04050607_08090a0b_0c0d0e0f,
+        plaintext: 0x00112233_44556677_8899aabb_ccddeeff,
+    }
+    .encode();
+    assert_eq!(&data.as_slice()[..6], &[0x01, 0x17, 0x20, 0x20, 0x0f, 0x0e]);
+    assert_eq!(&data.as_slice()[18..22], &[0x01, 0x00, 0xff, 0xee]);
+}
+
+#[test]
+fn controller_generates_random_numbers_and_encrypts() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
 
-    // ReadByGroupTypeReq { start: 1, end: ffff, group_type: Uuid16(2800) }
     connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
-        0x28,
+        0x04, 0x0e, 0x0c, 0x01, 0x18, 0x20, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
     ]);
-    assert_matches!(srv.do_work(), Ok(_));
-    // check response (1-3, 0x2800)
-    let response_data = connector.get_written_data();
-    assert_eq!(
-        response_data.as_slice(),
-        &[
-            0x02, 0x00, 0x20, 0x1A, 0x00, 0x16, 0x00, 0x04, 0x00, 0x11, 0x14, 0x01, 0x00, 0x03,
-            0x00, 0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1,
-            0x62, 0x6A, 0xA8,
-        ]
-    );
+    assert_matches!(ble.cmd_le_rand(), Ok(0x08070605_04030201));
 
-    // ReadByGroupTypeReq { start: 4, end: ffff, group_type: Uuid16(2800) }
-    connector.reset();
+    // FIPS-197, Appendix C.1
     connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x04, 0x00, 0xff, 0xff, 0x00,
-        0x28,
+        0x04, 0x0e, 0x14, 0x01, 0x17, 0x20, 0x00, 0x5a, 0xc5, 0xb4, 0x70, 0x80, 0xb7, 0xcd, 0xd8,
+        0x30, 0x04, 0x7b, 0x6a, 0xd8, 0xe0, 0xc4, 0x69,
     ]);
-    assert_matches!(srv.do_work(), Ok(_));
-    // check response (4-6, 0x2800)
-    let response_data = connector.get_written_data();
+    assert_matches!(
+        ble.cmd_le_encrypt(
+            0x00010203_04050607_08090a0b_0c0d0e0f,
+            0x00112233_44556677_8899aabb_ccddeeff
+        ),
+        Ok(0x69c4e0d8_6a7b0430_d8cdb780_70b4c55a)
+    );
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn virtual_controller_seeds_rng() {
+    use bleps::crypto::aes128;
+    use p256::elliptic_curve::rand_core::RngCore;
+
+    let link = VirtualLink::new();
+    let (controller, _) = link.controllers();
+    let mut ble = Ble::new(&controller);
+
+    let (key, plaintext) = (0x1234, 0x5678);
     assert_eq!(
-        response_data.as_slice(),
-        &[
-            0x02, 0x00, 0x20, 0x1a, 0x00, 0x16, 0x00, 0x04, 0x00, 0x11, 0x14, 0x04, 0x00, 0x07,
-            0x00, 0xC8, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1,
-            0x62, 0x6A, 0xA8,
-        ]
+        ble.cmd_le_encrypt(key, plaintext).unwrap(),
+        aes128(key, plaintext)
+    );
+
+    let mut rng = ble.controller_rng().unwrap();
+    let mut first = [0u8; 20];
+    let mut second = [0u8; 20];
+    rng.fill_bytes(&mut first);
+    rng.fill_bytes(&mut second);
+    assert_ne!(first, [0u8; 20]);
+    assert_ne!(first, second);
+
+    let mut other = ble.controller_rng().unwrap();
+    assert_ne!(rng.next_u64(), other.next_u64());
+}
+
+#[test]
+fn create_raw_command_works() {
+    let data = Command::Raw {
+        ogf: VENDOR_OGF,
+        ocf: 0x0001,
+        params: &[0x01, 0x02, 0x03],
+    }
+    .encode();
+    assert_eq!(data.as_slice(), &[0x01, 0x01, 0xfc, 0x03, 0x01, 0x02, 0x03]);
+}
+
+#[test]
+fn raw_commands_are_sent() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x05, 0x01, 0x01, 0xfc, 0x00, 0x2a]);
+    let res = ble.cmd_raw(VENDOR_OGF, 0x0001, &[0x07]).unwrap();
+    assert_matches!(res, EventType::CommandComplete { data, .. } if data.as_slice() == [0x00, 0x2a]);
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x01, 0x01, 0xfc, 0x01, 0x07]
+    );
+
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x01, 0x02, 0xfc, 0x01]);
+    assert_matches!(
+        ble.cmd_raw(VENDOR_OGF, 0x0002, &[]),
+        Err(bleps::Error::CommandFailed {
+            opcode: 0xfc02,
+            status: 0x01
+        })
     );
 
-    // ReadByGroupTypeReq { start: 7, end: ffff, group_type: Uuid16(2800) }
     connector.reset();
-    connector.provide_data_to_read(&[
-        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x07, 0x00, 0xff, 0xff, 0x00,
-        0x28,
-    ]);
-    assert_matches!(srv.do_work(), Ok(_));
-    // check response (not found)
-    let response_data = connector.get_written_data();
+    assert_matches!(
+        ble.cmd_raw(VENDOR_OGF, 0x0003, &[0; 256]),
+        Err(bleps::Error::CommandFailed {
+            opcode: 0xfc03,
+            status: 0x12
+        })
+    );
+    assert_matches!(
+        ble.cmd_raw(VENDOR_OGF, 0x0400, &[]),
+        Err(bleps::Error::Failed(0x12))
+    );
+    assert_matches!(
+        ble.cmd_raw(0x40, 0x0001, &[]),
+        Err(bleps::Error::Failed(0x12))
+    );
+    assert_eq!(connector.get_write_idx(), 0);
+}
+
+/// Read Transmit Power Level, which `bleps` doesn't provide
+struct ReadTransmitPowerLevel {
+    handle: u16,
+}
+
+#[derive(Debug, PartialEq)]
+struct TransmitPowerLevel {
+    handle: u16,
+    level: i8,
+}
+
+impl ReturnParameters for TransmitPowerLevel {
+    fn decode(data: &[u8]) -> Option<Self> {
+        Some(TransmitPowerLevel {
+            handle: u16::decode(data)?,
+            level: *data.get(2)? as i8,
+        })
+    }
+}
+
+impl CustomCommand for ReadTransmitPowerLevel {
+    const OGF: u8 = 0x03;
+    const OCF: u16 = 0x2d;
+    type ReturnParameters = TransmitPowerLevel;
+
+    fn write_parameters(&self, params: &mut Data) {
+        params.append(&self.handle.to_le_bytes());
+        // current transmit power level
+        params.append(&[0x00]);
+    }
+}
+
+#[test]
+fn custom_commands_decode_return_parameters() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x07, 0x01, 0x2d, 0x0c, 0x00, 0x40, 0x00, 0xf6]);
+    assert_matches!(
+        ble.cmd_custom(&ReadTransmitPowerLevel { handle: 0x0040 }),
+        Ok(TransmitPowerLevel {
+            handle: 0x0040,
+            level: -10
+        })
+    );
     assert_eq!(
-        response_data.as_slice(),
-        &[0x02, 0x00, 0x20, 0x09, 0x00, 0x05, 0x00, 0x04, 0x00, 0x01, 0x10, 0x07, 0x00, 0x0a]
+        connector.get_written_data().as_slice(),
+        &[0x01, 0x2d, 0x0c, 0x03, 0x40, 0x00, 0x00]
+    );
+
+    // the return parameters are cut short
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x06, 0x01, 0x2d, 0x0c, 0x00, 0x40, 0x00]);
+    assert_matches!(
+        ble.cmd_custom(&ReadTransmitPowerLevel { handle: 0x0040 }),
+        Err(bleps::Error::Failed(0))
+    );
+}
+
+#[test]
+fn create_filter_accept_list_commands_works() {
+    let data = Command::LeReadFilterAcceptListSize.encode();
+    assert_eq!(data.as_slice(), &[0x01, 0x0f, 0x20, 0x00]);
+
+    let data = Command::LeClearFilterAcceptList.encode();
+    assert_eq!(data.as_slice(), &[0x01, 0x10, 0x20, 0x00]);
+
+    let data = Command::LeAddDeviceToFilterAcceptList {
+        address_type: PeerAddressType::Random,
+        address: [1, 2, 3, 4, 5, 0xc6],
+    }
+    .encode();
+    assert_eq!(
+        data.as_slice(),
+        &[0x01, 0x11, 0x20, 0x07, 0x01, 1, 2, 3, 4, 5, 0xc6]
+    );
+
+    let data = Command::LeRemoveDeviceFromFilterAcceptList {
+        address_type: PeerAddressType::Public,
+        address: [1, 2, 3, 4, 5, 6],
+    }
+    .encode();
+    assert_eq!(
+        data.as_slice(),
+        &[0x01, 0x12, 0x20, 0x07, 0x00, 1, 2, 3, 4, 5, 6]
+    );
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn private_address_is_rotated() {
+    use bleps::{crypto::Irk, privacy::RpaRotation};
+
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    let irk = Irk::new(&mut OsRng);
+    let mut rotation = RpaRotation::with_timeout(irk, 1000);
+
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x01, 0x05, 0x20, 0x00]);
+    let address = ble
+        .rotate_private_address(&mut rotation, &mut OsRng)
+        .unwrap()
+        .unwrap();
+    assert!(irk.resolves(&address));
+    assert_eq!(rotation.address().unwrap().0, address.0);
+
+    let written = connector.get_written_data();
+    assert_eq!(&written.as_slice()[..4], &[0x01, 0x05, 0x20, 0x06]);
+    assert_eq!(&written.as_slice()[4..], &address.to_le_bytes());
+
+    // not expired yet
+    assert_matches!(
+        ble.rotate_private_address(&mut rotation, &mut OsRng),
+        Ok(None)
     );
+    assert_eq!(connector.get_written_data().len(), 10);
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn rpa_rotation_expires() {
+    use bleps::{crypto::Irk, privacy::RpaRotation};
+
+    let mut rotation = RpaRotation::with_timeout(Irk(0x1234), 1000);
+    let first = rotation.poll(0, &mut OsRng).unwrap();
+    assert_matches!(rotation.poll(999, &mut OsRng), None);
+
+    let second = rotation.poll(1000, &mut OsRng).unwrap();
+    assert_ne!(first.0, second.0);
+    assert_matches!(rotation.poll(1999, &mut OsRng), None);
+
+    rotation.expire();
+    assert_matches!(rotation.poll(1999, &mut OsRng), Some(_));
+}
+
+#[test]
+fn connect_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0f, 0x04, 0x00, 0x01, 0x0d, 0x20]);
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x13, 0x01, 0x00, 0x40, 0x00, 0x00, 0x01, 0x01, 0x02, 0x03, 0x04, 0x05, 0xc6,
+        0x18, 0x00, 0x02, 0x00, 0xf4, 0x01, 0x00,
+    ]);
+
+    let params = CreateConnectionParameters::new(PeerAddressType::Random, [1, 2, 3, 4, 5, 0xc6]);
+    let connection = ble.connect(&params, 1000).unwrap();
+
+    assert_eq!(connection.handle, 0x0040);
+    assert_eq!(connection.role, Role::Central);
+    assert_eq!(connection.peer_address.0, [1, 0xc6, 5, 4, 3, 2, 1]);
+    assert_eq!(connection.interval, 0x0018);
+    assert_eq!(connection.latency, 2);
+    assert_eq!(connection.timeout, 0x01f4);
+}
+
+#[test]
+fn connect_fails() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0f, 0x04, 0x00, 0x01, 0x0d, 0x20]);
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x13, 0x01, 0x3e, 0x00, 0x00, 0x00, 0x01, 0x01, 0x02, 0x03, 0x04, 0x05, 0xc6,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ]);
+
+    let params = CreateConnectionParameters::new(PeerAddressType::Random, [1, 2, 3, 4, 5, 0xc6]);
+    let res = ble.connect(&params, 1000);
+
+    assert_matches!(res, Err(bleps::Error::Failed(0x3e)));
+}
+
+#[test]
+fn connect_returns_connection_established_while_cancelling() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0f, 0x04, 0x00, 0x01, 0x0d, 0x20]);
+    // Disconnection Complete of another connection
+    connector.provide_data_to_read(&[0x04, 0x05, 0x04, 0x00, 0x02, 0x00, 0x13]);
+    // the connection gets established before Create Connection Cancel is handled
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x13, 0x01, 0x00, 0x40, 0x00, 0x00, 0x01, 0x01, 0x02, 0x03, 0x04, 0x05, 0xc6,
+        0x18, 0x00, 0x02, 0x00, 0xf4, 0x01, 0x00,
+    ]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x01, 0x0e, 0x20, 0x0c]);
+    for idx in 3..128 {
+        connector.set_current_millis_at(idx, 2000);
+    }
+
+    let params = CreateConnectionParameters::new(PeerAddressType::Random, [1, 2, 3, 4, 5, 0xc6]);
+    let connection = ble.connect(&params, 1000).unwrap();
+    assert_eq!(connection.handle, 0x0040);
+
+    // Create Connection Cancel was sent after the timeout
+    assert_eq!(
+        &connector.get_written_data().as_slice()[29..],
+        &[0x01, 0x0e, 0x20, 0x00]
+    );
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::DisconnectComplete {
+            handle: 0x0002,
+            ..
+        }))
+    );
+}
+
+#[test]
+fn connect_fails_with_command_status() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0f, 0x04, 0x0c, 0x01, 0x0d, 0x20]);
+
+    let params = CreateConnectionParameters::new(PeerAddressType::Random, [1, 2, 3, 4, 5, 0xc6]);
+    let res = ble.connect(&params, 1000);
+
+    assert_matches!(
+        res,
+        Err(bleps::Error::CommandFailed {
+            opcode: 0x200d,
+            status: 0x0c
+        })
+    );
+}
+
+#[test]
+fn disconnect_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0f, 0x04, 0x00, 0x01, 0x06, 0x04]);
+
+    let res = ble.cmd_disconnect(0x0040, 0x13);
+
+    assert_matches!(
+        res,
+        Ok(EventType::CommandStatus {
+            status: 0,
+            num_packets: 1,
+            opcode: 0x0406,
+        })
+    );
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x01, 0x06, 0x04, 0x03, 0x40, 0x00, 0x13]
+    );
+}
+
+#[test]
+fn commands_wait_for_credits() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // the controller doesn't accept further commands
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x00, 0x0b, 0x20, 0x00]);
+    assert_matches!(
+        ble.cmd_set_le_scan_parameters(&ScanParameters::default()),
+        Ok(_)
+    );
+
+    connector.set_current_millis_at(2, 2000);
+    assert_matches!(
+        ble.cmd_set_le_scan_enable(true, false),
+        Err(bleps::Error::CommandTimeout { opcode: 0x200c })
+    );
+    assert_eq!(connector.get_written_data().len(), 11);
+
+    // a NOP Command Complete grants a credit and the queued command is sent
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x03, 0x01, 0x00, 0x00]);
+    assert_matches!(ble.poll(), Some(PollResult::Event(_)));
+    assert_eq!(
+        &connector.get_written_data().as_slice()[11..],
+        &[0x01, 0x0c, 0x20, 0x02, 0x01, 0x00]
+    );
+}
+
+#[test]
+fn receiving_async_data_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
+        0x28,
+    ]);
+
+    let res = ble.poll();
+
+    assert_matches!(res,
+        Some(PollResult::AsyncData(AclPacket {
+            handle: 0,
+            boundary_flag: BoundaryFlag::FirstAutoFlushable,
+            bc_flag: ControllerBroadcastFlag::PointToPoint,
+            data,
+        })) if data.as_slice() == &[0x7, 0x0, 0x4, 0x0, 0x10, 0x1, 0x0, 0xff, 0xff, 0x0, 0x28]
+    );
+}
+
+#[test]
+fn receiving_disconnection_complete_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x05, 0x04, 0x00, 0x00, 0x00, 0x13]);
+
+    let res = ble.poll();
+
+    assert_matches!(
+        res,
+        Some(PollResult::Event(EventType::DisconnectComplete {
+            handle: 0,
+            status: ErrorCode::Okay,
+            reason: ErrorCode::RemoteUserTerminatedConnection
+        }))
+    );
+}
+
+#[test]
+fn receiving_number_of_completed_packets_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x04, 0x13, 0x09, 0x02, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x03, 0x00,
+    ]);
+
+    let res = ble.poll();
+
+    if let Some(PollResult::Event(EventType::NumberOfCompletedPackets { completed })) = res {
+        assert_eq!(completed.number_of_handles, 2);
+        let mut iter = completed.iter();
+        assert_eq!(iter.next(), Some((0, 1)));
+        assert_eq!(iter.next(), Some((1, 3)));
+        assert_eq!(iter.next(), None);
+    } else {
+        panic!("Unexpected result {:?}", res);
+    }
+}
+
+#[test]
+fn acl_writes_wait_for_completed_packets() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // the controller only buffers a single ACL packet
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x01, 0x0c, 0x00, 0x04,
+        0x0e, 0x04, 0x05, 0x01, 0x20, 0x00, 0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0xfb, 0x00,
+        0x01,
+    ]);
+    provide_controller_info(&connector);
+    ble.init().unwrap();
+    assert_eq!(connector.get_write_idx(), 40);
+
+    ble.write_acl(Data::new(&[0x02, 0x00, 0x20, 0x01, 0x00, 0xaa]))
+        .unwrap();
+    ble.write_acl(Data::new(&[0x02, 0x00, 0x20, 0x01, 0x00, 0xbb]))
+        .unwrap();
+    assert_eq!(connector.get_write_idx(), 46);
+    assert_eq!(connector.get_to_write_at(45), 0xaa);
+
+    connector.provide_data_to_read(&[0x04, 0x13, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00]);
+    ble.poll();
+
+    assert_eq!(connector.get_write_idx(), 52);
+    assert_eq!(connector.get_to_write_at(51), 0xbb);
+}
+
+#[test]
+fn queued_acl_packets_are_sent_in_order() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // the controller only buffers a single ACL packet
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x01, 0x0c, 0x00, 0x04,
+        0x0e, 0x04, 0x05, 0x01, 0x20, 0x00, 0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0xfb, 0x00,
+        0x01,
+    ]);
+    provide_controller_info(&connector);
+    ble.init().unwrap();
+
+    let completed = [0x04, 0x13, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00];
+    for byte in [0xaa, 0xbb, 0xcc] {
+        ble.write_acl(Data::new(&[0x02, 0x00, 0x20, 0x01, 0x00, byte]))
+            .unwrap();
+    }
+    connector.provide_data_to_read(&completed);
+    ble.poll();
+
+    // the queue wraps around
+    for byte in [0xdd, 0xee, 0xff] {
+        ble.write_acl(Data::new(&[0x02, 0x00, 0x20, 0x01, 0x00, byte]))
+            .unwrap();
+    }
+    for _ in 0..4 {
+        connector.provide_data_to_read(&completed);
+        ble.poll();
+    }
+
+    assert_eq!(connector.get_write_idx(), 40 + 6 * 6);
+    let sent = (0..6)
+        .map(|i| connector.get_to_write_at(45 + 6 * i))
+        .collect::<Vec<u8>>();
+    assert_eq!(sent, [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+}
+
+#[test]
+fn packets_polled_while_waiting_for_acl_buffers_are_kept() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // the controller only buffers a single ACL packet
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x01, 0x0c, 0x00, 0x04,
+        0x0e, 0x04, 0x05, 0x01, 0x20, 0x00, 0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0xfb, 0x00,
+        0x01,
+    ]);
+    provide_controller_info(&connector);
+    ble.init().unwrap();
+
+    // one packet is sent, the ACL queue holds four more
+    for _ in 0..5 {
+        ble.write_acl(Data::new(&[0x02, 0x00, 0x20, 0x01, 0x00, 0xaa]))
+            .unwrap();
+    }
+
+    // ReadReq { handle: 0x0003 } on connection 0x0001, DisconnectComplete { handle: 0x0002 }
+    // and NumberOfCompletedPackets { handle: 0x0000, count: 1 } arrive while waiting
+    connector.provide_data_to_read(&[
+        0x02, 0x01, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0a, 0x03, 0x00,
+    ]);
+    connector.provide_data_to_read(&[0x04, 0x05, 0x04, 0x00, 0x02, 0x00, 0x13]);
+    connector.provide_data_to_read(&[0x04, 0x13, 0x05, 0x01, 0x00, 0x00, 0x01, 0x00]);
+    ble.write_acl(Data::new(&[0x02, 0x00, 0x20, 0x01, 0x00, 0xbb]))
+        .unwrap();
+    assert_eq!(connector.get_write_idx(), 52);
+
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::AsyncData(AclPacket { handle: 0x0001, .. }))
+    );
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::DisconnectComplete {
+            handle: 0x0002,
+            ..
+        }))
+    );
+    assert_matches!(ble.poll(), None);
+}
+
+#[test]
+fn packets_polled_while_waiting_for_command_complete_are_kept() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // ReadReq { handle: 0x0003 } on connection 0x0001, LongTermKeyRequest { handle: 0x0001 }
+    // and DisconnectComplete { handle: 0x0002 } arrive before the Command Complete
+    connector.provide_data_to_read(&[
+        0x02, 0x01, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0a, 0x03, 0x00,
+    ]);
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x0d, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ]);
+    connector.provide_data_to_read(&[0x04, 0x05, 0x04, 0x00, 0x02, 0x00, 0x13]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x07, 0x05, 0x05, 0x14, 0x00, 0x01, 0x00, 0xce]);
+    assert_matches!(ble.cmd_read_rssi(0x0001), Ok(-50));
+
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::AsyncData(AclPacket { handle: 0x0001, .. }))
+    );
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::LongTermKeyRequest {
+            handle: 0x0001,
+            ..
+        }))
+    );
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::DisconnectComplete {
+            handle: 0x0002,
+            ..
+        }))
+    );
+    assert_matches!(ble.poll(), None);
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn attribute_server_replies_with_the_ltk_of_the_requesting_connection() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    let srv_data = [0x0f, 0x18];
+    let mut srv_att_data = &srv_data;
+    let attributes = &mut [Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_att_data)];
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new_with_ltk(
+        &mut ble,
+        attributes,
+        Addr::from_le_bytes(false, [0u8; 6]),
+        Some(0x1234),
+        &mut rng,
+    );
+
+    // ConnectionComplete { handle: 0x0001 } of the bonded peer
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x13, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
+        0x18, 0x00, 0x00, 0x00, 0xf4, 0x01, 0x00,
+    ]);
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+
+    // LongTermKeyRequest { handle: 0x0001 } is answered with the key of the connection
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x0d, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x06, 0x05, 0x1a, 0x20, 0x00, 0x01, 0x00]);
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[
+            0x01, 0x1a, 0x20, 0x12, 0x01, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+        ]
+    );
+
+    // LongTermKeyRequest { handle: 0x0002 } of a connection without a key is refused
+    connector.reset();
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x0d, 0x05, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x06, 0x05, 0x1b, 0x20, 0x00, 0x02, 0x00]);
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x01, 0x1b, 0x20, 0x02, 0x02, 0x00]
+    );
+}
+
+#[cfg(feature = "crypto")]
+#[test]
+fn attribute_server_pairs_with_secure_connections() {
+    use bleps::crypto::{IoCap, Nonce, PublicKey, SecretKey};
+
+    let link = VirtualLink::new();
+    let (peripheral_controller, central_controller) = link.controllers();
+    let mut peripheral = Ble::new(&peripheral_controller);
+    let mut central = Ble::new(&central_controller);
+
+    peripheral.init().unwrap();
+    central.init().unwrap();
+    peripheral.cmd_set_le_advertising_parameters().unwrap();
+    peripheral.cmd_set_le_advertise_enable(true).unwrap();
+
+    let central_address = Addr::from_le_bytes(false, central_controller.address());
+    let peripheral_address = Addr::from_le_bytes(false, peripheral_controller.address());
+    let params =
+        CreateConnectionParameters::new(PeerAddressType::Public, peripheral_controller.address());
+    let handle = central.connect(&params, 1000).unwrap().handle;
+
+    let displayed = Cell::new(None);
+    let mut pin_callback = |code| displayed.set(Some(code));
+    let srv_data = [0x0f, 0x18];
+    let mut srv_att_data = &srv_data;
+    let attributes = &mut [Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_att_data)];
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new_with_ltk(
+        &mut peripheral,
+        attributes,
+        peripheral_address,
+        None,
+        &mut rng,
+    );
+    srv.set_pin_callback(Some(&mut pin_callback));
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+    assert!(srv.connection(handle).is_some());
+
+    // the central sends its SMP PDUs and waits for the answers of the peripheral
+    let mut exchange = |srv: &mut AttributeServer<OsRng>, pdu: &[u8], answers: usize| {
+        central
+            .write_l2cap(handle, L2capPacket::encode_sm(Data::new(pdu)))
+            .unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..16 {
+            if received.len() == answers {
+                break;
+            }
+            assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+            if let Some(PollResult::AsyncData(packet)) = central.poll() {
+                let (_, l2cap) = L2capPacket::decode(packet).unwrap();
+                assert_eq!(l2cap.channel, 6);
+                received.push(l2cap.payload.as_slice().to_vec());
+            }
+        }
+        assert_eq!(received.len(), answers);
+        received
+    };
+
+    // Pairing Request with DisplayYesNo, bonding, MITM, Secure Connections and CT2
+    let pairing_request = [0x01, 0x01, 0x00, 0x2d, 0x10, 0x00, 0x00];
+    let response = exchange(&mut srv, &pairing_request, 1).remove(0);
+    assert_eq!(response[0], 0x02);
+    let ioa = IoCap::new(pairing_request[3], false, pairing_request[1]);
+    let iob = IoCap::new(response[3], response[2] != 0, response[1]);
+
+    // Pairing Public Key, answered with the key and the Pairing Confirm of the peripheral
+    let ska = SecretKey::new(&mut OsRng);
+    let pka = ska.public_key();
+    let mut pdu = vec![0x0c];
+    pdu.extend_from_slice(&pka.to_bytes());
+    let answers = exchange(&mut srv, &pdu, 2);
+    assert_eq!(answers[0][0], 0x0c);
+    assert_eq!(answers[1][0], 0x03);
+    let pkb = PublicKey::from_bytes(&answers[0][1..]);
+    let cb = u128::from_le_bytes(answers[1][1..].try_into().unwrap());
+    let dh_key = ska.dh_key(pkb).unwrap();
+
+    // Pairing Random
+    let na = Nonce::new(&mut OsRng);
+    let mut pdu = vec![0x04];
+    pdu.extend_from_slice(&na.0.to_le_bytes());
+    let random = exchange(&mut srv, &pdu, 1).remove(0);
+    assert_eq!(random[0], 0x04);
+    let nb = Nonce(u128::from_le_bytes(random[1..].try_into().unwrap()));
+    assert_eq!(nb.f4(pkb.x(), pka.x(), 0).0, cb);
+
+    // Pairing DHKey Check
+    let (mac_key, ltk) = dh_key.f5(na, nb, central_address, peripheral_address);
+    let ea = mac_key.f6(na, nb, 0, ioa, central_address, peripheral_address);
+    let mut pdu = vec![0x0d];
+    pdu.extend_from_slice(&ea.0.to_le_bytes());
+    let check = exchange(&mut srv, &pdu, 1).remove(0);
+    let eb = mac_key.f6(nb, na, 0, iob, peripheral_address, central_address);
+    assert_eq!(check[0], 0x0d);
+    assert_eq!(u128::from_le_bytes(check[1..].try_into().unwrap()), eb.0);
+    drop(exchange);
+
+    // the encryption only succeeds if both sides computed the same LTK
+    central
+        .cmd_le_start_encryption(handle, 0, 0, ltk.0)
+        .unwrap();
+    let mut encryption_change = None;
+    for _ in 0..16 {
+        assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+        if let Some(PollResult::Event(event @ EventType::EncryptionChange { .. })) = central.poll()
+        {
+            encryption_change = Some(event);
+            break;
+        }
+    }
+    assert_matches!(
+        encryption_change,
+        Some(EventType::EncryptionChange {
+            status: 0,
+            enabled: true,
+            ..
+        })
+    );
+    drop(srv);
+    assert_eq!(displayed.get(), Some(na.g2(pka.x(), pkb.x(), &nb).0));
+}
+
+#[test]
+fn receiving_read_by_group_type_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
+        0x28,
+    ]);
+
+    let res = ble.poll();
+    match res {
+        Some(res) => match res {
+            PollResult::Event(_) => assert!(true, "Expected async data"),
+            PollResult::AsyncData(res) => {
+                let res = Att::decode(&L2capPacket::decode(res).unwrap().1);
+                assert_matches!(
+                    res,
+                    Ok(Att::ReadByGroupTypeReq {
+                        start: 0x0001,
+                        end: 0xffff,
+                        group_type: Uuid::Uuid16(0x2800),
+                    })
+                )
+            }
+        },
+        None => assert!(true, "Expected result"),
+    }
+}
+
+#[test]
+fn receiving_fragmented_l2cap_frame_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // first fragment, an unrelated event and the continuing fragment
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x06, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x04, 0x13, 0x01, 0x00,
+        0x02, 0x00, 0x10, 0x05, 0x00, 0x00, 0xff, 0xff, 0x00, 0x28,
+    ]);
+
+    assert_matches!(ble.poll(), None);
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(
+            EventType::NumberOfCompletedPackets { .. }
+        ))
+    );
+
+    let res = ble.poll();
+    if let Some(PollResult::AsyncData(res)) = res {
+        let res = Att::decode(&L2capPacket::decode(res).unwrap().1);
+        assert_matches!(
+            res,
+            Ok(Att::ReadByGroupTypeReq {
+                start: 0x0001,
+                end: 0xffff,
+                group_type: Uuid::Uuid16(0x2800),
+            })
+        );
+    } else {
+        panic!("Unexpected result {:?}", res);
+    }
+}
+
+#[test]
+fn receiving_l2cap_frame_of_largest_mtu_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // WriteReq { handle: 0x0003 } with 253 bytes, a 260 byte frame in fragments of 251 and 9
+    let mut frame = vec![0x00, 0x01, 0x04, 0x00, 0x12, 0x03, 0x00];
+    frame.extend((0..253).map(|i| i as u8));
+    connector.provide_data_to_read(&[0x02, 0x00, 0x20, 0xfb, 0x00]);
+    connector.provide_data_to_read(&frame[..251]);
+    connector.provide_data_to_read(&[0x02, 0x00, 0x10, 0x09, 0x00]);
+    connector.provide_data_to_read(&frame[251..]);
+
+    assert_matches!(ble.poll(), None);
+    let Some(PollResult::AsyncData(res)) = ble.poll() else {
+        panic!("Expected the reassembled frame");
+    };
+    assert_eq!(res.data.as_slice(), &frame[..]);
+
+    let (_, packet) = L2capPacket::decode(res).unwrap();
+    assert_eq!(packet.length, 256);
+    match Att::decode(&packet) {
+        Ok(Att::WriteReq { handle, data }) => {
+            assert_eq!(handle, 0x0003);
+            assert_eq!(data.as_slice(), &frame[7..]);
+        }
+        res => panic!("Unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn decoding_empty_att_pdu_fails() {
+    let packet = || L2capPacket {
+        length: 0,
+        channel: 0x0004,
+        payload: Data::new(&[]),
+    };
+    assert_matches!(
+        Att::decode(&packet()),
+        Err(AttDecodeError::UnexpectedPayload)
+    );
+    assert_matches!(
+        AttResponse::decode(&packet()),
+        Err(AttDecodeError::UnexpectedPayload)
+    );
+}
+
+#[test]
+fn truncated_events_are_decoded_as_unknown() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // EncryptionChange without the enabled flag
+    connector.provide_data_to_read(&[0x04, 0x08, 0x03, 0x00, 0x01, 0x00]);
+    // LE ConnectionUpdateComplete without interval, latency and timeout
+    connector.provide_data_to_read(&[0x04, 0x3e, 0x04, 0x03, 0x00, 0x01, 0x00]);
+    // LE PhyUpdateComplete without the PHYs
+    connector.provide_data_to_read(&[0x04, 0x3e, 0x04, 0x0c, 0x00, 0x01, 0x00]);
+    // LE RemoteConnectionParameterRequest with the handle only
+    connector.provide_data_to_read(&[0x04, 0x3e, 0x03, 0x06, 0x01, 0x00]);
+    // LE meta event without a sub event
+    connector.provide_data_to_read(&[0x04, 0x3e, 0x00]);
+    // CommandComplete without the opcode
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x01, 0x05]);
+    for _ in 0..6 {
+        assert_matches!(ble.poll(), Some(PollResult::Event(EventType::Unknown)));
+    }
+
+    // HardwareError { code: 0x2a } is still decoded afterwards
+    connector.provide_data_to_read(&[0x04, 0x10, 0x01, 0x2a]);
+    assert_matches!(
+        ble.poll(),
+        Some(PollResult::Event(EventType::HardwareError { code: 0x2a }))
+    );
+}
+
+#[test]
+fn decoding_truncated_l2cap_packet_fails() {
+    let packet = AclPacket {
+        handle: 0,
+        boundary_flag: BoundaryFlag::FirstAutoFlushable,
+        bc_flag: ControllerBroadcastFlag::PointToPoint,
+        data: Data::new(&[0x07, 0x00]),
+    };
+    assert_matches!(
+        L2capPacket::decode(packet.clone()),
+        Err(L2capDecodeError::InvalidLength)
+    );
+
+    let packet = AclPacket {
+        data: Data::new(&[0x07, 0x00, 0x04, 0x00, 0x10]),
+        ..packet
+    };
+    assert_matches!(
+        L2capPacket::decode(packet),
+        Err(L2capDecodeError::InvalidLength)
+    );
+}
+
+#[test]
+fn l2cap_frames_are_fragmented_to_buffer_size() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // the controller accepts ACL packets with up to 27 bytes
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x01, 0x0c, 0x00, 0x04,
+        0x0e, 0x04, 0x05, 0x01, 0x20, 0x00, 0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0x1b, 0x00,
+        0x08,
+    ]);
+    provide_controller_info(&connector);
+    ble.init().unwrap();
+
+    let mut frame = Data::new(&[0x24, 0x00, 0x04, 0x00]);
+    frame.append(&[0xaa; 36]);
+    ble.write_l2cap(1, frame).unwrap();
+
+    assert_eq!(connector.get_write_idx(), 40 + 5 + 27 + 5 + 13);
+    let header = |idx| {
+        (idx..idx + 5)
+            .map(|i| connector.get_to_write_at(i))
+            .collect::<Vec<u8>>()
+    };
+    assert_eq!(header(40), [0x02, 0x01, 0x20, 0x1b, 0x00]);
+    assert_eq!(header(72), [0x02, 0x01, 0x10, 0x0d, 0x00]);
+}
+
+#[test]
+fn signaling_commands_roundtrip() {
+    let request = SignalingCommand::ConnectionParameterUpdateRequest {
+        identifier: 3,
+        parameters: ConnectionUpdateParameters::new(0x0018, 0x0028),
+    };
+    let encoded = request.encode();
+    assert_eq!(
+        encoded.as_slice(),
+        &[0x12, 0x03, 0x08, 0x00, 0x18, 0x00, 0x28, 0x00, 0x00, 0x00, 0xf4, 0x01]
+    );
+    assert_eq!(SignalingCommand::decode(encoded.as_slice()), Ok(request));
+
+    assert_eq!(
+        SignalingCommand::decode(&[0x01, 0x02, 0x06, 0x00, 0x02, 0x00, 0x40, 0x00, 0x41, 0x00]),
+        Ok(SignalingCommand::CommandReject {
+            identifier: 2,
+            reason: RejectReason::InvalidCid {
+                local_cid: 0x40,
+                remote_cid: 0x41,
+            },
+        })
+    );
+    assert_eq!(
+        SignalingCommand::decode(&[0x13, 0x01, 0x02, 0x00, 0x01, 0x00]),
+        Ok(SignalingCommand::ConnectionParameterUpdateResponse {
+            identifier: 1,
+            accepted: false,
+        })
+    );
+}
+
+#[test]
+fn decoding_invalid_signaling_commands_fails() {
+    assert_eq!(
+        SignalingCommand::decode(&[0x12, 0x01, 0x02, 0x00, 0x18, 0x00]),
+        Err(SignalingDecodeError::InvalidLength { identifier: 1 })
+    );
+    assert_eq!(
+        SignalingCommand::decode(&[0x20, 0x02, 0x00, 0x00]),
+        Err(SignalingDecodeError::UnknownCommand {
+            identifier: 2,
+            code: 0x20,
+        })
+    );
+    assert_eq!(
+        SignalingCommand::decode(&[0x13, 0x00, 0x02, 0x00, 0x00, 0x00]),
+        Err(SignalingDecodeError::InvalidIdentifier)
+    );
+}
+
+#[test]
+fn unknown_signaling_commands_are_rejected() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let res = ble.handle_signaling(0x0040, &[0x20, 0x05, 0x00, 0x00]);
+
+    assert_matches!(res, Ok(None));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[
+            0x02, 0x40, 0x20, 0x0a, 0x00, 0x06, 0x00, 0x05, 0x00, 0x01, 0x05, 0x02, 0x00, 0x00,
+            0x00
+        ]
+    );
+}
+
+#[test]
+fn connection_parameter_update_request_is_accepted() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[0x04, 0x0f, 0x04, 0x00, 0x01, 0x13, 0x20]);
+
+    let res = ble.handle_signaling(
+        0x0040,
+        &[
+            0x12, 0x07, 0x08, 0x00, 0x18, 0x00, 0x28, 0x00, 0x00, 0x00, 0xf4, 0x01,
+        ],
+    );
+
+    assert_matches!(
+        res,
+        Ok(Some(SignalingCommand::ConnectionParameterUpdateRequest {
+            identifier: 7,
+            ..
+        }))
+    );
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[
+            0x02, 0x40, 0x20, 0x0a, 0x00, 0x06, 0x00, 0x05, 0x00, 0x13, 0x07, 0x02, 0x00, 0x00,
+            0x00, 0x01, 0x13, 0x20, 0x0e, 0x40, 0x00, 0x18, 0x00, 0x28, 0x00, 0x00, 0x00, 0xf4,
+            0x01, 0x00, 0x00, 0x00, 0x00
+        ]
+    );
+}
+
+#[test]
+fn invalid_connection_parameter_update_request_is_rejected() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // the supervision timeout is too short for the interval
+    let res = ble.handle_signaling(
+        0x0040,
+        &[
+            0x12, 0x07, 0x08, 0x00, 0x18, 0x00, 0x28, 0x00, 0x00, 0x00, 0x0a, 0x00,
+        ],
+    );
+
+    assert_matches!(res, Ok(Some(_)));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[
+            0x02, 0x40, 0x20, 0x0a, 0x00, 0x06, 0x00, 0x05, 0x00, 0x13, 0x07, 0x02, 0x00, 0x01,
+            0x00
+        ]
+    );
+}
+
+#[test]
+fn disconnection_requests_are_rejected() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let res = ble.handle_signaling(0x0040, &[0x06, 0x09, 0x04, 0x00, 0x40, 0x00, 0x41, 0x00]);
+
+    assert_matches!(
+        res,
+        Ok(Some(SignalingCommand::DisconnectionRequest {
+            identifier: 9,
+            destination_cid: 0x40,
+            source_cid: 0x41,
+        }))
+    );
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[
+            0x02, 0x40, 0x20, 0x0e, 0x00, 0x0a, 0x00, 0x05, 0x00, 0x01, 0x09, 0x06, 0x00, 0x02,
+            0x00, 0x40, 0x00, 0x41, 0x00
+        ]
+    );
+}
+
+#[test]
+fn credit_based_signaling_commands_roundtrip() {
+    let request = SignalingCommand::LeCreditBasedConnectionRequest {
+        identifier: 1,
+        le_psm: 0x0080,
+        source_cid: 0x0041,
+        mtu: 0x0200,
+        mps: 0x0040,
+        initial_credits: 2,
+    };
+    let encoded = request.encode();
+    assert_eq!(
+        encoded.as_slice(),
+        &[0x14, 0x01, 0x0a, 0x00, 0x80, 0x00, 0x41, 0x00, 0x00, 0x02, 0x40, 0x00, 0x02, 0x00]
+    );
+    assert_eq!(SignalingCommand::decode(encoded.as_slice()), Ok(request));
+
+    assert_eq!(
+        SignalingCommand::decode(&[
+            0x15, 0x01, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00
+        ]),
+        Ok(SignalingCommand::LeCreditBasedConnectionResponse {
+            identifier: 1,
+            destination_cid: 0,
+            mtu: 0,
+            mps: 0,
+            initial_credits: 0,
+            result: CreditBasedConnectionResult::NoResourcesAvailable,
+        })
+    );
+
+    let credits = SignalingCommand::FlowControlCreditInd {
+        identifier: 2,
+        cid: 0x0040,
+        credits: 3,
+    };
+    assert_eq!(
+        credits.encode().as_slice(),
+        &[0x16, 0x02, 0x04, 0x00, 0x40, 0x00, 0x03, 0x00]
+    );
+}
+
+#[test]
+fn credit_based_connections_are_refused_without_channel() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let res = ble.handle_signaling(
+        0x0040,
+        &[
+            0x14, 0x03, 0x0a, 0x00, 0x80, 0x00, 0x41, 0x00, 0x00, 0x02, 0x40, 0x00, 0x02, 0x00,
+        ],
+    );
+
+    assert_matches!(
+        res,
+        Ok(Some(
+            SignalingCommand::LeCreditBasedConnectionRequest { .. }
+        ))
+    );
+    assert_eq!(
+        &connector.get_written_data().as_slice()[9..],
+        &[0x15, 0x03, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00]
+    );
+}
+
+#[test]
+fn l2cap_channel_accepts_and_grants_credits() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    let mut buffer = [0u8; 64];
+    let mut channel = L2capChannel::new(&mut ble, 0x0040, &mut buffer);
+
+    // LE Credit Based Connection Request for LE_PSM 0x80 with MTU 512, MPS 64, 2 credits
+    connector.provide_data_to_read(&[
+        0x02, 0x40, 0x20, 0x12, 0x00, 0x0e, 0x00, 0x05, 0x00, 0x14, 0x01, 0x0a, 0x00, 0x80, 0x00,
+        0x41, 0x00, 0x00, 0x02, 0x40, 0x00, 0x02, 0x00,
+    ]);
+    channel.accept(0x0080).unwrap();
+    assert!(channel.is_open());
+    assert_eq!(channel.remote_mtu(), 512);
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[
+            0x02, 0x40, 0x20, 0x12, 0x00, 0x0e, 0x00, 0x05, 0x00, 0x15, 0x01, 0x0a, 0x00, 0x40,
+            0x00, 0x00, 0x02, 0x40, 0x00, 0x01, 0x00, 0x00, 0x00
+        ]
+    );
+
+    // a single K-frame SDU
+    connector.provide_data_to_read(&[
+        0x02, 0x40, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x40, 0x00, 0x05, 0x00, b'h', b'e', b'l', b'l',
+        b'o',
+    ]);
+    let mut data = [0u8; 16];
+    let len = embedded_io_blocking::Read::read(&mut channel, &mut data).unwrap();
+    assert_eq!(&data[..len], b"hello");
+
+    // the consumed credit is given back
+    assert_eq!(
+        &connector.get_written_data().as_slice()[23..],
+        &[
+            0x02, 0x40, 0x20, 0x0c, 0x00, 0x08, 0x00, 0x05, 0x00, 0x16, 0x01, 0x04, 0x00, 0x40,
+            0x00, 0x01, 0x00
+        ]
+    );
+}
+
+#[test]
+fn l2cap_channel_closes_on_invalid_sdu() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    let mut buffer = [0u8; 64];
+    let mut channel = L2capChannel::new(&mut ble, 0x0040, &mut buffer);
+
+    connector.provide_data_to_read(&[
+        0x02, 0x40, 0x20, 0x12, 0x00, 0x0e, 0x00, 0x05, 0x00, 0x14, 0x01, 0x0a, 0x00, 0x80, 0x00,
+        0x41, 0x00, 0x00, 0x02, 0x40, 0x00, 0x02, 0x00,
+    ]);
+    channel.accept(0x0080).unwrap();
+
+    // the SDU is larger than our MTU
+    connector.provide_data_to_read(&[
+        0x02, 0x40, 0x20, 0x07, 0x00, 0x03, 0x00, 0x40, 0x00, 0x00, 0x03, b'h',
+    ]);
+    let mut data = [0u8; 16];
+    assert_matches!(channel.receive(&mut data), Ok(0));
+    assert!(!channel.is_open());
+
+    // Disconnection Request for our channel
+    assert_eq!(
+        &connector.get_written_data().as_slice()[23..],
+        &[
+            0x02, 0x40, 0x20, 0x0c, 0x00, 0x08, 0x00, 0x05, 0x00, 0x06, 0x01, 0x04, 0x00, 0x41,
+            0x00, 0x40, 0x00
+        ]
+    );
+}
+
+#[test]
+fn l2cap_channel_rejects_unacceptable_parameters() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    let mut buffer = [0u8; 64];
+    let mut channel = L2capChannel::new(&mut ble, 0x0040, &mut buffer);
+
+    // LE Credit Based Connection Response from CID 0x0041 with MTU 512, MPS 1, 2 credits
+    connector.provide_data_to_read(&[
+        0x02, 0x40, 0x20, 0x12, 0x00, 0x0e, 0x00, 0x05, 0x00, 0x15, 0x01, 0x0a, 0x00, 0x41, 0x00,
+        0x00, 0x02, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00,
+    ]);
+    assert_matches!(
+        channel.connect(0x0080),
+        Err(L2capChannelError::Refused(
+            CreditBasedConnectionResult::UnacceptableParameters
+        ))
+    );
+    assert!(!channel.is_open());
+
+    // Disconnection Request for the channel the peer opened
+    assert_eq!(
+        &connector.get_written_data().as_slice()[23..],
+        &[
+            0x02, 0x40, 0x20, 0x0c, 0x00, 0x08, 0x00, 0x05, 0x00, 0x06, 0x02, 0x04, 0x00, 0x41,
+            0x00, 0x40, 0x00
+        ]
+    );
+}
+
+#[test]
+fn l2cap_channel_needs_buffer_for_minimum_mtu() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    let mut buffer = [0u8; 16];
+    let mut channel = L2capChannel::new(&mut ble, 0x0040, &mut buffer);
+
+    assert_matches!(
+        channel.connect(0x0080),
+        Err(L2capChannelError::BufferTooSmall)
+    );
+}
+
+#[test]
+fn create_le_connection_update_works() {
+    let params = ConnectionUpdateParameters {
+        interval_min: 0x0006,
+        interval_max: 0x0c80,
+        max_latency: 0x0002,
+        supervision_timeout: 0x0c80,
+        min_ce_length: 0x0001,
+        max_ce_length: 0x0002,
+    };
+    let data = Command::LeConnectionUpdate {
+        handle: 0x0123,
+        params: &params,
+    }
+    .encode();
+    assert_eq!(
+        data.as_slice(),
+        &[
+            0x01, 0x13, 0x20, 0x0e, 0x23, 0x01, 0x06, 0x00, 0x80, 0x0c, 0x02, 0x00, 0x80, 0x0c,
+            0x01, 0x00, 0x02, 0x00
+        ]
+    );
+}
+
+#[test]
+fn create_read_by_group_type_resp_works() {
+    let mut res = Data::new_att_read_by_group_type_response();
+    res.append_att_read_by_group_type_response(0x0001, 0x0010, &Uuid::Uuid16(0x1801));
+    res.append_att_read_by_group_type_response(0x0020, 0x0030, &Uuid::Uuid16(0x1802));
+
+    assert_matches!(
+        res.as_slice(),
+        [0x11, 0x06, 0x01, 0x00, 0x10, 0x00, 0x01, 0x18, 0x20, 0x00, 0x30, 0x00, 0x02, 0x18]
+    );
+}
+
+#[test]
+fn create_read_by_group_type_resp_acl_works() {
+    let mut res = Data::new_att_read_by_group_type_response();
+    res.append_att_read_by_group_type_response(0x0001, 0x0010, &Uuid::Uuid16(0x1801));
+    res.append_att_read_by_group_type_response(0x0020, 0x0030, &Uuid::Uuid16(0x1802));
+    let res = L2capPacket::encode(res);
+    let res = AclPacket::encode(
+        0x0000,
+        BoundaryFlag::FirstAutoFlushable,
+        HostBroadcastFlag::NoBroadcast,
+        res,
+    );
+
+    assert_matches!(
+        res.as_slice(),
+        &[
+            0x02, 0x00, 0x20, 0x12, 0x00, 0x0e, 0x00, 0x04, 0x00, 0x11, 0x06, 0x01, 0x00, 0x10,
+            0x00, 0x01, 0x18, 0x20, 0x00, 0x30, 0x00, 0x02, 0x18,
+        ]
+    );
+}
+
+#[test]
+fn create_error_resp_works() {
+    let res = Data::new_att_error_response(
+        ATT_READ_BY_GROUP_TYPE_REQUEST_OPCODE,
+        0x1234,
+        AttErrorCode::AttributeNotFound,
+    );
+
+    assert_matches!(res.as_slice(), &[0x01, 0x10, 0x34, 0x12, 0x0a,]);
+}
+
+#[test]
+fn receiving_read_by_type_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x08, 0x01, 0x00, 0x02, 0x00, 0x02,
+        0x28,
+    ]);
+
+    let res = ble.poll();
+    match res {
+        Some(res) => match res {
+            PollResult::Event(_) => assert!(true, "Expected async data"),
+            PollResult::AsyncData(res) => {
+                let res = Att::decode(&L2capPacket::decode(res).unwrap().1);
+                assert_matches!(
+                    res,
+                    Ok(Att::ReadByTypeReq {
+                        start: 0x0001,
+                        end: 0x0002,
+                        attribute_type: Uuid::Uuid16(0x2802),
+                    })
+                )
+            }
+        },
+        None => assert!(true, "Expected result"),
+    }
+}
+
+#[test]
+fn create_read_by_type_resp_works() {
+    let mut res = Data::new_att_read_by_type_response();
+    res.append_value(0x0002u16);
+    res.append(&[1u8, 2u8, 3u8, 4u8]);
+    res.append_att_read_by_type_response();
+
+    assert_matches!(
+        res.as_slice(),
+        [0x09, 0x06, 0x02, 0x00, 0x01, 0x02, 0x03, 0x04]
+    );
+}
+
+// TODO test EXCHANGE_MTU
+
+// TODO test FIND_TYPE_VALUE
+
+#[test]
+fn receiving_read_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0a, 0x03, 0x00,
+    ]);
+
+    let res = ble.poll();
+    match res {
+        Some(res) => match res {
+            PollResult::Event(_) => assert!(true, "Expected async data"),
+            PollResult::AsyncData(res) => {
+                let res = Att::decode(&L2capPacket::decode(res).unwrap().1);
+                assert_matches!(res, Ok(Att::ReadReq { handle: 0x03 }))
+            }
+        },
+        None => assert!(true, "Expected result"),
+    }
+}
+
+#[test]
+fn create_read_resp_works() {
+    let mut res = Data::new_att_read_response();
+    res.append(&[0x01, 0x02, 0x03, 0x04]);
+
+    assert_matches!(res.as_slice(), &[0x0b, 0x01, 0x02, 0x03, 0x04,]);
+}
+
+#[test]
+fn receiving_write_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x08, 0x00, 0x04, 0x00, 0x04, 0x00, 0x12, 0x03, 0x00, 0x0ff,
+    ]);
+
+    let res = ble.poll();
+    match res {
+        Some(res) => match res {
+            PollResult::Event(_) => assert!(true, "Expected async data"),
+            PollResult::AsyncData(res) => {
+                let res = Att::decode(&L2capPacket::decode(res).unwrap().1);
+                assert_matches!(
+                    res,
+                    Ok(Att::WriteReq {
+                        handle: 0x03,
+                        data
+                    }) if data.as_slice() == &[0xff]
+                )
+            }
+        },
+        None => assert!(true, "Expected result"),
+    }
+}
+
+#[test]
+fn create_write_resp_works() {
+    let res = Data::new_att_write_response();
+
+    assert_matches!(res.as_slice(), &[0x13]);
+}
+
+#[test]
+fn create_advertising_data_works() {
+    let res = create_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::ServiceUuids16(&[Uuid::Uuid16(0x1809)]),
+        AdStructure::CompleteLocalName("Ble-Example!"),
+    ])
+    .unwrap();
+
+    println!("{:x?}", res);
+
+    assert_matches!(
+        res.as_slice(),
+        &[
+            21, 2, 1, 6, 3, 2, 9, 24, 13, 9, 66, 108, 101, 45, 69, 120, 97, 109, 112, 108, 101, 33,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+        ]
+    );
+}
+
+#[test]
+fn create_advertising_data_fails() {
+    let res = create_advertising_data(&[
+        AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+        AdStructure::ServiceUuids16(&[Uuid::Uuid16(0x1809)]),
+        AdStructure::CompleteLocalName(
+            "Ble-Example!Ble-Example!Ble-Example!Ble-Example!Ble-Example!Ble-Example!Ble-Example!",
+        ),
+    ]);
+
+    assert_matches!(res, Err(AdvertisementDataError::TooLong));
+}
+
+#[test]
+fn attribute_server_discover_two_services() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let mut rf1 = |_offset: usize, data: &mut [u8]| -> usize {
+        data[0] = 0;
+        1
+    };
+    let mut wf1 = |_offset: usize, _data: &[u8]| {};
+
+    let mut rf2 = |_offset: usize, data: &mut [u8]| -> usize {
+        data[0] = 0;
+        1
+    };
+    let mut wf2 = |_offset: usize, _data: &[u8]| {};
+
+    let srv_uuid: [u8; 16] = [
+        0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62, 0x6A,
+        0xA8,
+    ];
+    let mut srv_uuid_att_data = &srv_uuid[..];
+    let primaray_srv = Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_uuid_att_data);
+
+    let char_data = [
+        0x02, // 1 byte properties: READ = 0x02
+        0x03, 0x00, // 2 bytes handle = 0x0007
+        0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62, 0x6A,
+        0xA8, // 128 bit UUID like above
+    ];
+    let mut char_att_data = &char_data;
+    let char = Attribute::new(CHARACTERISTIC_UUID16, &mut char_att_data);
+
+    let mut custom_char_att_data = (&mut rf1, &mut wf1, ());
+    let custom_char_att_data_attr = Attribute::new(
+        Uuid::Uuid128([
+            0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62,
+            0x6A, 0xA8,
+        ]),
+        &mut custom_char_att_data,
+    );
+
+    let srv_uuid2: [u8; 16] = [
+        0xC8, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62, 0x6A,
+        0xA8,
+    ];
+    let mut srv_uuid_att_data2 = &srv_uuid2[..];
+    let primaray_srv2 = Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_uuid_att_data2);
+
+    let char_data2 = [
+        0x02 | 0x08, // 1 byte properties: READ = 0x02, WRITE WITH RESPONSE = 0x08
+        0x06,
+        0x00, // 2 bytes handle = 0x0007
+        0xC8,
+        0x15,
+        0x15,
+        0x96,
+        0x54,
+        0x56,
+        0x64,
+        0xB3,
+        0x38,
+        0x45,
+        0x26,
+        0x5D,
+        0xF1,
+        0x62,
+        0x6A,
+        0xA8, // 128 bit UUID like above
+    ];
+    let mut char_att_data2 = &char_data2;
+    let char2 = Attribute::new(CHARACTERISTIC_UUID16, &mut char_att_data2);
+
+    let mut custom_char_att_data2 = (&mut rf2, &mut wf2, ());
+    let custom_char_att_data_attr2 = Attribute::new(
+        Uuid::Uuid128([
+            0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1, 0x62,
+            0x6A, 0xA8,
+        ]),
+        &mut custom_char_att_data2,
+    );
+
+    let mut val_att_data = &(32u32,);
+    let val = Attribute::new(CHARACTERISTIC_UUID16, &mut val_att_data);
+
+    let attributes = &mut [
+        primaray_srv,
+        char,
+        custom_char_att_data_attr,
+        primaray_srv2,
+        char2,
+        custom_char_att_data_attr2,
+        val,
+    ];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    // ReadByGroupTypeReq { start: 1, end: ffff, group_type: Uuid16(2800) }
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff, 0x00,
+        0x28,
+    ]);
+    assert_matches!(srv.do_work(), Ok(_));
+    // check response (1-3, 0x2800)
+    let response_data = connector.get_written_data();
+    assert_eq!(
+        response_data.as_slice(),
+        &[
+            0x02, 0x00, 0x20, 0x1A, 0x00, 0x16, 0x00, 0x04, 0x00, 0x11, 0x14, 0x01, 0x00, 0x03,
+            0x00, 0xC9, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1,
+            0x62, 0x6A, 0xA8,
+        ]
+    );
+
+    // ReadByGroupTypeReq { start: 4, end: ffff, group_type: Uuid16(2800) }
+    connector.reset();
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x04, 0x00, 0xff, 0xff, 0x00,
+        0x28,
+    ]);
+    assert_matches!(srv.do_work(), Ok(_));
+    // check response (4-6, 0x2800)
+    let response_data = connector.get_written_data();
+    assert_eq!(
+        response_data.as_slice(),
+        &[
+            0x02, 0x00, 0x20, 0x1a, 0x00, 0x16, 0x00, 0x04, 0x00, 0x11, 0x14, 0x04, 0x00, 0x07,
+            0x00, 0xC8, 0x15, 0x15, 0x96, 0x54, 0x56, 0x64, 0xB3, 0x38, 0x45, 0x26, 0x5D, 0xF1,
+            0x62, 0x6A, 0xA8,
+        ]
+    );
+
+    // ReadByGroupTypeReq { start: 7, end: ffff, group_type: Uuid16(2800) }
+    connector.reset();
+    connector.provide_data_to_read(&[
+        0x02, 0x00, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x07, 0x00, 0xff, 0xff, 0x00,
+        0x28,
+    ]);
+    assert_matches!(srv.do_work(), Ok(_));
+    // check response (not found)
+    let response_data = connector.get_written_data();
+    assert_eq!(
+        response_data.as_slice(),
+        &[0x02, 0x00, 0x20, 0x09, 0x00, 0x05, 0x00, 0x04, 0x00, 0x01, 0x10, 0x07, 0x00, 0x0a]
+    );
+}
+
+#[test]
+fn attribute_server_tracks_connections() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let srv_data = [0x0f, 0x18];
+    let mut srv_att_data = &srv_data;
+    let char_data = [
+        0x02 | 0x10, // 1 byte properties: READ = 0x02, NOTIFY = 0x10
+        0x03,
+        0x00, // 2 bytes handle = 0x0003
+        0x19,
+        0x2a, // 16 bit UUID
+    ];
+    let mut char_att_data = &char_data;
+    let value_data = [0x42];
+    let mut value_att_data = &value_data;
+    let mut cccd_data = [0u8; 2];
+    let mut cccd_att_data = &mut cccd_data;
+
+    let attributes = &mut [
+        Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_att_data),
+        Attribute::new(CHARACTERISTIC_UUID16, &mut char_att_data),
+        Attribute::new(Uuid::Uuid16(0x2a19), &mut value_att_data),
+        Attribute::new(
+            CLIENT_CHARACTERISTIC_CONFIGURATION_UUID16,
+            &mut cccd_att_data,
+        ),
+    ];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    // ConnectionComplete { handle: 0x0001 } and ConnectionComplete { handle: 0x0002 }
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x13, 0x01, 0x00, 0x01, 0x00, 0x01, 0x01, 0x01, 0x02, 0x03, 0x04, 0x05, 0xc6,
+        0x18, 0x00, 0x00, 0x00, 0xf4, 0x01, 0x00,
+    ]);
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x13, 0x01, 0x00, 0x02, 0x00, 0x01, 0x01, 0x11, 0x12, 0x13, 0x14, 0x15, 0xc6,
+        0x18, 0x00, 0x00, 0x00, 0xf4, 0x01, 0x00,
+    ]);
+    assert_matches!(srv.do_work(), Ok(_));
+    assert_matches!(srv.do_work(), Ok(_));
+    assert_eq!(srv.connections().count(), 2);
+
+    // WriteReq { handle: 0x0004, data: [01, 00] } on connection 0x0002
+    connector.reset();
+    connector.provide_data_to_read(&[
+        0x02, 0x02, 0x20, 0x09, 0x00, 0x05, 0x00, 0x04, 0x00, 0x12, 0x04, 0x00, 0x01, 0x00,
+    ]);
+    assert_matches!(srv.do_work(), Ok(_));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x02, 0x02, 0x20, 0x05, 0x00, 0x01, 0x00, 0x04, 0x00, 0x13]
+    );
+    assert_eq!(srv.connection(0x0001).unwrap().cccd(0x0004), 0);
+    assert_eq!(srv.connection(0x0002).unwrap().cccd(0x0004), 1);
+
+    // the notification only goes to the subscribed connection
+    connector.reset();
+    assert_matches!(
+        srv.do_work_with_notification(Some(NotificationData::new(0x0003, &[0x43]))),
+        Ok(_)
+    );
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x02, 0x02, 0x20, 0x08, 0x00, 0x04, 0x00, 0x04, 0x00, 0x1b, 0x03, 0x00, 0x43]
+    );
+
+    // ReadReq { handle: 0x0004 } on connection 0x0001 sees its own configuration
+    connector.reset();
+    connector.provide_data_to_read(&[
+        0x02, 0x01, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0a, 0x04, 0x00,
+    ]);
+    assert_matches!(srv.do_work(), Ok(_));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x02, 0x01, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0b, 0x00, 0x00]
+    );
+
+    // DisconnectComplete { handle: 0x0002 }
+    connector.reset();
+    connector.provide_data_to_read(&[0x04, 0x05, 0x04, 0x00, 0x02, 0x00, 0x13]);
+    assert_matches!(srv.do_work(), Ok(WorkResult::GotDisconnected));
+    assert!(srv.connection(0x0002).is_none());
+    assert_eq!(srv.connections().count(), 1);
+}
+
+#[test]
+fn attribute_server_only_accepts_bonded_peers() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let value_data = [0x42];
+    let mut value_att_data = &value_data;
+    let attributes = &mut [Attribute::new(Uuid::Uuid16(0x2a19), &mut value_att_data)];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    // CommandComplete for LE Clear Filter Accept List, LE Read Filter Accept List Size (8) and
+    // LE Add Device To Filter Accept List
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x01, 0x10, 0x20, 0x00]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x05, 0x01, 0x0f, 0x20, 0x00, 0x08]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x01, 0x11, 0x20, 0x00]);
+    let bonded = [Addr::from_le_bytes(
+        true,
+        [0x01, 0x02, 0x03, 0x04, 0x05, 0xc6],
+    )];
+    assert_matches!(srv.set_bonded_only(Some(&bonded)), Ok(()));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[
+            0x01, 0x10, 0x20, 0x00, 0x01, 0x0f, 0x20, 0x00, 0x01, 0x11, 0x20, 0x07, 0x01, 0x01,
+            0x02, 0x03, 0x04, 0x05, 0xc6
+        ]
+    );
+
+    // ConnectionComplete { handle: 0x0001 } from an unknown peer, CommandStatus for Disconnect
+    connector.reset();
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x13, 0x01, 0x00, 0x01, 0x00, 0x01, 0x01, 0x11, 0x12, 0x13, 0x14, 0x15, 0xc6,
+        0x18, 0x00, 0x00, 0x00, 0xf4, 0x01, 0x00,
+    ]);
+    connector.provide_data_to_read(&[0x04, 0x0f, 0x04, 0x00, 0x01, 0x06, 0x04]);
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x01, 0x06, 0x04, 0x03, 0x01, 0x00, 0x05]
+    );
+    assert_eq!(srv.connections().count(), 0);
+
+    // ConnectionComplete { handle: 0x0002 } from the bonded peer
+    connector.reset();
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x13, 0x01, 0x00, 0x02, 0x00, 0x01, 0x01, 0x01, 0x02, 0x03, 0x04, 0x05, 0xc6,
+        0x18, 0x00, 0x00, 0x00, 0xf4, 0x01, 0x00,
+    ]);
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+    assert_eq!(connector.get_written_data().len(), 0);
+    assert!(srv.connection(0x0002).is_some());
+}
+
+#[test]
+fn attribute_server_pauses_advertising_while_updating_bonded_peers() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // CommandComplete for LE Set Advertise Enable
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x01, 0x0a, 0x20, 0x00]);
+    assert_matches!(ble.cmd_set_le_advertise_enable(true), Ok(_));
+
+    let value_data = [0x42];
+    let mut value_att_data = &value_data;
+    let attributes = &mut [Attribute::new(Uuid::Uuid16(0x2a19), &mut value_att_data)];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    // CommandComplete for LE Set Advertise Enable, LE Clear Filter Accept List,
+    // LE Read Filter Accept List Size (8), LE Add Device To Filter Accept List failing with
+    // Memory Capacity Exceeded and LE Set Advertise Enable
+    connector.reset();
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x01, 0x0a, 0x20, 0x00]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x01, 0x10, 0x20, 0x00]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x05, 0x01, 0x0f, 0x20, 0x00, 0x08]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x01, 0x11, 0x20, 0x07]);
+    connector.provide_data_to_read(&[0x04, 0x0e, 0x04, 0x01, 0x0a, 0x20, 0x00]);
+    let bonded = [Addr::from_le_bytes(
+        true,
+        [0x01, 0x02, 0x03, 0x04, 0x05, 0xc6],
+    )];
+    assert_matches!(
+        srv.set_bonded_only(Some(&bonded)),
+        Err(bleps::Error::CommandFailed {
+            opcode: 0x2011,
+            status: 0x07
+        })
+    );
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[
+            0x01, 0x0a, 0x20, 0x01, 0x00, 0x01, 0x10, 0x20, 0x00, 0x01, 0x0f, 0x20, 0x00, 0x01,
+            0x11, 0x20, 0x07, 0x01, 0x01, 0x02, 0x03, 0x04, 0x05, 0xc6, 0x01, 0x0a, 0x20, 0x01,
+            0x01
+        ]
+    );
+
+    // ConnectionComplete { handle: 0x0001 } from an unknown peer is still accepted
+    connector.reset();
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x13, 0x01, 0x00, 0x01, 0x00, 0x01, 0x01, 0x11, 0x12, 0x13, 0x14, 0x15, 0xc6,
+        0x18, 0x00, 0x00, 0x00, 0xf4, 0x01, 0x00,
+    ]);
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+    assert_eq!(connector.get_written_data().len(), 0);
+    assert!(srv.connection(0x0001).is_some());
+}
+
+#[test]
+fn attribute_server_returns_signaling_commands() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let value_data = [0x42];
+    let mut value_att_data = &value_data;
+    let attributes = &mut [Attribute::new(Uuid::Uuid16(0x2a19), &mut value_att_data)];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    // Connection Parameter Update Response rejecting the request with identifier 7
+    connector.provide_data_to_read(&[
+        0x02, 0x40, 0x20, 0x0a, 0x00, 0x06, 0x00, 0x05, 0x00, 0x13, 0x07, 0x02, 0x00, 0x01, 0x00,
+    ]);
+    assert_matches!(
+        srv.do_work(),
+        Ok(WorkResult::GotSignaling {
+            handle: 0x0040,
+            command: SignalingCommand::ConnectionParameterUpdateResponse {
+                identifier: 7,
+                accepted: false
+            }
+        })
+    );
+    assert_eq!(connector.get_written_data().len(), 0);
+}
+
+#[test]
+fn attribute_server_recovers_after_hardware_error() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let value_data = [0x42];
+    let mut value_att_data = &value_data;
+    let attributes = &mut [Attribute::new(Uuid::Uuid16(0x2a19), &mut value_att_data)];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    // ConnectionComplete { handle: 0x0001 }, HardwareError { code: 0x2a }
+    connector.provide_data_to_read(&[
+        0x04, 0x3e, 0x13, 0x01, 0x00, 0x01, 0x00, 0x01, 0x01, 0x11, 0x12, 0x13, 0x14, 0x15, 0xc6,
+        0x18, 0x00, 0x00, 0x00, 0xf4, 0x01, 0x00, 0x04, 0x10, 0x01, 0x2a,
+    ]);
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+    assert!(srv.connection(0x0001).is_some());
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+
+    connector.reset();
+    provide_init_responses(&connector);
+    assert_matches!(srv.do_work(), Ok(WorkResult::GotDisconnected));
+    assert_eq!(srv.connections().count(), 0);
+    assert_eq!(connector.get_write_idx(), 40);
+
+    // nothing to recover from anymore
+    connector.reset();
+    assert_matches!(srv.do_work(), Ok(WorkResult::DidWork));
+    assert_eq!(connector.get_write_idx(), 0);
+}
+
+/// Sends an ATT PDU on connection 0x0001 to the server and returns the ATT PDU it answers with
+fn att_request(
+    connector: &TestConnector,
+    srv: &mut AttributeServer<'_, OsRng>,
+    pdu: &[u8],
+) -> Vec<u8> {
+    let len = pdu.len() as u8;
+    connector.reset();
+    connector.provide_data_to_read(&[0x02, 0x01, 0x20, len + 4, 0x00, len, 0x00, 0x04, 0x00]);
+    connector.provide_data_to_read(pdu);
+    assert_matches!(srv.do_work(), Ok(_));
+    connector.get_written_data().as_slice()[9..].to_vec()
+}
+
+#[test]
+fn attribute_server_executes_prepared_writes_atomically() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let mut value_data = [0u8; 4];
+    let mut value_att_data = &mut value_data;
+    let read_only_data = [0x42];
+    let mut read_only_att_data = &read_only_data;
+    let attributes = &mut [
+        Attribute::new(Uuid::Uuid16(0x2a19), &mut value_att_data),
+        Attribute::new(Uuid::Uuid16(0x2a1a), &mut read_only_att_data),
+    ];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+    let mut value = [0u8; 4];
+
+    // PrepareWriteReq { handle: 0x0001, offset: 0, value: [01, 02] } is echoed
+    assert_eq!(
+        att_request(
+            &connector,
+            &mut srv,
+            &[0x16, 0x01, 0x00, 0x00, 0x00, 0x01, 0x02]
+        ),
+        [0x17, 0x01, 0x00, 0x00, 0x00, 0x01, 0x02]
+    );
+    assert!(srv.connection(0x0001).is_some());
+
+    // PrepareWriteReq { handle: 0x0001, offset: 2, value: [03, 04, 05] } exceeds the value,
+    // ExecuteWriteReq { flags: 0x01 } fails and writes nothing
+    assert_eq!(
+        att_request(
+            &connector,
+            &mut srv,
+            &[0x16, 0x01, 0x00, 0x02, 0x00, 0x03, 0x04, 0x05]
+        ),
+        [0x17, 0x01, 0x00, 0x02, 0x00, 0x03, 0x04, 0x05]
+    );
+    assert_eq!(
+        att_request(&connector, &mut srv, &[0x18, 0x01]),
+        [
+            0x01,
+            0x18,
+            0x01,
+            0x00,
+            AttErrorCode::InvalidAttributeValueLength as u8
+        ]
+    );
+    assert_eq!(srv.get_characteristic_value(0x0001, 0, &mut value), Some(4));
+    assert_eq!(value, [0, 0, 0, 0]);
+
+    // the queue was discarded, PrepareWriteReq { offset: 5 } is past the end of the value
+    assert_eq!(
+        att_request(&connector, &mut srv, &[0x16, 0x01, 0x00, 0x05, 0x00, 0x01]),
+        [0x17, 0x01, 0x00, 0x05, 0x00, 0x01]
+    );
+    assert_eq!(
+        att_request(&connector, &mut srv, &[0x18, 0x01]),
+        [0x01, 0x18, 0x01, 0x00, AttErrorCode::InvalidOffset as u8]
+    );
+
+    // ExecuteWriteReq { flags: 0x00 } cancels
+    att_request(
+        &connector,
+        &mut srv,
+        &[0x16, 0x01, 0x00, 0x00, 0x00, 0x09, 0x09],
+    );
+    assert_eq!(att_request(&connector, &mut srv, &[0x18, 0x00]), [0x19]);
+    assert_eq!(srv.get_characteristic_value(0x0001, 0, &mut value), Some(4));
+    assert_eq!(value, [0, 0, 0, 0]);
+
+    // a long write in two parts
+    att_request(
+        &connector,
+        &mut srv,
+        &[0x16, 0x01, 0x00, 0x00, 0x00, 0x01, 0x02],
+    );
+    att_request(
+        &connector,
+        &mut srv,
+        &[0x16, 0x01, 0x00, 0x02, 0x00, 0x03, 0x04],
+    );
+    assert_eq!(att_request(&connector, &mut srv, &[0x18, 0x01]), [0x19]);
+    assert_eq!(srv.get_characteristic_value(0x0001, 0, &mut value), Some(4));
+    assert_eq!(value, [1, 2, 3, 4]);
+    assert_eq!(att_request(&connector, &mut srv, &[0x18, 0x01]), [0x19]);
+
+    // unknown and read only attributes are rejected right away
+    assert_eq!(
+        att_request(&connector, &mut srv, &[0x16, 0x09, 0x00, 0x00, 0x00, 0x01]),
+        [0x01, 0x16, 0x09, 0x00, AttErrorCode::InvalidHandle as u8]
+    );
+    assert_eq!(
+        att_request(&connector, &mut srv, &[0x16, 0x02, 0x00, 0x00, 0x00, 0x01]),
+        [
+            0x01,
+            0x16,
+            0x02,
+            0x00,
+            AttErrorCode::WriteNotPermitted as u8
+        ]
+    );
+
+    for offset in 0..MAX_PREPARED_WRITES as u8 {
+        att_request(
+            &connector,
+            &mut srv,
+            &[0x16, 0x01, 0x00, offset, 0x00, 0x01],
+        );
+    }
+    assert_eq!(
+        att_request(&connector, &mut srv, &[0x16, 0x01, 0x00, 0x00, 0x00, 0x01]),
+        [0x01, 0x16, 0x01, 0x00, AttErrorCode::PrepareQueueFull as u8]
+    );
+    assert_eq!(att_request(&connector, &mut srv, &[0x18, 0x00]), [0x19]);
+}
+
+#[test]
+fn attribute_server_writes_each_prepared_value_once() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // a value without a known maximum length, which only accepts up to 4 bytes
+    let writes = RefCell::new(Vec::new());
+    let mut write = |offset: usize, data: &[u8]| {
+        if offset + data.len() > 4 {
+            return Err(AttErrorCode::InvalidAttributeValueLength);
+        }
+        writes.borrow_mut().push((offset, data.to_vec()));
+        Ok(())
+    };
+    let mut att_data = ((), &mut write, ());
+    let attributes = &mut [Attribute::new(Uuid::Uuid16(0x2a19), &mut att_data)];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    // a long write in two parts is written at once
+    att_request(
+        &connector,
+        &mut srv,
+        &[0x16, 0x01, 0x00, 0x00, 0x00, 0x01, 0x02],
+    );
+    att_request(
+        &connector,
+        &mut srv,
+        &[0x16, 0x01, 0x00, 0x02, 0x00, 0x03, 0x04],
+    );
+    assert_eq!(att_request(&connector, &mut srv, &[0x18, 0x01]), [0x19]);
+    assert_eq!(*writes.borrow(), [(0, vec![1, 2, 3, 4])]);
+
+    // the complete value is rejected, none of its parts is written
+    att_request(
+        &connector,
+        &mut srv,
+        &[0x16, 0x01, 0x00, 0x00, 0x00, 0x05, 0x06],
+    );
+    att_request(
+        &connector,
+        &mut srv,
+        &[0x16, 0x01, 0x00, 0x02, 0x00, 0x07, 0x08, 0x09],
+    );
+    assert_eq!(
+        att_request(&connector, &mut srv, &[0x18, 0x01]),
+        [
+            0x01,
+            0x18,
+            0x01,
+            0x00,
+            AttErrorCode::InvalidAttributeValueLength as u8
+        ]
+    );
+    assert_eq!(writes.borrow().len(), 1);
+
+    // a gap between the parts is rejected
+    att_request(&connector, &mut srv, &[0x16, 0x01, 0x00, 0x00, 0x00, 0x01]);
+    att_request(&connector, &mut srv, &[0x16, 0x01, 0x00, 0x03, 0x00, 0x04]);
+    assert_eq!(
+        att_request(&connector, &mut srv, &[0x18, 0x01]),
+        [0x01, 0x18, 0x01, 0x00, AttErrorCode::InvalidOffset as u8]
+    );
+    assert_eq!(writes.borrow().len(), 1);
+}
+
+#[test]
+fn attribute_server_executes_prepared_cccd_writes() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    let char_data = [0x10, 0x02, 0x00, 0x19, 0x2a];
+    let mut char_att_data = &char_data;
+    let value_data = [0x42];
+    let mut value_att_data = &value_data;
+    let mut cccd_data = [0u8; 2];
+    let mut cccd_att_data = &mut cccd_data;
+    let attributes = &mut [
+        Attribute::new(CHARACTERISTIC_UUID16, &mut char_att_data),
+        Attribute::new(Uuid::Uuid16(0x2a19), &mut value_att_data),
+        Attribute::new(
+            CLIENT_CHARACTERISTIC_CONFIGURATION_UUID16,
+            &mut cccd_att_data,
+        ),
+    ];
+
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    // the client subscribes with a prepared write of the CCCD
+    att_request(
+        &connector,
+        &mut srv,
+        &[0x16, 0x03, 0x00, 0x00, 0x00, 0x01, 0x00],
+    );
+    assert_eq!(att_request(&connector, &mut srv, &[0x18, 0x01]), [0x19]);
+    assert_eq!(srv.connection(0x0001).unwrap().cccd(0x0003), 1);
+
+    // and gets notified
+    connector.reset();
+    assert_matches!(
+        srv.do_work_with_notification(Some(NotificationData::new(0x0002, &[0x43]))),
+        Ok(_)
+    );
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x02, 0x01, 0x20, 0x08, 0x00, 0x04, 0x00, 0x04, 0x00, 0x1b, 0x02, 0x00, 0x43]
+    );
+}
+
+#[test]
+fn gatt_client_discover_services_works() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    let mut client = GattClient::new(&mut ble, 0x0040);
+
+    // ReadByGroupTypeRsp { 0x0001-0x0005: 0x180f, 0x0006-0xffff: 0x180a }
+    connector.provide_data_to_read(&[
+        0x02, 0x40, 0x20, 0x12, 0x00, 0x0e, 0x00, 0x04, 0x00, 0x11, 0x06, 0x01, 0x00, 0x05, 0x00,
+        0x0f, 0x18, 0x06, 0x00, 0xff, 0xff, 0x0a, 0x18,
+    ]);
+
+    let mut services = [Service::default(); 4];
+    let count = client.discover_services(&mut services).unwrap();
+
+    assert_eq!(count, 2);
+    assert_eq!(
+        services[0],
+        Service {
+            start_handle: 0x0001,
+            end_handle: 0x0005,
+            uuid: Uuid::Uuid16(0x180f)
+        }
+    );
+    assert_eq!(
+        services[1],
+        Service {
+            start_handle: 0x0006,
+            end_handle: 0xffff,
+            uuid: Uuid::Uuid16(0x180a)
+        }
+    );
+
+    // ReadByGroupTypeReq { start: 1, end: ffff, group_type: Uuid16(2800) }
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[
+            0x02, 0x40, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x04, 0x00, 0x10, 0x01, 0x00, 0xff, 0xff,
+            0x00, 0x28,
+        ]
+    );
+}
+
+#[test]
+fn gatt_client_read_delivers_notifications() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    let mut client = GattClient::new(&mut ble, 0x0040);
+
+    let notified = RefCell::new(None);
+    let mut callback = |handle: u16, data: &[u8]| {
+        notified.replace(Some((handle, data[0])));
+    };
+    client.set_notification_callback(Some(&mut callback));
+
+    // Notification { handle: 0x0010, value: 0xaa }
+    connector.provide_data_to_read(&[
+        0x02, 0x40, 0x20, 0x08, 0x00, 0x04, 0x00, 0x04, 0x00, 0x1b, 0x10, 0x00, 0xaa,
+    ]);
+    // ReadRsp [1, 2, 3]
+    connector.provide_data_to_read(&[
+        0x02, 0x40, 0x20, 0x08, 0x00, 0x04, 0x00, 0x04, 0x00, 0x0b, 0x01, 0x02, 0x03,
+    ]);
+
+    let mut buffer = [0u8; 16];
+    let len = client.read(0x0003, &mut buffer).unwrap();
+
+    assert_eq!(&buffer[..len], &[1, 2, 3]);
+    assert_eq!(*notified.borrow(), Some((0x0010, 0xaa)));
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x02, 0x40, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0a, 0x03, 0x00]
+    );
+}
+
+#[test]
+fn gatt_client_requests_at_most_the_supported_mtu() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    let mut client = GattClient::new(&mut ble, 0x0040);
+
+    // ExchangeMtuRsp { mtu: 517 }
+    connector.provide_data_to_read(&[
+        0x02, 0x40, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x03, 0x05, 0x02,
+    ]);
+
+    assert_matches!(client.exchange_mtu(517), Ok(mtu) if mtu == bleps::attribute_server::MTU);
+    let [mtu_lo, mtu_hi] = bleps::attribute_server::MTU.to_le_bytes();
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x02, 0x40, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x02, mtu_lo, mtu_hi]
+    );
+}
+
+#[test]
+fn gatt_client_write_fails() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    let mut client = GattClient::new(&mut ble, 0x0040);
+
+    // ErrorRsp { WriteReq, handle: 0x0003, WriteNotPermitted }
+    connector.provide_data_to_read(&[
+        0x02, 0x40, 0x20, 0x09, 0x00, 0x05, 0x00, 0x04, 0x00, 0x01, 0x12, 0x03, 0x00, 0x03,
+    ]);
+
+    let res = client.subscribe(0x0003, false);
+
+    assert_matches!(
+        res,
+        Err(GattClientError::ErrorResponse {
+            handle: 0x0003,
+            code: 0x03
+        })
+    );
+    assert_eq!(
+        connector.get_written_data().as_slice(),
+        &[0x02, 0x40, 0x20, 0x09, 0x00, 0x05, 0x00, 0x04, 0x00, 0x12, 0x03, 0x00, 0x01, 0x00]
+    );
+}
+
+#[test]
+fn gatt_client_rejects_empty_pdu() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+    let mut client = GattClient::new(&mut ble, 0x0040);
+
+    // an ATT PDU without opcode instead of the ReadRsp
+    connector.provide_data_to_read(&[0x02, 0x40, 0x20, 0x04, 0x00, 0x00, 0x00, 0x04, 0x00]);
+
+    let mut buffer = [0u8; 16];
+    assert_matches!(
+        client.read(0x0003, &mut buffer),
+        Err(GattClientError::InvalidPdu)
+    );
+}
+
+#[test]
+fn gatt_client_write_without_response_fails_without_buffers() {
+    let connector = connector();
+    let mut ble = Ble::new(&connector);
+
+    // the controller only buffers a single ACL packet
+    connector.provide_data_to_read(&[
+        0x04, 0x0e, 0x04, 0x05, 0x03, 0x0c, 0x00, 0x04, 0x0e, 0x04, 0x05, 0x01, 0x0c, 0x00, 0x04,
+        0x0e, 0x04, 0x05, 0x01, 0x20, 0x00, 0x04, 0x0e, 0x07, 0x05, 0x02, 0x20, 0x00, 0xfb, 0x00,
+        0x01,
+    ]);
+    provide_controller_info(&connector);
+    ble.init().unwrap();
+    let mut client = GattClient::new(&mut ble, 0x0040);
+
+    // one packet is sent, the ACL queue holds four more
+    for _ in 0..5 {
+        assert_matches!(client.write_without_response(0x0003, &[0x01]), Ok(()));
+    }
+
+    // no Number Of Completed Packets event arrives in time
+    let idx = connector.get_current_millis_idx();
+    for i in idx + 1..128 {
+        connector.set_current_millis_at(i, 2000);
+    }
+    assert_matches!(
+        client.write_without_response(0x0003, &[0x01]),
+        Err(GattClientError::Hci(bleps::Error::Timeout))
+    );
+}
+
+#[test]
+fn extended_advertising_works_after_init() {
+    let link = VirtualLink::new();
+    let (controller, _) = link.controllers();
+    let mut ble = Ble::new(&controller);
+
+    ble.init().unwrap();
+    assert_matches!(
+        ble.cmd_set_le_extended_advertising_parameters(
+            &ExtendedAdvertisingParameters::connectable(0)
+        ),
+        Ok(_)
+    );
+    assert_matches!(
+        ble.cmd_set_le_extended_advertising_enable(true, &[AdvertisingSet::new(0)]),
+        Ok(_)
+    );
+
+    // legacy advertising commands are disallowed until the next reset
+    assert_matches!(
+        ble.cmd_le_read_advertising_physical_channel_tx_power(),
+        Err(bleps::Error::CommandFailed {
+            opcode: 0x2007,
+            status: 0x0c
+        })
+    );
+    ble.cmd_reset().unwrap();
+    assert_matches!(
+        ble.cmd_le_read_advertising_physical_channel_tx_power(),
+        Ok(_)
+    );
+}
+
+#[test]
+fn virtual_controller_connects_two_stacks() {
+    let link = VirtualLink::new();
+    let (peripheral_controller, central_controller) = link.controllers();
+    let mut peripheral = Ble::new(&peripheral_controller);
+    let mut central = Ble::new(&central_controller);
+
+    peripheral.init().unwrap();
+    central.init().unwrap();
+    peripheral.cmd_set_le_advertising_parameters().unwrap();
+    peripheral.cmd_set_le_advertise_enable(true).unwrap();
+
+    let params =
+        CreateConnectionParameters::new(PeerAddressType::Public, peripheral_controller.address());
+    let connection = central.connect(&params, 1000).unwrap();
+    assert_eq!(connection.role, Role::Central);
+    assert_eq!(Some(connection.handle), link.connection_handle());
+
+    assert_matches!(
+        peripheral.poll(),
+        Some(PollResult::Event(EventType::ConnectionComplete {
+            status: 0,
+            role: 1,
+            ..
+        }))
+    );
+
+    // ACL data is relayed to the peer
+    central
+        .write_l2cap(
+            connection.handle,
+            L2capPacket::encode(Data::new(&[0x0a, 0x03, 0x00])),
+        )
+        .unwrap();
+    assert_matches!(
+        peripheral.poll(),
+        Some(PollResult::AsyncData(packet)) if packet.data.as_slice() == [0x03, 0x00, 0x04, 0x00, 0x0a, 0x03, 0x00]
+    );
+    assert_matches!(
+        central.poll(),
+        Some(PollResult::Event(
+            EventType::NumberOfCompletedPackets { .. }
+        ))
+    );
+
+    // encryption with a matching LTK
+    central
+        .cmd_le_start_encryption(connection.handle, 0, 0, 0x1234)
+        .unwrap();
+    assert_matches!(
+        peripheral.poll(),
+        Some(PollResult::Event(EventType::LongTermKeyRequest { .. }))
+    );
+    peripheral
+        .cmd_long_term_key_request_reply(connection.handle, 0x1234)
+        .unwrap();
+    assert_matches!(
+        central.poll(),
+        Some(PollResult::Event(EventType::EncryptionChange {
+            status: 0,
+            enabled: true,
+            ..
+        }))
+    );
+    assert_matches!(
+        peripheral.poll(),
+        Some(PollResult::Event(EventType::EncryptionChange {
+            status: 0,
+            enabled: true,
+            ..
+        }))
+    );
+
+    central
+        .cmd_disconnect(
+            connection.handle,
+            ErrorCode::RemoteUserTerminatedConnection as u8,
+        )
+        .unwrap();
+    assert_matches!(
+        peripheral.poll(),
+        Some(PollResult::Event(EventType::DisconnectComplete {
+            reason: ErrorCode::RemoteUserTerminatedConnection,
+            ..
+        }))
+    );
+    assert_eq!(link.connection_handle(), None);
+}
+
+#[test]
+fn virtual_controller_updates_connection_parameters() {
+    let link = VirtualLink::new();
+    let (peripheral_controller, central_controller) = link.controllers();
+    let mut peripheral = Ble::new(&peripheral_controller);
+    let mut central = Ble::new(&central_controller);
+
+    peripheral.init().unwrap();
+    central.init().unwrap();
+    peripheral.cmd_set_le_advertising_parameters().unwrap();
+    peripheral.cmd_set_le_advertise_enable(true).unwrap();
+    let params =
+        CreateConnectionParameters::new(PeerAddressType::Public, peripheral_controller.address());
+    let handle = central.connect(&params, 1000).unwrap().handle;
+    assert_matches!(peripheral.poll(), Some(PollResult::Event(_)));
+
+    let identifier = peripheral
+        .request_connection_parameter_update(handle, &ConnectionUpdateParameters::new(6, 12))
+        .unwrap();
+
+    let request = loop {
+        if let Some(PollResult::AsyncData(packet)) = central.poll() {
+            break L2capPacket::decode(packet).unwrap().1;
+        }
+    };
+    assert_eq!(request.channel, LE_SIGNALING_CHANNEL);
+    assert_matches!(
+        central.handle_signaling(handle, request.payload.as_slice()),
+        Ok(Some(
+            SignalingCommand::ConnectionParameterUpdateRequest { .. }
+        ))
+    );
+
+    let response = loop {
+        if let Some(PollResult::AsyncData(packet)) = peripheral.poll() {
+            break L2capPacket::decode(packet).unwrap().1;
+        }
+    };
+    assert_eq!(
+        SignalingCommand::decode(response.payload.as_slice()),
+        Ok(SignalingCommand::ConnectionParameterUpdateResponse {
+            identifier,
+            accepted: true,
+        })
+    );
+    loop {
+        if let Some(PollResult::Event(EventType::ConnectionUpdateComplete {
+            status,
+            interval,
+            ..
+        })) = peripheral.poll()
+        {
+            assert_eq!((status, interval), (0, 12));
+            break;
+        }
+    }
+}
+
+#[test]
+fn virtual_controller_reports_link_quality() {
+    let link = VirtualLink::new();
+    let (peripheral_controller, central_controller) = link.controllers();
+    let mut peripheral = Ble::new(&peripheral_controller);
+    let mut central = Ble::new(&central_controller);
+
+    peripheral.init().unwrap();
+    central.init().unwrap();
+    assert_eq!(central.controller_info().version.hci_version, 0x0c);
+    assert!(central
+        .controller_info()
+        .le_features
+        .supports(LeFeature::DataPacketLengthExtension));
+
+    peripheral.cmd_set_le_advertising_parameters().unwrap();
+    peripheral.cmd_set_le_advertise_enable(true).unwrap();
+    let params =
+        CreateConnectionParameters::new(PeerAddressType::Public, peripheral_controller.address());
+    let handle = central.connect(&params, 1000).unwrap().handle;
+
+    assert_eq!(
+        central.link_quality(handle).unwrap(),
+        LinkQuality {
+            rssi: -50,
+            channel_map: ChannelMap([0xff, 0xff, 0xff, 0xff, 0x1f]),
+        }
+    );
+    assert_matches!(
+        central.link_quality(handle + 1),
+        Err(bleps::Error::CommandFailed {
+            opcode: 0x1405,
+            status: 0x02
+        })
+    );
+
+    central.cmd_le_read_remote_features(handle).unwrap();
+    loop {
+        if let Some(PollResult::Event(EventType::ReadRemoteFeaturesComplete {
+            status,
+            features,
+            ..
+        })) = central.poll()
+        {
+            assert_eq!(status, 0);
+            assert!(LeFeatures(features).supports(LeFeature::ConnectionParametersRequest));
+            break;
+        }
+    }
+}
+
+#[test]
+fn virtual_controller_runs_attribute_server() {
+    let link = VirtualLink::new();
+    let (peripheral_controller, central_controller) = link.controllers();
+    let mut peripheral = Ble::new(&peripheral_controller);
+    let mut central = Ble::new(&central_controller);
+
+    peripheral.init().unwrap();
+    central.init().unwrap();
+    peripheral.cmd_set_le_advertise_enable(true).unwrap();
+
+    let value_data = [0x42];
+    let mut value_att_data = &value_data;
+    let attributes = &mut [Attribute::new(Uuid::Uuid16(0x2a19), &mut value_att_data)];
+    let mut rng = OsRng::default();
+    let mut srv = AttributeServer::new(&mut peripheral, attributes, &mut rng);
+
+    let params =
+        CreateConnectionParameters::new(PeerAddressType::Public, peripheral_controller.address());
+    let connection = central.connect(&params, 1000).unwrap();
+    assert_matches!(srv.do_work(), Ok(_));
+    assert_eq!(srv.connections().count(), 1);
+
+    // ReadReq { handle: 0x0001 }
+    central
+        .write_l2cap(
+            connection.handle,
+            L2capPacket::encode(Data::new(&[0x0a, 0x01, 0x00])),
+        )
+        .unwrap();
+    assert_matches!(srv.do_work(), Ok(_));
+
+    let mut response = None;
+    while response.is_none() {
+        if let Some(PollResult::AsyncData(packet)) = central.poll() {
+            response = Some(L2capPacket::decode(packet).unwrap().1.payload);
+        }
+    }
+    assert_eq!(response.unwrap().as_slice(), &[0x0b, 0x42]);
+}
+
+/// Runs a future to completion by polling it until it's ready
+#[cfg(feature = "async")]
+fn block_on<F: core::future::Future>(future: F) -> F::Output {
+    let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+    let mut future = std::pin::pin!(future);
+    loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn virtual_controller_runs_async_gatt_client() {
+    use bleps::{
+        async_attribute_server, asynch, clock::ClockWithDelay, gatt_client::AsyncGattClient,
+    };
+    use futures::future::{select, Either};
+
+    fn millis() -> u64 {
+        static MILLIS: AtomicU64 = AtomicU64::new(0);
+        MILLIS.fetch_add(1, Ordering::Relaxed)
+    }
+
+    let link = VirtualLink::new();
+    let (peripheral_controller, central_controller) = link.controllers();
+    let params =
+        CreateConnectionParameters::new(PeerAddressType::Public, peripheral_controller.address());
+    let mut peripheral = asynch::Ble::new(
+        peripheral_controller,
+        ClockWithDelay::new(millis, YieldDelay),
+    );
+    let mut central = asynch::Ble::new(central_controller, ClockWithDelay::new(millis, YieldDelay));
+
+    let srv_data = [0x0f, 0x18];
+    let mut srv_att_data = &srv_data;
+    let char_data = [0x02, 0x03, 0x00, 0x19, 0x2a];
+    let mut char_att_data = &char_data;
+    let value_data = [0x42];
+    let mut value_att_data = &value_data;
+    let attributes = &mut [
+        Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_att_data),
+        Attribute::new(CHARACTERISTIC_UUID16, &mut char_att_data),
+        Attribute::new(Uuid::Uuid16(0x2a19), &mut value_att_data),
+    ];
+    let mut rng = OsRng::default();
+
+    block_on(async {
+        peripheral.init().await.unwrap();
+        central.init().await.unwrap();
+        peripheral.cmd_set_le_advertise_enable(true).await.unwrap();
+        let connection = central.connect(&params, 1000).await.unwrap();
+
+        let mut srv =
+            async_attribute_server::AttributeServer::new(&mut peripheral, attributes, &mut rng);
+        let mut client = AsyncGattClient::new(&mut central, connection.handle);
+
+        let server = async {
+            loop {
+                srv.do_work().await.unwrap();
+            }
+        };
+        let client = async {
+            let mut services = [Service::default(); 2];
+            let count = client.discover_services(&mut services).await.unwrap();
+            assert_eq!(count, 1);
+            assert_eq!(services[0].uuid, Uuid::Uuid16(0x180f));
+
+            let mut buffer = [0u8; 4];
+            let len = client.read(0x0003, &mut buffer).await.unwrap();
+            assert_eq!(&buffer[..len], &[0x42]);
+        };
+
+        let server = core::pin::pin!(server);
+        let client = core::pin::pin!(client);
+        assert!(matches!(select(server, client).await, Either::Right(_)));
+    });
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn virtual_controller_streams_over_l2cap_channel() {
+    use bleps::{asynch, clock::ClockWithDelay, l2cap_channel::AsyncL2capChannel};
+    use embedded_io_async::{Read, Write};
+
+    fn millis() -> u64 {
+        static MILLIS: AtomicU64 = AtomicU64::new(0);
+        MILLIS.fetch_add(1, Ordering::Relaxed)
+    }
+
+    let link = VirtualLink::new();
+    let (peripheral_controller, central_controller) = link.controllers();
+    let params =
+        CreateConnectionParameters::new(PeerAddressType::Public, peripheral_controller.address());
+    let mut peripheral = asynch::Ble::new(
+        peripheral_controller,
+        ClockWithDelay::new(millis, YieldDelay),
+    );
+    let mut central = asynch::Ble::new(central_controller, ClockWithDelay::new(millis, YieldDelay));
+
+    let sent: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+    let mut peripheral_buffer = [0u8; 100];
+    let mut central_buffer = [0u8; 64];
+
+    block_on(async {
+        peripheral.init().await.unwrap();
+        central.init().await.unwrap();
+        peripheral.cmd_set_le_advertise_enable(true).await.unwrap();
+        let connection = central.connect(&params, 1000).await.unwrap();
+
+        let mut server =
+            AsyncL2capChannel::new(&mut peripheral, connection.handle, &mut peripheral_buffer);
+        let mut client =
+            AsyncL2capChannel::new(&mut central, connection.handle, &mut central_buffer);
+
+        let server = async {
+            server.accept(0x0080).await.unwrap();
+            let mut received = Vec::new();
+            let mut data = [0u8; 32];
+            loop {
+                match server.read(&mut data).await.unwrap() {
+                    0 => break received,
+                    len => received.extend_from_slice(&data[..len]),
+                }
+            }
+        };
+        let client = async {
+            client.connect(0x0080).await.unwrap();
+            client.write_all(&sent).await.unwrap();
+            client.disconnect().await.unwrap();
+            assert!(!client.is_open());
+        };
+
+        let (received, _) = futures::future::join(server, client).await;
+        assert_eq!(received, sent);
+    });
+}
+
+/// A delay which only yields once to let other futures run
+#[cfg(feature = "async")]
+struct YieldDelay;
+
+#[cfg(feature = "async")]
+impl embedded_hal_async::delay::DelayNs for YieldDelay {
+    async fn delay_ns(&mut self, _ns: u32) {
+        let mut yielded = false;
+        core::future::poll_fn(|cx| {
+            if yielded {
+                return std::task::Poll::Ready(());
+            }
+            yielded = true;
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        })
+        .await
+    }
+}
+
+/// An async transport on which the controller never answers
+#[cfg(feature = "async")]
+struct SilentController;
+
+#[cfg(feature = "async")]
+impl embedded_io_async::ErrorType for SilentController {
+    type Error = core::convert::Infallible;
+}
+
+#[cfg(feature = "async")]
+impl embedded_io_async::Read for SilentController {
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, Self::Error> {
+        core::future::pending().await
+    }
+}
+
+#[cfg(feature = "async")]
+impl embedded_io_async::Write for SilentController {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        Ok(buf.len())
+    }
+}
+
+/// A clock which only advances while sleeping
+#[cfg(feature = "async")]
+struct SleepingClock<'a> {
+    now: &'a Cell<u64>,
+    sleeps: &'a Cell<usize>,
+}
+
+#[cfg(feature = "async")]
+impl<'a> bleps::clock::Clock for SleepingClock<'a> {
+    fn now_millis(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a> embedded_hal_async::delay::DelayNs for SleepingClock<'a> {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.delay_ms(ns / 1_000_000).await
+    }
+
+    async fn delay_ms(&mut self, ms: u32) {
+        self.now.set(self.now.get() + ms as u64);
+        self.sleeps.set(self.sleeps.get() + 1);
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_commands_time_out_while_sleeping() {
+    let now = Cell::new(0);
+    let sleeps = Cell::new(0);
+    let clock = SleepingClock {
+        now: &now,
+        sleeps: &sleeps,
+    };
+    let mut ble = bleps::asynch::Ble::new(SilentController, clock);
+    assert!(ble.command_timeouts_mut().set(0x0c03, 5000));
+
+    let res = block_on(ble.cmd_reset());
+
+    assert_matches!(res, Err(bleps::Error::CommandTimeout { opcode: 0x0c03 }));
+    assert_eq!(sleeps.get(), 1);
+    assert_eq!(now.get(), 5001);
+}
+
+/// An async transport reading scripted bytes, reads wait forever once all were read
+#[cfg(feature = "async")]
+struct ScriptedController<'a> {
+    to_read: &'a RefCell<std::collections::VecDeque<u8>>,
+    written: &'a RefCell<Vec<u8>>,
+}
+
+#[cfg(feature = "async")]
+impl embedded_io_async::ErrorType for ScriptedController<'_> {
+    type Error = core::convert::Infallible;
+}
+
+#[cfg(feature = "async")]
+impl embedded_io_async::Read for ScriptedController<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.to_read.borrow().is_empty() {
+            return core::future::pending().await;
+        }
+
+        let mut to_read = self.to_read.borrow_mut();
+        let len = buf.len().min(to_read.len());
+        for (dst, src) in buf.iter_mut().zip(to_read.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "async")]
+impl embedded_io_async::Write for ScriptedController<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.written.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn l2cap_channel_keeps_att_requests_for_the_attribute_server() {
+    use bleps::{async_attribute_server, l2cap_channel::AsyncL2capChannel};
+    use embedded_io_async::Read;
+
+    let to_read = RefCell::new(std::collections::VecDeque::new());
+    let written = RefCell::new(Vec::new());
+    let now = Cell::new(0);
+    let sleeps = Cell::new(0);
+    let controller = ScriptedController {
+        to_read: &to_read,
+        written: &written,
+    };
+    let clock = SleepingClock {
+        now: &now,
+        sleeps: &sleeps,
+    };
+    let mut ble = bleps::asynch::Ble::new(controller, clock);
+
+    // LE Credit Based Connection Request for LE_PSM 0x80 with MTU 512, MPS 64, 2 credits
+    to_read.borrow_mut().extend([
+        0x02, 0x40, 0x20, 0x12, 0x00, 0x0e, 0x00, 0x05, 0x00, 0x14, 0x01, 0x0a, 0x00, 0x80, 0x00,
+        0x41, 0x00, 0x00, 0x02, 0x40, 0x00, 0x02, 0x00,
+    ]);
+    // ATT Read Request for handle 0x0003 arriving in between the K-frames
+    to_read.borrow_mut().extend([
+        0x02, 0x40, 0x20, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x0a, 0x03, 0x00,
+    ]);
+    // a single K-frame SDU
+    to_read.borrow_mut().extend([
+        0x02, 0x40, 0x20, 0x0b, 0x00, 0x07, 0x00, 0x40, 0x00, 0x05, 0x00, b'h', b'e', b'l', b'l',
+        b'o',
+    ]);
+
+    block_on(async {
+        let mut buffer = [0u8; 64];
+        let mut channel = AsyncL2capChannel::new(&mut ble, 0x0040, &mut buffer);
+        channel.accept(0x0080).await.unwrap();
+        let mut data = [0u8; 16];
+        let len = channel.read(&mut data).await.unwrap();
+        assert_eq!(&data[..len], b"hello");
+    });
+    written.borrow_mut().clear();
+
+    let srv_data = [0x0f, 0x18];
+    let mut srv_att_data = &srv_data;
+    let char_data = [0x02, 0x03, 0x00, 0x19, 0x2a];
+    let mut char_att_data = &char_data;
+    let value_data = [0x42];
+    let mut value_att_data = &value_data;
+    let attributes = &mut [
+        Attribute::new(PRIMARY_SERVICE_UUID16, &mut srv_att_data),
+        Attribute::new(CHARACTERISTIC_UUID16, &mut char_att_data),
+        Attribute::new(Uuid::Uuid16(0x2a19), &mut value_att_data),
+    ];
+    let mut rng = OsRng::default();
+    let mut srv = async_attribute_server::AttributeServer::new(&mut ble, attributes, &mut rng);
+
+    // the request held back by the channel is answered
+    assert_matches!(block_on(srv.do_work()), Ok(WorkResult::DidWork));
+    assert_eq!(
+        written.borrow().as_slice(),
+        &[0x02, 0x40, 0x20, 0x06, 0x00, 0x02, 0x00, 0x04, 0x00, 0x0b, 0x42]
+    );
+}
+
+#[test]
+fn hci_connector_takes_closure_as_clock() {
+    let now = Cell::new(42);
+    let connector = HciConnector::new(MockSerial::new(&[]), || now.get());
+
+    assert_eq!(connector.millis(), 42);
+    now.set(1000);
+    assert_eq!(connector.millis(), 1000);
 }
@@ -11,11 +11,14 @@ use bleps::{
         create_advertising_data, AdStructure, BR_EDR_NOT_SUPPORTED, LE_GENERAL_DISCOVERABLE,
     },
     attribute_server::{AttributeServer, NotificationData, WorkResult},
-    gatt, Addr, Ble, HciConnector,
+    gatt,
+    h4::H4Transport,
+    Addr, Ble,
 };
 use embedded_io_adapters::std::FromStd;
-use embedded_io_blocking::{Error, ErrorType, Read, Write};
+use embedded_io_blocking::{ErrorType, Read, ReadReady, Write};
 use rand_core::OsRng;
+use serialport::SerialPort;
 
 fn main() {
     env_logger::init();
@@ -34,7 +37,7 @@ fn main() {
 
     println!("Reset the target");
 
-    let mut serial = FromStd::new(port);
+    let mut serial = Serial(FromStd::new(port));
 
     let mut buffer = [0u8; 1];
 
@@ -57,8 +60,7 @@ fn main() {
     let mut ltk = None;
 
     loop {
-        let connector = BleConnector::new(&mut serial);
-        let hci = HciConnector::new(connector, current_millis);
+        let hci = H4Transport::new(&mut serial, current_millis);
         let mut ble = Ble::new(&hci);
 
         println!("{:?}", ble.init());
@@ -231,64 +233,32 @@ fn current_millis() -> u64 {
     std::time::Instant::now().elapsed().as_millis() as u64
 }
 
-pub struct BleConnector<'a, T> {
-    connection: &'a mut T,
-}
-
-impl<'a, T> BleConnector<'a, T>
-where
-    T: Read + Write,
-{
-    fn new(connection: &'a mut T) -> Self {
-        Self { connection }
-    }
-}
+/// The serial port, which tells `H4Transport` if there are bytes to read so polling doesn't
+/// block until the port's timeout
+struct Serial(FromStd<Box<dyn SerialPort>>);
 
-#[derive(Debug)]
-pub enum BleConnectorError {
-    Unknown,
+impl ErrorType for Serial {
+    type Error = std::io::Error;
 }
 
-impl Error for BleConnectorError {
-    fn kind(&self) -> embedded_io_blocking::ErrorKind {
-        embedded_io_blocking::ErrorKind::Other
+impl Read for Serial {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0.read(buf)
     }
 }
 
-impl<'a, T> ErrorType for BleConnector<'a, T>
-where
-    T: Read + Write,
-{
-    type Error = BleConnectorError;
-}
-
-impl<'a, T> Read for BleConnector<'a, T>
-where
-    T: Read + Write,
-{
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        match self.connection.read(buf) {
-            Ok(len) => Ok(len),
-            Err(_) => Err(BleConnectorError::Unknown),
-        }
+impl ReadReady for Serial {
+    fn read_ready(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.0.inner().bytes_to_read()? > 0)
     }
 }
 
-impl<'a, T> Write for BleConnector<'a, T>
-where
-    T: Read + Write,
-{
+impl Write for Serial {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        match self.connection.write(buf) {
-            Ok(len) => Ok(len),
-            Err(_) => Err(BleConnectorError::Unknown),
-        }
+        self.0.write(buf)
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
-        match self.connection.flush() {
-            Ok(v) => Ok(v),
-            Err(_) => Err(BleConnectorError::Unknown),
-        }
+        self.0.flush()
     }
 }
@@ -158,6 +158,9 @@ fn ble_server<'a>(
         }]
     }]);
 
+    #[cfg(feature = "crypto")]
+    let mut rng = ble.controller_rng().unwrap();
+    #[cfg(not(feature = "crypto"))]
     let mut rng = bleps::no_rng::NoRng;
     let mut srv = AttributeServer::new(&mut ble, &mut gatt_attributes, &mut rng);
 